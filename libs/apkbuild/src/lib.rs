@@ -0,0 +1,207 @@
+use pkgspec::SpecStruct;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq, SpecStruct)]
+pub struct ApkBuild {
+    /// Maintainer of the package, rendered as a comment at the top of the APKBUILD
+    maintainer: Option<String>,
+    /// Name of the package
+    pkgname: String,
+    /// The version of the software. Must be a valid version of apk like `1.2.3_rc1`
+    pkgver: String,
+    /// Release number of the package, must be an integer
+    pkgrel: String,
+    /// A brief description of the package
+    pkgdesc: String,
+    /// The url pointing to the website of the package
+    url: Option<String>,
+    /// Architectures on which the given package is available
+    arch: Vec<String>,
+    /// License of the package as an SPDX identifier
+    license: String,
+
+    /// A list of packages this package depends on to run
+    depends: Vec<String>,
+    /// A list of packages this package depends on to build
+    makedepends: Vec<String>,
+    /// A list of packages this package depends on to run it's test suite
+    checkdepends: Vec<String>,
+    /// A list of "virtual provisions" that this package provides
+    provides: Vec<String>,
+    /// Priority of this package among packages providing the same virtual provision
+    provider_priority: Option<String>,
+    /// A list of packages this package replaces
+    replaces: Vec<String>,
+    /// A list of packages that cause this package to be installed when all of them are installed
+    install_if: Vec<String>,
+    /// A list of install scripts like `$pkgname.post-install`
+    install: Vec<String>,
+    /// A list of options like `!check` or `!strip`
+    options: Vec<String>,
+
+    /// A list of source files required to build the package
+    source: Vec<String>,
+    /// A list of SHA512 hashes for every source file specified in `source` list
+    sha512sums: Vec<String>,
+
+    /// The function that is used to install files into the directory that will become the root
+    /// directory of the build package
+    package_func: String,
+    /// An optional function that extracts the sources, overrides the default one of abuild
+    unpack_func: Option<String>,
+    /// An optional function that prepares the sources for the building
+    prepare_func: Option<String>,
+    /// An optional function used to compile and adjust source files in preparation for install
+    build_func: Option<String>,
+    /// An optional function that should test the functionality of the package before installation
+    check_func: Option<String>,
+}
+
+impl ApkBuild {
+    /// Renders this APKBUILD and saves it to the given path
+    pub fn save_to<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(path, self.render())
+    }
+
+    /// Renders this APKBUILD
+    pub fn render(&self) -> String {
+        let mut apk = String::new();
+
+        macro_rules! push_field {
+            ($field:ident) => {
+                apk.push_str(&format!("{}=\"{}\"\n", stringify!($field), &self.$field));
+            };
+        }
+
+        macro_rules! push_if_some {
+            ($field:ident) => {
+                if let Some(value) = &self.$field {
+                    apk.push_str(&format!("{}=\"{}\"\n", stringify!($field), value));
+                }
+            };
+        }
+
+        macro_rules! push_list {
+            ($field:ident) => {
+                if !self.$field.is_empty() {
+                    apk.push_str(&format!(
+                        "{}=\"{}\"\n",
+                        stringify!($field),
+                        self.$field.join(" ")
+                    ));
+                }
+            };
+        }
+
+        macro_rules! push_func {
+            ($name:expr, $body:expr) => {
+                apk.push_str(&format!("\n{}() {{\n{}\n}}\n", $name, $body));
+            };
+        }
+
+        if let Some(maintainer) = &self.maintainer {
+            apk.push_str(&format!("# Maintainer: {}\n", maintainer));
+        }
+        push_field!(pkgname);
+        push_field!(pkgver);
+        push_field!(pkgrel);
+        push_field!(pkgdesc);
+        push_if_some!(url);
+        push_list!(arch);
+        push_field!(license);
+        push_list!(depends);
+        push_list!(makedepends);
+        push_list!(checkdepends);
+        push_list!(provides);
+        push_if_some!(provider_priority);
+        push_list!(replaces);
+        push_list!(install_if);
+        push_list!(install);
+        push_list!(options);
+        push_list!(source);
+
+        if let Some(unpack) = &self.unpack_func {
+            push_func!("unpack", unpack);
+        }
+        if let Some(prepare) = &self.prepare_func {
+            push_func!("prepare", prepare);
+        }
+        if let Some(build) = &self.build_func {
+            push_func!("build", build);
+        }
+        if let Some(check) = &self.check_func {
+            push_func!("check", check);
+        }
+        push_func!("package", &self.package_func);
+
+        if !self.sha512sums.is_empty() {
+            apk.push_str(&format!(
+                "\nsha512sums=\"\n{}\n\"\n",
+                self.sha512sums.join("\n")
+            ));
+        }
+
+        apk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn builds_an_apkbuild() {
+        let got = ApkBuild::builder()
+            .maintainer("Wojciech Kępka <wojciech@wkepka.dev>")
+            .pkgname("apkbuild")
+            .pkgver("0.1.0")
+            .pkgrel("1")
+            .pkgdesc("short description...")
+            .url("https://github.com/wojciechkepka/pkger")
+            .add_arch_entries(vec!["x86_64"])
+            .license("MIT")
+            .add_depends_entries(vec!["musl", "openssl"])
+            .add_install_entries(vec!["apkbuild.post-install"])
+            .add_options_entries(vec!["!check", "!strip"])
+            .add_source_entries(vec!["apkbuild-0.1.0.tar.gz"])
+            .add_sha512sums_entries(vec!["abc123  apkbuild-0.1.0.tar.gz"])
+            .unpack_func("    :")
+            .package_func("    echo test")
+            .build()
+            .render();
+
+        let expect = r#"# Maintainer: Wojciech Kępka <wojciech@wkepka.dev>
+pkgname="apkbuild"
+pkgver="0.1.0"
+pkgrel="1"
+pkgdesc="short description..."
+url="https://github.com/wojciechkepka/pkger"
+arch="x86_64"
+license="MIT"
+depends="musl openssl"
+install="apkbuild.post-install"
+options="!check !strip"
+source="apkbuild-0.1.0.tar.gz"
+
+unpack() {
+    :
+}
+
+package() {
+    echo test
+}
+
+sha512sums="
+abc123  apkbuild-0.1.0.tar.gz
+"
+"#;
+
+        assert_eq!(expect, got);
+    }
+}
@@ -36,6 +36,9 @@ pub struct BinaryDebControl {
     /// The URL of the web site for this package
     homepage: Option<String>,
     built_using: Option<String>,
+    /// How the package behaves when installed for multiple architectures on multiarch systems,
+    /// one of `same`, `foreign`, `allowed` or `no`
+    multi_arch: Option<String>,
 
     /// This is a boolean field which may occur only in the control file of a binary package or in a per-package fields
     /// paragraph of a source package control file.
@@ -50,6 +53,11 @@ pub struct BinaryDebControl {
     provides: Vec<String>,
     replaces: Vec<String>,
     enchances: Vec<String>,
+
+    /// Fields that have no dedicated field in this struct, rendered verbatim after the other
+    /// fields
+    #[skip]
+    extra_fields: Vec<String>,
 }
 
 impl BinaryDebControl {
@@ -120,6 +128,7 @@ Essential:      {}
         if_some_push!(installed_size,      "Installed-Size: {}\n");
         if_some_push!(homepage,            "Homepage:       {}\n");
         if_some_push!(built_using,         "Built-Using:    {}\n");
+        if_some_push!(multi_arch,          "Multi-Arch:     {}\n");
         if_not_empty_entries!(pre_depends, "Pre-Depends:    {}\n");
         if_not_empty_entries!(depends,     "Depends:        {}\n");
         if_not_empty_entries!(recommends,  "Recommends:     {}\n");
@@ -130,11 +139,29 @@ Essential:      {}
         if_not_empty_entries!(replaces,    "Replaces:       {}\n");
         if_not_empty_entries!(enchances,   "Enchances:      {}\n");
         };
+        for field in &self.extra_fields {
+            control.push_str(field);
+            control.push('\n');
+        }
 
         control
     }
 }
 
+impl BinaryDebControlBuilder {
+    /// Adds a field that has no dedicated field, like `XB-Custom-Field`
+    pub fn add_field<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.inner
+            .extra_fields
+            .push(format!("{}: {}", name.as_ref(), value.as_ref()));
+        self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,6 +183,7 @@ mod tests {
             installed_size: Some("1Mb".to_string()),
             homepage: Some("https://github.com/wojciechkepka/debcontrol".to_string()),
             built_using: Some("rustc".to_string()),
+            multi_arch: Some("same".to_string()),
             essential: true,
             pre_depends: vec!["rustc".to_string(), "cargo".to_string()],
             depends: vec!["rustc".to_string(), "cargo".to_string()],
@@ -170,6 +198,7 @@ mod tests {
             ],
             replaces: vec!["rustc".to_string(), "cargo".to_string()],
             enchances: vec!["rustc".to_string(), "cargo".to_string()],
+            extra_fields: vec![],
         };
         const OUT: &str = r#"Package:        debcontrol
 Version:        1.0.0-0
@@ -182,6 +211,7 @@ Section:        devel
 Installed-Size: 1Mb
 Homepage:       https://github.com/wojciechkepka/debcontrol
 Built-Using:    rustc
+Multi-Arch:     same
 Pre-Depends:    rustc, cargo
 Depends:        rustc, cargo
 Conflicts:      rustc, cargo
@@ -200,6 +230,7 @@ Enchances:      rustc, cargo
             .section("devel")
             .homepage("https://github.com/wojciechkepka/debcontrol")
             .built_using("rustc")
+            .multi_arch("same")
             .add_pre_depends_entries(vec!["rustc", "cargo"])
             .add_depends_entries(vec!["rustc", "cargo"])
             .add_conflicts_entries(vec!["rustc", "cargo"])
@@ -231,4 +262,15 @@ Essential:      no
             .render();
         assert_eq!(EXPECT, got);
     }
+
+    #[test]
+    fn renders_extra_fields() {
+        let got = DebControlBuilder::binary_package_builder("test")
+            .version("1.0.0")
+            .add_depends_entries(vec!["libc6"])
+            .add_field("XB-Custom-Field", "value")
+            .build()
+            .render();
+        assert!(got.ends_with("Depends:        libc6\nXB-Custom-Field: value\n"));
+    }
 }
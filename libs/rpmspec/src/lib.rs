@@ -108,6 +108,9 @@ pub struct RpmSpec {
     /// User defined macros
     #[skip]
     macros: Vec<String>,
+    /// Tags that have no field in this struct, rendered verbatim after the other tags
+    #[skip]
+    extra_tags: Vec<String>,
 }
 
 impl RpmSpec {
@@ -192,6 +195,7 @@ impl RpmSpec {
         if_not_empty_entries!(build_requires, "BuildRequires: {}\n");
         if_not_empty_entries!(..i patches,    "Patch{}:        {}\n");
         if_not_empty_entries!(..i sources,    "Source{}:       {}\n");
+        if_not_empty_entries!(extra_tags,     "{}\n");
         spec.push_str(&format!("\n%description\n{}\n\n", self.description));
         if_some_script!("prep", prep_script);
         if_some_script!("build", build_script);
@@ -236,6 +240,18 @@ impl RpmSpecBuilder {
         self.inner.macros.push(_macro);
         self
     }
+
+    /// Adds a tag that has no dedicated field, like `DistTag`
+    pub fn add_tag<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.inner
+            .extra_tags
+            .push(format!("{}: {}", name.as_ref(), value.as_ref()));
+        self
+    }
 }
 
 #[cfg(test)]
@@ -333,6 +349,7 @@ install -m 644 README /docs/README"#;
                 "githash 0ab32f".to_string(),
                 "python(-c) import os".to_string(),
             ],
+            extra_tags: vec![],
         };
 
         assert_eq!(expect, spec);
@@ -410,4 +427,18 @@ LICENSE
         let got = spec.render();
         assert_eq!(expect_rendered, got);
     }
+
+    #[test]
+    fn renders_extra_tags() {
+        let spec = RpmSpec::builder()
+            .name("rpmspec")
+            .version("0.1.0")
+            .release("1")
+            .add_sources_entries(vec!["source.tar.gz"])
+            .add_tag("DistTag", "rhel8")
+            .build()
+            .render();
+
+        assert!(spec.contains("Source0:       source.tar.gz\nDistTag: rhel8\n\n%description\n"));
+    }
 }
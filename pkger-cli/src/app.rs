@@ -1,21 +1,52 @@
+use crate::audit;
+use crate::badge::{self, BadgeFormat};
+use crate::bump;
+use crate::changes;
 use crate::config::Configuration;
 use crate::gen;
-use crate::job::{JobCtx, JobResult};
-use crate::opts::{BuildOpts, Commands, ListObject, Opts};
-use pkger_core::build::Context;
-use pkger_core::docker::DockerConnectionPool;
-use pkger_core::image::{state::DEFAULT_STATE_FILE, Image, ImagesState};
-use pkger_core::recipe::{self, BuildTarget, ImageTarget, Recipe};
-use pkger_core::{ErrContext, Result};
-
-use futures::stream::FuturesUnordered;
+use crate::import;
+use crate::job::{JobCtx, JobQueue, JobResult, StageOutput};
+use crate::opts::{
+    BuildOpts, CacheCommand, CacheOpts, Commands, ExportFormat, ExportOpts, ListObject, Opts,
+    OwnersOpts, PrepareImagesOpts, RepoOpts, StateCommand,
+};
+use crate::report::{self, ReportFormat};
+use crate::run_archive::RunArchive;
+use crate::test_recipes;
+use crate::vault;
+use crate::webhook;
+use pkger_core::build::{
+    cache::ArtifactCache, credentials::SourceCredentials, lock::Lockfile,
+    publish::ArtifactPublisher, Context,
+};
+use pkger_core::docker::{Docker, DockerConnectionPool, Runtime};
+use pkger_core::failure::{self, FailureKind};
+use pkger_core::image::{
+    backend::{FileBackend, StateBackend, StateBackendConfig},
+    gc,
+    state::{prune_missing, DEFAULT_STATE_FILE},
+    transfer, Image, ImageState, ImagesState, MUSL_IMAGE, SANDBOX_IMAGE,
+};
+use pkger_core::recipe::{self, BuildTarget, ImageTarget, Recipe, RecipeTarget};
+use pkger_core::repo::{self, Changes, RepoType};
+use pkger_core::units::HumanDuration;
+use pkger_core::{ErrContext, Error, Result};
+
+use chrono::{DateTime, Local};
+use futures::future::{self, Either};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 use tempdir::TempDir;
-use tokio::task;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
 use tracing::{error, info, info_span, trace, warn, Instrument};
 
 fn set_ctrlc_handler(is_running: Arc<AtomicBool>) {
@@ -27,6 +58,25 @@ fn set_ctrlc_handler(is_running: Arc<AtomicBool>) {
     };
 }
 
+/// Clears `jobs_running` once `is_running` is cleared by the ctrl-c handler or the build is
+/// `preempted`. This way jobs of a single build can be stopped without stopping the whole
+/// application.
+fn forward_interrupts(
+    is_running: Arc<AtomicBool>,
+    preempted: Arc<AtomicBool>,
+    jobs_running: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        while jobs_running.load(Ordering::SeqCst) {
+            if !is_running.load(Ordering::SeqCst) || preempted.load(Ordering::SeqCst) {
+                jobs_running.store(false, Ordering::SeqCst);
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+}
+
 fn create_app_dirs() -> Result<TempDir> {
     let tempdir = TempDir::new("pkger")?;
     let pkger_dir = tempdir.path();
@@ -38,6 +88,184 @@ fn create_app_dirs() -> Result<TempDir> {
     Ok(tempdir)
 }
 
+static STATE_FILE: &str = "images.state";
+/// Interval of heartbeat lines of running builds in CI
+static DEFAULT_HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Returns the interval of heartbeat lines of running builds. Heartbeats are printed by default
+/// when running in CI or when the output is not a terminal, an interval of 0 disables them.
+fn heartbeat_interval(secs: Option<u64>) -> Option<Duration> {
+    match secs {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None if env::var_os("CI").is_some() || !io::stdout().is_terminal() => {
+            Some(DEFAULT_HEARTBEAT)
+        }
+        None => None,
+    }
+}
+
+/// Returns the directory where the state should be kept. If not specified in the configuration
+/// `$XDG_STATE_HOME/pkger` is used falling back to `~/.local/state/pkger`.
+fn state_dir(config: &Configuration) -> Option<PathBuf> {
+    if let Some(dir) = &config.state_dir {
+        return Some(dir.clone());
+    }
+
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs_next::home_dir().map(|home| home.join(".local").join("state")))
+        .map(|dir| dir.join("pkger"))
+}
+
+/// Finds the location of the state file, moving the legacy state file from the current
+/// directory to the state directory if necessary.
+fn state_file(config: &Configuration) -> PathBuf {
+    let span = info_span!("state-file");
+    let _enter = span.enter();
+
+    let state_dir = match state_dir(config) {
+        Some(dir) => dir,
+        None => {
+            warn!("no state directory available, using legacy state file");
+            return PathBuf::from(DEFAULT_STATE_FILE);
+        }
+    };
+    let state_file = state_dir.join(STATE_FILE);
+
+    if let Err(e) = fs::create_dir_all(&state_dir) {
+        warn!(dir = %state_dir.display(), reason = %e, "failed to create state directory, using legacy state file");
+        return PathBuf::from(DEFAULT_STATE_FILE);
+    }
+
+    let legacy = PathBuf::from(DEFAULT_STATE_FILE);
+    if legacy.exists() && !state_file.exists() {
+        info!(from = %legacy.display(), to = %state_file.display(), "migrating legacy state file");
+        let result = fs::rename(&legacy, &state_file).or_else(|_| {
+            // rename doesn't work across filesystems
+            fs::copy(&legacy, &state_file).and_then(|_| fs::remove_file(&legacy))
+        });
+        if let Err(e) = result {
+            warn!(reason = %e, "failed to migrate legacy state file");
+            return legacy;
+        }
+    }
+
+    trace!(state_file = %state_file.display());
+    state_file
+}
+
+/// Loads the images state from the configured backend. A corrupted local state is replaced by an
+/// empty one, while a shared backend that fails to load is an error so that it isn't overwritten.
+fn load_images_state(config: &Configuration) -> Result<ImagesState> {
+    let backend: Arc<dyn StateBackend> = match &config.state_backend {
+        None | Some(StateBackendConfig::File { path: None }) => {
+            Arc::new(FileBackend::new(state_file(config)))
+        }
+        Some(backend) => backend.backend(Path::new(DEFAULT_STATE_FILE))?,
+    };
+    trace!(backend = ?backend);
+    match ImagesState::load(backend.clone()) {
+        Ok(state) => Ok(state),
+        Err(e) if backend.is_local() => {
+            warn!(reason = %e, "failed to load image state, starting with an empty state");
+            Ok(ImagesState::empty(backend))
+        }
+        Err(e) => Err(e.context("failed to load image state")),
+    }
+}
+
+/// Deprecated recipes are left out of builds of all or changed recipes unless named explicitly
+fn is_deprecated(recipe: &Recipe) -> bool {
+    if recipe.metadata.deprecated.is_some() {
+        info!(recipe = %recipe.metadata.name, "skipping deprecated recipe");
+        true
+    } else {
+        false
+    }
+}
+
+/// Key of the build stage whose output is packaged by the build of `recipe` for `target`. Recipes
+/// with a build stage share one across all images, images listing multiple package formats
+/// built from the scripts get a stage of their own.
+fn build_stage_key(recipe: &Recipe, target: &ImageTarget) -> Option<String> {
+    if recipe.metadata.has_build_stage() {
+        return Some(recipe.metadata.name.clone());
+    }
+    let scripted = |build_target: &BuildTarget| !recipe.uses_native_packaging(build_target);
+    let formats = recipe
+        .metadata
+        .build_targets_of(&target.image)
+        .into_iter()
+        .filter(|build_target| scripted(build_target))
+        .count();
+    if formats > 1 && scripted(&target.build_target) {
+        // names of recipes can't contain `/`
+        Some(format!("{}/{}", recipe.metadata.name, target.image))
+    } else {
+        None
+    }
+}
+
+/// Image target on which the build stage of `recipe` runs for the build of `target`, see
+/// [`build_stage_key`]
+fn build_stage_target(recipe: &Recipe, target: &ImageTarget) -> ImageTarget {
+    match &recipe.metadata.build_image {
+        // images with multiple package formats build with the first one listed
+        _ if !recipe.metadata.has_build_stage() => recipe
+            .metadata
+            .images
+            .iter()
+            .flatten()
+            .find(|image_target| {
+                image_target.image == target.image
+                    && !recipe.uses_native_packaging(&image_target.build_target)
+            })
+            .unwrap_or(target)
+            .clone(),
+        Some(build_image) => recipe
+            .metadata
+            .images
+            .iter()
+            .flatten()
+            .find(|image_target| &image_target.image == build_image)
+            .cloned()
+            .unwrap_or_else(|| ImageTarget::new(build_image, BuildTarget::default(), None::<&str>)),
+        // the `static` preset builds on the musl image unless a build image is set
+        None => ImageTarget::new(MUSL_IMAGE, BuildTarget::Gzip, Some("alpine")),
+    }
+}
+
+fn short_id(id: &str) -> &str {
+    let id = id.trim_start_matches("sha256:");
+    &id[..id.len().min(12)]
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn print_image_state(recipe: &str, target: &str, state: &ImageState) {
+    let mut deps = state.deps.iter().map(String::as_str).collect::<Vec<_>>();
+    deps.sort_unstable();
+
+    println!("image:      {}:{}", state.image, state.tag);
+    println!("recipe:     {}", recipe);
+    println!("target:     {}", target);
+    println!("id:         {}", state.id);
+    println!("os:         {} {}", state.os.name(), state.os.version());
+    println!("simple:     {}", state.simple);
+    println!("created:    {}", format_time(state.timestamp));
+    if let Some(last_used) = state.last_used {
+        println!("last used:  {}", format_time(last_used));
+    }
+    println!("deps:       {}", deps.join(" "));
+    println!();
+}
+
 pub struct Application {
     config: Arc<Configuration>,
     recipes: Arc<recipe::Loader>,
@@ -45,9 +273,199 @@ pub struct Application {
     images_state: Arc<RwLock<ImagesState>>,
     user_images_dir: PathBuf,
     is_running: Arc<AtomicBool>,
+    /// Set by `pkger serve` to interrupt the running build for one with a higher priority
+    preempted: Arc<AtomicBool>,
     _pkger_dir: TempDir,
 }
 
+#[derive(Default)]
+/// Options of a single invocation that apply to all of its jobs
+struct BuildOptions {
+    /// Maximum number of jobs running at once
+    jobs: Option<usize>,
+    fail_fast: bool,
+    /// Directory to which transcripts of builds are written
+    emit_script: Option<PathBuf>,
+    /// Whether only the images are prepared without running any builds
+    prepare_only: bool,
+    /// Lockfile of the build and whether the build is locked
+    lockfile: Option<(Arc<Mutex<Lockfile>>, bool)>,
+    /// Format of the report of the build
+    report: Option<ReportFormat>,
+    /// Whether the logs, packaging files and manifests of the build are archived
+    archive: bool,
+    /// Formats of badges of recipes of the build
+    badges: Vec<BadgeFormat>,
+    /// Interval of heartbeat lines
+    heartbeat: Option<Duration>,
+    /// Whether builds save checkpoints
+    checkpoints: bool,
+    /// Whether builds also create source packages
+    source_package: bool,
+    /// Publishers selected for the build, all configured publishers when empty
+    publish: Vec<String>,
+}
+
+/// Creates the contexts of all jobs of one invocation with the same configuration and options
+struct ContextBuilder<'app> {
+    app: &'app Application,
+    opts: &'app BuildOptions,
+    jobs_running: Arc<AtomicBool>,
+    credentials: Arc<Vec<SourceCredentials>>,
+    cache: Option<(Arc<dyn ArtifactCache>, bool)>,
+    publishers: Vec<Arc<dyn ArtifactPublisher>>,
+}
+
+impl<'app> ContextBuilder<'app> {
+    fn new(
+        app: &'app Application,
+        opts: &'app BuildOptions,
+        jobs_running: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let publishers = match &app.config.publish {
+            Some(config) => config
+                .publishers(&opts.publish, &app.config.output_dir)
+                .context("invalid publish configuration")?,
+            None if !opts.publish.is_empty() => {
+                return Err(Error::msg(
+                    "publishing requires `publish` in the configuration",
+                ))
+            }
+            None => vec![],
+        };
+        Ok(Self {
+            app,
+            opts,
+            jobs_running,
+            credentials: Arc::new(app.config.credentials.clone()),
+            cache: app
+                .config
+                .cache
+                .as_ref()
+                .map(|config| (config.connect(), config.push)),
+            publishers,
+        })
+    }
+
+    /// Context of a job building `target` of `recipe` on `image`. Sandbox builds run on a fixture
+    /// image that is thrown away so they don't use the state, lockfile, checkpoints, cache or
+    /// publishers.
+    fn context(
+        &self,
+        recipe: Arc<Recipe>,
+        image: Image,
+        target: ImageTarget,
+        is_simple: bool,
+        is_sandbox: bool,
+    ) -> Context {
+        let app = self.app;
+        let config = &app.config;
+        let opts = self.opts;
+        let images_state = if is_sandbox {
+            Arc::new(RwLock::new(ImagesState::default()))
+        } else {
+            app.images_state.clone()
+        };
+        let allow_privileged = config.privileged_recipes.contains(&recipe.metadata.name);
+        let docker = app.docker_for(&recipe, &target);
+        let mut ctx = Context::new(
+            recipe,
+            image,
+            docker,
+            target,
+            config.output_dir.as_path(),
+            images_state,
+            self.jobs_running.clone(),
+            is_simple,
+        );
+        ctx = ctx.with_runtime(app.docker.runtime());
+        if let Some(dir) = &opts.emit_script {
+            ctx = ctx.with_transcript(dir);
+        }
+        if let Some(signing) = &config.signing {
+            ctx = ctx.with_signing(signing.clone());
+        }
+        if !self.credentials.is_empty() {
+            ctx = ctx.with_credentials(self.credentials.clone());
+        }
+        if let Some(security) = &config.security {
+            ctx = ctx.with_security(security.clone());
+        }
+        if let Some(disk) = &config.disk {
+            ctx = ctx.with_disk(disk.clone());
+        }
+        if let Some(stall_detection) = &config.stall_detection {
+            ctx = ctx.with_stall_detection(stall_detection.clone());
+        }
+        if let Some(output_limit) = &config.output_limit {
+            ctx = ctx.with_output_limit(output_limit.clone());
+        }
+        if allow_privileged {
+            ctx = ctx.with_privileged(true);
+        }
+        if opts.source_package {
+            ctx = ctx.with_source_package(true);
+        }
+        if is_sandbox {
+            return ctx;
+        }
+        if let Some((lockfile, locked)) = &opts.lockfile {
+            ctx = ctx.with_lockfile(lockfile.clone(), *locked);
+        }
+        if opts.checkpoints {
+            ctx = ctx.with_checkpoints(true);
+        }
+        if let Some((cache, push)) = &self.cache {
+            ctx = ctx.with_cache(cache.clone(), *push);
+        }
+        for publisher in &self.publishers {
+            ctx = ctx.with_publisher(publisher.clone());
+        }
+        ctx
+    }
+}
+
+/// A build triggered by a schedule or a webhook waiting to be started by `pkger serve`
+struct PendingBuild {
+    /// What triggered the build like ``schedule `0 3 * * *` ``
+    source: String,
+    /// Highest priority of the built recipes
+    priority: i32,
+    opts: BuildOpts,
+}
+
+impl PendingBuild {
+    fn new(recipes: &recipe::Loader, source: String, opts: BuildOpts) -> Self {
+        let names = if opts.all || opts.recipes.is_empty() {
+            recipes.list()
+        } else {
+            opts.recipes.clone()
+        };
+        // recipes that fail to load fail the build later
+        let priority = names
+            .iter()
+            .filter_map(|name| recipes.load(name).ok())
+            .map(|recipe| recipe.metadata.priority())
+            .max()
+            .unwrap_or_default();
+        Self {
+            source,
+            priority,
+            opts,
+        }
+    }
+
+    /// Removes the first of the builds with the highest priority
+    fn take_next(pending: &mut Vec<PendingBuild>) -> Option<PendingBuild> {
+        let next = pending
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, build)| (build.priority, Reverse(*i)))
+            .map(|(i, _)| i)?;
+        Some(pending.remove(next))
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum BuildTask {
     Simple {
@@ -58,25 +476,29 @@ pub enum BuildTask {
         recipe: Arc<Recipe>,
         target: ImageTarget,
     },
+    Sandbox {
+        recipe: Arc<Recipe>,
+    },
 }
 
 impl Application {
     pub fn new(config: Configuration) -> Result<Self> {
         let _pkger_dir = create_app_dirs()?;
-        let recipes = recipe::Loader::new(&config.recipes_dir)?;
+        let recipes =
+            recipe::Loader::new(&config.recipes_dir)?.with_trusted(!config.untrusted_recipes);
         let user_images_dir = config
             .images_dir
             .clone()
             .unwrap_or_else(|| _pkger_dir.path().join("images"));
+        let images_state = load_images_state(&config)?;
         let pkger = Application {
             config: Arc::new(config),
             recipes: Arc::new(recipes),
             docker: Arc::new(DockerConnectionPool::default()),
-            images_state: Arc::new(RwLock::new(
-                ImagesState::try_from_path(DEFAULT_STATE_FILE).unwrap_or_default(),
-            )),
+            images_state: Arc::new(RwLock::new(images_state)),
             user_images_dir,
             is_running: Arc::new(AtomicBool::new(true)),
+            preempted: Arc::new(AtomicBool::new(false)),
             _pkger_dir,
         };
         let is_running = pkger.is_running.clone();
@@ -86,14 +508,8 @@ impl Application {
 
     pub async fn process_opts(&mut self, opts: Opts) -> Result<()> {
         match opts.command {
-            Commands::Build(build_opts) => {
-                let tasks = self
-                    .process_build_opts(build_opts)
-                    .context("processing build opts")?;
-                self.process_tasks(tasks).await?;
-                self.save_images_state();
-                Ok(())
-            }
+            Commands::Build(build_opts) => self.build(build_opts).await,
+            Commands::PrepareImages(prepare_opts) => self.prepare_images(prepare_opts).await,
             Commands::GenRecipe(gen_recipe_opts) => gen::recipe(gen_recipe_opts),
             Commands::List(list_opts) => match list_opts.object {
                 ListObject::Images => {
@@ -101,16 +517,279 @@ impl Application {
                     Ok(())
                 }
                 ListObject::Recipes => {
-                    self.list_recipes();
+                    self.list_recipes(list_opts.long);
                     Ok(())
                 }
             },
+            Commands::Serve => self.serve().await,
+            Commands::Owners(owners_opts) => self.owners(owners_opts),
+            Commands::State(state_opts) => self.state(state_opts.command),
+            Commands::Cache(cache_opts) => self.cache(cache_opts).await,
+            Commands::Export(export_opts) => self.export(export_opts),
+            Commands::Bump(bump_opts) => bump::recipe(&self.recipes, &self.config, bump_opts),
+            Commands::Import(import_opts) => import::recipe(import_opts),
+            Commands::TestRecipes(test_opts) => test_recipes::run(&self.recipes, test_opts),
+            Commands::Repo(repo_opts) => self.repo(repo_opts).await,
+            Commands::Doctor => unreachable!("doctor runs before the configuration is loaded"),
+        }
+    }
+
+    async fn build(&mut self, opts: BuildOpts) -> Result<()> {
+        let fail_fast = match (opts.fail_fast, opts.keep_going) {
+            (true, true) => {
+                return Err(Error::msg(
+                    "`--fail-fast` and `--keep-going` can't be used together",
+                ))
+            }
+            (true, false) => true,
+            (false, true) => false,
+            (false, false) => self.config.fail_fast,
+        };
+        if let Some(vault) = self.config.vault.clone() {
+            let vars = tokio::task::spawn_blocking(move || vault::load_secrets(&vault))
+                .await
+                .context("fetching secrets was interrupted")?
+                .context("failed to fetch secrets from vault")?;
+            self.recipes = Arc::new(self.recipes.as_ref().clone().with_vars(vars));
+        }
+        let locked = opts.locked;
+        let mut options = BuildOptions {
+            jobs: opts.jobs,
+            fail_fast,
+            emit_script: opts.emit_script.clone(),
+            prepare_only: false,
+            lockfile: None,
+            report: opts.report.or(self.config.report),
+            archive: opts.archive || self.config.archive,
+            badges: if opts.badges.is_empty() {
+                self.config.badges.clone()
+            } else {
+                opts.badges.clone()
+            },
+            heartbeat: heartbeat_interval(opts.heartbeat),
+            checkpoints: opts.checkpoints || self.config.checkpoints,
+            source_package: opts.source_package,
+            publish: opts.publish.clone(),
+        };
+        let tasks = self
+            .process_build_opts(opts)
+            .context("processing build opts")?;
+        let lockfile = self.load_lockfile(&tasks, locked)?;
+        options.lockfile = Some((lockfile.clone(), locked));
+        let result = self.process_tasks(tasks, &options).await;
+        if !locked {
+            match lockfile.lock() {
+                Ok(lockfile) => {
+                    if let Err(e) = lockfile.save() {
+                        error!(reason = %e, "failed to save lockfile");
+                    }
+                }
+                Err(e) => error!(reason = %e, "failed to save lockfile"),
+            }
+        }
+        self.prune_images_state().await;
+        if let Some(policy) = &self.config.image_gc {
+            gc::collect(&self.images_state, self.docker.hosts(), policy).await;
         }
+        self.save_images_state();
+        result
     }
 
-    fn list_recipes(&self) {
-        for name in self.recipes.list() {
-            println!("{}", name);
+    /// Drops entries of the image state whose images were removed from Docker or whose recipes
+    /// or targets were deleted, so that the state doesn't grow forever
+    async fn prune_images_state(&self) {
+        let dropped = prune_missing(&self.images_state, self.docker.hosts()).await;
+        if dropped > 0 {
+            info!(entries = %dropped, "dropped states of removed images");
+        }
+
+        let recipes = match self.recipes.load_all() {
+            Ok(recipes) => recipes,
+            Err(e) => {
+                warn!(reason = %e, "failed to load recipes, not pruning image state");
+                return;
+            }
+        };
+        // a recipe that failed to load is not a deleted one
+        if recipes.len() < self.recipes.list().len() {
+            trace!("some recipes failed to load, not pruning targets of image state");
+            return;
+        }
+        match self.images_state.write() {
+            Ok(mut state) => {
+                state.prune_targets(&recipes);
+            }
+            Err(e) => warn!(reason = %e, "failed to access image state"),
+        }
+    }
+
+    /// Loads the lockfile in which recipes of `tasks` are pinned. If the build is `locked` every
+    /// recipe must match its pin.
+    fn load_lockfile(&self, tasks: &[BuildTask], locked: bool) -> Result<Arc<Mutex<Lockfile>>> {
+        let path = self.config.lockfile_path();
+        let lockfile = Lockfile::load(&path).context("failed to load lockfile")?;
+        trace!(path = %lockfile.path().display(), locked = %locked, "loaded lockfile");
+
+        if locked {
+            let mut verified = HashSet::new();
+            let mut drifted = vec![];
+            for task in tasks {
+                let recipe = match task {
+                    BuildTask::Custom { recipe, .. } | BuildTask::Simple { recipe, .. } => recipe,
+                    BuildTask::Sandbox { .. } => continue,
+                };
+                if !verified.insert(recipe.metadata.name.clone()) {
+                    continue;
+                }
+                if let Err(e) = lockfile.verify(recipe) {
+                    error!(recipe = %recipe.metadata.name, "{}", e);
+                    drifted.push(recipe.metadata.name.clone());
+                }
+            }
+            if !drifted.is_empty() {
+                return Err(Error::new(FailureKind::Verification).context(format!(
+                    "recipes don't match the lockfile `{}`: {}",
+                    path.display(),
+                    drifted.join(", ")
+                )));
+            }
+        }
+
+        Ok(Arc::new(Mutex::new(lockfile)))
+    }
+
+    /// Builds and caches the images of the selected recipes with their dependencies installed
+    /// without running any builds, so that later builds start right away.
+    async fn prepare_images(&mut self, opts: PrepareImagesOpts) -> Result<()> {
+        let options = BuildOptions {
+            jobs: opts.jobs,
+            fail_fast: self.config.fail_fast,
+            prepare_only: true,
+            ..Default::default()
+        };
+        let tasks = self
+            .process_build_opts(opts.build_opts())
+            .context("processing build opts")?;
+        let result = self.process_tasks(tasks, &options).await;
+        self.prune_images_state().await;
+        self.save_images_state();
+        result
+    }
+
+    /// Runs until interrupted starting builds whenever one of the configured schedules fires or
+    /// a webhook reports a tag matching one of the rules. Triggered builds wait until the running
+    /// one finishes and start in order of priority. With `preempt` set a build of recipes with a
+    /// higher priority interrupts the running build, which runs again afterwards.
+    async fn serve(&mut self) -> Result<()> {
+        let span = info_span!("serve");
+        async move {
+            let config = self.config.clone();
+            let schedules = config.schedules.as_deref().unwrap_or_default();
+            if schedules.is_empty() && config.webhooks.is_none() {
+                return Err(Error::msg(
+                    "no schedules or webhooks defined in the configuration",
+                ));
+            }
+            schedules.iter().for_each(|schedule| {
+                info!(cron = %schedule.cron, recipes = ?schedule.recipes, all = %schedule.all, "loaded schedule");
+            });
+            let triggers = match &config.webhooks {
+                Some(webhooks) => Some(webhook::listen(webhooks.clone())?),
+                None => None,
+            };
+
+            let preempted = self.preempted.clone();
+            let mut pending = vec![];
+            // schedules are checked once at the beginning of every minute
+            let mut minute = Local::now().timestamp() / 60;
+            while self.is_running.load(Ordering::SeqCst) {
+                let recipes = self.recipes.clone();
+                let mut collect = |pending: &mut Vec<PendingBuild>| {
+                    let now = Local::now();
+                    if now.timestamp() / 60 != minute {
+                        minute = now.timestamp() / 60;
+                        for schedule in schedules.iter().filter(|s| s.cron.matches(&now)) {
+                            let source = format!("schedule `{}`", schedule.cron);
+                            // a schedule matching again before its build started is built once
+                            if !pending.iter().any(|build| build.source == source) {
+                                pending.push(PendingBuild::new(&recipes, source, schedule.build_opts()));
+                            }
+                        }
+                    }
+                    for trigger in triggers.iter().flat_map(|triggers| triggers.try_iter()) {
+                        let source = format!("tag `{}` of `{}`", trigger.event.tag, trigger.event.repository);
+                        pending.push(PendingBuild::new(&recipes, source, trigger.opts));
+                    }
+                };
+
+                collect(&mut pending);
+                let build = match PendingBuild::take_next(&mut pending) {
+                    Some(build) => build,
+                    None => {
+                        time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                info!(source = %build.source, priority = %build.priority, "running triggered build");
+                // commands of variables like `git describe` run again for every build
+                recipe::clear_variables_cache();
+                preempted.store(false, Ordering::SeqCst);
+                let span = info_span!("triggered-build", source = %build.source);
+                let result = {
+                    let run = self.build(build.opts.clone()).instrument(span);
+                    futures::pin_mut!(run);
+                    loop {
+                        let tick = Box::pin(time::sleep(Duration::from_secs(1)));
+                        match future::select(run.as_mut(), tick).await {
+                            Either::Left((result, _)) => break result,
+                            Either::Right(_) => {
+                                collect(&mut pending);
+                                let urgent = pending.iter().find(|other| other.priority > build.priority);
+                                if let (Some(urgent), true, false) = (urgent, config.preempt, preempted.load(Ordering::SeqCst)) {
+                                    warn!(source = %build.source, by = %urgent.source, "preempting build");
+                                    preempted.store(true, Ordering::SeqCst);
+                                }
+                            }
+                        }
+                    }
+                };
+                // a build that finished before noticing the preemption is done
+                if preempted.swap(false, Ordering::SeqCst) && result.is_err() {
+                    info!(source = %build.source, "build will run again after builds with a higher priority");
+                    // ahead of builds with the same priority triggered in the meantime
+                    pending.insert(0, build);
+                } else if let Err(e) = result {
+                    error!(source = %build.source, "triggered build failed\n{}", failure::report(&e));
+                }
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    fn list_recipes(&self, long: bool) {
+        let mut names = self.recipes.list();
+        names.sort_unstable();
+        for name in names {
+            if !long {
+                println!("{}", name);
+                continue;
+            }
+            match self.recipes.load(&name) {
+                Ok(recipe) => {
+                    let mut summary = recipe
+                        .summary()
+                        .unwrap_or_else(|| recipe.metadata.description.clone());
+                    if let Some(deprecation) = &recipe.metadata.deprecated {
+                        summary = format!("{} ({})", summary, deprecation.note());
+                    }
+                    println!("{:<24} {:<12} {}", name, recipe.metadata.version, summary);
+                }
+                Err(e) => warn!(recipe = %name, reason = %e, "failed to load recipe"),
+            }
         }
     }
 
@@ -132,33 +811,390 @@ impl Application {
         };
     }
 
+    fn export(&self, opts: ExportOpts) -> Result<()> {
+        let span = info_span!("export", recipe = %opts.recipe);
+        let _enter = span.enter();
+
+        let recipe = self.recipes.load(&opts.recipe).context("loading recipe")?;
+        let image = opts.image.unwrap_or_default();
+
+        match opts.format {
+            ExportFormat::Spec => {
+                let spec = recipe.export_rpm_spec(&image);
+                if let Some(dir) = opts.output_dir {
+                    let path = dir.join(format!("{}.spec", recipe.metadata.name));
+                    spec.save_to(&path)
+                        .context(format!("failed to save spec file `{}`", path.display()))?;
+                    info!(path = %path.display(), "saved spec file");
+                } else {
+                    println!("{}", spec.render());
+                }
+            }
+            ExportFormat::Debian => {
+                let dir = opts.output_dir.unwrap_or_else(|| PathBuf::from("."));
+                let path = recipe.export_debian_dir(&image).save_to(&dir)?;
+                info!(path = %path.display(), "saved debian directory");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn repo(&self, opts: RepoOpts) -> Result<()> {
+        let dir = opts.dir.unwrap_or_else(|| self.config.output_dir.clone());
+        let signing = if opts.sign {
+            Some(self.config.signing.as_ref().ok_or_else(|| {
+                Error::msg("signing the repository requires `signing` in the configuration")
+            })?)
+        } else {
+            None
+        };
+
+        let changes = Changes {
+            add: opts.add,
+            remove: opts.remove,
+            keep: opts.keep.or_else(|| {
+                self.config
+                    .repo
+                    .as_ref()
+                    .and_then(|repo| repo.keep_versions)
+            }),
+        };
+        if changes.keep == Some(0) {
+            return Err(Error::msg(
+                "at least one version of every package must be kept",
+            ));
+        }
+
+        let update = match opts.repo_type {
+            RepoType::Apt => repo::apt::generate(&dir, signing, &changes),
+            RepoType::Yum => {
+                let docker = self.docker_pool(opts.docker.as_deref(), opts.runtime)?;
+                repo::yum::generate(&dir, signing, &changes, &docker.connect()).await
+            }
+        }
+        .context("failed to generate repository metadata")?;
+        for path in &update.saved {
+            info!(path = %path.display(), "saved");
+        }
+
+        if opts.publish {
+            let s3 = self
+                .config
+                .publish
+                .as_ref()
+                .and_then(|publish| publish.s3.as_ref())
+                .ok_or_else(|| {
+                    Error::msg(
+                        "publishing the repository requires `publish.s3` in the configuration",
+                    )
+                })?;
+            // keys of the metadata must line up with the keys of packages published by builds
+            let base = if dir.starts_with(&self.config.output_dir) {
+                self.config.output_dir.as_path()
+            } else {
+                dir.as_path()
+            };
+            let publisher = s3.publisher(base)?;
+            // only the changed files are uploaded, packages are uploaded before the metadata
+            // referencing them
+            for path in update.added.into_iter().chain(update.saved) {
+                let files = if path.is_dir() {
+                    fs::read_dir(&path)
+                        .and_then(|entries| {
+                            entries
+                                .map(|entry| entry.map(|entry| entry.path()))
+                                .collect::<io::Result<Vec<_>>>()
+                        })
+                        .context(format!("failed to read `{}`", path.display()))?
+                } else {
+                    vec![path]
+                };
+                for file in files {
+                    let key = publisher.publish(&file)?;
+                    info!(key = %key, "uploaded");
+                }
+            }
+            for path in update.removed {
+                let key = publisher.remove(&path)?;
+                info!(key = %key, "deleted");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn state(&mut self, command: StateCommand) -> Result<()> {
+        match command {
+            StateCommand::List => self.list_state(),
+            StateCommand::Show { image, recipe } => self.show_state(&image, recipe.as_deref()),
+            StateCommand::Rm { images, recipe } => {
+                self.remove_state(&images, recipe.as_deref())?;
+                self.save_images_state();
+                Ok(())
+            }
+        }
+    }
+
+    fn owners(&self, opts: OwnersOpts) -> Result<()> {
+        let print_owners = |recipe: &Recipe| {
+            if recipe.metadata.owners.is_empty() {
+                println!("{}: no owners", recipe.metadata.name);
+            } else {
+                println!(
+                    "{}: {}",
+                    recipe.metadata.name,
+                    recipe.metadata.owners.join(", ")
+                );
+            }
+        };
+
+        // a name of a recipe takes precedence over a path
+        if self.recipes.list().contains(&opts.target) {
+            print_owners(&self.recipes.load(&opts.target)?);
+            return Ok(());
+        }
+
+        let path = fs::canonicalize(&opts.target).context(format!(
+            "`{}` is neither a recipe nor an existing path",
+            opts.target
+        ))?;
+        let recipes = self
+            .recipes
+            .load_all()
+            .context("loading recipes")?
+            .into_iter()
+            .filter(|recipe| {
+                changes::recipe_changed(recipe, &self.user_images_dir, std::slice::from_ref(&path))
+            })
+            .collect::<Vec<_>>();
+        if recipes.is_empty() {
+            return Err(Error::msg(format!(
+                "no recipe is affected by `{}`",
+                path.display()
+            )));
+        }
+        recipes.iter().for_each(print_owners);
+
+        Ok(())
+    }
+
+    async fn cache(&mut self, opts: CacheOpts) -> Result<()> {
+        self.docker = Arc::new(self.docker_pool(opts.docker.as_deref(), opts.runtime)?);
+        match opts.command {
+            CacheCommand::Export { path } => {
+                let entries = self
+                    .images_state
+                    .read()
+                    .map_err(|e| Error::msg(format!("failed to read image state - {}", e)))?
+                    .images
+                    .iter()
+                    .map(|(target, state)| (target.clone(), state.clone()))
+                    .collect::<Vec<_>>();
+                let count = transfer::export(&self.docker.connect(), &entries, &path).await?;
+                info!(images = count, path = %path.display(), "exported image cache");
+                Ok(())
+            }
+            CacheCommand::Import { path } => {
+                let mut entries = vec![];
+                for docker in self.docker.hosts() {
+                    entries = transfer::import(docker, &path).await?;
+                }
+                {
+                    let mut state = self
+                        .images_state
+                        .write()
+                        .map_err(|e| Error::msg(format!("failed to update image state - {}", e)))?;
+                    for (target, image) in &entries {
+                        state.update(target, image);
+                    }
+                }
+                self.save_images_state();
+                info!(images = entries.len(), path = %path.display(), "imported image cache");
+                Ok(())
+            }
+        }
+    }
+
+    fn list_state(&self) -> Result<()> {
+        let state = self
+            .images_state
+            .read()
+            .map_err(|e| Error::msg(format!("failed to read image state - {}", e)))?;
+
+        let mut entries = state.images.iter().collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| (a.image(), a.recipe()).cmp(&(b.image(), b.recipe())));
+
+        println!(
+            "{:<24} {:<24} {:<6} {:<12} {:<8} LAST USED",
+            "IMAGE", "RECIPE", "TARGET", "ID", "TAG"
+        );
+        for (target, image) in entries {
+            println!(
+                "{:<24} {:<24} {:<6} {:<12} {:<8} {}",
+                target.image(),
+                target.recipe(),
+                target.build_target().as_ref(),
+                short_id(&image.id),
+                image.tag,
+                format_time(image.last_used.unwrap_or(image.timestamp)),
+            );
+        }
+
+        if !state.unreferenced.is_empty() {
+            println!(
+                "\n{} unreferenced images awaiting removal",
+                state.unreferenced.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn show_state(&self, image: &str, recipe: Option<&str>) -> Result<()> {
+        let state = self
+            .images_state
+            .read()
+            .map_err(|e| Error::msg(format!("failed to read image state - {}", e)))?;
+
+        let entries = state
+            .images
+            .iter()
+            .filter(|(target, _)| {
+                target.image() == image && recipe.map(|r| r == target.recipe()).unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            return Err(Error::msg(format!("no state found for image `{}`", image)));
+        }
+
+        for (target, image) in entries {
+            print_image_state(target.recipe(), target.build_target().as_ref(), image);
+        }
+
+        Ok(())
+    }
+
+    fn remove_state(&self, images: &[String], recipe: Option<&str>) -> Result<()> {
+        let mut state = self
+            .images_state
+            .write()
+            .map_err(|e| Error::msg(format!("failed to access image state - {}", e)))?;
+
+        let targets = state
+            .images
+            .keys()
+            .filter(|target| {
+                images.iter().any(|image| image == target.image())
+                    && recipe.map(|r| r == target.recipe()).unwrap_or(true)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if targets.is_empty() {
+            return Err(Error::msg("no matching images found in state"));
+        }
+
+        for target in targets {
+            if let Some(image) = state.evict(&target) {
+                println!(
+                    "removed {} ({}) used by {}",
+                    target.image(),
+                    short_id(&image.id),
+                    target.recipe()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     fn process_build_opts(&mut self, opts: BuildOpts) -> Result<Vec<BuildTask>> {
         let span = info_span!("process-build-opts");
         let _enter = span.enter();
         let mut tasks = Vec::new();
         let mut recipes = Vec::new();
 
-        if opts.all {
+        if let Some(reference) = &opts.changed_since {
+            let changed = changes::changed_since(&self.config.recipes_dir, reference)
+                .context("failed to find changed recipes")?;
+            recipes = self
+                .recipes
+                .load_all()
+                .context("loading recipes")?
+                .into_iter()
+                .filter(|recipe| {
+                    (opts.recipes.is_empty() || opts.recipes.contains(&recipe.metadata.name))
+                        && changes::recipe_changed(recipe, &self.user_images_dir, &changed)
+                })
+                .filter(|recipe| {
+                    opts.recipes.contains(&recipe.metadata.name) || !is_deprecated(recipe)
+                })
+                .collect();
+            info!(reference = %reference, recipes = %recipes.len(), "found changed recipes");
+            if recipes.is_empty() {
+                return Ok(tasks);
+            }
+        } else if opts.all {
             recipes = self
                 .recipes
                 .load_all()
                 .context("loading recipes")?
                 .into_iter()
-                .map(Arc::new)
+                .filter(|recipe| !is_deprecated(recipe))
                 .collect();
         } else if !opts.recipes.is_empty() {
-            for recipe_name in opts.recipes {
+            for recipe_name in &opts.recipes {
                 trace!(recipe = %recipe_name, "loading");
-                recipes.push(Arc::new(
-                    self.recipes.load(&recipe_name).context("loading recipe")?,
-                ));
+                let recipe = self.recipes.load(recipe_name).context("loading recipe")?;
+                if let Some(deprecation) = &recipe.metadata.deprecated {
+                    if self.config.fail_deprecated {
+                        return Err(Error::msg(format!(
+                            "recipe `{}` is {}",
+                            recipe_name,
+                            deprecation.note()
+                        )));
+                    }
+                    warn!(recipe = %recipe_name, "recipe is {}", deprecation.note());
+                }
+                recipes.push(recipe);
             }
         } else {
             warn!("no recipes to build");
             return Ok(tasks);
         }
 
-        if opts.all {
+        for feature in opts.features.iter().filter(|f| !f.contains('/')) {
+            if !recipes
+                .iter()
+                .any(|recipe| recipe.metadata.features.contains_key(feature))
+            {
+                return Err(Error::msg(format!(
+                    "none of the recipes has feature `{}`",
+                    feature
+                )));
+            }
+        }
+        let recipes = recipes
+            .into_iter()
+            .map(|recipe| {
+                let name = recipe.metadata.name.clone();
+                recipe
+                    .with_features(&opts.features, !opts.no_default_features)
+                    .map(Arc::new)
+                    .context(format!("failed to enable features of recipe `{}`", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if opts.sandbox {
+            // build specified recipes only on the fixture image
+            for recipe in &recipes {
+                tasks.push(BuildTask::Sandbox {
+                    recipe: recipe.clone(),
+                });
+            }
+        } else if opts.all {
             // build all recipes for all targets
             for recipe in &recipes {
                 if let Some(images) = &recipe.metadata.images {
@@ -186,7 +1222,7 @@ impl Application {
             for recipe in &recipes {
                 if let Some(images) = &recipe.metadata.images {
                     for image in opt_images {
-                        if let Some(target) = images.iter().find(|target| &target.image == image) {
+                        for target in images.iter().filter(|target| &target.image == image) {
                             tasks.push(BuildTask::Custom {
                                 recipe: recipe.clone(),
                                 target: target.clone(),
@@ -209,34 +1245,77 @@ impl Application {
             }
         }
 
-        self.docker = Arc::new(
-            // check if docker uri provided as cli arg
-            match &opts.docker {
-                Some(uri) => {
-                    trace!(uri = %uri, "using docker uri from opts");
+        self.docker = Arc::new(self.docker_pool(opts.docker.as_deref(), opts.runtime)?);
+        Ok(tasks)
+    }
+
+    fn docker_pool(
+        &self,
+        uri: Option<&str>,
+        runtime: Option<Runtime>,
+    ) -> Result<DockerConnectionPool> {
+        let runtime = runtime.or(self.config.runtime).unwrap_or_default();
+        trace!(runtime = %runtime.as_ref());
+        // check if docker uri provided as cli arg
+        match uri {
+            Some(uri) => {
+                trace!(uri = %uri, "using docker uri from opts");
+                DockerConnectionPool::new(uri)
+            }
+            None => {
+                // otherwhise check if available as config parameter
+                if let Some(hosts) = &self.config.docker_hosts {
+                    trace!(hosts = hosts.len(), "using docker hosts from config");
+                    DockerConnectionPool::with_hosts(
+                        hosts.iter().map(|host| (host.uri.as_str(), host.weight)),
+                    )
+                } else if let Some(uri) = &self.config.docker {
+                    trace!(uri = %uri, "using docker uri from config");
                     DockerConnectionPool::new(uri)
-                }
-                None => {
-                    // otherwhise check if available as config parameter
-                    if let Some(uri) = &self.config.docker {
-                        trace!(uri = %uri, "using docker uri from config");
-                        DockerConnectionPool::new(uri)
-                    } else {
-                        trace!("using default docker uri");
-                        Ok(DockerConnectionPool::default())
-                    }
+                } else {
+                    trace!("using default docker uri");
+                    Ok(DockerConnectionPool::for_runtime(runtime))
                 }
             }
-            .context("Failed to initialize docker connection")?,
-        );
-        Ok(tasks)
+        }
+        .map(|pool| pool.with_runtime(runtime))
+        .context("Failed to initialize docker connection")
     }
 
-    async fn process_tasks(&mut self, tasks: Vec<BuildTask>) -> Result<()> {
+    async fn process_tasks(&self, tasks: Vec<BuildTask>, opts: &BuildOptions) -> Result<()> {
         let span = info_span!("process-jobs");
         async move {
-            let jobs = FuturesUnordered::new();
+            let prepare_only = opts.prepare_only;
+            let fail_fast = opts.fail_fast;
+            let emit_script = opts.emit_script.as_deref();
+            let jobs_running = Arc::new(AtomicBool::new(true));
+            let contexts = ContextBuilder::new(self, opts, jobs_running.clone())?;
+            let interrupts = forward_interrupts(
+                self.is_running.clone(),
+                self.preempted.clone(),
+                jobs_running.clone(),
+            );
+
+            let mut errors = vec![];
+            let mut subjects = HashMap::new();
+            let mut versions = HashMap::new();
+            let mut archive = if opts.archive && !prepare_only {
+                Some(RunArchive::now())
+            } else {
+                None
+            };
+
+            let mut queue = JobQueue::new(opts.jobs).interruptible(jobs_running.clone());
+            if fail_fast {
+                queue = queue.fail_fast();
+            }
+            if let Some(interval) = opts.heartbeat {
+                queue = queue.heartbeat(interval);
+            }
+            // build stages by their keys, with the id of the job and its output
+            let mut stages: HashMap<String, Option<(String, StageOutput)>> = HashMap::new();
             for task in tasks {
+                let is_sandbox = matches!(task, BuildTask::Sandbox { .. });
                 let (recipe, image, target, is_simple) =  match task {
                     BuildTask::Custom { recipe, target } => {
                         let image = Image::new(target.image.clone(), self.user_images_dir.join(&target.image));
@@ -247,52 +1326,188 @@ impl Application {
                         let name = image.name.clone();
                         (recipe, image, ImageTarget::new(name, target, None::<&str>), true)
                     }
+                    BuildTask::Sandbox { recipe } => {
+                        let image = Image::sandbox(&self._pkger_dir.path().join("images"))?;
+                        (recipe, image, ImageTarget::new(SANDBOX_IMAGE, BuildTarget::Gzip, Some("busybox")), true)
+                    }
                 };
-                    jobs.push(task::spawn(
-                            JobCtx::Build(Context::new(
-                                recipe,
-                                image,
-                                self.docker.connect(),
-                                target,
-                                self.config.output_dir.as_path(),
-                                self.images_state.clone(),
-                                self.is_running.clone(),
-                                is_simple,
-                            ))
-                            .run(),
-                        ));
+                let stage_key = if !is_simple && !prepare_only {
+                    build_stage_key(&recipe, &target)
+                } else {
+                    None
+                };
+                let stage = match stage_key {
+                    Some(key) => {
+                        let stage = stages.entry(key).or_insert_with(|| {
+                            let ctx = self.build_stage_context(&contexts, &recipe, &target)?;
+                            let output = StageOutput::default();
+                            let id = ctx.id().to_string();
+                            subjects.insert(id.clone(), audit::Subject::of(&ctx));
+                            let job = JobCtx::BuildStage(ctx, output.clone());
+                            queue.push(job.priority(), job);
+                            Some((id, output))
+                        });
+                        match stage {
+                            Some(stage) => Some(stage.clone()),
+                            None => {
+                                warn!(recipe = %recipe.metadata.name, image = %target.image, "skipping, build stage failed");
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                versions.insert(recipe.metadata.name.clone(), recipe.metadata.version.clone());
+                let ctx = contexts.context(recipe, image, target, is_simple, is_sandbox);
+                subjects.insert(ctx.id().to_string(), audit::Subject::of(&ctx));
+                if let Some(archive) = &mut archive {
+                    let target = ctx.target();
+                    archive.add_packaging(ctx.id(), ctx.recipe().render_snapshot(target.image(), target.build_target()));
                 }
+                match stage {
+                    Some((id, output)) => {
+                        let job = JobCtx::Package(ctx, output);
+                        queue.push_after(job.priority(), job, &id);
+                    }
+                    None => {
+                        let job = JobCtx::Build(ctx);
+                        queue.push(job.priority(), job);
+                    }
+                }
+            }
 
-            let mut errors = vec![];
+            if prepare_only {
+                errors.extend(queue.prepare_images().await);
+                info!(failed = %errors.len(), "finished preparing images");
+            } else {
+                errors.extend(queue.run().await);
+            }
+            interrupts.abort();
+            if self.preempted.load(Ordering::SeqCst) {
+                // the build runs again, its results are only recorded then
+                return Err(Error::msg(format!(
+                    "build preempted after {} of {} jobs finished",
+                    errors.len(),
+                    subjects.len()
+                )));
+            }
 
-            for job in jobs {
-                let handle = job.await;
-                if let Err(e) = handle {
-                    error!(reason = %e, "failed to join the handle for a job");
-                    continue;
+            if let (Some(config), false) = (&self.config.audit, prepare_only) {
+                audit::record_all(config, &errors, &subjects, &self.config.output_dir);
+            }
+            let mut report_path = None;
+            if let (Some(format), false) = (opts.report, prepare_only) {
+                match report::save(format, &errors, &subjects, &self.config.output_dir, emit_script) {
+                    Ok(path) => {
+                        info!(path = %path.display(), "saved report");
+                        report_path = Some(path);
+                    }
+                    Err(e) => error!(reason = %e, "failed to save report"),
+                }
+            }
+            if !opts.badges.is_empty() && !prepare_only {
+                match badge::save_all(&opts.badges, &errors, &subjects, &versions, &self.config.output_dir) {
+                    Ok(paths) => info!(badges = %paths.len(), "saved badges"),
+                    Err(e) => error!(reason = %e, "failed to save badges"),
+                }
+            }
+            if let Some(mut archive) = archive {
+                archive.add_results(&errors, &self.config.output_dir);
+                let mut ids = subjects.keys().collect::<Vec<_>>();
+                ids.sort();
+                for id in ids {
+                    if let Some(output_limit) = &self.config.output_limit {
+                        archive.add_log(id, output_limit);
+                    }
+                    if let Some(dir) = emit_script {
+                        archive.add_transcript(id, dir);
+                    }
+                }
+                if let Some(path) = &report_path {
+                    archive.add_report(path);
+                }
+                match archive.save(&self.config.output_dir) {
+                    Ok(path) => info!(path = %path.display(), "saved run archive"),
+                    Err(e) => error!(reason = %e, "failed to save run archive"),
                 }
-
-                errors.push(handle.unwrap());
             }
 
             errors.iter().for_each(|err| match err {
-                JobResult::Failure { id, duration, reason } => {
-                    error!(id = %id, reason = %reason, duration = %format!("{}s", duration.as_secs_f32()), "job failed");
+                JobResult::Failure { id, duration, reason, .. } => {
+                    match subjects.get(id).map(audit::Subject::owners).filter(|owners| !owners.is_empty()) {
+                        Some(owners) => error!(id = %id, owners = %owners.join(", "), duration = %HumanDuration(*duration), "job failed\n{}", reason),
+                        None => error!(id = %id, duration = %HumanDuration(*duration), "job failed\n{}", reason),
+                    }
                 }
                 JobResult::Success { id, duration, output } => {
-                    info!(id = %id, output = %output, duration = %format!("{}s", duration.as_secs_f32()), "job succeded");
+                    info!(id = %id, output = %output, duration = %HumanDuration(*duration), "job succeded");
                 }
             });
 
+            let failures = errors
+                .iter()
+                .filter_map(|result| match result {
+                    JobResult::Failure { kind, .. } => Some(*kind),
+                    JobResult::Success { .. } => None,
+                })
+                .collect::<Vec<_>>();
+            if !failures.is_empty() {
+                let msg = format!("{} of {} jobs failed", failures.len(), errors.len());
+                // the first classified failure decides the exit code
+                return Err(match failures.into_iter().flatten().next() {
+                    Some(kind) => Error::new(kind).context(msg),
+                    None => Error::msg(msg),
+                });
+            }
+
             Ok(())
         }.instrument(span).await
     }
 
+    /// Context of the build stage whose output is packaged by the build of `target`, see
+    /// [`build_stage_key`]. The stage runs once for every recipe that has a `build_image` so that
+    /// the output can be packaged on each image target without rerunning the scripts, and once for
+    /// every image that lists multiple package formats so that each format is packaged from the
+    /// same output.
+    fn build_stage_context(
+        &self,
+        contexts: &ContextBuilder,
+        recipe: &Arc<Recipe>,
+        target: &ImageTarget,
+    ) -> Option<Context> {
+        let target = build_stage_target(recipe, target);
+        let image = if target.image == MUSL_IMAGE && recipe.metadata.build_image.is_none() {
+            match Image::musl(&self._pkger_dir.path().join("images")) {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!(recipe = %recipe.metadata.name, reason = %e, "failed to create the musl image");
+                    return None;
+                }
+            }
+        } else {
+            Image::new(
+                target.image.clone(),
+                self.user_images_dir.join(&target.image),
+            )
+        };
+        Some(contexts.context(recipe.clone(), image, target, false, false))
+    }
+
+    /// Connection to the daemon that builds `target` of `recipe`. Targets are pinned to the daemon
+    /// their image was built on as every daemon has its own images.
+    fn docker_for(&self, recipe: &Recipe, target: &ImageTarget) -> Docker {
+        let target = RecipeTarget::new(recipe.metadata.name.clone(), target.clone());
+        let state = self.images_state.read().ok();
+        self.docker
+            .connect_to(state.as_ref().and_then(|state| state.host_of(&target)))
+    }
+
     fn save_images_state(&self) {
         let span = info_span!("save-images-state");
         let _enter = span.enter();
 
-        let result = self.images_state.read();
+        let result = self.images_state.write();
 
         if let Err(e) = result {
             error!(reason = %e, "failed to save image state");
@@ -300,8 +1515,104 @@ impl Application {
         }
 
         // it's ok to unwrap, we check the wrapping error above
-        if let Err(e) = (*result.unwrap()).save() {
+        if let Err(e) = result.unwrap().save() {
             error!(reason = %e, "failed to save image state");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pkger_core::recipe::RecipeRep;
+
+    fn recipe(metadata: &str) -> Recipe {
+        let yaml = format!(
+            "metadata:\n  name: foo\n  version: 1.0.0\n  description: foo\n  license: MIT\n{}build:\n  steps: []\n",
+            metadata
+        );
+        Recipe::new(
+            RecipeRep::from_yaml_bytes(yaml.as_bytes()).unwrap(),
+            PathBuf::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn selects_build_stages() {
+        let target =
+            |image: &str, build_target| ImageTarget::new(image, build_target, None::<&str>);
+
+        let multiple =
+            recipe("  images:\n    - centos8\n    - name: debian10\n      target: [gzip, deb]\n");
+        assert_eq!(
+            build_stage_key(&multiple, &target("centos8", BuildTarget::Rpm)),
+            None
+        );
+        let deb = target("debian10", BuildTarget::Deb);
+        assert_eq!(
+            build_stage_key(&multiple, &deb).as_deref(),
+            Some("foo/debian10")
+        );
+        assert_eq!(
+            build_stage_target(&multiple, &deb),
+            target("debian10", BuildTarget::Gzip)
+        );
+
+        let native = recipe(
+            "  images:\n    - name: debian10\n      target: [deb, gzip]\n  deb:\n    debian_dir: debian\n",
+        );
+        assert_eq!(build_stage_key(&native, &deb), None);
+
+        let build_image =
+            recipe("  build_image: builder\n  images:\n    - centos8\n    - debian10\n");
+        assert_eq!(build_stage_key(&build_image, &deb).as_deref(), Some("foo"));
+        assert_eq!(
+            build_stage_target(&build_image, &deb),
+            target("builder", BuildTarget::Gzip)
+        );
+
+        let static_preset = recipe("  preset: static\n  images:\n    - centos8\n");
+        let rpm = target("centos8", BuildTarget::Rpm);
+        assert_eq!(
+            build_stage_key(&static_preset, &rpm).as_deref(),
+            Some("foo")
+        );
+        assert_eq!(build_stage_target(&static_preset, &rpm).image, MUSL_IMAGE);
+    }
+
+    #[test]
+    fn orders_triggered_builds() {
+        let dir = TempDir::new("pkger-recipes").unwrap();
+        for (name, priority) in [("nightly", 0), ("hotfix", 10)] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+            fs::write(
+                dir.path().join(name).join("recipe.yml"),
+                format!(
+                    "metadata:\n  name: {}\n  version: 1.0.0\n  description: foo\n  license: MIT\n  priority: {}\nbuild:\n  steps: []\n",
+                    name, priority
+                ),
+            )
+            .unwrap();
+        }
+        let recipes = recipe::Loader::new(dir.path()).unwrap();
+        let build = |source: &str, names: &[&str]| {
+            let opts = BuildOpts {
+                recipes: names.iter().map(|name| name.to_string()).collect(),
+                ..Default::default()
+            };
+            PendingBuild::new(&recipes, source.to_string(), opts)
+        };
+
+        let mut pending = vec![
+            build("first", &["nightly"]),
+            build("all", &[]),
+            build("urgent", &["hotfix"]),
+        ];
+        assert_eq!(pending[1].priority, 10);
+        let order = std::iter::from_fn(|| PendingBuild::take_next(&mut pending))
+            .map(|build| build.source)
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["all", "urgent", "first"]);
+    }
+}
@@ -0,0 +1,283 @@
+//! Audit log of all builds. For each finished build a record describing who ran it, what was
+//! built and what was produced is appended to a JSON lines file and/or sent to a remote endpoint.
+//! Each record in the file contains the hash of the previous line so that removing or modifying
+//! records can be detected.
+use crate::job::JobResult;
+use crate::{Error, Result};
+use pkger_core::build::manifest::ArtifactManifest;
+use pkger_core::build::Context;
+use pkger_core::recipe::recipe_file;
+use pkger_core::{secrets, ErrContext};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{error, info_span, trace};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditConfig {
+    /// JSON lines file to which the records are appended
+    pub path: Option<PathBuf>,
+    /// Endpoint to which each record is sent with a POST request
+    pub url: Option<String>,
+}
+
+/// What a build was about, captured before it is run
+#[derive(Clone, Debug)]
+pub struct Subject {
+    recipe: String,
+    recipe_dir: PathBuf,
+    image: String,
+    owners: Vec<String>,
+}
+
+impl Subject {
+    pub fn of(ctx: &Context) -> Self {
+        Self {
+            recipe: ctx.recipe().metadata.name.clone(),
+            recipe_dir: ctx.recipe().recipe_dir.clone(),
+            image: ctx.target().image().to_string(),
+            owners: ctx.recipe().metadata.owners.clone(),
+        }
+    }
+
+    pub fn recipe(&self) -> &str {
+        &self.recipe
+    }
+
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    /// Owners of the recipe of the build
+    pub fn owners(&self) -> &[String] {
+        &self.owners
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Artifact {
+    /// Path to the artifact relative to the output directory
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub id: String,
+    pub timestamp: String,
+    pub user: String,
+    pub host: String,
+    pub recipe: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    /// Owners of the recipe to whom failures should be routed
+    pub owners: Vec<String>,
+    /// SHA256 of the recipe file at the time of the build
+    pub recipe_hash: Option<String>,
+    pub image: String,
+    /// Id of the Docker image the package was built on
+    pub image_id: Option<String>,
+    pub success: bool,
+    pub duration_secs: f32,
+    pub error: Option<String>,
+    pub artifacts: Vec<Artifact>,
+    pub signatures: Vec<PathBuf>,
+    /// SHA256 of the previous line of the audit log
+    pub previous: Option<String>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn current_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn current_host() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|host| host.trim().to_string())
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+impl AuditRecord {
+    pub fn new(result: &JobResult, subject: &Subject, output_dir: &Path) -> Self {
+        let recipe_hash = recipe_file(&subject.recipe_dir)
+            .ok()
+            .and_then(|path| fs::read(path).ok())
+            .map(|recipe| sha256_hex(&recipe));
+
+        let mut record = Self {
+            id: String::new(),
+            timestamp: Utc::now().to_rfc3339(),
+            user: current_user(),
+            host: current_host(),
+            recipe: subject.recipe.clone(),
+            owners: subject.owners.clone(),
+            recipe_hash,
+            image: subject.image.clone(),
+            image_id: None,
+            success: false,
+            duration_secs: 0.,
+            error: None,
+            artifacts: vec![],
+            signatures: vec![],
+            previous: None,
+        };
+
+        match result {
+            JobResult::Success {
+                id,
+                duration,
+                output,
+            } => {
+                record.id = id.clone();
+                record.success = true;
+                record.duration_secs = duration.as_secs_f32();
+
+                let package = Path::new(output);
+                let manifest = fs::read(ArtifactManifest::path_for(package))
+                    .ok()
+                    .and_then(|data| serde_json::from_slice::<ArtifactManifest>(&data).ok());
+                if let Some(manifest) = manifest {
+                    record.image_id = Some(manifest.image_id).filter(|id| !id.is_empty());
+                    record.signatures.extend(manifest.signature);
+                }
+                if let Ok(data) = fs::read(package) {
+                    record.artifacts.push(Artifact {
+                        path: package
+                            .strip_prefix(output_dir)
+                            .unwrap_or(package)
+                            .to_path_buf(),
+                        sha256: sha256_hex(&data),
+                    });
+                }
+            }
+            JobResult::Failure {
+                id,
+                duration,
+                reason,
+                ..
+            } => {
+                record.id = id.clone();
+                record.duration_secs = duration.as_secs_f32();
+                record.error = Some(secrets::redact(reason).into_owned());
+            }
+        }
+
+        record
+    }
+}
+
+/// Appends `record` to the file at `path` linking it to the last record in the file
+fn append(path: &Path, record: &mut AuditRecord) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    record.previous = fs::read_to_string(path)
+        .ok()
+        .and_then(|log| log.lines().last().map(|line| sha256_hex(line.as_bytes())));
+
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .context(format!("failed to append to `{}`", path.display()))
+}
+
+fn send(url: &str, record: &AuditRecord) -> Result<()> {
+    ureq::post(url)
+        .send_json(serde_json::to_value(record)?)
+        .map(|_| ())
+        .map_err(|e| Error::msg(format!("failed to send audit record - {}", e)))
+}
+
+/// Records all `results` of a run. Failures to write the audit log are logged but don't fail
+/// the builds.
+pub fn record_all(
+    config: &AuditConfig,
+    results: &[JobResult],
+    subjects: &HashMap<String, Subject>,
+    output_dir: &Path,
+) {
+    let span = info_span!("audit");
+    let _enter = span.enter();
+
+    for result in results {
+        let id = match result {
+            JobResult::Success { id, .. } | JobResult::Failure { id, .. } => id,
+        };
+        let subject = match subjects.get(id) {
+            Some(subject) => subject,
+            None => continue,
+        };
+        let mut record = AuditRecord::new(result, subject, output_dir);
+        trace!(record = ?record);
+
+        if let Some(path) = &config.path {
+            if let Err(e) = append(path, &mut record) {
+                error!(id = %id, reason = %e, "failed to write audit record");
+            }
+        }
+        if let Some(url) = &config.url {
+            if let Err(e) = send(url, &record) {
+                error!(id = %id, reason = %e, "failed to send audit record");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn record(id: &str) -> AuditRecord {
+        AuditRecord {
+            id: id.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            user: "pkger".to_string(),
+            host: "localhost".to_string(),
+            recipe: "test".to_string(),
+            owners: vec![],
+            recipe_hash: None,
+            image: "centos8".to_string(),
+            image_id: None,
+            success: true,
+            duration_secs: 1.,
+            error: None,
+            artifacts: vec![],
+            signatures: vec![],
+            previous: None,
+        }
+    }
+
+    #[test]
+    fn links_appended_records() {
+        let dir = TempDir::new("pkger-audit").unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let mut first = record("first");
+        append(&path, &mut first).unwrap();
+        assert_eq!(first.previous, None);
+
+        let mut second = record("second");
+        append(&path, &mut second).unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(second.previous, Some(sha256_hex(lines[0].as_bytes())));
+    }
+}
@@ -0,0 +1,221 @@
+//! Badges of recipes. After a run every built recipe gets a badge with its version and whether
+//! all of its builds succeeded, either as an SVG image or as a shields.io endpoint file, so that
+//! packaging status can be embedded in READMEs and internal dashboards. Badges of recipes that
+//! weren't built are left untouched and keep showing their last build.
+use crate::audit::Subject;
+use crate::job::JobResult;
+use crate::{Error, Result};
+use pkger_core::ErrContext;
+
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+static BADGES_DIR: &str = "badges";
+static PASSING_COLOR: &str = "#4c1";
+static FAILING_COLOR: &str = "#e05d44";
+static LABEL_COLOR: &str = "#555";
+/// Approximate width of a character of the badge font in pixels
+static CHAR_WIDTH: usize = 7;
+static PADDING: usize = 10;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BadgeFormat {
+    Svg,
+    /// JSON endpoint file rendered by shields.io
+    Shields,
+}
+
+impl FromStr for BadgeFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "svg" => Ok(BadgeFormat::Svg),
+            "shields" | "json" => Ok(BadgeFormat::Shields),
+            _ => Err(Error::msg(format!("unknown badge format {}", s))),
+        }
+    }
+}
+
+impl BadgeFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            BadgeFormat::Svg => "svg",
+            BadgeFormat::Shields => "json",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Badge {
+    recipe: String,
+    version: String,
+    passing: bool,
+}
+
+impl Badge {
+    fn message(&self) -> String {
+        let status = if self.passing { "passing" } else { "failing" };
+        if self.version.is_empty() {
+            status.to_string()
+        } else {
+            format!("{} {}", self.version, status)
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        if self.passing {
+            PASSING_COLOR
+        } else {
+            FAILING_COLOR
+        }
+    }
+
+    fn render(&self, format: BadgeFormat) -> String {
+        match format {
+            BadgeFormat::Svg => self.render_svg(),
+            BadgeFormat::Shields => json!({
+                "schemaVersion": 1,
+                "label": self.recipe,
+                "message": self.message(),
+                "color": if self.passing { "brightgreen" } else { "red" },
+            })
+            .to_string(),
+        }
+    }
+
+    fn render_svg(&self) -> String {
+        let label = escape_xml(&self.recipe);
+        let message = escape_xml(&self.message());
+        let label_width = self.recipe.chars().count() * CHAR_WIDTH + PADDING;
+        let message_width = self.message().chars().count() * CHAR_WIDTH + PADDING;
+        let width = label_width + message_width;
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">
+  <title>{label}: {message}</title>
+  <clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+  <g clip-path="url(#r)">
+    <rect width="{label_width}" height="20" fill="{label_color}"/>
+    <rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/>
+  </g>
+  <g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+    <text x="{label_x}" y="14">{label}</text>
+    <text x="{message_x}" y="14">{message}</text>
+  </g>
+</svg>
+"##,
+            width = width,
+            label = label,
+            message = message,
+            label_width = label_width,
+            message_width = message_width,
+            label_color = LABEL_COLOR,
+            color = self.color(),
+            label_x = label_width / 2,
+            message_x = label_width + message_width / 2,
+        )
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Badges of all recipes of `builds`, pairs of the name of a recipe and whether its build
+/// succeeded. A recipe is passing if all of its builds succeeded.
+fn badges<'a, I>(builds: I, versions: &HashMap<String, String>) -> Vec<Badge>
+where
+    I: IntoIterator<Item = (&'a str, bool)>,
+{
+    let mut passing = BTreeMap::new();
+    for (recipe, succeeded) in builds {
+        *passing.entry(recipe.to_string()).or_insert(true) &= succeeded;
+    }
+    passing
+        .into_iter()
+        .map(|(recipe, passing)| Badge {
+            version: versions.get(&recipe).cloned().unwrap_or_default(),
+            recipe,
+            passing,
+        })
+        .collect()
+}
+
+/// Saves a badge in every format of `formats` for each recipe built in `results` to the `badges`
+/// directory of `output_dir`. `versions` are the versions of the built recipes by name. Returns
+/// the paths of the saved badges.
+pub fn save_all(
+    formats: &[BadgeFormat],
+    results: &[JobResult],
+    subjects: &HashMap<String, Subject>,
+    versions: &HashMap<String, String>,
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let dir = output_dir.join(BADGES_DIR);
+    fs::create_dir_all(&dir)?;
+    let builds = results.iter().filter_map(|result| {
+        subjects.get(result.id()).map(|subject| {
+            (
+                subject.recipe(),
+                matches!(result, JobResult::Success { .. }),
+            )
+        })
+    });
+    let mut saved = vec![];
+    for badge in badges(builds, versions) {
+        for format in formats {
+            let path = dir.join(format!("{}.{}", badge.recipe, format.extension()));
+            fs::write(&path, badge.render(*format))
+                .context(format!("failed to save badge `{}`", path.display()))?;
+            saved.push(path);
+        }
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_badges() {
+        let versions = vec![("foo".to_string(), "1.2.0".to_string())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let builds = vec![("foo", true), ("bar", true), ("foo", true), ("bar", false)];
+        let badges = badges(builds, &versions);
+        assert_eq!(
+            badges,
+            vec![
+                Badge {
+                    recipe: "bar".to_string(),
+                    version: String::new(),
+                    passing: false,
+                },
+                Badge {
+                    recipe: "foo".to_string(),
+                    version: "1.2.0".to_string(),
+                    passing: true,
+                },
+            ]
+        );
+
+        let svg = badges[1].render(BadgeFormat::Svg);
+        assert!(svg.contains(">1.2.0 passing</text>"));
+        assert!(svg.contains(PASSING_COLOR));
+        let endpoint: serde_json::Value =
+            serde_json::from_str(&badges[0].render(BadgeFormat::Shields)).unwrap();
+        assert_eq!(endpoint["label"], "bar");
+        assert_eq!(endpoint["message"], "failing");
+        assert_eq!(endpoint["color"], "red");
+        assert_eq!(escape_xml("a<b>&\""), "a&lt;b&gt;&amp;&quot;");
+    }
+}
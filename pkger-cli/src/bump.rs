@@ -0,0 +1,229 @@
+//! Routine parts of version bumps of recipes. The version in the recipe file and in the URL of
+//! its source is replaced, the new source is fetched and pinned in the lockfile and an entry is
+//! added to the changelog of the recipe.
+use crate::config::Configuration;
+use crate::opts::BumpOpts;
+use crate::{Error, Result};
+use pkger_core::build::lock::{recipe_hash, Lockfile, RecipePin};
+use pkger_core::recipe::{
+    load_changelog, recipe_file, ChangelogEntry, Loader, DEFAULT_CHANGELOG_FILE,
+};
+use pkger_core::ErrContext;
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::process::Command;
+use tempdir::TempDir;
+use tracing::{info, info_span, trace, warn};
+
+pub fn recipe(loader: &Loader, config: &Configuration, opts: BumpOpts) -> Result<()> {
+    let span = info_span!("bump", recipe = %opts.recipe);
+    let _enter = span.enter();
+    trace!(opts = ?opts);
+
+    let recipe = loader.load(&opts.recipe)?;
+    let old_version = recipe.metadata.version.clone();
+    let version = match opts.version {
+        Some(version) => version,
+        None => prompt(&format!(
+            "new version of `{}` (current {}): ",
+            recipe.metadata.name, old_version
+        ))?,
+    };
+    if version.is_empty() || version == old_version {
+        return Err(Error::msg(format!(
+            "the new version must differ from the current one `{}`",
+            old_version
+        )));
+    }
+
+    let path = recipe_file(&recipe.recipe_dir)?;
+    let old = fs::read_to_string(&path)
+        .context(format!("failed to read recipe file `{}`", path.display()))?;
+    let new = replace_version(&old, &old_version, &version)?;
+    fs::write(&path, &new).context(format!("failed to save `{}`", path.display()))?;
+    info!(from = %old_version, to = %version, "bumped version");
+
+    let changes = if opts.message.is_empty() {
+        vec![format!("Update to version {}", version)]
+    } else {
+        opts.message
+    };
+    add_changelog_entry(
+        &recipe.recipe_dir.join(DEFAULT_CHANGELOG_FILE),
+        ChangelogEntry {
+            version: version.clone(),
+            release: None,
+            date: Utc::now().format("%Y-%m-%d").to_string(),
+            author: None,
+            changes,
+        },
+    )?;
+
+    let new_source = recipe
+        .metadata
+        .source
+        .as_ref()
+        .map(|source| source.replace(&old_version, &version));
+    let source = match (&new_source, opts.no_fetch) {
+        (Some(source), false) => Some(
+            source_digest(source).context(format!("failed to fetch new source `{}`", source))?,
+        ),
+        _ => None,
+    };
+    if recipe.metadata.git.is_some() {
+        warn!("git sources are not pinned, update the branch of the repository if necessary");
+    }
+
+    let mut lockfile = Lockfile::load(config.lockfile_path())?;
+    lockfile.pin(
+        &recipe.metadata.name,
+        &version,
+        RecipePin {
+            recipe: recipe_hash(&recipe.recipe_dir)?,
+            source,
+        },
+    );
+    lockfile.save()?;
+    info!(path = %lockfile.path().display(), "pinned new version");
+
+    if opts.edit {
+        edit_diff(&path, &old, &new)?;
+    }
+
+    Ok(())
+}
+
+/// Asks the user for a value on stdin
+fn prompt(question: &str) -> Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// Replaces `old` with `new` in the version of the recipe and in its source
+fn replace_version(recipe: &str, old: &str, new: &str) -> Result<String> {
+    let mut replaced = false;
+    let lines = recipe
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            if !replaced && trimmed.starts_with("version:") {
+                let value = trimmed["version:".len()..]
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == '\'');
+                if value == old {
+                    replaced = true;
+                    return line.replacen(old, new, 1);
+                }
+            } else if trimmed.starts_with("source:") {
+                return line.replace(old, new);
+            }
+            line.to_string()
+        })
+        .collect::<Vec<_>>();
+    if !replaced {
+        return Err(Error::msg(format!(
+            "version `{}` not found in the recipe file",
+            old
+        )));
+    }
+
+    let mut bumped = lines.join("\n");
+    if recipe.ends_with('\n') {
+        bumped.push('\n');
+    }
+    Ok(bumped)
+}
+
+fn add_changelog_entry(path: &Path, entry: ChangelogEntry) -> Result<()> {
+    let mut entries = load_changelog(path)?;
+    entries.insert(0, entry);
+    fs::write(path, serde_yaml::to_string(&entries)?)
+        .context(format!("failed to save changelog `{}`", path.display()))
+}
+
+/// Returns the digest of the source the same way it is computed during a build
+fn source_digest(source: &str) -> Result<String> {
+    let data = if source.starts_with("http") {
+        info!(url = %source, "fetching new source");
+        let mut data = vec![];
+        ureq::get(source)
+            .call()
+            .map_err(|e| Error::msg(e.to_string()))?
+            .into_reader()
+            .read_to_end(&mut data)?;
+        data
+    } else {
+        fs::read(source)?
+    };
+    Ok(format!("sha256:{:x}", Sha256::digest(&data)))
+}
+
+/// Opens the diff of the recipe file in `$EDITOR`
+fn edit_diff(path: &Path, old: &str, new: &str) -> Result<()> {
+    let editor = env::var("EDITOR").map_err(|_| Error::msg("`$EDITOR` is not set"))?;
+    let tmp = TempDir::new("pkger-bump")?;
+    let old_path = tmp.path().join("recipe.yml.old");
+    let diff_path = tmp.path().join("recipe.yml.diff");
+    fs::write(&old_path, old)?;
+
+    let output = Command::new("diff")
+        .arg("-u")
+        .arg(&old_path)
+        .arg(path)
+        .output()
+        .context("failed to run `diff`")?;
+    let diff = if output.stdout.is_empty() {
+        new.to_string()
+    } else {
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+    fs::write(&diff_path, diff)?;
+
+    let status = Command::new(editor)
+        .arg(&diff_path)
+        .status()
+        .context("failed to run `$EDITOR`")?;
+    if !status.success() {
+        warn!(status = ?status.code(), "editor exited with an error");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_version() {
+        let recipe = r#"metadata:
+  name: foo
+  version: "1.0.0"
+  source: https://example.com/foo-1.0.0.tar.gz
+  depends:
+    - bar
+build:
+  steps:
+    - cmd: echo version: 1.0.0
+"#;
+        let expected = r#"metadata:
+  name: foo
+  version: "1.1.0"
+  source: https://example.com/foo-1.1.0.tar.gz
+  depends:
+    - bar
+build:
+  steps:
+    - cmd: echo version: 1.0.0
+"#;
+        assert_eq!(replace_version(recipe, "1.0.0", "1.1.0").unwrap(), expected);
+        assert!(replace_version(recipe, "2.0.0", "2.1.0").is_err());
+    }
+}
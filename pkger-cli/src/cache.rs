@@ -0,0 +1,150 @@
+//! Backends of the remote artifact cache. Archives are stored as `<key>.tar` either on an HTTP
+//! server accepting `GET` and `PUT` requests or in an S3 bucket.
+use crate::s3::S3Bucket;
+use crate::{Error, Result};
+use pkger_core::build::cache::ArtifactCache;
+use pkger_core::ErrContext;
+
+use serde::Deserialize;
+use std::io::Read;
+use std::sync::Arc;
+use tracing::trace;
+
+static ARCHIVE_EXTENSION: &str = "tar";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(flatten)]
+    pub backend: CacheBackend,
+    #[serde(default = "default_push")]
+    /// Whether artifacts built locally are pushed to the cache
+    pub push: bool,
+}
+
+fn default_push() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum CacheBackend {
+    Http {
+        /// Base URL under which the archives are stored
+        url: String,
+        /// Sent as a bearer token in the `Authorization` header
+        token: Option<String>,
+    },
+    S3 {
+        bucket: String,
+        region: String,
+        /// Endpoint of an S3 compatible storage, defaults to AWS
+        endpoint: Option<String>,
+        /// Prefix of the keys of the archives in the bucket
+        #[serde(default)]
+        prefix: String,
+        access_key_id: String,
+        secret_access_key: String,
+    },
+}
+
+impl CacheConfig {
+    pub fn connect(&self) -> Arc<dyn ArtifactCache> {
+        match &self.backend {
+            CacheBackend::Http { url, token } => Arc::new(HttpCache {
+                url: url.trim_end_matches('/').to_string(),
+                token: token.clone(),
+            }),
+            CacheBackend::S3 {
+                bucket,
+                region,
+                endpoint,
+                prefix,
+                access_key_id,
+                secret_access_key,
+            } => Arc::new(S3Cache {
+                bucket: S3Bucket::new(
+                    bucket,
+                    region,
+                    endpoint.as_deref(),
+                    prefix,
+                    access_key_id,
+                    secret_access_key,
+                ),
+            }),
+        }
+    }
+}
+
+/// Reads the body of a response to a `GET` request treating `404` as a missing archive
+fn read_archive(
+    response: std::result::Result<ureq::Response, ureq::Error>,
+) -> Result<Option<Vec<u8>>> {
+    match response {
+        Ok(response) => {
+            let mut archive = Vec::new();
+            response
+                .into_reader()
+                .read_to_end(&mut archive)
+                .context("failed to download archive")?;
+            Ok(Some(archive))
+        }
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(e) => Err(Error::msg(format!("failed to download archive - {}", e))),
+    }
+}
+
+fn check_upload(response: std::result::Result<ureq::Response, ureq::Error>) -> Result<()> {
+    response
+        .map(|_| ())
+        .map_err(|e| Error::msg(format!("failed to upload archive - {}", e)))
+}
+
+#[derive(Debug)]
+struct HttpCache {
+    url: String,
+    token: Option<String>,
+}
+
+impl HttpCache {
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let url = format!("{}/{}.{}", self.url, key, ARCHIVE_EXTENSION);
+        trace!(method = %method, url = %url);
+        let request = ureq::request(method, &url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+}
+
+impl ArtifactCache for HttpCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        read_archive(self.request("GET", key).call())
+    }
+
+    fn put(&self, key: &str, archive: Vec<u8>) -> Result<()> {
+        check_upload(self.request("PUT", key).send_bytes(&archive))
+    }
+}
+
+#[derive(Debug)]
+struct S3Cache {
+    bucket: S3Bucket,
+}
+
+impl S3Cache {
+    fn request(&self, method: &str, key: &str, payload: &[u8]) -> ureq::Request {
+        self.bucket
+            .request(method, &format!("{}.{}", key, ARCHIVE_EXTENSION), payload)
+    }
+}
+
+impl ArtifactCache for S3Cache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        read_archive(self.request("GET", key, &[]).call())
+    }
+
+    fn put(&self, key: &str, archive: Vec<u8>) -> Result<()> {
+        check_upload(self.request("PUT", key, &archive).send_bytes(&archive))
+    }
+}
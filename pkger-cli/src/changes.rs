@@ -0,0 +1,113 @@
+//! Detection of recipes affected by changes in a git repository, so that pipelines over large
+//! repositories of recipes only build what changed.
+use crate::{Error, Result};
+use pkger_core::recipe::Recipe;
+use pkger_core::ErrContext;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{info_span, trace};
+
+/// Runs git in `dir` returning the lines of its output
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<String>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "`git {}` failed - {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns absolute paths of files in the repository containing `dir` that changed since
+/// `reference`, including uncommitted and untracked files
+pub fn changed_since(dir: &Path, reference: &str) -> Result<Vec<PathBuf>> {
+    let span = info_span!("changed-since", reference = %reference);
+    let _enter = span.enter();
+
+    let root = git(dir, &["rev-parse", "--show-toplevel"])?
+        .pop()
+        .ok_or_else(|| Error::msg("failed to find the root of the git repository"))?;
+    let root = PathBuf::from(root);
+
+    let mut changed = git(dir, &["diff", "--name-only", reference, "--"])
+        .context(format!("failed to diff against `{}`", reference))?;
+    changed.extend(git(dir, &["ls-files", "--others", "--exclude-standard"])?);
+    trace!(changed = ?changed);
+
+    Ok(changed.into_iter().map(|path| root.join(path)).collect())
+}
+
+/// Returns true if any of `changed` is one of `paths` or is inside of one of them
+fn is_affected(paths: &[PathBuf], changed: &[PathBuf]) -> bool {
+    changed
+        .iter()
+        .any(|changed| paths.iter().any(|path| changed.starts_with(path)))
+}
+
+/// Returns true if the directory of `recipe`, of a recipe it extends, its local sources or one of
+/// its custom images in `images_dir` changed
+pub fn recipe_changed(recipe: &Recipe, images_dir: &Path, changed: &[PathBuf]) -> bool {
+    let mut paths = vec![recipe.recipe_dir.clone()];
+    paths.extend(recipe.bases.iter().cloned());
+    for source in recipe
+        .metadata
+        .source
+        .iter()
+        .chain(&recipe.metadata.extra_sources)
+    {
+        if !source.starts_with("http") {
+            paths.push(PathBuf::from(source));
+        }
+    }
+    if let Some(images) = &recipe.metadata.images {
+        paths.extend(images.iter().map(|target| images_dir.join(&target.image)));
+    }
+    // git reports paths with symlinks resolved
+    let paths = paths
+        .into_iter()
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .collect::<Vec<_>>();
+
+    is_affected(&paths, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_affected_paths() {
+        let paths = vec![
+            PathBuf::from("/repo/recipes/foo"),
+            PathBuf::from("/repo/src/foo.tar.gz"),
+        ];
+        assert!(is_affected(
+            &paths,
+            &[PathBuf::from("/repo/recipes/foo/recipe.yml")]
+        ));
+        assert!(is_affected(
+            &paths,
+            &[PathBuf::from("/repo/src/foo.tar.gz")]
+        ));
+        assert!(!is_affected(
+            &paths,
+            &[
+                PathBuf::from("/repo/recipes/foobar/recipe.yml"),
+                PathBuf::from("/repo/README.md")
+            ]
+        ));
+    }
+}
@@ -1,6 +1,24 @@
+use crate::audit::AuditConfig;
+use crate::badge::BadgeFormat;
+use crate::cache::CacheConfig;
+use crate::publish::PublishConfig;
+use crate::report::ReportFormat;
+use crate::schedule::Schedule;
+use crate::vault::VaultConfig;
+use crate::webhook::WebhookConfig;
 use crate::Result;
+use pkger_core::build::credentials::SourceCredentials;
+use pkger_core::build::lock::LOCKFILE;
+use pkger_core::build::sign::SigningConfig;
+use pkger_core::container::{OutputLimit, StallDetection};
+use pkger_core::docker::Runtime;
+use pkger_core::image::{backend::StateBackendConfig, gc::GcPolicy};
+use pkger_core::recipe::{DiskOptions, SecurityOptions};
+use pkger_core::repo::RepoConfig;
+use pkger_core::secrets;
 
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -9,10 +27,113 @@ pub struct Configuration {
     pub recipes_dir: PathBuf,
     pub output_dir: PathBuf,
     pub images_dir: Option<PathBuf>,
+    /// Directory where the state of pkger is kept, defaults to `$XDG_STATE_HOME/pkger`
+    pub state_dir: Option<PathBuf>,
+    /// Where the state of images is stored, defaults to a file in `state_dir`
+    pub state_backend: Option<StateBackendConfig>,
     pub docker: Option<String>,
+    /// Container runtime serving the API of `docker` or `docker_hosts`, defaults to `docker`
+    pub runtime: Option<Runtime>,
+    /// Multiple Docker daemons to distribute builds across
+    pub docker_hosts: Option<Vec<DockerHost>>,
+    /// Policy of removing unused images created by pkger
+    pub image_gc: Option<GcPolicy>,
+    /// Builds to run periodically with `pkger serve`
+    pub schedules: Option<Vec<Schedule>>,
+    /// Webhooks of pushed tags that trigger builds of `pkger serve`
+    pub webhooks: Option<WebhookConfig>,
+    #[serde(default)]
+    /// Whether `pkger serve` interrupts the running build when a build of recipes with a higher
+    /// priority is triggered
+    pub preempt: bool,
+    #[serde(default)]
+    /// Whether builds should stop after the first failure by default
+    pub fail_fast: bool,
+    #[serde(default)]
+    /// Whether builds of deprecated recipes should fail instead of only warning
+    pub fail_deprecated: bool,
+    #[serde(default)]
+    /// Whether builds save checkpoints of their containers after each phase by default
+    pub checkpoints: bool,
+    /// Vault server from which credentials of builds are fetched
+    pub vault: Option<VaultConfig>,
+    /// Key used to sign built packages
+    pub signing: Option<SigningConfig>,
+    #[serde(default)]
+    /// Credentials of private git repositories and HTTP sources
+    pub credentials: Vec<SourceCredentials>,
+    /// Where records of all builds are saved
+    pub audit: Option<AuditConfig>,
+    /// Restrictions of build containers applied on top of the ones of each recipe
+    pub security: Option<SecurityOptions>,
+    /// Disk limits of builds, recipes can only lower them
+    pub disk: Option<DiskOptions>,
+    /// Detection of build commands that hang
+    pub stall_detection: Option<StallDetection>,
+    /// Truncation of long output of build commands
+    pub output_limit: Option<OutputLimit>,
+    /// Remote cache of built artifacts
+    pub cache: Option<CacheConfig>,
+    /// Distribution of built artifacts as torrents or on IPFS
+    pub publish: Option<PublishConfig>,
+    /// Retention of packages in repositories updated with `pkger repo`
+    pub repo: Option<RepoConfig>,
+    #[serde(default)]
+    /// Names of recipes whose steps may run with extended privileges
+    pub privileged_recipes: Vec<String>,
+    /// Lockfile in which built recipes are pinned, defaults to `pkger.lock` in the parent
+    /// directory of `recipes_dir`
+    pub lockfile: Option<PathBuf>,
+    /// Format of the report saved after every build
+    pub report: Option<ReportFormat>,
+    #[serde(default)]
+    /// Whether the logs, packaging files and manifests of every build are bundled into an archive
+    pub archive: bool,
+    #[serde(default)]
+    /// Formats of the badges of recipes saved after every build
+    pub badges: Vec<BadgeFormat>,
+    #[serde(default)]
+    /// Regular expressions of values redacted from logs and saved scripts of builds
+    pub redact: Vec<String>,
+    #[serde(default)]
+    /// Whether variables of recipes can't run commands and only read files of the recipe
+    pub untrusted_recipes: bool,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct DockerHost {
+    pub uri: String,
+    #[serde(default = "default_weight")]
+    /// Relative share of builds that should run on this host
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
 impl Configuration {
+    /// Loads the configuration resolving all `env:VAR` and `file:/path` references
     pub fn load<P: AsRef<Path>>(val: P) -> Result<Self> {
-        Ok(serde_yaml::from_slice(&fs::read(val.as_ref())?)?)
+        let mut value: serde_yaml::Value = serde_yaml::from_slice(&fs::read(val.as_ref())?)?;
+        secrets::resolve(&mut value, &HashMap::new())?;
+        let config: Self = serde_yaml::from_value(value)?;
+        secrets::add_rules(&config.redact)?;
+        for credentials in &config.credentials {
+            credentials.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Path of the lockfile in which built recipes are pinned
+    pub fn lockfile_path(&self) -> PathBuf {
+        match &self.lockfile {
+            Some(path) => path.clone(),
+            None => self
+                .recipes_dir
+                .parent()
+                .unwrap_or(&self.recipes_dir)
+                .join(LOCKFILE),
+        }
     }
 }
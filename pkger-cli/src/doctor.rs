@@ -0,0 +1,388 @@
+//! Diagnostics of the environment in which pkger runs. Every check prints its result and, if it
+//! failed, how to fix the problem.
+use crate::config::Configuration;
+use pkger_core::build::{available_space, sign::DEFAULT_GPG};
+use pkger_core::docker::{Docker, DockerConnectionPool};
+use pkger_core::recipe::{self, parse_size, BuildArch};
+use pkger_core::units::HumanSize;
+use pkger_core::{Error, Result};
+
+use colored::Colorize;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Free space below which the output directory is reported if the configuration sets no
+/// `disk.min_free`
+const DEFAULT_MIN_FREE: u64 = 1024 * 1024 * 1024;
+
+static BINFMT_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok<M: Into<String>>(name: &'static str, message: M) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning<M: Into<String>, F: Into<String>>(name: &'static str, message: M, fix: F) -> Self {
+        Self {
+            name,
+            status: Status::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error<M: Into<String>, F: Into<String>>(name: &'static str, message: M, fix: F) -> Self {
+        Self {
+            name,
+            status: Status::Error,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn print(&self) {
+        let status = match self.status {
+            Status::Ok => "ok".green(),
+            Status::Warning => "warning".yellow(),
+            Status::Error => "error".red(),
+        };
+        println!("{:<8} {} ... {}", self.name.bold(), self.message, status);
+        if let Some(fix) = &self.fix {
+            println!("         {} {}", "fix:".bold(), fix);
+        }
+    }
+}
+
+/// Runs all checks with the configuration at `config_path`, fails if any check found an error
+pub async fn run(config_path: &str) -> Result<()> {
+    let mut checks = vec![];
+    match Configuration::load(config_path) {
+        Ok(config) => {
+            checks.push(Check::ok(
+                "config",
+                format!("configuration `{}` is valid", config_path),
+            ));
+            checks.push(check_recipes(&config));
+            checks.push(check_output_dir(&config));
+            checks.extend(check_docker(&config).await);
+            checks.push(check_binfmt(&config));
+            checks.push(check_gpg(&config));
+        }
+        Err(e) => {
+            let fix = if Path::new(config_path).exists() {
+                "correct the reported field of the configuration".to_string()
+            } else {
+                "create the configuration file or pass its location with `--config`, `recipes_dir` and `output_dir` are required".to_string()
+            };
+            checks.push(Check::error(
+                "config",
+                format!("failed to load `{}`: {}", config_path, e),
+                fix,
+            ));
+        }
+    }
+
+    checks.iter().for_each(Check::print);
+    let errors = checks
+        .iter()
+        .filter(|check| matches!(check.status, Status::Error))
+        .count();
+    let warnings = checks
+        .iter()
+        .filter(|check| matches!(check.status, Status::Warning))
+        .count();
+    println!("\n{} errors, {} warnings", errors, warnings);
+
+    if errors > 0 {
+        return Err(Error::msg(format!("{} checks failed", errors)));
+    }
+    Ok(())
+}
+
+fn check_recipes(config: &Configuration) -> Check {
+    let dir = &config.recipes_dir;
+    let loader = match recipe::Loader::new(dir) {
+        Ok(loader) => loader.with_trusted(!config.untrusted_recipes),
+        Err(e) => {
+            return Check::error(
+                "recipes",
+                format!("recipes directory `{}` is not usable: {}", dir.display(), e),
+                "create the directory or point `recipes_dir` to an existing one",
+            )
+        }
+    };
+    if let Err(e) = fs::read_dir(dir) {
+        return Check::error(
+            "recipes",
+            format!("failed to read `{}`: {}", dir.display(), e),
+            "make the directory readable by the current user",
+        );
+    }
+
+    let names = loader.list();
+    let invalid = names
+        .iter()
+        .filter_map(|name| {
+            loader
+                .load(name)
+                .err()
+                .map(|e| format!("{} ({:#})", name, e))
+        })
+        .collect::<Vec<_>>();
+    if invalid.is_empty() {
+        Check::ok(
+            "recipes",
+            format!("{} recipes in `{}` load", names.len(), dir.display()),
+        )
+    } else {
+        Check::warning(
+            "recipes",
+            format!(
+                "{} of {} recipes fail to load: {}",
+                invalid.len(),
+                names.len(),
+                invalid.join(", ")
+            ),
+            "correct the recipes, `pkger test-recipes` shows what they render to",
+        )
+    }
+}
+
+fn check_output_dir(config: &Configuration) -> Check {
+    let dir = &config.output_dir;
+    if let Err(e) = fs::create_dir_all(dir) {
+        return Check::error(
+            "output",
+            format!("failed to create `{}`: {}", dir.display(), e),
+            "point `output_dir` to a directory writable by the current user",
+        );
+    }
+    let probe = dir.join(".pkger-doctor");
+    if let Err(e) = fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+        return Check::error(
+            "output",
+            format!("`{}` is not writable: {}", dir.display(), e),
+            "point `output_dir` to a directory writable by the current user",
+        );
+    }
+
+    let min_free = config
+        .disk
+        .as_ref()
+        .and_then(|disk| disk.min_free.as_deref())
+        .and_then(|min_free| parse_size(min_free).ok())
+        .unwrap_or(DEFAULT_MIN_FREE);
+    match available_space(dir) {
+        Ok(available) if available < min_free => Check::warning(
+            "output",
+            format!(
+                "only {} available in `{}`, less than {}",
+                HumanSize(available),
+                dir.display(),
+                HumanSize(min_free)
+            ),
+            "free up space, for example by removing old packages or unused images with `image_gc`",
+        ),
+        Ok(available) => Check::ok(
+            "output",
+            format!(
+                "`{}` is writable with {} available",
+                dir.display(),
+                HumanSize(available)
+            ),
+        ),
+        Err(e) => Check::warning(
+            "output",
+            format!("failed to check free space in `{}`: {}", dir.display(), e),
+            "make sure the output directory is on a local filesystem",
+        ),
+    }
+}
+
+/// Returns the daemons of the configuration with their uris
+fn docker_hosts(config: &Configuration) -> Result<Vec<(String, Docker)>> {
+    if let Some(hosts) = &config.docker_hosts {
+        hosts
+            .iter()
+            .map(|host| {
+                Ok((
+                    host.uri.clone(),
+                    DockerConnectionPool::new(&host.uri)?.connect(),
+                ))
+            })
+            .collect()
+    } else if let Some(uri) = &config.docker {
+        Ok(vec![(
+            uri.clone(),
+            DockerConnectionPool::new(uri)?.connect(),
+        )])
+    } else {
+        let runtime = config.runtime.unwrap_or_default();
+        Ok(vec![(
+            format!("default {} socket", runtime.as_ref()),
+            DockerConnectionPool::for_runtime(runtime).connect(),
+        )])
+    }
+}
+
+async fn check_docker(config: &Configuration) -> Vec<Check> {
+    let hosts = match docker_hosts(config) {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            return vec![Check::error(
+                "docker",
+                format!("invalid docker uri: {:#}", e),
+                "set `docker` to an uri like `unix:///var/run/docker.sock` or `tcp://host:2376`",
+            )]
+        }
+    };
+
+    let mut checks = vec![];
+    for (uri, docker) in hosts {
+        checks.push(match docker.version().await {
+            Ok(version) => Check::ok(
+                "docker",
+                format!(
+                    "connected to {}, version {} (API {})",
+                    uri, version.version, version.api_version
+                ),
+            ),
+            Err(e) => Check::error(
+                "docker",
+                format!("failed to connect to {}: {}", uri, e),
+                "start the daemon and make sure the current user can access it, for a local socket add the user to the `docker` group",
+            ),
+        });
+    }
+    checks
+}
+
+/// Name of the binfmt handler of qemu that runs binaries of `arch`, `None` if the host runs them
+/// natively
+fn qemu_handler(arch: &BuildArch) -> Option<String> {
+    let host = env::consts::ARCH;
+    let (handler, native) = match arch {
+        BuildArch::All => return None,
+        BuildArch::x86_64 => ("x86_64", &["x86_64"][..]),
+        BuildArch::x86 => ("i386", &["x86", "x86_64"][..]),
+        BuildArch::Arm | BuildArch::Armv6h | BuildArch::Armv7h => ("arm", &["arm", "aarch64"][..]),
+        BuildArch::Arm64 => ("aarch64", &["aarch64"][..]),
+        BuildArch::Other(arch) => return Some(arch.clone()).filter(|arch| arch != host),
+    };
+    Some(handler.to_string()).filter(|_| !native.contains(&host))
+}
+
+fn check_binfmt(config: &Configuration) -> Check {
+    let recipes = recipe::Loader::new(&config.recipes_dir)
+        .and_then(|loader| loader.with_trusted(!config.untrusted_recipes).load_all())
+        .unwrap_or_default();
+    let handlers = recipes
+        .iter()
+        .flat_map(|recipe| {
+            let metadata = &recipe.metadata;
+            std::iter::once(&metadata.arch).chain(
+                metadata
+                    .image_options
+                    .values()
+                    .filter_map(|options| options.arch.as_ref()),
+            )
+        })
+        .filter_map(qemu_handler)
+        .collect::<BTreeSet<_>>();
+    if handlers.is_empty() {
+        return Check::ok("binfmt", "no recipe targets a foreign architecture");
+    }
+
+    let missing = handlers
+        .iter()
+        .filter(|handler| {
+            !Path::new(BINFMT_DIR)
+                .join(format!("qemu-{}", handler))
+                .exists()
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Check::ok(
+            "binfmt",
+            format!(
+                "handlers of foreign architectures are registered: {}",
+                handlers.into_iter().collect::<Vec<_>>().join(", ")
+            ),
+        )
+    } else {
+        Check::warning(
+            "binfmt",
+            format!(
+                "recipes target architectures without a registered qemu handler: {}",
+                missing.join(", ")
+            ),
+            "register the handlers with `docker run --rm --privileged multiarch/qemu-user-static --reset -p yes` or install `qemu-user-static`",
+        )
+    }
+}
+
+fn check_gpg(config: &Configuration) -> Check {
+    let signing = match &config.signing {
+        Some(signing) => signing,
+        None => return Check::ok("gpg", "signing is not configured"),
+    };
+    let gpg = signing
+        .gpg
+        .as_deref()
+        .unwrap_or_else(|| Path::new(DEFAULT_GPG));
+    let gpg_command = || {
+        let mut cmd = Command::new(gpg);
+        if let Some(homedir) = &signing.homedir {
+            cmd.arg("--homedir").arg(homedir);
+        }
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+        cmd
+    };
+
+    match gpg_command().arg("--version").status() {
+        Ok(status) if status.success() => {}
+        _ => {
+            return Check::error(
+                "gpg",
+                format!("failed to run `{}`", gpg.display()),
+                "install GnuPG or point `signing.gpg` to the gpg binary",
+            )
+        }
+    }
+    match gpg_command()
+        .args(["--list-secret-keys", signing.key.as_str()])
+        .status()
+    {
+        Ok(status) if status.success() => Check::ok(
+            "gpg",
+            format!("secret key `{}` is available", signing.key),
+        ),
+        _ => Check::error(
+            "gpg",
+            format!("secret key `{}` is not available", signing.key),
+            "import the key with `gpg --import` or set `signing.homedir` to the keyring containing it",
+        ),
+    }
+}
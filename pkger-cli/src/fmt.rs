@@ -1,15 +1,21 @@
 use crate::opts::Opts;
+use crate::system_log::SystemLog;
 
 use chrono::Utc;
 use colored::Colorize;
+use pkger_core::secrets;
+
 use std::env;
 use std::fmt;
-use tracing::{field::Field, info_span, trace, Level};
+use std::io;
+use tracing::{field::Field, info_span, trace, warn, Level};
 use tracing_core::{Event, Subscriber};
 use tracing_subscriber::field::{MakeExt, MakeVisitor, RecordFields, VisitFmt};
 use tracing_subscriber::field::{Visit, VisitOutput};
 use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
 
 static DEFAULT_FIELD_DELIM: &str = ", ";
 
@@ -40,19 +46,50 @@ pub fn setup_tracing(opts: &Opts) {
     let fields_fmt = PkgerFieldsFmt::from(&fmt_filter);
     let events_fmt = PkgerEventFmt::from(&fmt_filter);
 
+    let (system_log, system_log_error) = match opts.log.map(SystemLog::connect) {
+        Some(Ok(log)) => (Some(log), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
     tracing_subscriber::fmt::fmt()
         .with_max_level(Level::TRACE)
         .with_env_filter(&filter)
         .fmt_fields(fields_fmt)
         .event_format(events_fmt)
+        .with_writer(|| RedactedWriter(io::stdout()))
+        .finish()
+        .with(system_log)
         .init();
 
+    if let Some(e) = system_log_error {
+        warn!(log = ?opts.log, reason = %e, "failed to connect to the system log");
+    }
+
     trace!(log_filter = %filter);
     trace!(fmt_filter = ?fmt_filter);
 }
 
 //####################################################################################################
 
+/// Writer that redacts secrets resolved from recipes before passing the output further. Each
+/// event is formatted before it is written so a secret is never split between two writes.
+struct RedactedWriter<W>(W);
+
+impl<W: io::Write> io::Write for RedactedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let out = String::from_utf8_lossy(buf);
+        self.0.write_all(secrets::redact(&out).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+//####################################################################################################
+
 #[derive(Debug)]
 struct FmtFilter<'delim> {
     show_date: bool,
@@ -1,6 +1,6 @@
 use crate::opts::GenRecipeOpts;
 use crate::Result;
-use pkger_core::recipe::{DebRep, MetadataRep, PkgRep, RecipeRep, RpmRep};
+use pkger_core::recipe::{DebRep, MetadataRep, PkgRep, RecipeRep, RpmRep, SourceRep};
 
 use serde_yaml::{Mapping, Value as YamlValue};
 use std::fs;
@@ -56,6 +56,7 @@ pub fn recipe(opts: Box<GenRecipeOpts>) -> Result<()> {
         installed_size: opts.installed_size,
         built_using: opts.built_using,
         essential: opts.essential,
+        multi_arch: opts.multi_arch,
 
         pre_depends: vec_as_deps!(opts.pre_depends),
         recommends: vec_as_deps!(opts.recommends),
@@ -63,6 +64,12 @@ pub fn recipe(opts: Box<GenRecipeOpts>) -> Result<()> {
         breaks: vec_as_deps!(opts.breaks),
         replaces: vec_as_deps!(opts.replaces.clone()),
         enchances: vec_as_deps!(opts.enchances),
+
+        extra_fields: None,
+
+        conffiles: None,
+
+        debian_dir: None,
     };
 
     let rpm = RpmRep {
@@ -75,6 +82,10 @@ pub fn recipe(opts: Box<GenRecipeOpts>) -> Result<()> {
         preun_script: None,
         postun_script: None,
         config_noreplace: opts.config_noreplace,
+        file_attributes: None,
+        extra_tags: None,
+
+        spec_file: None,
     };
 
     let pkg = PkgRep {
@@ -90,19 +101,42 @@ pub fn recipe(opts: Box<GenRecipeOpts>) -> Result<()> {
         description: opts.description.unwrap_or_else(|| "missing".to_string()),
         license: opts.license.unwrap_or_else(|| "missing".to_string()),
         images: None,
+        build_image: None,
+        preset: None,
 
         maintainer: opts.maintainer,
+        owners: None,
+        docs: None,
         url: opts.url,
         arch: opts.arch,
-        source: opts.source,
+        source: opts.source.map(SourceRep::from),
+        mirrors: None,
+        checksum: None,
+        sha256: None,
+        sha512: None,
         git,
+        signature: None,
+        fetch_artifacts: None,
         skip_default_deps: opts.skip_default_deps,
         exclude: opts.exclude,
         group: opts.group,
         release: opts.release,
         epoch: opts.epoch,
+        priority: None,
+        deprecated: None,
+        features: None,
+        changelog: None,
+        security: None,
+        disk: None,
+        network: None,
+        devices: None,
+        gpus: None,
+        rpath: None,
+        symbols: None,
+        noninteractive: None,
 
         build_depends: vec_as_deps!(opts.build_depends),
+        dependency_names: None,
         depends: vec_as_deps!(opts.depends),
         conflicts: vec_as_deps!(opts.conflicts),
         provides: vec_as_deps!(opts.provides),
@@ -111,6 +145,7 @@ pub fn recipe(opts: Box<GenRecipeOpts>) -> Result<()> {
         deb: Some(deb),
         rpm: Some(rpm),
         pkg: Some(pkg),
+        apk: None,
     };
 
     let recipe = RecipeRep {
@@ -0,0 +1,739 @@
+use crate::opts::ImportOpts;
+use crate::{Error, Result};
+use pkger_core::recipe::{
+    BuildRep, Command, DebRep, InstallRep, MetadataRep, RecipeRep, RpmRep, SourceRep,
+};
+use pkger_core::ErrContext;
+
+use serde_yaml::Value as YamlValue;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, info_span, trace, warn};
+
+static DEFAULT_RECIPE_FILE: &str = "recipe.yml";
+
+/// Common RPM macros and their values used when converting scripts of a spec file
+const RPM_MACROS: &[(&str, &str)] = &[
+    ("buildroot", "$PKGER_OUT_DIR"),
+    ("_builddir", "$PKGER_BLD_DIR"),
+    ("_prefix", "/usr"),
+    ("_exec_prefix", "/usr"),
+    ("_bindir", "/usr/bin"),
+    ("_sbindir", "/usr/sbin"),
+    ("_libdir", "/usr/lib64"),
+    ("_libexecdir", "/usr/libexec"),
+    ("_datadir", "/usr/share"),
+    ("_mandir", "/usr/share/man"),
+    ("_docdir", "/usr/share/doc"),
+    ("_infodir", "/usr/share/info"),
+    ("_includedir", "/usr/include"),
+    ("_sysconfdir", "/etc"),
+    ("_localstatedir", "/var"),
+    ("_sharedstatedir", "/var/lib"),
+    ("_unitdir", "/usr/lib/systemd/system"),
+];
+
+/// Creates a recipe from an existing spec file or `debian/` directory
+pub fn recipe(opts: ImportOpts) -> Result<()> {
+    let span = info_span!("import", path = %opts.path.display());
+    let _enter = span.enter();
+    trace!(opts = ?opts);
+
+    let recipe = if opts.path.is_dir() {
+        let debian_dir = if opts.path.join("control").exists() {
+            opts.path.clone()
+        } else {
+            opts.path.join("debian")
+        };
+        from_debian_dir(&debian_dir)?
+    } else {
+        let spec = fs::read_to_string(&opts.path).context(format!(
+            "failed to read spec file `{}`",
+            opts.path.display()
+        ))?;
+        from_spec(&spec)?
+    };
+
+    let rendered = serde_yaml::to_string(&recipe)?;
+
+    if let Some(output_dir) = opts.output_dir {
+        let recipe_dir = output_dir.join(&recipe.metadata.name);
+        fs::create_dir_all(&recipe_dir)?;
+        let path = recipe_dir.join(DEFAULT_RECIPE_FILE);
+        fs::write(&path, rendered)?;
+        info!(path = %path.display(), "saved recipe");
+    } else {
+        println!("{}", rendered);
+    }
+    Ok(())
+}
+
+fn deps_value(deps: Vec<String>) -> Option<YamlValue> {
+    if deps.is_empty() {
+        None
+    } else {
+        Some(YamlValue::Sequence(
+            deps.into_iter().map(YamlValue::from).collect(),
+        ))
+    }
+}
+
+fn steps(lines: Vec<String>) -> Vec<Command> {
+    lines
+        .iter()
+        .map(|line| Command::from(line.as_str()))
+        .collect()
+}
+
+fn empty_metadata(name: String, version: String, description: String) -> MetadataRep {
+    MetadataRep {
+        name,
+        version,
+        description,
+        license: "missing".to_string(),
+        images: None,
+        build_image: None,
+        preset: None,
+        maintainer: None,
+        owners: None,
+        docs: None,
+        url: None,
+        arch: None,
+        source: None,
+        mirrors: None,
+        checksum: None,
+        sha256: None,
+        sha512: None,
+        git: None,
+        signature: None,
+        fetch_artifacts: None,
+        skip_default_deps: None,
+        exclude: None,
+        group: None,
+        release: None,
+        epoch: None,
+        priority: None,
+        deprecated: None,
+        features: None,
+        changelog: None,
+        security: None,
+        disk: None,
+        network: None,
+        devices: None,
+        gpus: None,
+        rpath: None,
+        symbols: None,
+        noninteractive: None,
+        build_depends: None,
+        dependency_names: None,
+        depends: None,
+        conflicts: None,
+        provides: None,
+        patches: None,
+        deb: None,
+        rpm: None,
+        pkg: None,
+        apk: None,
+    }
+}
+
+//####################################################################################################
+// RPM
+
+/// Expands `%{macro}` and `%{?macro}` occurrences with known values. Unknown macros are left
+/// untouched unless they are conditional.
+fn expand_rpm_macros(s: &str, macros: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("%{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                let (name, conditional) = match name.strip_prefix('?') {
+                    Some(name) => (name, true),
+                    None => (name, false),
+                };
+                match macros.get(name) {
+                    Some(value) => out.push_str(value),
+                    None if conditional => {}
+                    None => out.push_str(&rest[start..start + end + 3]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out.replace("$RPM_BUILD_ROOT", "$PKGER_OUT_DIR")
+}
+
+/// Splits a list of dependencies like `foo >= 1.0, bar baz` into names only
+fn parse_rpm_deps(s: &str) -> Vec<String> {
+    let mut deps = Vec::new();
+    let mut skip_next = false;
+    for token in s.split(|c: char| c == ',' || c.is_whitespace()) {
+        if token.is_empty() {
+            continue;
+        }
+        if skip_next {
+            skip_next = false;
+            continue;
+        }
+        if ["<", "<=", "=", ">=", ">"].contains(&token) {
+            skip_next = true;
+            continue;
+        }
+        deps.push(token.to_string());
+    }
+    deps
+}
+
+/// Translates commonly used macros that only exist in rpmbuild to plain shell commands
+fn translate_rpm_step(line: &str) -> String {
+    let line = line.trim();
+    match line.split_whitespace().next().unwrap_or_default() {
+        "%configure" => line.replacen("%configure", "./configure --prefix=/usr", 1),
+        "%make_build" => line.replacen("%make_build", "make", 1),
+        "%make_install" => line.replacen("%make_install", "make install DESTDIR=$PKGER_OUT_DIR", 1),
+        "%cmake" => line.replacen("%cmake", "cmake -DCMAKE_INSTALL_PREFIX=/usr", 1),
+        _ => line.to_string(),
+    }
+}
+
+pub fn from_spec(spec: &str) -> Result<RecipeRep> {
+    let mut macros = RPM_MACROS
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<HashMap<_, _>>();
+    let mut tags: HashMap<String, String> = HashMap::new();
+    let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+    let mut sections: HashMap<String, Vec<String>> = HashMap::new();
+    let mut section: Option<String> = None;
+
+    for line in spec.lines() {
+        let trimmed = line.trim();
+
+        if let Some(def) = trimmed
+            .strip_prefix("%global ")
+            .or_else(|| trimmed.strip_prefix("%define "))
+        {
+            let mut parts = def.trim().splitn(2, char::is_whitespace);
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                let value = expand_rpm_macros(value.trim(), &macros);
+                macros.insert(name.to_string(), value);
+            }
+            continue;
+        }
+
+        if let Some(header) = trimmed.strip_prefix('%') {
+            let mut header = header.split_whitespace();
+            let name = header.next().unwrap_or_default().to_string();
+            // sections like `%description devel` or `%files -n foo` belong to subpackages
+            let subpackage =
+                matches!(header.next(), Some(arg) if !arg.starts_with('-') || arg == "-n");
+            match name.as_str() {
+                "description" | "prep" | "build" | "install" | "check" | "files" | "changelog"
+                | "pre" | "post" | "preun" | "postun" | "package" => {
+                    section = if subpackage || name == "package" {
+                        Some("package".to_string())
+                    } else {
+                        Some(name)
+                    };
+                    continue;
+                }
+                "if" | "ifarch" | "ifnarch" | "ifos" | "else" | "endif" => {
+                    warn!(line = %trimmed, "conditionals are not supported, ignoring");
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        match &section {
+            None => {
+                if let Some((tag, value)) = trimmed.split_once(':') {
+                    let tag = tag.trim().to_lowercase();
+                    if tag.contains(char::is_whitespace) {
+                        continue;
+                    }
+                    let value = expand_rpm_macros(value.trim(), &macros);
+                    match tag.as_str() {
+                        "name" | "version" | "release" => {
+                            macros.insert(tag.clone(), value.clone());
+                            tags.insert(tag, value);
+                        }
+                        "buildrequires" | "requires" | "conflicts" | "provides" | "obsoletes" => {
+                            lists.entry(tag).or_default().extend(parse_rpm_deps(&value));
+                        }
+                        tag if tag.starts_with("patch") => {
+                            lists.entry("patch".to_string()).or_default().push(value);
+                        }
+                        tag if tag.starts_with("source") => {
+                            lists.entry("source".to_string()).or_default().push(value);
+                        }
+                        _ => {
+                            tags.insert(tag, value);
+                        }
+                    }
+                }
+            }
+            Some(section) => {
+                if section == "package" {
+                    trace!(line = %trimmed, "subpackages are not supported, skipping");
+                    continue;
+                }
+                sections
+                    .entry(section.clone())
+                    .or_default()
+                    .push(line.to_string());
+            }
+        }
+    }
+
+    let name = tags
+        .remove("name")
+        .ok_or_else(|| Error::msg("spec file is missing the `Name` tag"))?;
+    let version = tags
+        .remove("version")
+        .ok_or_else(|| Error::msg("spec file is missing the `Version` tag"))?;
+
+    let section_text = |name: &str| {
+        sections
+            .get(name)
+            .map(|lines| expand_rpm_macros(lines.join("\n").trim(), &macros))
+            .filter(|s| !s.is_empty())
+    };
+    let section_steps = |name: &str| {
+        sections
+            .get(name)
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| expand_rpm_macros(line, &macros))
+                    .filter(|line| !line.trim().is_empty() && !line.trim().starts_with('#'))
+                    .map(|line| translate_rpm_step(&line))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+
+    let description = section_text("description")
+        .or_else(|| tags.get("summary").cloned())
+        .unwrap_or_else(|| "missing".to_string());
+    let mut metadata = empty_metadata(name, version, description);
+
+    let mut take_list = |tag: &str| lists.remove(tag).unwrap_or_default();
+    metadata.source = SourceRep::from_list(take_list("source"));
+    metadata.patches = deps_value(take_list("patch"));
+    metadata.build_depends = deps_value(take_list("buildrequires"));
+    metadata.depends = deps_value(take_list("requires"));
+    metadata.conflicts = deps_value(take_list("conflicts"));
+    metadata.provides = deps_value(take_list("provides"));
+    let obsoletes = deps_value(take_list("obsoletes"));
+
+    metadata.release = tags
+        .remove("release")
+        .map(|release| release.trim_end_matches("%{?dist}").to_string());
+    metadata.license = tags
+        .remove("license")
+        .unwrap_or_else(|| "missing".to_string());
+    metadata.url = tags.remove("url");
+    metadata.epoch = tags.remove("epoch");
+    metadata.group = tags.remove("group");
+    metadata.maintainer = tags.remove("packager");
+    metadata.arch = tags.remove("buildarch");
+
+    metadata.rpm = Some(RpmRep {
+        obsoletes,
+        vendor: tags.remove("vendor"),
+        icon: tags.remove("icon"),
+        summary: tags.remove("summary"),
+        pre_script: section_text("pre"),
+        post_script: section_text("post"),
+        preun_script: section_text("preun"),
+        postun_script: section_text("postun"),
+        config_noreplace: None,
+        file_attributes: None,
+        extra_tags: None,
+        spec_file: None,
+    });
+
+    let build = section_steps("build");
+    let install = section_steps("install");
+
+    Ok(RecipeRep {
+        metadata,
+        env: None,
+        configure: None,
+        build: BuildRep {
+            steps: steps(build),
+            working_dir: None,
+            shell: None,
+        },
+        install: if install.is_empty() {
+            None
+        } else {
+            Some(InstallRep {
+                steps: steps(install),
+                // spec files install from the build directory
+                working_dir: Some(PathBuf::from("$PKGER_BLD_DIR")),
+                shell: None,
+            })
+        },
+    })
+}
+
+//####################################################################################################
+// DEB
+
+/// Parses paragraphs of a control file into maps of lowercase field names and values
+fn parse_control(control: &str) -> Vec<HashMap<String, String>> {
+    let mut paragraphs = Vec::new();
+    let mut current: HashMap<String, String> = HashMap::new();
+    let mut last_field: Option<String> = None;
+
+    for line in control.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            last_field = None;
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let Some(field) = &last_field {
+                if let Some(value) = current.get_mut(field) {
+                    value.push('\n');
+                    value.push_str(line.trim());
+                }
+            }
+            continue;
+        }
+        if let Some((field, value)) = line.split_once(':') {
+            let field = field.trim().to_lowercase();
+            current.insert(field.clone(), value.trim().to_string());
+            last_field = Some(field);
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+
+    paragraphs
+}
+
+/// Splits a relationship field like `foo (>= 1.0), bar | baz, ${misc:Depends}` into package
+/// names, using the first of alternatives and skipping substitution variables.
+fn parse_deb_deps(s: &str) -> Vec<String> {
+    s.split(',')
+        .filter_map(|dep| {
+            let dep = dep.split('|').next().unwrap_or_default().trim();
+            let name = dep
+                .split(|c: char| c.is_whitespace() || c == '(' || c == '[' || c == ':')
+                .next()
+                .unwrap_or_default();
+            if name.is_empty() || name.starts_with('$') || name.starts_with("debhelper") {
+                None
+            } else {
+                Some(name.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Extracts the recipe of a `override_<target>` rule from `debian/rules`
+fn parse_rules_target(rules: &str, target: &str, package: &str) -> Vec<String> {
+    let header = format!("{}:", target);
+    let mut steps = Vec::new();
+    let mut in_target = false;
+
+    for line in rules.lines() {
+        if line.starts_with(&header) {
+            in_target = true;
+            continue;
+        }
+        if in_target {
+            if let Some(step) = line.strip_prefix('\t') {
+                let out_dir = format!("debian/{}", package);
+                let step = step
+                    .replace(&format!("$(CURDIR)/{}", out_dir), "$PKGER_OUT_DIR")
+                    .replace(&out_dir, "$PKGER_OUT_DIR")
+                    .replace("$(CURDIR)", "$PKGER_BLD_DIR")
+                    .replace("$$", "$");
+                steps.push(step);
+            } else if !line.trim().is_empty() {
+                break;
+            }
+        }
+    }
+
+    steps
+}
+
+fn read_debian_file(debian_dir: &Path, name: &str) -> Result<Option<String>> {
+    let path = debian_dir.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read_to_string(&path)
+        .map(Some)
+        .context(format!("failed to read `{}`", path.display()))
+}
+
+fn from_debian_dir(debian_dir: &Path) -> Result<RecipeRep> {
+    let control = read_debian_file(debian_dir, "control")?
+        .ok_or_else(|| Error::msg(format!("no control file in `{}`", debian_dir.display())))?;
+    let changelog = read_debian_file(debian_dir, "changelog")?.unwrap_or_default();
+    let rules = read_debian_file(debian_dir, "rules")?.unwrap_or_default();
+
+    from_debian(&control, &changelog, &rules)
+}
+
+pub fn from_debian(control: &str, changelog: &str, rules: &str) -> Result<RecipeRep> {
+    let mut paragraphs = parse_control(control).into_iter();
+    let mut source = paragraphs
+        .next()
+        .ok_or_else(|| Error::msg("control file is empty"))?;
+    let mut binary = paragraphs.next().unwrap_or_default();
+    if paragraphs.next().is_some() {
+        warn!("only the first binary package is imported");
+    }
+
+    let name = binary
+        .remove("package")
+        .or_else(|| source.get("source").cloned())
+        .ok_or_else(|| Error::msg("control file is missing the package name"))?;
+
+    // first line of the changelog looks like `name (epoch:version-revision) unstable; ...`
+    let full_version = changelog
+        .lines()
+        .next()
+        .and_then(|line| line.split_once('(').map(|(_, rest)| rest))
+        .and_then(|rest| rest.split_once(')').map(|(version, _)| version.to_string()));
+    let (epoch, version) = match full_version.as_deref().and_then(|v| v.split_once(':')) {
+        Some((epoch, version)) => (Some(epoch.to_string()), version.to_string()),
+        None => (None, full_version.clone().unwrap_or_default()),
+    };
+    let (version, release) = match version.rsplit_once('-') {
+        Some((version, release)) => (version.to_string(), Some(release.to_string())),
+        None if version.is_empty() => {
+            warn!("no version found in changelog");
+            ("1.0.0".to_string(), None)
+        }
+        None => (version, None),
+    };
+
+    let description = binary
+        .remove("description")
+        .unwrap_or_else(|| "missing".to_string());
+    let mut metadata = empty_metadata(name.clone(), version, description);
+    metadata.release = release;
+    metadata.epoch = epoch;
+    metadata.maintainer = source.remove("maintainer");
+    metadata.url = source.remove("homepage");
+    metadata.group = binary
+        .remove("section")
+        .or_else(|| source.remove("section"));
+    metadata.arch = binary.remove("architecture");
+
+    let essential = binary.remove("essential").map(|e| e == "yes");
+    let multi_arch = binary.remove("multi-arch");
+    let mut deps = |field: &str| {
+        deps_value(
+            binary
+                .remove(field)
+                .map(|deps| parse_deb_deps(&deps))
+                .unwrap_or_default(),
+        )
+    };
+    metadata.depends = deps("depends");
+    metadata.conflicts = deps("conflicts");
+    metadata.provides = deps("provides");
+    let deb = DebRep {
+        priority: source.remove("priority"),
+        installed_size: None,
+        built_using: None,
+        essential,
+        multi_arch,
+        pre_depends: deps("pre-depends"),
+        recommends: deps("recommends"),
+        suggests: deps("suggests"),
+        breaks: deps("breaks"),
+        replaces: deps("replaces"),
+        enchances: deps("enhances"),
+        extra_fields: None,
+        conffiles: None,
+        debian_dir: None,
+    };
+    metadata.deb = Some(deb);
+    metadata.build_depends = deps_value(
+        source
+            .remove("build-depends")
+            .map(|deps| parse_deb_deps(&deps))
+            .unwrap_or_default(),
+    );
+
+    let configure = parse_rules_target(rules, "override_dh_auto_configure", &name);
+    let mut build = parse_rules_target(rules, "override_dh_auto_build", &name);
+    let mut install = parse_rules_target(rules, "override_dh_auto_install", &name);
+    if build.is_empty() {
+        warn!("no build override in rules, assuming a Makefile based build");
+        build.push("make".to_string());
+    }
+    if install.is_empty() {
+        install.push("make install DESTDIR=$PKGER_OUT_DIR".to_string());
+    }
+
+    let mut build_steps = configure;
+    build_steps.extend(build);
+
+    Ok(RecipeRep {
+        metadata,
+        env: None,
+        configure: None,
+        build: BuildRep {
+            steps: steps(build_steps),
+            working_dir: None,
+            shell: None,
+        },
+        install: Some(InstallRep {
+            steps: steps(install),
+            working_dir: Some(PathBuf::from("$PKGER_BLD_DIR")),
+            shell: None,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+%global debug_package %{nil}
+Name:           hello
+Version:        2.10
+Release:        1%{?dist}
+Summary:        Prints a greeting
+License:        GPLv3+
+URL:            https://www.gnu.org/software/hello/
+Source0:        https://ftp.gnu.org/gnu/hello/hello-%{version}.tar.gz
+BuildRequires:  gcc, make >= 4.0
+Requires:       glibc
+
+%package devel
+Summary:        Development files
+
+%description devel
+Headers.
+
+%description
+The GNU Hello program produces a familiar, friendly greeting.
+
+%prep
+%setup -q
+
+%build
+%configure
+%make_build
+
+%install
+%make_install
+rm -f %{buildroot}%{_infodir}/dir
+
+%files
+%{_bindir}/hello
+"#;
+
+    #[test]
+    fn imports_spec() {
+        let recipe = from_spec(SPEC).unwrap();
+        let metadata = recipe.metadata;
+        assert_eq!(metadata.name, "hello");
+        assert_eq!(metadata.version, "2.10");
+        assert_eq!(metadata.release.as_deref(), Some("1"));
+        assert_eq!(metadata.license, "GPLv3+");
+        assert_eq!(
+            metadata.source,
+            Some(SourceRep::from(
+                "https://ftp.gnu.org/gnu/hello/hello-2.10.tar.gz".to_string()
+            ))
+        );
+        assert_eq!(
+            metadata.description,
+            "The GNU Hello program produces a familiar, friendly greeting."
+        );
+        assert_eq!(
+            metadata.build_depends,
+            deps_value(vec!["gcc".to_string(), "make".to_string()])
+        );
+
+        let build = recipe
+            .build
+            .steps
+            .iter()
+            .map(|s| s.cmd.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(build, vec!["./configure --prefix=/usr", "make"]);
+
+        let install = recipe.install.unwrap();
+        let install = install
+            .steps
+            .iter()
+            .map(|s| s.cmd.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            install,
+            vec![
+                "make install DESTDIR=$PKGER_OUT_DIR",
+                "rm -f $PKGER_OUT_DIR/usr/share/info/dir"
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_debian() {
+        let control = r#"Source: hello
+Maintainer: John Doe <john@example.com>
+Build-Depends: debhelper-compat (= 12), gcc, libfoo-dev [amd64] | libbar-dev
+Homepage: https://www.gnu.org/software/hello/
+
+Package: hello
+Architecture: any
+Depends: ${shlibs:Depends}, ${misc:Depends}, libc6 (>= 2.14)
+Description: example package
+ The GNU Hello program produces a familiar, friendly greeting.
+"#;
+        let changelog = "hello (1:2.10-3) unstable; urgency=medium\n";
+        let rules = "#!/usr/bin/make -f\n%:\n\tdh $@\n\noverride_dh_auto_install:\n\tinstall -D hello $(CURDIR)/debian/hello/usr/bin/hello\n";
+
+        let recipe = from_debian(control, changelog, rules).unwrap();
+        let metadata = recipe.metadata;
+        assert_eq!(metadata.name, "hello");
+        assert_eq!(metadata.version, "2.10");
+        assert_eq!(metadata.release.as_deref(), Some("3"));
+        assert_eq!(metadata.epoch.as_deref(), Some("1"));
+        assert_eq!(metadata.arch.as_deref(), Some("any"));
+        assert_eq!(
+            metadata.build_depends,
+            deps_value(vec!["gcc".to_string(), "libfoo-dev".to_string()])
+        );
+        assert_eq!(metadata.depends, deps_value(vec!["libc6".to_string()]));
+
+        let install = recipe.install.unwrap();
+        assert_eq!(
+            install.steps[0].cmd,
+            "install -D hello $PKGER_OUT_DIR/usr/bin/hello"
+        );
+    }
+}
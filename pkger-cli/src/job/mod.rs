@@ -1,6 +1,14 @@
-use pkger_core::build::{self, Context};
+mod queue;
+
+pub use queue::JobQueue;
+
+use pkger_core::build::{self, progress::Progress, Context};
 use pkger_core::docker;
+use pkger_core::failure::{self, FailureKind};
+use pkger_core::units::HumanSize;
+use pkger_core::{Error, Result};
 
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 pub enum JobResult {
@@ -13,10 +21,17 @@ pub enum JobResult {
         id: String,
         duration: Duration,
         reason: String,
+        kind: Option<FailureKind>,
     },
 }
 
 impl JobResult {
+    pub fn id(&self) -> &str {
+        match self {
+            JobResult::Success { id, .. } | JobResult::Failure { id, .. } => id,
+        }
+    }
+
     pub fn success<I, O>(id: I, duration: Duration, output: O) -> Self
     where
         I: Into<String>,
@@ -29,45 +44,126 @@ impl JobResult {
         }
     }
 
-    pub fn failure<I, E>(id: I, duration: Duration, err: E) -> Self
+    /// Creates a failure classified by the `FailureKind` attached to `err`
+    pub fn failure<I>(id: I, duration: Duration, err: Error) -> Self
     where
         I: Into<String>,
-        E: Into<String>,
     {
+        let kind = FailureKind::of(&err);
         Self::Failure {
             id: id.into(),
             duration,
-            reason: err.into(),
+            reason: failure_reason(err),
+            kind,
         }
     }
 }
 
+/// Output of a build stage shared with the builds that package it, set once the stage succeeds
+pub type StageOutput = Arc<Mutex<Option<Arc<Vec<u8>>>>>;
+
 pub enum JobCtx {
     Build(Context),
+    /// Runs the configure, build and install scripts once and saves the output
+    BuildStage(Context, StageOutput),
+    /// Packages the output of a build stage, queued after the stage
+    Package(Context, StageOutput),
+}
+
+impl JobCtx {
+    fn ctx(&self) -> &Context {
+        match self {
+            JobCtx::Build(ctx) | JobCtx::BuildStage(ctx, _) | JobCtx::Package(ctx, _) => ctx,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.ctx().id()
+    }
+
+    /// Priority of this job, jobs with higher priority are started first
+    pub fn priority(&self) -> i32 {
+        self.ctx().recipe().metadata.priority()
+    }
+
+    /// Name of the image this job runs on
+    pub fn image(&self) -> &str {
+        self.ctx().target().image()
+    }
+
+    /// Short description of this job like `foo x centos8`
+    pub fn label(&self) -> String {
+        let ctx = self.ctx();
+        let label = format!("{} x {}", ctx.recipe().metadata.name, ctx.target().image());
+        match self {
+            JobCtx::BuildStage(..) => format!("{} (build stage)", label),
+            _ => label,
+        }
+    }
+
+    /// Handle to the phase this job is in
+    pub fn progress(&self) -> Progress {
+        self.ctx().progress()
+    }
+
+    /// Builds the image of this job ahead of running it
+    pub async fn prepare(&mut self) -> Result<()> {
+        match self {
+            JobCtx::Build(ctx) | JobCtx::BuildStage(ctx, _) | JobCtx::Package(ctx, _) => {
+                build::prepare_image(ctx).await
+            }
+        }
+    }
+}
+
+fn failure_reason(e: Error) -> String {
+    match e.downcast::<docker::Error>() {
+        Ok(err) => match err {
+            docker::Error::Fault { code: _, message } => message,
+            e => e.to_string(),
+        },
+        Err(e) => failure::report(&e),
+    }
 }
 
 impl JobCtx {
     pub async fn run(self) -> JobResult {
         let start = Instant::now();
-        match self {
-            JobCtx::Build(mut ctx) => match build::run(&mut ctx).await {
-                Err(e) => {
-                    let duration = start.elapsed();
-                    let reason = match e.downcast::<docker::Error>() {
-                        Ok(err) => match err {
-                            docker::Error::Fault { code: _, message } => message,
-                            e => e.to_string(),
-                        },
-                        Err(e) => format!("{:?}", e),
-                    };
-                    JobResult::failure(ctx.id(), duration, reason)
+        let (ctx, result) = match self {
+            JobCtx::Build(mut ctx) => {
+                let result = build::run(&mut ctx).await;
+                (
+                    ctx,
+                    result.map(|output| output.to_string_lossy().to_string()),
+                )
+            }
+            JobCtx::BuildStage(mut ctx, output) => {
+                let result = build::run_build_stage(&mut ctx).await.map(|data| {
+                    let size = HumanSize(data.len() as u64).to_string();
+                    if let Ok(mut output) = output.lock() {
+                        *output = Some(Arc::new(data));
+                    }
+                    format!("{} of build output", size)
+                });
+                (ctx, result)
+            }
+            JobCtx::Package(ctx, output) => {
+                match output.lock().ok().and_then(|output| output.clone()) {
+                    Some(output) => {
+                        let mut ctx = ctx.with_prebuilt_output(output);
+                        let result = build::run(&mut ctx).await;
+                        (
+                            ctx,
+                            result.map(|output| output.to_string_lossy().to_string()),
+                        )
+                    }
+                    None => (ctx, Err(Error::msg("the build stage has no output"))),
                 }
-                Ok(output) => JobResult::success(
-                    ctx.id(),
-                    start.elapsed(),
-                    output.to_string_lossy().to_string(),
-                ),
-            },
+            }
+        };
+        match result {
+            Ok(output) => JobResult::success(ctx.id(), start.elapsed(), output),
+            Err(e) => JobResult::failure(ctx.id(), start.elapsed(), e),
         }
     }
 }
@@ -0,0 +1,295 @@
+use crate::job::{JobCtx, JobResult};
+use pkger_core::build::progress::Progress;
+use pkger_core::units::HumanDuration;
+
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{task, time};
+use tracing::{error, info, info_span, trace, warn, Instrument};
+
+/// A job waiting in the queue with its priority and the id of the job it has to run after
+struct QueuedJob {
+    priority: i32,
+    job: JobCtx,
+    after: Option<String>,
+}
+
+/// Jobs that are running by their ids with their labels, start times and progress
+type RunningJobs = Arc<Mutex<BTreeMap<String, (String, Instant, Progress)>>>;
+
+/// Queue of jobs waiting to be run. Jobs with higher priority are always started before the ones
+/// with lower priority, jobs with equal priority keep the order in which they were added. A job
+/// queued after another one only starts once that job succeeds and is skipped if it fails, the
+/// job it waits for gets the highest priority of the jobs waiting for it. The image of a job is
+/// built when the job starts, so images needed by urgent jobs are built first. All jobs are
+/// queued before the first one starts, so jobs that are already running are never interrupted
+/// for other jobs of the queue.
+pub struct JobQueue {
+    jobs: Vec<QueuedJob>,
+    max_jobs: usize,
+    is_running: Option<Arc<AtomicBool>>,
+    fail_fast: bool,
+    heartbeat: Option<Duration>,
+}
+
+impl JobQueue {
+    /// Creates a new queue that runs at most `max_jobs` at once. If `max_jobs` is not provided or
+    /// is equal to 0 all jobs are started at once.
+    pub fn new(max_jobs: Option<usize>) -> Self {
+        Self {
+            jobs: Vec::new(),
+            max_jobs: max_jobs.filter(|n| *n > 0).unwrap_or(usize::MAX),
+            is_running: None,
+            fail_fast: false,
+            heartbeat: None,
+        }
+    }
+
+    /// Stops starting jobs once `is_running`, shared with the running jobs, is cleared
+    pub fn interruptible(mut self, is_running: Arc<AtomicBool>) -> Self {
+        self.is_running = Some(is_running);
+        self
+    }
+
+    /// Stops the queue after the first failed job. The failure clears the flag of
+    /// [`interruptible`](JobQueue::interruptible), which interrupts the jobs that are already
+    /// running, and no more jobs are started.
+    pub fn fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// Prints a line with the elapsed time and phase of every running job each `interval`, so that
+    /// CI systems killing jobs without output don't interrupt long silent builds.
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.is_running
+            .as_ref()
+            .map(|is_running| !is_running.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+
+    fn stop_on_failure(&self, result: &JobResult) {
+        if let (Some(is_running), JobResult::Failure { id, .. }, true) =
+            (&self.is_running, result, self.fail_fast)
+        {
+            if is_running.swap(false, Ordering::SeqCst) {
+                warn!(id = %id, "job failed, stopping remaining jobs");
+            }
+        }
+    }
+
+    pub fn push(&mut self, priority: i32, job: JobCtx) {
+        self.jobs.push(QueuedJob {
+            priority,
+            job,
+            after: None,
+        });
+    }
+
+    /// Adds a job that only starts after the job with id `after` succeeds
+    pub fn push_after(&mut self, priority: i32, job: JobCtx, after: &str) {
+        self.jobs.push(QueuedJob {
+            priority,
+            job,
+            after: Some(after.to_string()),
+        });
+    }
+
+    /// Only builds the images of all queued jobs in the order in which they would run, returning
+    /// the failures.
+    pub async fn prepare_images(self) -> Vec<JobResult> {
+        let span = info_span!("prepare-images");
+        self.schedule(true)
+            .instrument(span)
+            .await
+            .into_iter()
+            .filter(|result| matches!(result, JobResult::Failure { .. }))
+            .collect()
+    }
+
+    /// Runs all queued jobs returning their results in the order of completion. When failing
+    /// fast the jobs that were not started before the first failure are skipped.
+    pub async fn run(self) -> Vec<JobResult> {
+        let span = info_span!("job-queue");
+        self.schedule(false).instrument(span).await
+    }
+
+    /// Starts the queued jobs in order, building the image of every job right before it runs
+    async fn schedule(mut self, prepare_only: bool) -> Vec<JobResult> {
+        let mut jobs = std::mem::take(&mut self.jobs);
+        let waiting = jobs
+            .iter()
+            .filter_map(|queued| Some((queued.after.clone()?, queued.priority)))
+            .collect::<Vec<_>>();
+        for queued in &mut jobs {
+            for (id, priority) in &waiting {
+                if queued.job.id() == id {
+                    queued.priority = queued.priority.max(*priority);
+                }
+            }
+        }
+        jobs.sort_by_key(|queued| Reverse(queued.priority));
+        let total = jobs.len();
+        trace!(jobs = %total, max_jobs = %self.max_jobs, "running jobs");
+        let labels = jobs
+            .iter()
+            .map(|queued| (queued.job.id().to_string(), queued.job.label()))
+            .collect::<Vec<_>>();
+
+        let running = RunningJobs::default();
+        let heartbeat = self
+            .heartbeat
+            .map(|interval| task::spawn(heartbeat(interval, running.clone())));
+
+        // ids of finished jobs mapped to whether they succeeded
+        let mut finished = HashMap::new();
+        let mut images: HashMap<String, Arc<AsyncMutex<()>>> = HashMap::new();
+        let mut handles = FuturesUnordered::new();
+        let mut results = Vec::new();
+        loop {
+            while handles.len() < self.max_jobs && !self.is_stopped() {
+                let ready = jobs.iter().position(|queued| match &queued.after {
+                    Some(id) => finished.contains_key(id),
+                    None => true,
+                });
+                let queued = match ready {
+                    Some(i) => jobs.remove(i),
+                    None => break,
+                };
+                if let Some(id) = &queued.after {
+                    if finished.get(id) == Some(&false) {
+                        warn!(id = %queued.job.id(), after = %id, "skipping, the job it runs after failed");
+                        finished.insert(queued.job.id().to_string(), false);
+                        continue;
+                    }
+                }
+                if let Ok(mut running) = running.lock() {
+                    running.insert(
+                        queued.job.id().to_string(),
+                        (queued.job.label(), Instant::now(), queued.job.progress()),
+                    );
+                }
+                let image = images
+                    .entry(queued.job.image().to_string())
+                    .or_default()
+                    .clone();
+                handles.push(task::spawn(run_job(queued.job, image, prepare_only)));
+            }
+
+            match handles.next().await {
+                Some(Ok(result)) => {
+                    if let Ok(mut running) = running.lock() {
+                        running.remove(result.id());
+                    }
+                    finished.insert(
+                        result.id().to_string(),
+                        matches!(result, JobResult::Success { .. }),
+                    );
+                    self.stop_on_failure(&result);
+                    results.push(result);
+                }
+                Some(Err(e)) => error!(reason = %e, "failed to join the handle for a job"),
+                None => break,
+            }
+        }
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+
+        if results.len() < total {
+            warn!(skipped = %(total - results.len()), "skipped jobs after a failure or an interruption");
+        }
+        if total > 1 && !prepare_only {
+            info!("summary\n{}", summary(&labels, &results));
+        }
+
+        results
+    }
+}
+
+/// Builds the image of `job` and runs it unless only the image is prepared. The lock of the image
+/// is held while building it, so jobs sharing an image wait for the first one and only build it
+/// once.
+async fn run_job(mut job: JobCtx, image: Arc<AsyncMutex<()>>, prepare_only: bool) -> JobResult {
+    let start = Instant::now();
+    let prepared = {
+        let _image = image.lock().await;
+        job.prepare().await
+    };
+    match prepared {
+        Err(e) => JobResult::failure(job.id(), start.elapsed(), e),
+        Ok(_) if prepare_only => JobResult::success(job.id(), start.elapsed(), job.image()),
+        Ok(_) => job.run().await,
+    }
+}
+
+/// Renders a line with the status and duration of every job in the order the jobs were queued
+/// followed by the totals
+fn summary(labels: &[(String, String)], results: &[JobResult]) -> String {
+    let width = labels
+        .iter()
+        .map(|(_, label)| label.chars().count())
+        .max()
+        .unwrap_or_default();
+    let (mut succeeded, mut failed, mut skipped) = (0, 0, 0);
+    let mut lines = labels
+        .iter()
+        .map(|(id, label)| {
+            let (status, duration) = match results.iter().find(|result| result.id() == id) {
+                Some(JobResult::Success { duration, .. }) => {
+                    succeeded += 1;
+                    ("ok", HumanDuration(*duration).to_string())
+                }
+                Some(JobResult::Failure { duration, .. }) => {
+                    failed += 1;
+                    ("FAILED", HumanDuration(*duration).to_string())
+                }
+                None => {
+                    skipped += 1;
+                    ("skipped", String::new())
+                }
+            };
+            format!(
+                "  {:<7} {:<width$} {}",
+                status,
+                label,
+                duration,
+                width = width
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{} succeeded, {} failed, {} skipped",
+        succeeded, failed, skipped
+    ));
+    lines.join("\n")
+}
+
+async fn heartbeat(interval: Duration, running: RunningJobs) {
+    loop {
+        time::sleep(interval).await;
+        if let Ok(running) = running.lock() {
+            for (label, start, progress) in running.values() {
+                let phase = progress.phase();
+                info!(
+                    "[{}] building… {} elapsed, phase: {}",
+                    label,
+                    HumanDuration(start.elapsed()),
+                    if phase.is_empty() { "starting" } else { &phase }
+                );
+            }
+        }
+    }
+}
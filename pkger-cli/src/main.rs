@@ -1,15 +1,32 @@
 mod app;
+mod audit;
+mod badge;
+mod bump;
+mod cache;
+mod changes;
 mod config;
+mod doctor;
 mod fmt;
 mod gen;
+mod import;
 mod job;
 mod opts; // generate
+mod publish;
+mod report;
+mod run_archive;
+mod s3;
+mod schedule;
+mod system_log;
+mod test_recipes;
+mod vault;
+mod webhook;
 
 use app::Application;
 use config::Configuration;
-use opts::Opts;
+use opts::{Commands, Opts};
 
-use pkger_core::{Error, Result};
+use pkger_core::failure::{self, FailureKind};
+use pkger_core::{units, Error, Result};
 
 use std::process;
 use tracing::{error, trace, warn};
@@ -21,6 +38,10 @@ async fn main() -> Result<()> {
     let opts = Opts::from_args();
 
     fmt::setup_tracing(&opts);
+    units::set_raw(opts.raw);
+    if opts.trace_docker {
+        pkger_core::docker::trace::enable();
+    }
 
     trace!(opts = ?opts);
 
@@ -37,12 +58,21 @@ async fn main() -> Result<()> {
         }
     });
     trace!(config_path = %config_path);
+    // diagnostics have to run with an invalid configuration as well
+    if let Commands::Doctor = opts.command {
+        if let Err(e) = doctor::run(&config_path).await {
+            error!(reason = %e, "doctor found problems");
+            process::exit(1);
+        }
+        return Ok(());
+    }
     let result = Configuration::load(&config_path);
     if let Err(e) = &result {
         error!(reason = %e, config_path = %config_path, "failed to read config file");
         process::exit(1);
     }
-    let config = result.unwrap();
+    let mut config = result.unwrap();
+    config.untrusted_recipes |= opts.untrusted_recipes;
     trace!(config = ?config);
 
     let mut app = match Application::new(config) {
@@ -54,9 +84,11 @@ async fn main() -> Result<()> {
     };
 
     if let Err(reason) = app.process_opts(opts).await {
-        let reason = format!("\nError: {:?}", reason);
-        error!(%reason, "execution failed");
-        process::exit(1);
+        let code = FailureKind::of(&reason)
+            .map(|kind| kind.exit_code())
+            .unwrap_or(1);
+        error!("execution failed\n{}", failure::report(&reason));
+        process::exit(code);
     }
     Ok(())
 }
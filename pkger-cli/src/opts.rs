@@ -1,5 +1,10 @@
+use crate::badge::BadgeFormat;
+use crate::report::ReportFormat;
+use crate::system_log::LogTarget;
 use crate::Error;
 use clap::{Clap, Subcommand};
+use pkger_core::docker::Runtime;
+use pkger_core::repo::RepoType;
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -26,9 +31,25 @@ pub struct Opts {
     /// Fields, S - Spans. L - Levels is on by default, use L to turn it off. All characters can be
     /// upper or lower case, the order doesn't matter, duplicates and errors are silently ignored.
     pub filter: Option<String>,
+    #[clap(long)]
+    /// Print durations and sizes as raw numbers of seconds and bytes instead of human friendly
+    /// values like `3m 42s` or `1.5 MiB`. Useful when the output is parsed by other tools.
+    pub raw: bool,
+    #[clap(long)]
+    /// Log every request to the Docker API with its method, path, status, duration and the
+    /// beginning of request and response bodies. Useful for debugging problems with the daemon.
+    pub trace_docker: bool,
+    #[clap(long)]
+    /// Also send logs to `syslog` or `journald`. Fields of log events are kept as separate fields
+    /// of journal entries prefixed with `PKGER_`.
+    pub log: Option<LogTarget>,
     #[clap(short, long)]
     /// Path to the config file (default - "~/.pkger.yml").
     pub config: Option<String>,
+    #[clap(long)]
+    /// Don't run commands of recipe variables (`cmd:`) and only let them read files in the
+    /// directory of the recipe. Use with recipes from untrusted repositories.
+    pub untrusted_recipes: bool,
 
     #[clap(subcommand)]
     /// Subcommand to run
@@ -45,15 +66,214 @@ impl Opts {
 pub enum Commands {
     /// Runs a build creating specified packages on target platforms.
     Build(BuildOpts),
+    /// Builds and caches the images needed by recipes, with dependencies installed, without
+    /// building any packages. Useful for baking CI runner images or warming up ephemeral runners.
+    PrepareImages(PrepareImagesOpts),
     /// Creates a directory with a recipe generated from provided arguments
     GenRecipe(Box<GenRecipeOpts>),
     List(ListOpts),
+    /// Runs continuously building recipes according to schedules and webhooks defined in the
+    /// configuration.
+    Serve,
+    /// Prints owners of a recipe or of recipes affected by changes of a path, like a file in a
+    /// recipe directory, a local source or a custom image.
+    Owners(OwnersOpts),
+    /// Inspects and edits the saved state of images.
+    State(StateOpts),
+    /// Moves cached images together with their state between machines, so that ephemeral CI
+    /// runners can restore a warm cache from artifact storage.
+    Cache(CacheOpts),
+    /// Writes native packaging files generated from a recipe, like a spec file or a `debian/`
+    /// directory, so that the package can be built without pkger.
+    Export(ExportOpts),
+    /// Bumps the version of a recipe, pins its new source in the lockfile and adds an entry to
+    /// its changelog.
+    Bump(BumpOpts),
+    /// Creates a recipe from an existing spec file or `debian/` directory. The result is a best
+    /// effort conversion that should be reviewed before building.
+    Import(ImportOpts),
+    /// Renders packaging files, scripts and dependencies of recipes without running containers
+    /// and compares them against snapshots saved next to the recipes.
+    TestRecipes(TestRecipesOpts),
+    /// Generates the metadata of a package repository from the built packages, so that the
+    /// output directory can be served to package managers directly.
+    Repo(RepoOpts),
+    /// Checks the environment pkger runs in, like the connection to Docker, the configuration
+    /// and free disk space, and prints how to fix the problems found.
+    Doctor,
+}
+
+#[derive(Debug, Clap)]
+pub struct RepoOpts {
+    #[clap(long = "type", short = 't')]
+    /// Type of the repository, `apt` or `yum`
+    pub repo_type: RepoType,
+    /// Directory with the packages, searched recursively. Defaults to the `output_dir`.
+    pub dir: Option<PathBuf>,
+    #[clap(long)]
+    /// Sign the repository with the key from `signing` of the configuration
+    pub sign: bool,
+    #[clap(long)]
+    /// URL to Docker daemon in which `createrepo_c` runs if it's not installed on the host.
+    pub docker: Option<String>,
+    #[clap(long)]
+    /// Container runtime serving the API, `docker` or `podman`.
+    pub runtime: Option<Runtime>,
+    #[clap(long)]
+    /// Upload the repository metadata to the bucket from `publish.s3` of the configuration.
+    pub publish: bool,
+    #[clap(long)]
+    /// Package copied into the repository before updating it, can be passed multiple times.
+    pub add: Vec<PathBuf>,
+    #[clap(long)]
+    /// Package removed from the repository, by its path relative to the repository. Can be passed
+    /// multiple times.
+    pub remove: Vec<String>,
+    #[clap(long)]
+    /// Number of the newest versions of every package kept in the repository, older ones are
+    /// removed. Defaults to `repo.keep_versions` of the configuration.
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Clap)]
+pub struct TestRecipesOpts {
+    /// Names of the recipes to test. By default all recipes are tested.
+    pub recipes: Vec<String>,
+    #[clap(long, short)]
+    /// Overwrite the snapshots with the current output instead of comparing them.
+    pub update: bool,
+}
+
+#[derive(Debug, Clap)]
+pub struct BumpOpts {
+    /// Name of the recipe to bump
+    pub recipe: String,
+    #[clap(long, short)]
+    /// The new version, asked for if not provided
+    pub version: Option<String>,
+    #[clap(long, short)]
+    /// Changes added to the changelog entry of the new version, can be passed multiple times
+    pub message: Vec<String>,
+    #[clap(long)]
+    /// Don't fetch the new source, the source of the new version is left unpinned
+    pub no_fetch: bool,
+    #[clap(long)]
+    /// Open the diff of the recipe file in `$EDITOR` afterwards
+    pub edit: bool,
+}
+
+#[derive(Debug, Clap)]
+pub struct ImportOpts {
+    /// Path to a spec file, a `debian/` directory or a directory that contains it
+    pub path: PathBuf,
+    /// Directory in which a directory with the recipe should be created. If no path is provided
+    /// the recipe will be printed to stdout.
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+pub struct ExportOpts {
+    /// What to export, can be one of: `spec`, `debian`
+    pub format: ExportFormat,
+    /// Name of the recipe to export
+    pub recipe: String,
+    /// Directory in which the files should be created. If no path is provided the spec file will
+    /// be printed to stdout and the `debian/` directory will be created in the current directory.
+    pub output_dir: Option<PathBuf>,
+    #[clap(long, short)]
+    /// Image whose dependencies, patches and steps should be used. By default only the common
+    /// ones are exported.
+    pub image: Option<String>,
+}
+
+#[derive(Debug, Clap)]
+pub enum ExportFormat {
+    Spec,
+    Debian,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "spec" => Ok(ExportFormat::Spec),
+            "debian" => Ok(ExportFormat::Debian),
+            _ => Err(Error::msg(format!("unknown export format {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clap)]
+pub struct StateOpts {
+    #[clap(subcommand)]
+    pub command: StateCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StateCommand {
+    /// Lists all images saved in the state.
+    List,
+    /// Prints all details of saved states of an image.
+    Show {
+        /// Name of the image
+        image: String,
+        #[clap(long, short)]
+        /// Only show the state of the image used by this recipe
+        recipe: Option<String>,
+    },
+    /// Removes images from the state so that they are rebuilt on next build. If `image_gc` is
+    /// configured the images are also removed from Docker after the next build.
+    Rm {
+        /// Names of the images to remove
+        images: Vec<String>,
+        #[clap(long, short)]
+        /// Only remove the states of images used by this recipe
+        recipe: Option<String>,
+    },
+}
+
+#[derive(Debug, Clap)]
+pub struct OwnersOpts {
+    /// Name of a recipe or a path
+    pub target: String,
+}
+
+#[derive(Debug, Clap)]
+pub struct CacheOpts {
+    #[clap(subcommand)]
+    pub command: CacheCommand,
+    #[clap(long)]
+    /// URL to Docker daemon listening on a unix or tcp socket. An example could be
+    /// `unix:///var/run/docker.socket` or a tcp uri `tcp://127.0.0.1:81`.
+    pub docker: Option<String>,
+    #[clap(long)]
+    /// Container runtime serving the API, `docker` or `podman`.
+    pub runtime: Option<Runtime>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Saves cached images that exist in Docker and their state to a tar archive.
+    Export {
+        /// Path of the created archive
+        path: PathBuf,
+    },
+    /// Loads images and their state from an archive created by `cache export`.
+    Import {
+        /// Path of the archive
+        path: PathBuf,
+    },
 }
 
 #[derive(Debug, Clap)]
 pub struct ListOpts {
     /// What objects to list, can be one of: `images`, `recipes`
     pub object: ListObject,
+    #[clap(long, short)]
+    /// Print the version and a summary of each recipe taken from its documentation or
+    /// description.
+    pub long: bool,
 }
 
 #[derive(Debug, Clap)]
@@ -74,7 +294,7 @@ impl FromStr for ListObject {
     }
 }
 
-#[derive(Debug, Clap)]
+#[derive(Clone, Debug, Default, Clap)]
 pub struct BuildOpts {
     /// Recipes to build. If empty all recipes in the `recipes_dir` directory will be built.
     pub recipes: Vec<String>,
@@ -89,14 +309,128 @@ pub struct BuildOpts {
     /// `targets` is specified.
     pub images: Option<Vec<String>>,
     #[clap(long)]
+    /// Build the recipes on a built-in busybox image packaging them as gzip archives instead of
+    /// using their image targets. Dependencies are not installed and the state of the image is
+    /// not saved. Useful to quickly validate changes of pkger itself.
+    pub sandbox: bool,
+    #[clap(long)]
     /// URL to Docker daemon listening on a unix or tcp socket. An example could be
     /// `unix:///var/run/docker.socket` or a tcp uri `tcp://127.0.0.1:81`. By default pkger will
     /// try to connect to a unix socket at `/run/docker.sock`.
     pub docker: Option<String>,
+    #[clap(long)]
+    /// Container runtime serving the API, `docker` or `podman`. With `podman` pkger connects to
+    /// the socket of rootless Podman in `$XDG_RUNTIME_DIR` by default.
+    pub runtime: Option<Runtime>,
 
     #[clap(long, short)]
     /// If set to true, all recipes will be built.
     pub all: bool,
+    #[clap(long)]
+    /// Build only the recipes affected by changes since this git reference, like `origin/master`.
+    /// A recipe is affected when its directory, its local source or one of its custom images
+    /// changed, including uncommitted and untracked files. If recipes are also listed only those
+    /// of them that changed are built.
+    pub changed_since: Option<String>,
+    #[clap(long, short)]
+    /// Maximum number of builds running at once. Builds of recipes with higher `priority` are
+    /// started first. By default all builds are started at once.
+    pub jobs: Option<usize>,
+    #[clap(long)]
+    /// Stop all builds after the first failure. Builds that are already running are interrupted.
+    pub fail_fast: bool,
+    #[clap(long)]
+    /// Run all builds even if some of them fail. This is the default unless `fail_fast` is
+    /// enabled in the configuration.
+    pub keep_going: bool,
+    #[clap(long)]
+    /// Directory where a shell script with all commands executed in the container is saved for
+    /// each build. The scripts are named after the id of the build and are saved even if the
+    /// build fails.
+    pub emit_script: Option<PathBuf>,
+    #[clap(long)]
+    /// Refuse to build recipes that changed since they were pinned in the lockfile. The recipe
+    /// directory and the fetched source must match the pins of the built version and the
+    /// lockfile is left untouched.
+    pub locked: bool,
+    #[clap(long)]
+    /// Save a report of the build in the output directory as `html` or `markdown`. The report
+    /// lists the status, duration and artifact of every build and links the scripts saved with
+    /// `--emit-script`.
+    pub report: Option<ReportFormat>,
+    #[clap(long)]
+    /// Bundle the logs, transcripts and rendered packaging files of all builds together with the
+    /// manifests of the artifacts and the report into `run-<timestamp>.tar.zst` in the output
+    /// directory.
+    pub archive: bool,
+    #[clap(long, use_delimiter = true)]
+    /// Save a badge with the version and the status of the build of every built recipe in the
+    /// `badges` directory of the output directory, as an `svg` image or a `shields` endpoint file
+    /// for shields.io.
+    pub badges: Vec<BadgeFormat>,
+    #[clap(long)]
+    /// Interval in seconds of lines printed for every running build with its elapsed time and
+    /// phase. Defaults to 60 when `CI` is set or the output is not a terminal, 0 disables them.
+    pub heartbeat: Option<u64>,
+    #[clap(long)]
+    /// Save the container of every build after each phase so that a failed build is retried
+    /// from the last phase that succeeded.
+    pub checkpoints: bool,
+    #[clap(long)]
+    /// Also build source packages, an SRPM next to RPM packages and a `.dsc` with its archives
+    /// next to DEB packages.
+    pub source_package: bool,
+    #[clap(long, use_delimiter = true)]
+    /// Features of the recipes to enable like `tls,ldap`. An entry like `recipe/feature` enables
+    /// the feature only for that recipe.
+    pub features: Vec<String>,
+    #[clap(long)]
+    /// Don't enable the default features of the recipes.
+    pub no_default_features: bool,
+    #[clap(long, use_delimiter = true)]
+    /// Publish the artifacts only with these publishers from `publish` of the configuration like
+    /// `s3`. By default all configured publishers are used.
+    pub publish: Vec<String>,
+}
+
+#[derive(Debug, Clap)]
+pub struct PrepareImagesOpts {
+    /// Recipes whose images should be prepared.
+    pub recipes: Vec<String>,
+    #[clap(short, long)]
+    /// A list of targets like `rpm deb pkg` whose simple images should be prepared instead of the
+    /// image targets of the recipes.
+    pub simple: Option<Vec<String>>,
+    #[clap(short, long)]
+    /// Prepare only these images of the recipes.
+    pub images: Option<Vec<String>>,
+    #[clap(long)]
+    /// URL to Docker daemon listening on a unix or tcp socket.
+    pub docker: Option<String>,
+    #[clap(long)]
+    /// Container runtime serving the API, `docker` or `podman`.
+    pub runtime: Option<Runtime>,
+    #[clap(long, short)]
+    /// If set to true, images of all recipes will be prepared.
+    pub all: bool,
+    #[clap(long, short)]
+    /// Maximum number of images prepared at once.
+    pub jobs: Option<usize>,
+}
+
+impl PrepareImagesOpts {
+    pub fn build_opts(&self) -> BuildOpts {
+        BuildOpts {
+            recipes: self.recipes.clone(),
+            simple: self.simple.clone(),
+            images: self.images.clone(),
+            docker: self.docker.clone(),
+            runtime: self.runtime,
+            all: self.all,
+            jobs: self.jobs,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, Clap)]
@@ -181,6 +515,9 @@ pub struct GenRecipeOpts {
     #[clap(long)]
     /// Only applies to DEB build
     pub essential: Option<bool>,
+    #[clap(long)]
+    /// Only applies to DEB build
+    pub multi_arch: Option<String>,
 
     #[clap(long)]
     /// Only applies to DEB build
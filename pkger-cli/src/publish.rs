@@ -0,0 +1,475 @@
+//! Publishers distributing built artifacts. Torrents are saved next to the package as
+//! `<package>.torrent`, IPFS publishing adds the package to a node through its HTTP API and S3
+//! publishing uploads it to a bucket. Artifacts published on IPFS or S3 can also be fetched back
+//! by later builds.
+use crate::s3::S3Bucket;
+use crate::{Error, Result};
+use pkger_core::build::publish::ArtifactPublisher;
+use pkger_core::recipe::parse_size;
+use pkger_core::ErrContext;
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::trace;
+
+static DEFAULT_PIECE_LENGTH: u64 = 256 << 10;
+static DEFAULT_IPFS_API: &str = "http://127.0.0.1:5001";
+static BOUNDARY: &str = "pkger-ipfs-boundary";
+static ACCESS_KEY_ID_ENV: &str = "AWS_ACCESS_KEY_ID";
+static SECRET_ACCESS_KEY_ENV: &str = "AWS_SECRET_ACCESS_KEY";
+static SESSION_TOKEN_ENV: &str = "AWS_SESSION_TOKEN";
+
+/// Names of the publishers in the configuration
+pub static PUBLISHERS: &[&str] = &["torrent", "ipfs", "s3"];
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PublishConfig {
+    /// Creates a torrent of every artifact
+    pub torrent: Option<TorrentConfig>,
+    /// Adds every artifact to an IPFS node
+    pub ipfs: Option<IpfsConfig>,
+    /// Uploads every artifact to an S3 bucket
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TorrentConfig {
+    #[serde(default)]
+    /// Announce URLs of trackers, the first one is the primary tracker
+    pub trackers: Vec<String>,
+    #[serde(default)]
+    /// URLs from which clients can download the artifact over HTTP. URLs ending with `/` have
+    /// the file name of the artifact appended by clients.
+    pub web_seeds: Vec<String>,
+    /// Size of the pieces of the torrent like `512K`, defaults to `256K`
+    pub piece_length: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct IpfsConfig {
+    #[serde(default = "default_ipfs_api")]
+    /// URL of the HTTP API of the IPFS node
+    pub api: String,
+    #[serde(default = "default_pin")]
+    /// Whether the node should pin the artifacts so that they are not garbage collected
+    pub pin: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Endpoint of an S3 compatible storage, defaults to AWS
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    /// Prefix of the keys of the artifacts in the bucket
+    pub prefix: String,
+    /// Defaults to the `AWS_ACCESS_KEY_ID` environment variable
+    pub access_key_id: Option<String>,
+    /// Defaults to the `AWS_SECRET_ACCESS_KEY` environment variable
+    pub secret_access_key: Option<String>,
+}
+
+fn default_ipfs_api() -> String {
+    DEFAULT_IPFS_API.to_string()
+}
+
+fn default_pin() -> bool {
+    true
+}
+
+impl PublishConfig {
+    /// Returns the configured publishers, only those named in `only` if it's not empty. Keys of
+    /// artifacts uploaded to S3 are their paths relative to `output_dir`.
+    pub fn publishers(
+        &self,
+        only: &[String],
+        output_dir: &Path,
+    ) -> Result<Vec<Arc<dyn ArtifactPublisher>>> {
+        for name in only {
+            let configured = match name.as_str() {
+                "torrent" => self.torrent.is_some(),
+                "ipfs" => self.ipfs.is_some(),
+                "s3" => self.s3.is_some(),
+                _ => {
+                    return Err(Error::msg(format!(
+                        "unknown publisher `{}`, expected one of {}",
+                        name,
+                        PUBLISHERS.join(", ")
+                    )))
+                }
+            };
+            if !configured {
+                return Err(Error::msg(format!(
+                    "publisher `{}` is not configured in `publish`",
+                    name
+                )));
+            }
+        }
+        let enabled = |name: &str| only.is_empty() || only.iter().any(|only| only == name);
+
+        let mut publishers: Vec<Arc<dyn ArtifactPublisher>> = vec![];
+        if let Some(torrent) = self.torrent.as_ref().filter(|_| enabled("torrent")) {
+            let piece_length = match &torrent.piece_length {
+                Some(length) => parse_size(length)?,
+                None => DEFAULT_PIECE_LENGTH,
+            };
+            if piece_length == 0 {
+                return Err(Error::msg("piece length of torrents can't be 0"));
+            }
+            publishers.push(Arc::new(TorrentPublisher {
+                trackers: torrent.trackers.clone(),
+                web_seeds: torrent.web_seeds.clone(),
+                piece_length,
+            }));
+        }
+        if let Some(ipfs) = self.ipfs.as_ref().filter(|_| enabled("ipfs")) {
+            publishers.push(Arc::new(IpfsPublisher {
+                api: ipfs.api.trim_end_matches('/').to_string(),
+                pin: ipfs.pin,
+            }));
+        }
+        if let Some(s3) = self.s3.as_ref().filter(|_| enabled("s3")) {
+            publishers.push(Arc::new(s3.publisher(output_dir)?));
+        }
+        Ok(publishers)
+    }
+}
+
+fn credential(value: &Option<String>, var: &str) -> Result<String> {
+    match value {
+        Some(value) => Ok(value.clone()),
+        None => env::var(var).map_err(|_| {
+            Error::msg(format!(
+                "S3 publishing requires credentials in the configuration or `{}`",
+                var
+            ))
+        }),
+    }
+}
+
+impl S3Config {
+    pub fn publisher(&self, output_dir: &Path) -> Result<S3Publisher> {
+        let access_key_id = credential(&self.access_key_id, ACCESS_KEY_ID_ENV)?;
+        let secret_access_key = credential(&self.secret_access_key, SECRET_ACCESS_KEY_ENV)?;
+        // a session token only belongs to the credentials from the environment
+        let session_token = if self.access_key_id.is_none() {
+            env::var(SESSION_TOKEN_ENV).ok()
+        } else {
+            None
+        };
+        Ok(S3Publisher {
+            bucket: S3Bucket::new(
+                &self.bucket,
+                &self.region,
+                self.endpoint.as_deref(),
+                &self.prefix,
+                &access_key_id,
+                &secret_access_key,
+            )
+            .with_session_token(session_token),
+            output_dir: output_dir.to_path_buf(),
+        })
+    }
+}
+
+/// Minimal bencode value, the encoding of torrent files
+enum Bencode<'a> {
+    Int(u64),
+    Bytes(&'a [u8]),
+    List(Vec<Bencode<'a>>),
+    /// Keys are sorted when encoding as the format requires
+    Dict(Vec<(&'a str, Bencode<'a>)>),
+}
+
+impl Bencode<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(n) => out.extend(format!("i{}e", n).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend(format!("{}:", bytes.len()).as_bytes());
+                out.extend(*bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode(out));
+                out.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                let mut entries = entries.iter().collect::<Vec<_>>();
+                entries.sort_by_key(|(key, _)| *key);
+                out.push(b'd');
+                for (key, value) in entries {
+                    Bencode::Bytes(key.as_bytes()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+#[derive(Debug)]
+struct TorrentPublisher {
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    piece_length: u64,
+}
+
+impl TorrentPublisher {
+    /// Returns the contents of a single file torrent of `data` named `name` and its info hash
+    fn torrent(&self, name: &str, data: &[u8]) -> (Vec<u8>, String) {
+        let pieces = data
+            .chunks(self.piece_length as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect::<Vec<_>>();
+        let info = Bencode::Dict(vec![
+            ("length", Bencode::Int(data.len() as u64)),
+            ("name", Bencode::Bytes(name.as_bytes())),
+            ("piece length", Bencode::Int(self.piece_length)),
+            ("pieces", Bencode::Bytes(&pieces)),
+        ]);
+        let info_hash = format!("{:x}", Sha1::digest(&info.to_bytes()));
+
+        let mut torrent = vec![("info", info)];
+        if let Some(tracker) = self.trackers.first() {
+            torrent.push(("announce", Bencode::Bytes(tracker.as_bytes())));
+        }
+        if self.trackers.len() > 1 {
+            torrent.push((
+                "announce-list",
+                Bencode::List(
+                    self.trackers
+                        .iter()
+                        .map(|tracker| Bencode::List(vec![Bencode::Bytes(tracker.as_bytes())]))
+                        .collect(),
+                ),
+            ));
+        }
+        if !self.web_seeds.is_empty() {
+            torrent.push((
+                "url-list",
+                Bencode::List(
+                    self.web_seeds
+                        .iter()
+                        .map(|seed| Bencode::Bytes(seed.as_bytes()))
+                        .collect(),
+                ),
+            ));
+        }
+
+        (Bencode::Dict(torrent).to_bytes(), info_hash)
+    }
+}
+
+impl ArtifactPublisher for TorrentPublisher {
+    fn name(&self) -> &str {
+        "btih"
+    }
+
+    fn publish(&self, package: &Path) -> Result<String> {
+        let name = package
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let data = fs::read(package).context("failed to read package")?;
+        let (torrent, info_hash) = self.torrent(&name, &data);
+
+        let mut path = PathBuf::from(package);
+        path.set_file_name(format!("{}.torrent", name));
+        trace!(path = %path.display(), info_hash = %info_hash);
+        fs::write(&path, torrent).context(format!("failed to save `{}`", path.display()))?;
+
+        Ok(info_hash)
+    }
+}
+
+#[derive(Debug)]
+struct IpfsPublisher {
+    api: String,
+    pin: bool,
+}
+
+#[derive(Deserialize)]
+struct AddResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+impl ArtifactPublisher for IpfsPublisher {
+    fn name(&self) -> &str {
+        "ipfs"
+    }
+
+    fn publish(&self, package: &Path) -> Result<String> {
+        let name = package
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let data = fs::read(package).context("failed to read package")?;
+
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY, name
+        )
+        .into_bytes();
+        body.extend(data);
+        body.extend(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+        let url = format!("{}/api/v0/add?pin={}&cid-version=1", self.api, self.pin);
+        trace!(url = %url, size = %body.len());
+        let response = ureq::post(&url)
+            .set(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .send_bytes(&body)
+            .map_err(|e| Error::msg(format!("failed to add package to IPFS - {}", e)))?;
+        let response: AddResponse = response
+            .into_json()
+            .context("invalid response of the IPFS node")?;
+
+        Ok(response.hash)
+    }
+
+    fn fetch(&self, id: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/api/v0/cat?arg={}", self.api, id);
+        trace!(url = %url);
+        let response = ureq::post(&url)
+            .call()
+            .map_err(|e| Error::msg(format!("failed to fetch `{}` from IPFS - {}", id, e)))?;
+        let mut data = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut data)
+            .context("failed to read response of the IPFS node")?;
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+pub struct S3Publisher {
+    bucket: S3Bucket,
+    output_dir: PathBuf,
+}
+
+impl S3Publisher {
+    /// Returns the name of the object of `path`, its path relative to the output directory or
+    /// the file name if it's outside of it
+    fn object(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.output_dir) {
+            Ok(relative) => relative.to_string_lossy().to_string(),
+            Err(_) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Deletes the object of `path`, returns its name
+    pub fn remove(&self, path: &Path) -> Result<String> {
+        let object = self.object(path);
+        trace!(key = %self.bucket.key(&object), "deleting");
+        self.bucket
+            .request("DELETE", &object, &[])
+            .call()
+            .map_err(|e| Error::msg(format!("failed to delete `{}` from S3 - {}", object, e)))?;
+
+        Ok(object)
+    }
+}
+
+impl ArtifactPublisher for S3Publisher {
+    fn name(&self) -> &str {
+        "s3"
+    }
+
+    fn publish(&self, package: &Path) -> Result<String> {
+        let data = fs::read(package).context(format!("failed to read `{}`", package.display()))?;
+        let object = self.object(package);
+        trace!(key = %self.bucket.key(&object), size = %data.len());
+        self.bucket
+            .request("PUT", &object, &data)
+            .send_bytes(&data)
+            .map_err(|e| Error::msg(format!("failed to upload `{}` to S3 - {}", object, e)))?;
+
+        Ok(object)
+    }
+
+    fn fetch(&self, id: &str) -> Result<Vec<u8>> {
+        let response = self
+            .bucket
+            .request("GET", id, &[])
+            .call()
+            .map_err(|e| Error::msg(format!("failed to fetch `{}` from S3 - {}", id, e)))?;
+        let mut data = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut data)
+            .context("failed to read response of S3")?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_torrent() {
+        let publisher = TorrentPublisher {
+            trackers: vec!["udp://tracker.example.org:1337".to_string()],
+            web_seeds: vec![],
+            piece_length: 16 << 10,
+        };
+        let (torrent, info_hash) = publisher.torrent("foo-1.0.tar.gz", b"hello");
+
+        assert_eq!(info_hash, "bcfe4804f375eda0464adc9e62b0e2fc475dd7ee");
+        assert!(torrent.starts_with(b"d8:announce30:udp://tracker.example.org:13374:infod6:length"));
+    }
+
+    #[test]
+    fn selects_publishers() {
+        let config: PublishConfig = serde_yaml::from_str(
+            "ipfs: {}\ns3:\n  bucket: pkger\n  region: eu-central-1\n  access_key_id: id\n  secret_access_key: secret\n",
+        )
+        .unwrap();
+        let output_dir = Path::new("/out");
+
+        let names = |only: &[&str]| {
+            config
+                .publishers(
+                    &only.iter().map(|name| name.to_string()).collect::<Vec<_>>(),
+                    output_dir,
+                )
+                .map(|publishers| {
+                    publishers
+                        .iter()
+                        .map(|publisher| publisher.name().to_string())
+                        .collect::<Vec<_>>()
+                })
+        };
+        assert_eq!(names(&[]).unwrap(), vec!["ipfs", "s3"]);
+        assert_eq!(names(&["s3"]).unwrap(), vec!["s3"]);
+        assert!(names(&["torrent"]).is_err());
+        assert!(names(&["ftp"]).is_err());
+
+        let publisher = config.s3.as_ref().unwrap().publisher(output_dir).unwrap();
+        assert_eq!(
+            publisher.object(Path::new("/out/debian10/foo_1.0_amd64.deb")),
+            "debian10/foo_1.0_amd64.deb"
+        );
+        assert_eq!(publisher.object(Path::new("/tmp/foo.rpm")), "foo.rpm");
+    }
+}
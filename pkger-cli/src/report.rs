@@ -0,0 +1,355 @@
+//! Reports of a run rendered as HTML or markdown. A report lists the status, duration and
+//! artifacts of every build with links to the transcripts of the builds, so it can be published
+//! as an artifact of a CI pipeline or a page of an internal dashboard.
+use crate::audit::Subject;
+use crate::job::JobResult;
+use crate::{Error, Result};
+use pkger_core::units::HumanDuration;
+use pkger_core::{secrets, ErrContext};
+
+use chrono::Local;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+static REPORT_NAME: &str = "pkger-report";
+/// Width of the longest bar of the timing chart in markdown
+static CHART_WIDTH: usize = 40;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "html" => Ok(ReportFormat::Html),
+            "markdown" | "md" => Ok(ReportFormat::Markdown),
+            _ => Err(Error::msg(format!("unknown report format {}", s))),
+        }
+    }
+}
+
+impl ReportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Html => "html",
+            ReportFormat::Markdown => "md",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    id: String,
+    recipe: String,
+    image: String,
+    duration: Duration,
+    /// Path to the artifact relative to the output directory
+    artifact: Option<PathBuf>,
+    error: Option<String>,
+    /// Path to the transcript of the build if it was saved
+    transcript: Option<PathBuf>,
+}
+
+fn entries(
+    results: &[JobResult],
+    subjects: &HashMap<String, Subject>,
+    output_dir: &Path,
+    transcripts: Option<&Path>,
+) -> Vec<Entry> {
+    let mut entries = results
+        .iter()
+        .map(|result| {
+            let (id, duration) = match result {
+                JobResult::Success { id, duration, .. }
+                | JobResult::Failure { id, duration, .. } => (id, *duration),
+            };
+            let subject = subjects.get(id);
+            let (artifact, error) = match result {
+                JobResult::Success { output, .. } => {
+                    let output = Path::new(output);
+                    (
+                        Some(
+                            output
+                                .strip_prefix(output_dir)
+                                .unwrap_or(output)
+                                .to_path_buf(),
+                        ),
+                        None,
+                    )
+                }
+                JobResult::Failure { reason, .. } => {
+                    (None, Some(secrets::redact(reason).into_owned()))
+                }
+            };
+            Entry {
+                id: id.clone(),
+                recipe: subject.map(|s| s.recipe().to_string()).unwrap_or_default(),
+                image: subject.map(|s| s.image().to_string()).unwrap_or_default(),
+                duration,
+                artifact,
+                error,
+                transcript: transcripts
+                    .map(|dir| dir.join(format!("{}.sh", id)))
+                    .filter(|path| path.exists()),
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| (&a.recipe, &a.image).cmp(&(&b.recipe, &b.image)));
+    entries
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns the share of the longest duration taken by `duration`
+fn share(duration: Duration, longest: Duration) -> f64 {
+    if longest.as_secs_f64() == 0. {
+        0.
+    } else {
+        duration.as_secs_f64() / longest.as_secs_f64()
+    }
+}
+
+fn render_markdown(entries: &[Entry], generated: &str) -> String {
+    let longest = entries.iter().map(|e| e.duration).max().unwrap_or_default();
+    let failed = entries.iter().filter(|e| e.error.is_some()).count();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# pkger build report\n");
+    let _ = writeln!(
+        out,
+        "Generated {}, {} builds, {} failed.\n",
+        generated,
+        entries.len(),
+        failed
+    );
+    let _ = writeln!(
+        out,
+        "| recipe | image | status | duration | artifact | transcript |"
+    );
+    let _ = writeln!(
+        out,
+        "|--------|-------|--------|----------|----------|------------|"
+    );
+    for entry in entries {
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} | {} | {} |",
+            entry.recipe,
+            entry.image,
+            if entry.error.is_some() {
+                "failed"
+            } else {
+                "ok"
+            },
+            HumanDuration(entry.duration),
+            entry
+                .artifact
+                .as_ref()
+                .map(|path| format!("[{0}]({0})", path.display()))
+                .unwrap_or_default(),
+            entry
+                .transcript
+                .as_ref()
+                .map(|path| format!("[{}]({})", entry.id, path.display()))
+                .unwrap_or_default(),
+        );
+    }
+
+    let _ = writeln!(out, "\n## Timing\n\n```");
+    for entry in entries {
+        let width = (share(entry.duration, longest) * CHART_WIDTH as f64).round() as usize;
+        let _ = writeln!(
+            out,
+            "{:<40} {} {}",
+            format!("{} {}", entry.recipe, entry.image),
+            "#".repeat(width.max(1)),
+            HumanDuration(entry.duration)
+        );
+    }
+    let _ = writeln!(out, "```");
+
+    let failures = entries
+        .iter()
+        .filter_map(|e| e.error.as_ref().map(|err| (e, err)));
+    for (i, (entry, error)) in failures.enumerate() {
+        if i == 0 {
+            let _ = writeln!(out, "\n## Failures");
+        }
+        let _ = writeln!(
+            out,
+            "\n### {} {}\n\n```\n{}\n```",
+            entry.recipe, entry.image, error
+        );
+    }
+
+    out
+}
+
+fn render_html(entries: &[Entry], generated: &str) -> String {
+    let longest = entries.iter().map(|e| e.duration).max().unwrap_or_default();
+    let failed = entries.iter().filter(|e| e.error.is_some()).count();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>pkger build report</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+td, th {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}
+.ok {{ color: #2a7d2a; }}
+.failed {{ color: #b02a2a; }}
+.bar {{ background: #4a7bd0; height: 1em; }}
+.bar.failed {{ background: #b02a2a; }}
+pre {{ background: #f4f4f4; padding: 1em; overflow-x: auto; }}
+</style>
+</head>
+<body>
+<h1>pkger build report</h1>
+<p>Generated {}, {} builds, {} failed.</p>"#,
+        generated,
+        entries.len(),
+        failed
+    );
+
+    let _ = writeln!(out, "<table>\n<tr><th>recipe</th><th>image</th><th>status</th><th>duration</th><th>timing</th><th>artifact</th><th>transcript</th></tr>");
+    for entry in entries {
+        let status = if entry.error.is_some() {
+            "failed"
+        } else {
+            "ok"
+        };
+        let _ = writeln!(
+            out,
+            r#"<tr><td>{0}</td><td>{1}</td><td class="{2}">{2}</td><td>{3}</td><td style="width: 200px"><div class="bar {2}" style="width: {4:.0}%"></div></td><td>{5}</td><td>{6}</td></tr>"#,
+            escape_html(&entry.recipe),
+            escape_html(&entry.image),
+            status,
+            HumanDuration(entry.duration),
+            (share(entry.duration, longest) * 100.).max(1.),
+            entry
+                .artifact
+                .as_ref()
+                .map(|path| {
+                    let path = escape_html(&path.to_string_lossy());
+                    format!(r#"<a href="{0}">{0}</a>"#, path)
+                })
+                .unwrap_or_default(),
+            entry
+                .transcript
+                .as_ref()
+                .map(|path| format!(
+                    r#"<a href="{}">{}</a>"#,
+                    escape_html(&path.to_string_lossy()),
+                    escape_html(&entry.id)
+                ))
+                .unwrap_or_default(),
+        );
+    }
+    let _ = writeln!(out, "</table>");
+
+    let failures = entries
+        .iter()
+        .filter_map(|e| e.error.as_ref().map(|err| (e, err)));
+    for (i, (entry, error)) in failures.enumerate() {
+        if i == 0 {
+            let _ = writeln!(out, "<h2>Failures</h2>");
+        }
+        let _ = writeln!(
+            out,
+            "<h3>{} {}</h3>\n<pre>{}</pre>",
+            escape_html(&entry.recipe),
+            escape_html(&entry.image),
+            escape_html(error)
+        );
+    }
+    let _ = writeln!(out, "</body>\n</html>");
+
+    out
+}
+
+/// Renders the report of `results` in `format` saving it in `output_dir`. Returns the path to the
+/// saved report.
+pub fn save(
+    format: ReportFormat,
+    results: &[JobResult],
+    subjects: &HashMap<String, Subject>,
+    output_dir: &Path,
+    transcripts: Option<&Path>,
+) -> Result<PathBuf> {
+    let entries = entries(results, subjects, output_dir, transcripts);
+    let generated = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let report = match format {
+        ReportFormat::Html => render_html(&entries, &generated),
+        ReportFormat::Markdown => render_markdown(&entries, &generated),
+    };
+
+    fs::create_dir_all(output_dir)?;
+    let path = output_dir.join(format!("{}.{}", REPORT_NAME, format.extension()));
+    fs::write(&path, report).context(format!("failed to save report `{}`", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_reports() {
+        let entries = vec![
+            Entry {
+                id: "pkger-foo-centos8-1".to_string(),
+                recipe: "foo".to_string(),
+                image: "centos8".to_string(),
+                duration: Duration::from_secs(222),
+                artifact: Some(PathBuf::from("centos8/foo-1.0.0-0.x86_64.rpm")),
+                error: None,
+                transcript: None,
+            },
+            Entry {
+                id: "pkger-foo-debian10-1".to_string(),
+                recipe: "foo".to_string(),
+                image: "debian10".to_string(),
+                duration: Duration::from_secs(111),
+                artifact: None,
+                error: Some("failed to run `make <all>`".to_string()),
+                transcript: Some(PathBuf::from("/tmp/pkger-foo-debian10-1.sh")),
+            },
+        ];
+
+        let markdown = render_markdown(&entries, "now");
+        assert!(markdown.contains("2 builds, 1 failed"));
+        assert!(markdown.contains(
+            "| foo | centos8 | ok | 3m 42s | [centos8/foo-1.0.0-0.x86_64.rpm](centos8/foo-1.0.0-0.x86_64.rpm) |  |"
+        ));
+        assert!(markdown.contains(&format!(
+            "foo debian10{} {}",
+            " ".repeat(28),
+            "#".repeat(20)
+        )));
+
+        let html = render_html(&entries, "now");
+        assert!(html.contains("<pre>failed to run `make &lt;all&gt;`</pre>"));
+        assert!(html.contains(r#"<a href="/tmp/pkger-foo-debian10-1.sh">pkger-foo-debian10-1</a>"#));
+    }
+}
@@ -0,0 +1,221 @@
+//! Archives of a run. Logs, transcripts and rendered packaging files of every build of a run are
+//! bundled together with the manifests of built artifacts and the report into a single
+//! `run-<timestamp>.tar.zst` in the output directory, ready to be attached to a ticket or retained
+//! per release.
+use crate::job::JobResult;
+use crate::Result;
+use pkger_core::archive::{tar, zstd};
+use pkger_core::build::manifest::ArtifactManifest;
+use pkger_core::container::OutputLimit;
+use pkger_core::units::HumanDuration;
+use pkger_core::{secrets, ErrContext};
+
+use chrono::Local;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::{trace, warn};
+
+/// Compression level of the archive, 0 is the default level of zstd
+static COMPRESSION_LEVEL: i32 = 0;
+
+#[derive(Debug)]
+pub struct RunArchive {
+    /// Name of the archive without the extension, also the top directory of its entries
+    name: String,
+    entries: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl RunArchive {
+    /// Archive of a run named after the current time
+    pub fn now() -> Self {
+        Self::named(format!("run-{}", Local::now().format("%Y%m%d-%H%M%S")))
+    }
+
+    fn named(name: String) -> Self {
+        Self {
+            name,
+            entries: vec![],
+        }
+    }
+
+    fn add<P: AsRef<Path>>(&mut self, path: P, data: Vec<u8>) {
+        let path = Path::new(&self.name).join(path);
+        trace!(entry = %path.display(), size = %data.len(), "adding to run archive");
+        self.entries.push((path, data));
+    }
+
+    /// Adds the file on the host at `file` as `path`, files that don't exist are skipped
+    fn add_file<P: AsRef<Path>>(&mut self, path: P, file: &Path) {
+        match fs::read(file) {
+            Ok(data) => self.add(path, data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                warn!(file = %file.display(), reason = %e, "failed to add file to run archive")
+            }
+        }
+    }
+
+    /// Adds a summary of `results` and the manifests of the built artifacts
+    pub fn add_results(&mut self, results: &[JobResult], output_dir: &Path) {
+        let mut summary = String::new();
+        for result in results {
+            match result {
+                JobResult::Success {
+                    id,
+                    duration,
+                    output,
+                } => {
+                    summary.push_str(&format!(
+                        "{} succeeded in {}: {}\n",
+                        id,
+                        HumanDuration(*duration),
+                        output
+                    ));
+                    let manifest = ArtifactManifest::path_for(Path::new(output));
+                    let relative = manifest
+                        .strip_prefix(output_dir)
+                        .unwrap_or(&manifest)
+                        .to_path_buf();
+                    self.add_file(Path::new("manifests").join(relative), &manifest);
+                }
+                JobResult::Failure {
+                    id,
+                    duration,
+                    reason,
+                    ..
+                } => {
+                    summary.push_str(&format!(
+                        "{} failed in {}\n{}\n",
+                        id,
+                        HumanDuration(*duration),
+                        secrets::redact(reason)
+                    ));
+                }
+            }
+        }
+        self.add("summary.txt", summary.into_bytes());
+    }
+
+    /// Adds the full output of the build with `id` saved with the output limit
+    pub fn add_log(&mut self, id: &str, output_limit: &OutputLimit) {
+        self.add_file(
+            Path::new("logs").join(format!("{}.log", id)),
+            &output_limit.log_path(id),
+        );
+    }
+
+    /// Adds the transcript of the build with `id` saved in `dir`
+    pub fn add_transcript(&mut self, id: &str, dir: &Path) {
+        self.add_file(
+            Path::new("transcripts").join(format!("{}.sh", id)),
+            &dir.join(format!("{}.sh", id)),
+        );
+    }
+
+    /// Adds the packaging files, scripts and dependencies rendered for the build with `id`
+    pub fn add_packaging(&mut self, id: &str, rendered: String) {
+        self.add(
+            Path::new("packaging").join(format!("{}.txt", id)),
+            rendered.into_bytes(),
+        );
+    }
+
+    /// Adds the report of the run at `report`
+    pub fn add_report(&mut self, report: &Path) {
+        let name = report.file_name().unwrap_or_default().to_os_string();
+        self.add_file(name, report);
+    }
+
+    /// Compresses the archive into `output_dir` returning its path
+    pub fn save(self, output_dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(output_dir)?;
+        let path = output_dir.join(format!("{}.tar.zst", self.name));
+        let file = File::create(&path)
+            .context(format!("failed to create run archive `{}`", path.display()))?;
+        let encoder = zstd::Encoder::new(file, COMPRESSION_LEVEL)?;
+        let mut archive = tar::Builder::new(encoder);
+        for (entry, data) in &self.entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, entry, &data[..])?;
+        }
+        archive
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .context("failed to save run archive")?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn archives_runs() {
+        let tmp = tempdir::TempDir::new("pkger-run-archive").unwrap();
+        let output_dir = tmp.path();
+        fs::create_dir_all(output_dir.join("centos8")).unwrap();
+        fs::write(output_dir.join("centos8/foo-1.0.rpm.json"), "{}").unwrap();
+        fs::write(output_dir.join("pkger-foo-centos8-1.sh"), "make\n").unwrap();
+
+        let mut archive = RunArchive::named("run-1".to_string());
+        archive.add_results(
+            &[
+                JobResult::Success {
+                    id: "pkger-foo-centos8-1".to_string(),
+                    duration: Duration::from_secs(3),
+                    output: output_dir
+                        .join("centos8/foo-1.0.rpm")
+                        .to_string_lossy()
+                        .to_string(),
+                },
+                JobResult::Failure {
+                    id: "pkger-bar-debian10-1".to_string(),
+                    duration: Duration::from_secs(1),
+                    reason: "make failed".to_string(),
+                    kind: None,
+                },
+            ],
+            output_dir,
+        );
+        archive.add_transcript("pkger-foo-centos8-1", output_dir);
+        archive.add_transcript("pkger-bar-debian10-1", output_dir);
+        archive.add_packaging("pkger-foo-centos8-1", "Name: foo\n".to_string());
+        let path = archive.save(output_dir).unwrap();
+        assert_eq!(path, output_dir.join("run-1.tar.zst"));
+
+        let decoder = zstd::Decoder::new(File::open(&path).unwrap()).unwrap();
+        let mut entries = tar::Archive::new(decoder)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = String::new();
+                entry.read_to_string(&mut data).unwrap();
+                (entry.path().unwrap().to_string_lossy().to_string(), data)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        let paths = entries
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "run-1/manifests/centos8/foo-1.0.rpm.json",
+                "run-1/packaging/pkger-foo-centos8-1.txt",
+                "run-1/summary.txt",
+                "run-1/transcripts/pkger-foo-centos8-1.sh",
+            ]
+        );
+        assert!(entries[2].1.contains("pkger-bar-debian10-1 failed"));
+        assert!(entries[2].1.contains("make failed"));
+    }
+}
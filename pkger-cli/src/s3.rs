@@ -0,0 +1,204 @@
+//! Minimal client of S3 compatible storage signing requests with AWS Signature Version 4. Used by
+//! the build cache and the S3 publisher.
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tracing::trace;
+
+pub struct S3Bucket {
+    bucket: String,
+    region: String,
+    endpoint: Option<String>,
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl fmt::Debug for S3Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Bucket")
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("valid hmac key");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes a key of an object leaving the `/` separators and unreserved characters
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+impl S3Bucket {
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        prefix: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            endpoint: endpoint.map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+            prefix: prefix.trim_matches('/').to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Sends the token of temporary credentials with every request
+    pub fn with_session_token(mut self, token: Option<String>) -> Self {
+        self.session_token = token;
+        self
+    }
+
+    /// Returns the key of `object` in the bucket
+    pub fn key(&self, object: &str) -> String {
+        if self.prefix.is_empty() {
+            object.to_string()
+        } else {
+            format!("{}/{}", self.prefix, object)
+        }
+    }
+
+    /// Returns the host and the path of `object`. Custom endpoints are addressed with path
+    /// style, AWS with virtual hosted style.
+    fn location(&self, object: &str) -> (String, String) {
+        let key = encode_key(&self.key(object));
+        match &self.endpoint {
+            Some(endpoint) => (endpoint.clone(), format!("/{}/{}", self.bucket, key)),
+            None => (
+                format!("https://{}.s3.{}.amazonaws.com", self.bucket, self.region),
+                format!("/{}", key),
+            ),
+        }
+    }
+
+    /// Creates a request of `object` signed with AWS Signature Version 4
+    pub fn request(&self, method: &str, object: &str, payload: &[u8]) -> ureq::Request {
+        let (base, path) = self.location(object);
+        let host = base
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(&base)
+            .to_string();
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sha256_hex(payload);
+
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", timestamp.as_str()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+
+        let signing_key = ["s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                &hmac_sha256(format!("AWS4{}", self.secret_access_key).as_bytes(), &date),
+                &self.region,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let signature = hmac_sha256(&signing_key, &string_to_sign)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let url = format!("{}{}", base, path);
+        trace!(method = %method, url = %url);
+        let mut request = ureq::request(method, &url);
+        // the host header is set by ureq from the url
+        for (name, value) in headers.iter().skip(1) {
+            request = request.set(name, value);
+        }
+        request.set(
+            "Authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_objects() {
+        let bucket = S3Bucket::new("pkger", "eu-central-1", None, "/packages/", "id", "secret");
+        assert_eq!(
+            bucket.location("debian10/foo_1:1.0+git~1_amd64.deb"),
+            (
+                "https://pkger.s3.eu-central-1.amazonaws.com".to_string(),
+                "/packages/debian10/foo_1%3A1.0%2Bgit~1_amd64.deb".to_string()
+            )
+        );
+
+        let bucket = S3Bucket::new(
+            "pkger",
+            "us-east-1",
+            Some("https://minio.example.com/"),
+            "",
+            "id",
+            "secret",
+        );
+        assert_eq!(bucket.key("foo.tar"), "foo.tar");
+        assert_eq!(
+            bucket.location("foo.tar"),
+            (
+                "https://minio.example.com".to_string(),
+                "/pkger/foo.tar".to_string()
+            )
+        );
+    }
+}
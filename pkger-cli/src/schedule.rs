@@ -0,0 +1,212 @@
+use crate::opts::BuildOpts;
+use crate::{Error, Result};
+
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Deserialize)]
+/// An entry of the configuration that defines when and what recipes should be built by
+/// `pkger serve`.
+pub struct Schedule {
+    /// Standard 5 field cron expression like `0 3 * * *`
+    pub cron: Cron,
+    #[serde(default)]
+    /// Recipes to build
+    pub recipes: Vec<String>,
+    #[serde(default)]
+    /// If set to true, all recipes will be built
+    pub all: bool,
+    /// Only build on the specified images
+    pub images: Option<Vec<String>>,
+    /// Build simple targets instead of images defined in recipes
+    pub simple: Option<Vec<String>>,
+}
+
+impl Schedule {
+    pub fn build_opts(&self) -> BuildOpts {
+        BuildOpts {
+            recipes: self.recipes.clone(),
+            all: self.all,
+            images: self.images.clone(),
+            simple: self.simple.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+//####################################################################################################
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+/// A parsed cron expression with fields: minute, hour, day of month, month, day of week.
+pub struct Cron {
+    expr: String,
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days_of_month: Vec<u32>,
+    months: Vec<u32>,
+    days_of_week: Vec<u32>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = Error;
+
+    fn try_from(expr: String) -> Result<Self> {
+        Cron::try_from(expr.as_str())
+    }
+}
+
+impl TryFrom<&str> for Cron {
+    type Error = Error;
+
+    fn try_from(expr: &str) -> Result<Self> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(Error::msg(format!(
+                "expected 5 fields in cron expression `{}`, found {}",
+                expr,
+                fields.len()
+            )));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 mean sunday
+        days_of_week
+            .iter_mut()
+            .filter(|d| **d == 7)
+            .for_each(|d| *d = 0);
+        days_of_week.sort_unstable();
+        days_of_week.dedup();
+
+        Ok(Self {
+            expr: expr.to_string(),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            // like in vixie cron a field starting with `*`, like `*/2`, doesn't restrict the day
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl Cron {
+    /// Checks whether this schedule should fire in the minute of the given `time`
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day_of_month = self.days_of_month.contains(&time.day());
+        let day_of_week = self
+            .days_of_week
+            .contains(&time.weekday().num_days_from_sunday());
+
+        // when both day fields are restricted the schedule fires if either of them matches
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && self.minutes.contains(&time.minute())
+            && self.hours.contains(&time.hour())
+            && self.months.contains(&time.month())
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<u32>> {
+    let mut values = Vec::new();
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| Error::msg(format!("invalid step `{}` in `{}`", step, field)))?,
+            ),
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, field)?, parse_value(end, field)?)
+        } else {
+            let value = parse_value(range, field)?;
+            if part.contains('/') {
+                (value, max)
+            } else {
+                (value, value)
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(Error::msg(format!(
+                "value out of range {}-{} in `{}`",
+                min, max, field
+            )));
+        }
+
+        values.extend((start..=end).step_by(step as usize));
+    }
+
+    values.sort_unstable();
+    values.dedup();
+
+    Ok(values)
+}
+
+fn parse_value(value: &str, field: &str) -> Result<u32> {
+    value
+        .parse::<u32>()
+        .map_err(|_| Error::msg(format!("invalid value `{}` in `{}`", value, field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn parses_cron_fields() {
+        let cron = Cron::try_from("*/15 3,5 1-3 * 1-5/2").unwrap();
+        assert_eq!(cron.minutes, vec![0, 15, 30, 45]);
+        assert_eq!(cron.hours, vec![3, 5]);
+        assert_eq!(cron.days_of_month, vec![1, 2, 3]);
+        assert_eq!(cron.months, (1..=12).collect::<Vec<_>>());
+        assert_eq!(cron.days_of_week, vec![1, 3, 5]);
+
+        assert!(Cron::try_from("* * * *").is_err());
+        assert!(Cron::try_from("60 * * * *").is_err());
+        assert!(Cron::try_from("*/0 * * * *").is_err());
+    }
+
+    #[test]
+    fn matches_time() {
+        let cron = Cron::try_from("0 3 * * *").unwrap();
+        assert!(cron.matches(&Utc.ymd(2021, 7, 13).and_hms(3, 0, 0)));
+        assert!(!cron.matches(&Utc.ymd(2021, 7, 13).and_hms(3, 1, 0)));
+
+        // 2021-07-13 is a tuesday
+        let cron = Cron::try_from("0 0 1 * 2").unwrap();
+        assert!(cron.matches(&Utc.ymd(2021, 7, 13).and_hms(0, 0, 0)));
+        assert!(cron.matches(&Utc.ymd(2021, 7, 1).and_hms(0, 0, 0)));
+        assert!(!cron.matches(&Utc.ymd(2021, 7, 14).and_hms(0, 0, 0)));
+
+        // odd days that are mondays
+        let cron = Cron::try_from("0 3 */2 * 1").unwrap();
+        assert!(cron.matches(&Utc.ymd(2021, 7, 19).and_hms(3, 0, 0)));
+        assert!(!cron.matches(&Utc.ymd(2021, 7, 12).and_hms(3, 0, 0)));
+        assert!(!cron.matches(&Utc.ymd(2021, 7, 13).and_hms(3, 0, 0)));
+    }
+}
@@ -0,0 +1,221 @@
+//! Sending logs to syslog or the systemd journal in addition to stdout, for pkger running as a
+//! daemon on build servers. Fields of events are kept as separate fields of journal entries.
+use crate::Error;
+use pkger_core::secrets;
+
+use std::fmt;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use tracing::field::{Field, Visit};
+use tracing::Level;
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+static SYSLOG_SOCKET: &str = "/dev/log";
+static JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+static IDENTIFIER: &str = "pkger";
+/// The `user` facility of syslog
+static FACILITY: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogTarget {
+    Syslog,
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" | "journal" => Ok(LogTarget::Journald),
+            _ => Err(Error::msg(format!("unknown log target {}", s))),
+        }
+    }
+}
+
+/// Layer writing every event to the socket of syslog or the journal. Failing to send an event is
+/// ignored so that logging never interrupts a build.
+pub struct SystemLog {
+    target: LogTarget,
+    socket: UnixDatagram,
+}
+
+impl SystemLog {
+    pub fn connect(target: LogTarget) -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(match target {
+            LogTarget::Syslog => SYSLOG_SOCKET,
+            LogTarget::Journald => JOURNALD_SOCKET,
+        })?;
+        Ok(Self { target, socket })
+    }
+}
+
+impl<S> Layer<S> for SystemLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| span.name())
+                    .collect::<Vec<_>>()
+                    .join("~>")
+            })
+            .unwrap_or_default();
+        let level = *event.metadata().level();
+
+        let datagram = match self.target {
+            LogTarget::Syslog => {
+                syslog_message(level, &spans, &visitor.message, &visitor.fields).into_bytes()
+            }
+            LogTarget::Journald => {
+                let mut fields = vec![
+                    ("MESSAGE".to_string(), visitor.message),
+                    ("PRIORITY".to_string(), severity(level).to_string()),
+                    ("SYSLOG_IDENTIFIER".to_string(), IDENTIFIER.to_string()),
+                    ("TARGET".to_string(), event.metadata().target().to_string()),
+                ];
+                if !spans.is_empty() {
+                    fields.push(("SPANS".to_string(), spans));
+                }
+                fields.extend(
+                    visitor
+                        .fields
+                        .into_iter()
+                        .map(|(name, value)| (journal_field_name(&name), value)),
+                );
+                journal_entry(&fields)
+            }
+        };
+        let _ = self.socket.send(&datagram);
+    }
+}
+
+#[derive(Default)]
+struct FieldsVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldsVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+impl FieldsVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        let value = secrets::redact(&value).into_owned();
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.push((field.name().to_string(), value));
+        }
+    }
+}
+
+/// Severity of syslog corresponding to `level`
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Formats a message for the local syslog socket like `<14>pkger[42]: build~>foo job succeded id=1`
+fn syslog_message(level: Level, spans: &str, message: &str, fields: &[(String, String)]) -> String {
+    let mut out = format!(
+        "<{}>{}[{}]: ",
+        FACILITY * 8 + severity(level),
+        IDENTIFIER,
+        process::id()
+    );
+    if !spans.is_empty() {
+        out.push_str(spans);
+        out.push(' ');
+    }
+    out.push_str(message);
+    for (name, value) in fields {
+        out.push_str(&format!(" {}={}", name, value));
+    }
+    out
+}
+
+/// Journal field names may only contain uppercase letters, digits and underscores, fields of
+/// events are prefixed with `PKGER_` so that they don't clash with fields of the journal
+fn journal_field_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("PKGER_{}", name.trim_start_matches('_'))
+}
+
+/// Serializes fields with the native protocol of the journal. Values containing a newline are
+/// prefixed with their length instead of being separated with `=`.
+fn journal_entry(fields: &[(String, String)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (name, value) in fields {
+        out.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            out.push(b'=');
+        }
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_entries() {
+        let fields = vec![("id".to_string(), "pkger-foo-1".to_string())];
+        assert!(
+            syslog_message(Level::ERROR, "build", "job failed", &fields).ends_with(&format!(
+                "pkger[{}]: build job failed id=pkger-foo-1",
+                process::id()
+            ))
+        );
+        assert!(syslog_message(Level::INFO, "", "done", &[]).starts_with("<14>"));
+
+        assert_eq!(journal_field_name("id"), "PKGER_ID");
+        assert_eq!(journal_field_name("_exit.code"), "PKGER_EXIT_CODE");
+
+        let entry = journal_entry(&[
+            ("MESSAGE".to_string(), "job failed\nstderr".to_string()),
+            ("PKGER_ID".to_string(), "foo".to_string()),
+        ]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&17u64.to_le_bytes());
+        expected.extend_from_slice(b"job failed\nstderr\nPKGER_ID=foo\n");
+        assert_eq!(entry, expected);
+    }
+}
@@ -0,0 +1,172 @@
+//! Containerless checks of recipes. Everything pkger generates from a recipe is rendered for each
+//! of its image targets and compared against snapshots saved in the `snapshots` directory of the
+//! recipe, so that changes of the resulting packages show up in reviews of the recipe.
+use crate::opts::TestRecipesOpts;
+use crate::{Error, Result};
+use pkger_core::recipe::{BuildTarget, Loader, Recipe};
+use pkger_core::ErrContext;
+
+use colored::Colorize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, trace};
+
+static SNAPSHOTS_DIR: &str = "snapshots";
+static SNAPSHOT_EXTENSION: &str = "snap";
+static COMMON_IMAGE: &str = "common";
+
+/// Returns the images and build targets that should be checked for `recipe`. Recipes without
+/// image targets are checked with common dependencies and steps for every packaging format.
+fn targets(recipe: &Recipe) -> Vec<(String, BuildTarget)> {
+    match &recipe.metadata.images {
+        Some(images) if !images.is_empty() => images
+            .iter()
+            .map(|target| (target.image.clone(), target.build_target))
+            .collect(),
+        _ => [BuildTarget::Rpm, BuildTarget::Deb, BuildTarget::Pkg]
+            .iter()
+            .map(|target| (String::new(), *target))
+            .collect(),
+    }
+}
+
+fn snapshot_path(recipe: &Recipe, image: &str, target: &BuildTarget) -> PathBuf {
+    let image = if image.is_empty() {
+        COMMON_IMAGE
+    } else {
+        image
+    };
+    recipe.recipe_dir.join(SNAPSHOTS_DIR).join(format!(
+        "{}-{}.{}",
+        image,
+        target.as_ref(),
+        SNAPSHOT_EXTENSION
+    ))
+}
+
+/// Returns the number and contents of the first line that differs between `expected` and `got`
+fn first_difference<'a>(expected: &'a str, got: &'a str) -> Option<(usize, &'a str, &'a str)> {
+    let mut expected_lines = expected.lines();
+    let mut got_lines = got.lines();
+    let mut n = 1;
+    loop {
+        match (expected_lines.next(), got_lines.next()) {
+            (None, None) => return None,
+            (expected, got) if expected != got => {
+                return Some((n, expected.unwrap_or("<end>"), got.unwrap_or("<end>")))
+            }
+            _ => n += 1,
+        }
+    }
+}
+
+/// Compares the rendered output with the saved snapshot or overwrites the snapshot if `update`
+/// is set
+fn check(path: &Path, rendered: &str, update: bool) -> Result<()> {
+    if update {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        return fs::write(path, rendered)
+            .context(format!("failed to save snapshot `{}`", path.display()));
+    }
+
+    let saved = fs::read_to_string(path).map_err(|_| {
+        Error::msg(format!(
+            "snapshot `{}` doesn't exist, rerun with `--update` to create it",
+            path.display()
+        ))
+    })?;
+    match first_difference(&saved, rendered) {
+        None => Ok(()),
+        Some((line, expected, got)) => Err(Error::msg(format!(
+            "output differs from snapshot `{}` at line {}\n  expected: {}\n       got: {}\nrerun with `--update` if the change is intended",
+            path.display(),
+            line,
+            expected,
+            got
+        ))),
+    }
+}
+
+pub fn run(loader: &Loader, opts: TestRecipesOpts) -> Result<()> {
+    let span = info_span!("test-recipes");
+    let _enter = span.enter();
+
+    let names = if opts.recipes.is_empty() {
+        let mut names = loader.list();
+        names.sort();
+        names
+    } else {
+        opts.recipes
+    };
+    trace!(recipes = ?names);
+
+    let mut total = 0;
+    let mut failed = 0;
+    for name in &names {
+        let recipe = match loader.load(name) {
+            Ok(recipe) => recipe,
+            Err(e) => {
+                total += 1;
+                failed += 1;
+                println!("{} {} ... {}\n{:?}", "test".bold(), name, "FAILED".red(), e);
+                continue;
+            }
+        };
+
+        for (image, target) in targets(&recipe) {
+            total += 1;
+            let path = snapshot_path(&recipe, &image, &target);
+            let rendered = recipe.render_snapshot(&image, &target);
+            let label = format!(
+                "{} ({} {})",
+                name,
+                if image.is_empty() {
+                    COMMON_IMAGE
+                } else {
+                    &image
+                },
+                target.as_ref()
+            );
+            match check(&path, &rendered, opts.update) {
+                Ok(_) if opts.update => {
+                    println!("{} {} ... {}", "test".bold(), label, "updated".yellow())
+                }
+                Ok(_) => println!("{} {} ... {}", "test".bold(), label, "ok".green()),
+                Err(e) => {
+                    failed += 1;
+                    println!(
+                        "{} {} ... {}\n{:?}",
+                        "test".bold(),
+                        label,
+                        "FAILED".red(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", total - failed, failed);
+    if failed > 0 {
+        return Err(Error::msg(format!(
+            "{} of {} recipe tests failed",
+            failed, total
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_first_difference() {
+        assert_eq!(first_difference("a\nb\n", "a\nb\n"), None);
+        assert_eq!(first_difference("a\nb\n", "a\nc\n"), Some((2, "b", "c")));
+        assert_eq!(first_difference("a\n", "a\nb\n"), Some((2, "<end>", "b")));
+    }
+}
@@ -0,0 +1,206 @@
+//! Fetching of build credentials from HashiCorp Vault. Fetched secrets are exposed to recipes as
+//! variables or files so that recipes can reference them with `env:VAR` or `file:/path`.
+use crate::{Error, Result};
+use pkger_core::{secrets, ErrContext};
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{info, info_span, trace};
+
+static VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VaultConfig {
+    /// Address of the Vault server like `https://vault.example.com:8200`
+    pub address: String,
+    /// Vault Enterprise namespace
+    pub namespace: Option<String>,
+    /// Authentication method, defaults to the token from the `VAULT_TOKEN` environment variable
+    pub auth: Option<VaultAuth>,
+    #[serde(default)]
+    pub secrets: Vec<VaultSecret>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultAuth {
+    Token(String),
+    AppRole {
+        role_id: String,
+        secret_id: String,
+        #[serde(default = "default_approle_mount")]
+        mount: String,
+    },
+}
+
+fn default_approle_mount() -> String {
+    "approle".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Maps a key of a secret in Vault to an environment variable or a file
+pub struct VaultSecret {
+    /// Path of the secret like `secret/data/pkger/registry`
+    pub path: String,
+    pub key: String,
+    pub env: Option<String>,
+    pub file: Option<PathBuf>,
+}
+
+struct Client<'cfg> {
+    config: &'cfg VaultConfig,
+    token: String,
+}
+
+impl<'cfg> Client<'cfg> {
+    fn login(config: &'cfg VaultConfig) -> Result<Self> {
+        let token = match &config.auth {
+            Some(VaultAuth::Token(token)) => token.clone(),
+            Some(VaultAuth::AppRole {
+                role_id,
+                secret_id,
+                mount,
+            }) => {
+                trace!(mount = %mount, "logging in with approle");
+                let mut client = Client {
+                    config,
+                    token: String::new(),
+                };
+                let response = client
+                    .request("POST", &format!("auth/{}/login", mount))
+                    .send_json(serde_json::json!({
+                        "role_id": role_id,
+                        "secret_id": secret_id,
+                    }));
+                let response = client
+                    .parse_response(response)
+                    .context("approle login failed")?;
+                client.token = response["auth"]["client_token"]
+                    .as_str()
+                    .map(ToString::to_string)
+                    .ok_or_else(|| Error::msg("approle login response has no client token"))?;
+                secrets::register(client.token.as_str());
+                return Ok(client);
+            }
+            None => env::var(VAULT_TOKEN_ENV).map_err(|_| {
+                Error::msg(format!(
+                    "no vault authentication configured and `{}` is not set",
+                    VAULT_TOKEN_ENV
+                ))
+            })?,
+        };
+        secrets::register(token.as_str());
+
+        Ok(Client { config, token })
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let url = format!(
+            "{}/v1/{}",
+            self.config.address.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let mut request = ureq::request(method, &url);
+        if !self.token.is_empty() {
+            request = request.set("X-Vault-Token", &self.token);
+        }
+        if let Some(namespace) = &self.config.namespace {
+            request = request.set("X-Vault-Namespace", namespace);
+        }
+        request
+    }
+
+    fn parse_response(
+        &self,
+        response: std::result::Result<ureq::Response, ureq::Error>,
+    ) -> Result<Value> {
+        match response {
+            Ok(response) => response.into_json().context("invalid response from vault"),
+            Err(ureq::Error::Status(code, response)) => Err(Error::msg(format!(
+                "vault responded with status {} - {}",
+                code,
+                response.into_string().unwrap_or_default().trim()
+            ))),
+            Err(e) => Err(Error::msg(format!("failed to connect to vault - {}", e))),
+        }
+    }
+
+    /// Reads `key` of the secret at `path` supporting both versions of the KV secrets engine
+    fn read(&self, path: &str, key: &str) -> Result<String> {
+        let response = self.request("GET", path).call();
+        let response = self.parse_response(response)?;
+        let data = &response["data"];
+        let value = match data["data"].get(key) {
+            Some(value) => value,
+            None => data
+                .get(key)
+                .ok_or_else(|| Error::msg(format!("secret has no key `{}`", key)))?,
+        };
+        Ok(match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        })
+    }
+}
+
+fn write_secret_file(path: &Path, value: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(value.as_bytes()))
+        .context(format!("failed to write secret to `{}`", path.display()))
+}
+
+/// Fetches all configured secrets writing the ones with a `file` set and returning the values of
+/// the ones with an `env` set by the name of the variable. All fetched values are redacted from
+/// the output.
+pub fn load_secrets(config: &VaultConfig) -> Result<HashMap<String, String>> {
+    let span = info_span!("vault", address = %config.address);
+    let _enter = span.enter();
+
+    let mut vars = HashMap::new();
+    if config.secrets.is_empty() {
+        return Ok(vars);
+    }
+
+    let client = Client::login(config)?;
+
+    for secret in &config.secrets {
+        if secret.env.is_none() && secret.file.is_none() {
+            return Err(Error::msg(format!(
+                "secret `{}` of `{}` has neither `env` nor `file` set",
+                secret.key, secret.path
+            )));
+        }
+
+        trace!(path = %secret.path, key = %secret.key, "fetching");
+        let value = client.read(&secret.path, &secret.key).context(format!(
+            "failed to read `{}` of `{}`",
+            secret.key, secret.path
+        ))?;
+        secrets::register(value.as_str());
+
+        if let Some(file) = &secret.file {
+            write_secret_file(file, &value)?;
+        }
+        if let Some(var) = &secret.env {
+            vars.insert(var.clone(), value);
+        }
+    }
+    info!(count = %config.secrets.len(), "fetched secrets");
+
+    Ok(vars)
+}
@@ -0,0 +1,405 @@
+//! Builds of `pkger serve` triggered by webhooks of GitHub and GitLab when a tag is pushed to a
+//! repository. Requests are authenticated with the configured secret, GitHub signs the payload
+//! with it and GitLab sends it as a token, and pushed tags are mapped to builds by rules.
+use crate::opts::BuildOpts;
+use pkger_core::archive::glob_match;
+use pkger_core::{secrets, ErrContext, Error, Result};
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn};
+
+/// Maximum size of an accepted payload
+const BODY_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// Time after which a client that stopped sending its request is disconnected
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Deserialize)]
+/// Listener of webhooks that trigger builds of `pkger serve`
+pub struct WebhookConfig {
+    /// Address on which webhooks are received like `0.0.0.0:8080`
+    pub listen: SocketAddr,
+    /// Secret of GitHub webhooks and token of GitLab webhooks
+    pub secret: String,
+    #[serde(default)]
+    /// Rules mapping pushed tags to builds
+    pub rules: Vec<WebhookRule>,
+}
+
+impl fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("listen", &self.listen)
+            .field("secret", &secrets::REDACTED)
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+/// Builds recipes when a tag is pushed to a repository
+pub struct WebhookRule {
+    /// Path of the repository like `owner/name` on GitHub or `group/project` on GitLab
+    pub repository: String,
+    /// Pattern of tags that trigger the build like `v*`, all tags by default
+    pub tag: Option<String>,
+    #[serde(default)]
+    /// Recipes to build
+    pub recipes: Vec<String>,
+    #[serde(default)]
+    /// If set to true, all recipes will be built
+    pub all: bool,
+    /// Only build on the specified images
+    pub images: Option<Vec<String>>,
+    /// Build simple targets instead of images defined in recipes
+    pub simple: Option<Vec<String>>,
+}
+
+impl WebhookRule {
+    fn matches(&self, event: &TagEvent) -> bool {
+        self.repository.eq_ignore_ascii_case(&event.repository)
+            && self
+                .tag
+                .as_deref()
+                .map(|pattern| glob_match(pattern, &event.tag))
+                .unwrap_or(true)
+    }
+
+    pub fn build_opts(&self) -> BuildOpts {
+        BuildOpts {
+            recipes: self.recipes.clone(),
+            all: self.all,
+            images: self.images.clone(),
+            simple: self.simple.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A tag pushed to a repository
+pub struct TagEvent {
+    pub repository: String,
+    pub tag: String,
+}
+
+/// A build triggered by a pushed tag
+pub struct Trigger {
+    pub event: TagEvent,
+    pub opts: BuildOpts,
+}
+
+/// Starts receiving webhooks at the configured address. Builds of every rule matching a pushed
+/// tag are sent to the returned channel.
+pub fn listen(config: WebhookConfig) -> Result<Receiver<Trigger>> {
+    let listener = TcpListener::bind(config.listen).context(format!(
+        "failed to listen for webhooks on `{}`",
+        config.listen
+    ))?;
+    info!(address = %config.listen, rules = %config.rules.len(), "listening for webhooks");
+
+    let (triggers, received) = mpsc::channel();
+    thread::spawn(move || {
+        let span = info_span!("webhooks", address = %config.listen);
+        let _enter = span.enter();
+        for conn in listener.incoming() {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!(reason = %e, "failed to accept a webhook connection");
+                    continue;
+                }
+            };
+            let event = match respond(conn, &config) {
+                Ok(Some(event)) => event,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!(reason = %e, "failed to handle a webhook request");
+                    continue;
+                }
+            };
+            let rules = config.rules.iter().filter(|rule| rule.matches(&event));
+            let mut matched = 0;
+            for rule in rules {
+                matched += 1;
+                let trigger = Trigger {
+                    event: event.clone(),
+                    opts: rule.build_opts(),
+                };
+                if triggers.send(trigger).is_err() {
+                    return;
+                }
+            }
+            info!(repository = %event.repository, tag = %event.tag, rules = %matched, "received tag push");
+        }
+    });
+
+    Ok(received)
+}
+
+/// Outcome of a webhook request
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// A tag was pushed
+    Tag(TagEvent),
+    /// An authenticated event that doesn't trigger builds, like a ping or a deleted tag
+    Ignored,
+    /// The request is invalid, with the status and reason of the response
+    Rejected(u16, &'static str),
+}
+
+/// Reads a request and answers it, returning the pushed tag if there is one
+fn respond(mut conn: TcpStream, config: &WebhookConfig) -> Result<Option<TagEvent>> {
+    conn.set_read_timeout(Some(TIMEOUT))?;
+    conn.set_write_timeout(Some(TIMEOUT))?;
+    let outcome = read_request(&mut conn, &config.secret)?;
+    let (status, reason) = match &outcome {
+        Outcome::Tag(_) => (202, "Accepted"),
+        Outcome::Ignored => (204, "No Content"),
+        Outcome::Rejected(status, reason) => {
+            warn!(status = %status, "rejected webhook request, {}", reason.to_lowercase());
+            (*status, *reason)
+        }
+    };
+    write!(
+        conn,
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    )?;
+    Ok(match outcome {
+        Outcome::Tag(event) => Some(event),
+        _ => None,
+    })
+}
+
+/// Headers of a request relevant to webhooks
+#[derive(Default)]
+struct Request {
+    method: String,
+    content_length: Option<u64>,
+    github_event: Option<String>,
+    github_signature: Option<String>,
+    gitlab_event: Option<String>,
+    gitlab_token: Option<String>,
+}
+
+fn read_request(conn: &mut TcpStream, secret: &str) -> Result<Outcome> {
+    let mut reader = BufReader::new(conn.take(BODY_LIMIT + 64 * 1024));
+    let mut request = Request::default();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::msg(
+                "connection closed before the end of the request",
+            ));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if request.method.is_empty() {
+            request.method = line.split(' ').next().unwrap_or_default().to_string();
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = Some(value.trim().to_string());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => request.content_length = value.and_then(|v| v.parse().ok()),
+                "x-github-event" => request.github_event = value,
+                "x-hub-signature-256" => request.github_signature = value,
+                "x-gitlab-event" => request.gitlab_event = value,
+                "x-gitlab-token" => request.gitlab_token = value,
+                _ => {}
+            }
+        }
+    }
+
+    if request.method != "POST" {
+        return Ok(Outcome::Rejected(405, "Method Not Allowed"));
+    }
+    let len = match request.content_length {
+        Some(len) if len > BODY_LIMIT => return Ok(Outcome::Rejected(413, "Payload Too Large")),
+        Some(len) => len,
+        None => return Ok(Outcome::Rejected(411, "Length Required")),
+    };
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+    Ok(parse_event(&request, &body, secret))
+}
+
+/// Authenticates a request and extracts the pushed tag from its payload
+fn parse_event(request: &Request, body: &[u8], secret: &str) -> Outcome {
+    #[derive(Deserialize)]
+    struct GithubPush {
+        #[serde(rename = "ref")]
+        reference: String,
+        #[serde(default)]
+        deleted: bool,
+        repository: GithubRepository,
+    }
+    #[derive(Deserialize)]
+    struct GithubRepository {
+        full_name: String,
+    }
+    #[derive(Deserialize)]
+    struct GitlabTagPush {
+        #[serde(rename = "ref")]
+        reference: String,
+        after: String,
+        project: GitlabProject,
+    }
+    #[derive(Deserialize)]
+    struct GitlabProject {
+        path_with_namespace: String,
+    }
+
+    let (repository, reference, deleted) = if let Some(signature) = &request.github_signature {
+        if !verify_signature(secret, body, signature) {
+            return Outcome::Rejected(401, "Unauthorized");
+        }
+        if request.github_event.as_deref() != Some("push") {
+            return Outcome::Ignored;
+        }
+        match serde_json::from_slice::<GithubPush>(body) {
+            Ok(push) => (push.repository.full_name, push.reference, push.deleted),
+            Err(_) => return Outcome::Rejected(400, "Bad Request"),
+        }
+    } else if let Some(token) = &request.gitlab_token {
+        if !constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+            return Outcome::Rejected(401, "Unauthorized");
+        }
+        if request.gitlab_event.as_deref() != Some("Tag Push Hook") {
+            return Outcome::Ignored;
+        }
+        match serde_json::from_slice::<GitlabTagPush>(body) {
+            Ok(push) => {
+                // the commit after deleting a tag is all zeros
+                let deleted = push.after.chars().all(|c| c == '0');
+                (push.project.path_with_namespace, push.reference, deleted)
+            }
+            Err(_) => return Outcome::Rejected(400, "Bad Request"),
+        }
+    } else {
+        return Outcome::Rejected(401, "Unauthorized");
+    };
+
+    match reference.strip_prefix("refs/tags/") {
+        Some(tag) if !deleted => Outcome::Tag(TagEvent {
+            repository,
+            tag: tag.to_string(),
+        }),
+        _ => Outcome::Ignored,
+    }
+}
+
+/// Verifies the `sha256=<hex>` signature of a GitHub payload
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match signature.strip_prefix("sha256=").and_then(decode_hex) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("valid hmac key");
+    mac.update(body);
+    mac.verify(&signature).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compares secrets in time independent of the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let signature = mac.finalize().into_bytes();
+        format!(
+            "sha256={}",
+            signature
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        )
+    }
+
+    #[test]
+    fn parses_tag_pushes() {
+        let tag = |repository: &str, tag: &str| {
+            Outcome::Tag(TagEvent {
+                repository: repository.to_string(),
+                tag: tag.to_string(),
+            })
+        };
+
+        let body =
+            br#"{"ref":"refs/tags/v1.0.0","deleted":false,"repository":{"full_name":"wojciechkepka/pkger"}}"#;
+        let mut github = Request {
+            method: "POST".into(),
+            github_event: Some("push".into()),
+            github_signature: Some(sign("secret", body)),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_event(&github, body, "secret"),
+            tag("wojciechkepka/pkger", "v1.0.0")
+        );
+        assert_eq!(
+            parse_event(&github, body, "other"),
+            Outcome::Rejected(401, "Unauthorized")
+        );
+        github.github_event = Some("ping".into());
+        assert_eq!(parse_event(&github, body, "secret"), Outcome::Ignored);
+
+        let body = br#"{"ref":"refs/tags/v2","after":"0000000000000000000000000000000000000000","project":{"path_with_namespace":"group/pkger"}}"#;
+        let gitlab = Request {
+            method: "POST".into(),
+            gitlab_event: Some("Tag Push Hook".into()),
+            gitlab_token: Some("secret".into()),
+            ..Default::default()
+        };
+        assert_eq!(parse_event(&gitlab, body, "secret"), Outcome::Ignored);
+        let body = br#"{"ref":"refs/tags/v2","after":"da1560886d4f094c3e6c9ef40349f7d38b5d27d7","project":{"path_with_namespace":"group/pkger"}}"#;
+        assert_eq!(
+            parse_event(&gitlab, body, "secret"),
+            tag("group/pkger", "v2")
+        );
+        assert_eq!(
+            parse_event(&Request::default(), body, "secret"),
+            Outcome::Rejected(401, "Unauthorized")
+        );
+
+        let rule: WebhookRule =
+            serde_yaml::from_str("repository: Group/pkger\ntag: v*\nrecipes: [pkger]").unwrap();
+        let event = TagEvent {
+            repository: "group/pkger".into(),
+            tag: "v2".into(),
+        };
+        assert!(rule.matches(&event));
+        assert!(!rule.matches(&TagEvent {
+            tag: "nightly".into(),
+            ..event
+        }));
+    }
+}
@@ -2,6 +2,7 @@
 
 pub use flate2;
 pub use tar;
+pub use zstd;
 
 use crate::{ErrContext, Result};
 
@@ -10,7 +11,7 @@ use flate2::Compression;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::{info_span, trace};
 
 /// Unpacks a given tar archive to the path specified by `output_dir`
@@ -18,22 +19,77 @@ pub fn unpack_tarball<T: io::Read, P: AsRef<Path>>(
     archive: &mut tar::Archive<T>,
     output_dir: P,
 ) -> Result<()> {
+    unpack_tarball_matching(archive, output_dir, |_| true).map(|_| ())
+}
+
+/// Unpacks regular files of a given tar archive for which `filter` returns true when called with
+/// the file name to the path specified by `output_dir`. Returns the paths of unpacked files.
+pub fn unpack_tarball_matching<T, P, F>(
+    archive: &mut tar::Archive<T>,
+    output_dir: P,
+    filter: F,
+) -> Result<Vec<PathBuf>>
+where
+    T: io::Read,
+    P: AsRef<Path>,
+    F: Fn(&str) -> bool,
+{
     let output_dir = output_dir.as_ref();
     let span = info_span!("unpack-archive", output_dir = %output_dir.display());
     let _enter = span.enter();
 
+    let mut unpacked = vec![];
     for entry in archive.entries()? {
         let mut entry = entry?;
         if let tar::EntryType::Regular = entry.header().entry_type() {
             let path = entry.header().path()?.to_path_buf();
-            trace!(parent: &span, entry = %path.display(), "unpacking");
             let name = path.file_name().unwrap_or_default();
+            if !filter(&name.to_string_lossy()) {
+                trace!(parent: &span, entry = %path.display(), "skipping");
+                continue;
+            }
+            trace!(parent: &span, entry = %path.display(), "unpacking");
 
-            entry.unpack(output_dir.join(name))?;
+            let dest = output_dir.join(name);
+            entry.unpack(&dest)?;
+            unpacked.push(dest);
         }
     }
 
-    Ok(())
+    Ok(unpacked)
+}
+
+/// Matches `name` against a shell-like `pattern` where `*` matches any sequence of characters and
+/// `?` matches a single character
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // position in the pattern after the last `*` and the position in the name it matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, n));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 /// Save the give tar archive as gzip encoded tar to path specified by `output_dir` with the
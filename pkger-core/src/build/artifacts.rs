@@ -0,0 +1,112 @@
+//! Packages of earlier builds fetched into the build container and installed before the scripts
+//! run, so that a recipe can be built on top of packages that pkger built before.
+use crate::build::cache::blocking;
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::build::remote;
+use crate::container::ExecOpts;
+use crate::failure::FailureKind;
+use crate::image::ImageState;
+use crate::recipe::{FetchedArtifact, PackageManager};
+use crate::{ErrContext, Error, Result};
+
+use std::path::Path;
+use tracing::{info, info_span, trace, Instrument};
+
+/// Fetches the artifacts listed in `fetch_artifacts` of the recipe that apply to the target of
+/// the build and installs them with the package manager of the image
+pub async fn fetch_and_install(ctx: &Context<'_>, image_state: &ImageState) -> Result<()> {
+    let build_ctx = ctx.build_ctx;
+    let target = build_ctx.target.build_target();
+    let artifacts = build_ctx
+        .recipe
+        .metadata
+        .fetch_artifacts
+        .iter()
+        .filter(|artifact| artifact.applies_to(*target))
+        .collect::<Vec<_>>();
+    if artifacts.is_empty() {
+        return Ok(());
+    }
+
+    let span = info_span!("fetch-artifacts");
+    async move {
+        let pkg_mngr = image_state.os.package_manager();
+        if let PackageManager::Unavailable = pkg_mngr {
+            return Err(Error::msg(format!(
+                "image `{}` has no package manager to install fetched artifacts",
+                image_state.image
+            )));
+        }
+
+        let dir = build_ctx.container_tmp_dir.join("artifacts");
+        create_dirs(ctx, &[dir.as_path()]).await?;
+
+        let mut paths = vec![];
+        for artifact in artifacts {
+            let dest = dir.join(artifact.file_name());
+            fetch(ctx, artifact, &dest).await.context(format!(
+                "failed to fetch artifact `{}`",
+                artifact.file_name()
+            ))?;
+            paths.push(dest.to_string_lossy().to_string());
+        }
+
+        info!(artifacts = %paths.join(", "), "installing fetched artifacts");
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "{} {} {}",
+                pkg_mngr.as_ref(),
+                pkg_mngr.install_local_args().join(" "),
+                paths.join(" ")
+            )),
+        )
+        .await
+        .map(|_| ())
+        .context("failed to install fetched artifacts")
+    }
+    .instrument(span)
+    .await
+}
+
+async fn fetch(ctx: &Context<'_>, artifact: &FetchedArtifact, dest: &Path) -> Result<()> {
+    let build_ctx = ctx.build_ctx;
+    match (&artifact.url, &artifact.publisher, &artifact.id) {
+        (Some(url), _, _) if url.starts_with("http") => remote::fetch_file(ctx, url, dest).await?,
+        (Some(path), _, _) => {
+            let path = build_ctx.recipe.recipe_dir.join(path);
+            trace!(path = %path.display(), "reading artifact");
+            remote::fetch_file(ctx, &path.to_string_lossy(), dest).await?
+        }
+        (None, Some(name), Some(id)) => {
+            let publisher = build_ctx
+                .publishers
+                .iter()
+                .find(|publisher| publisher.name() == name)
+                .cloned()
+                .ok_or_else(|| Error::msg(format!("publisher `{}` is not configured", name)))?;
+            info!(publisher = %name, id = %id, "fetching");
+            let id = id.clone();
+            let data = blocking(move || publisher.fetch(&id)).await?;
+            copy_file_into(ctx, dest, &data).await?
+        }
+        _ => return Err(Error::msg("artifact has neither a `url` nor a `publisher`")),
+    }
+
+    if let Some(checksum) = &artifact.checksum {
+        let out = checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!("{} {}", checksum.command(), dest.display())),
+        )
+        .await?;
+        if let Some(actual) = checksum.mismatch(&out.stdout.join("")) {
+            return Err(Error::msg(format!(
+                "checksum mismatch, expected `{}`, got `{}`",
+                checksum, actual
+            ))
+            .context(FailureKind::Verification));
+        }
+    }
+
+    Ok(())
+}
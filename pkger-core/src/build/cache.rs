@@ -0,0 +1,279 @@
+//! Cache of finished artifacts shared between machines. Artifacts are stored under a hash of
+//! everything that determines their content, so that a build of an unchanged recipe on an
+//! unchanged image can be skipped by pulling the artifact built elsewhere.
+use crate::archive::{create_tarball, tar, unpack_tarball};
+use crate::build::manifest::{ArtifactManifest, MANIFEST_EXTENSION};
+use crate::build::Context;
+use crate::image::ImageState;
+use crate::units::HumanSize;
+use crate::{ErrContext, Error, Result};
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, info_span, trace, Instrument};
+
+/// Storage of artifact archives, like an HTTP server or an S3 bucket
+pub trait ArtifactCache: fmt::Debug + Send + Sync {
+    /// Returns the archive stored under `key` or `None` if there is none
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &str, archive: Vec<u8>) -> Result<()>;
+}
+
+/// Feeds contents of all files in `dir` to `hasher` in a stable order
+pub(crate) fn hash_dir(hasher: &mut Sha256, base: &Path, dir: &Path) -> Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            hash_dir(hasher, base, &path)?;
+        } else {
+            let relative = path.strip_prefix(base).unwrap_or(&path);
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(&fs::read(&path)?);
+            hasher.update([0]);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the key of the artifact of this build. It covers the image directory with its
+/// Dockerfile, the repository digests of the images it's based on, the dependencies installed in
+/// the image, the digest of the fetched source, the recipe rendered for the image and target, the
+/// contents of local patches, the architecture, release and the version of pkger. Recipes with
+/// remote patches are not cached as their contents are only known after the build fetched them,
+/// `None` is returned for them.
+pub async fn cache_key(
+    ctx: &Context,
+    image_state: &ImageState,
+    source_digest: Option<&str>,
+) -> Result<Option<String>> {
+    let recipe = &ctx.recipe;
+    let image = ctx.target.image();
+    let build_target = ctx.target.build_target();
+
+    let patches = recipe
+        .metadata
+        .patches
+        .as_ref()
+        .map(|patches| patches.resolve_names(image))
+        .unwrap_or_default();
+    if patches
+        .iter()
+        .any(|patch| patch.patch().starts_with("http"))
+    {
+        trace!("recipe has remote patches, not caching");
+        return Ok(None);
+    }
+
+    let release = ctx.release();
+    let rendered = recipe.render_snapshot(image, build_target);
+    let mut hasher = Sha256::new();
+    hasher.update(concat!("pkger-", env!("CARGO_PKG_VERSION")).as_bytes());
+    hash_dir(&mut hasher, &ctx.image.path, &ctx.image.path).context(format!(
+        "failed to hash image `{}`",
+        ctx.image.path.display()
+    ))?;
+
+    let dockerfile = fs::read_to_string(ctx.image.path.join("Dockerfile"))?;
+    for base in base_images(&dockerfile) {
+        let details = ctx
+            .docker
+            .images()
+            .get(&base)
+            .inspect()
+            .await
+            .map_err(|e| Error::msg(format!("failed to inspect base image `{}` - {}", base, e)))?;
+        // images that were never pushed or pulled only have a local id
+        let mut digests = details.repo_digests;
+        digests.sort_unstable();
+        let digest = digests
+            .first()
+            .and_then(|digest| digest.split_once('@'))
+            .map(|(_, digest)| digest.to_string())
+            .unwrap_or(details.id);
+        trace!(image = %base, digest = %digest, "hashing base image");
+        hasher.update(digest.as_bytes());
+        hasher.update([0]);
+    }
+
+    let mut deps = image_state.deps.iter().collect::<Vec<_>>();
+    deps.sort_unstable();
+    for part in deps.into_iter().chain(&image_state.snapshot) {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    for part in [
+        build_target.as_ref(),
+        ctx.arch().rpm_name(),
+        recipe.metadata.name.as_str(),
+        recipe.metadata.version.as_str(),
+        release.as_str(),
+        source_digest.unwrap_or_default(),
+        rendered.as_str(),
+    ]
+    .iter()
+    {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    for patch in patches {
+        let src = patch.patch();
+        hasher.update(src.as_bytes());
+        hasher.update([0]);
+        let path = recipe.recipe_dir.join(src);
+        hasher.update(
+            &fs::read(&path).context(format!("failed to hash patch `{}`", path.display()))?,
+        );
+        hasher.update([0]);
+    }
+
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+/// Returns the images the stages of `dockerfile` are based on, stages based on earlier stages or
+/// on `scratch` are skipped
+fn base_images(dockerfile: &str) -> Vec<String> {
+    let mut stages = HashSet::new();
+    let mut bases = vec![];
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !words
+            .next()
+            .map(|word| word.eq_ignore_ascii_case("FROM"))
+            .unwrap_or_default()
+        {
+            continue;
+        }
+        // flags like `--platform`
+        let words = words
+            .filter(|word| !word.starts_with("--"))
+            .collect::<Vec<_>>();
+        let base = match words.first() {
+            Some(base) => *base,
+            None => continue,
+        };
+        if base != "scratch" && !stages.contains(&base.to_lowercase()) {
+            bases.push(base.to_string());
+        }
+        if let [_, alias, name] = words.as_slice() {
+            if alias.eq_ignore_ascii_case("AS") {
+                stages.insert(name.to_lowercase());
+            }
+        }
+    }
+    bases
+}
+
+/// Runs `f` on a thread where blocking is acceptable, like network or file IO
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::msg(format!("blocking task failed - {}", e)))?
+}
+
+/// Unpacks the artifact stored under `key` into `out_dir` returning its manifest if the cache
+/// contains it
+pub async fn restore(
+    cache: &Arc<dyn ArtifactCache>,
+    key: &str,
+    out_dir: &Path,
+) -> Result<Option<ArtifactManifest>> {
+    let span = info_span!("cache-restore", key = %key);
+    async move {
+        let archive = {
+            let cache = cache.clone();
+            let key = key.to_string();
+            match blocking(move || cache.get(&key)).await? {
+                Some(archive) => archive,
+                None => {
+                    info!("cache miss");
+                    return Ok(None);
+                }
+            }
+        };
+        trace!(size = %HumanSize(archive.len() as u64), "unpacking");
+
+        let mut manifest = None;
+        let mut entries = tar::Archive::new(&archive[..]);
+        for entry in entries.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_path_buf();
+            if path.extension().map(|ext| ext == MANIFEST_EXTENSION) == Some(true) {
+                manifest = Some(serde_json::from_reader::<_, ArtifactManifest>(&mut entry)?);
+            }
+        }
+        let manifest = manifest.ok_or_else(|| Error::msg("cached archive has no manifest"))?;
+
+        unpack_tarball(&mut tar::Archive::new(&archive[..]), out_dir)?;
+        info!(path = %manifest.path.display(), "restored artifact from cache");
+
+        Ok(Some(manifest))
+    }
+    .instrument(span)
+    .await
+}
+
+/// Stores the artifact at `package` together with its manifest under `key`
+pub async fn store(
+    cache: &Arc<dyn ArtifactCache>,
+    key: &str,
+    package: &Path,
+    manifest: &ArtifactManifest,
+) -> Result<()> {
+    let span = info_span!("cache-store", key = %key);
+    async move {
+        let package_name = PathBuf::from(package.file_name().unwrap_or_default());
+        let manifest_name = ArtifactManifest::path_for(&package_name);
+        // signatures are made with the key of the host so they are not shared
+        let mut manifest = manifest.clone();
+        manifest.signature = None;
+
+        let package_data = fs::read(package)?;
+        let manifest_data = serde_json::to_vec_pretty(&manifest)?;
+        let archive = create_tarball(
+            vec![
+                (&package_name, &package_data[..]),
+                (&manifest_name, &manifest_data[..]),
+            ]
+            .into_iter(),
+        )?;
+        trace!(size = %HumanSize(archive.len() as u64), "uploading");
+
+        let cache = cache.clone();
+        let key = key.to_string();
+        blocking(move || cache.put(&key, archive)).await?;
+        info!("stored artifact in cache");
+
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_base_images() {
+        let dockerfile = "FROM --platform=linux/amd64 rust:1.56 AS builder\nRUN cargo build\nfrom builder as test\nFROM scratch\nFROM debian:11\nCOPY --from=builder /out /out\n";
+        assert_eq!(base_images(dockerfile), vec!["rust:1.56", "debian:11"]);
+        assert!(base_images("RUN true").is_empty());
+    }
+}
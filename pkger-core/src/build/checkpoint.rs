@@ -0,0 +1,187 @@
+//! Checkpoints of build containers committed to images after each phase of a build, so that a
+//! build failing in a later phase can be retried from the last phase that succeeded instead of
+//! from scratch. A checkpoint is only reused if everything its phase depends on is unchanged and
+//! all checkpoints of a build are removed once the build succeeds.
+use crate::build;
+use crate::build::container::{self, Context};
+use crate::image::ImageState;
+use crate::{ErrContext, Result};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Fetched source with patches applied
+    Source,
+    Configure,
+    Build,
+    Install,
+}
+
+impl AsRef<str> for Phase {
+    fn as_ref(&self) -> &str {
+        match self {
+            Phase::Source => "source",
+            Phase::Configure => "configure",
+            Phase::Build => "build",
+            Phase::Install => "install",
+        }
+    }
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [
+        Phase::Source,
+        Phase::Configure,
+        Phase::Build,
+        Phase::Install,
+    ];
+
+    /// Whether this phase was already completed by a build resumed after `resumed`
+    pub fn is_completed(&self, resumed: Option<Phase>) -> bool {
+        resumed.map(|resumed| resumed >= *self).unwrap_or_default()
+    }
+}
+
+/// Name of the repository of images with checkpoints of builds of the recipe on the image
+fn repository(ctx: &build::Context) -> String {
+    format!(
+        "pkger-checkpoint-{}-{}",
+        ctx.recipe.metadata.name,
+        ctx.target.image()
+    )
+    .to_lowercase()
+}
+
+/// Returns the key of the checkpoint after `phase`. It covers the image with dependencies, the
+/// source and patches of the recipe, its environment and the scripts of all phases up to `phase`.
+fn key(ctx: &build::Context, image_state: &ImageState, phase: Phase) -> String {
+    let metadata = &ctx.recipe.metadata;
+    let mut env = ctx.recipe.env.iter().collect::<Vec<_>>();
+    env.sort_unstable();
+    let mut parts = vec![
+        concat!("pkger-", env!("CARGO_PKG_VERSION")).to_string(),
+        image_state.id.clone(),
+        ctx.target.build_target().as_ref().to_string(),
+        ctx.arch().rpm_name().to_string(),
+        metadata.version.clone(),
+        ctx.release(),
+        format!("{:?}", metadata.source),
+        format!("{:?}", metadata.extra_sources),
+        format!("{:?}", metadata.git),
+        format!("{:?}", metadata.fetch_artifacts),
+        format!(
+            "{:?}",
+            metadata
+                .patches
+                .as_ref()
+                .map(|patches| patches.resolve_names(ctx.target.image()))
+        ),
+        format!("{:?}", env),
+        ctx.container_bld_dir.to_string_lossy().to_string(),
+    ];
+    if phase >= Phase::Configure {
+        parts.push(format!("{:?}", ctx.recipe.configure_script));
+    }
+    if phase >= Phase::Build {
+        parts.push(format!("{:?}", ctx.recipe.build_script));
+    }
+    if phase >= Phase::Install {
+        parts.push(format!("{:?}", ctx.recipe.install_script));
+    }
+
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the reference of the image with the checkpoint after `phase` like
+/// `pkger-checkpoint-foo-centos8:build-0123456789abcdef`
+fn reference(ctx: &build::Context, image_state: &ImageState, phase: Phase) -> String {
+    format!(
+        "{}:{}-{}",
+        repository(ctx),
+        phase.as_ref(),
+        &key(ctx, image_state, phase)[..16]
+    )
+}
+
+/// Commits the container of the build after `phase`. Failing to save a checkpoint doesn't fail
+/// the build.
+pub async fn save(ctx: &Context<'_>, image_state: &ImageState, phase: Phase) {
+    let reference = reference(ctx.build_ctx, image_state, phase);
+    let span = info_span!("save-checkpoint", phase = %phase.as_ref());
+    async move {
+        let (repo, tag) = reference.split_at(reference.rfind(':').unwrap_or(reference.len()));
+        match ctx
+            .container
+            .commit(repo, tag.trim_start_matches(':'))
+            .await
+        {
+            Ok(_) => info!(image = %reference, "saved checkpoint"),
+            Err(e) => warn!(reason = %e, "failed to save checkpoint"),
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Replaces the container of the build with one created from the latest checkpoint of the build
+/// if there is one. Returns the phase of the checkpoint.
+pub async fn resume(ctx: &mut Context<'_>, image_state: &ImageState) -> Result<Option<Phase>> {
+    let span = info_span!("resume-from-checkpoint");
+    async move {
+        let build_ctx = ctx.build_ctx;
+        let images = build_ctx.docker.images();
+        for phase in Phase::ALL.iter().rev() {
+            let reference = reference(build_ctx, image_state, *phase);
+            if images.get(&reference).inspect().await.is_err() {
+                debug!(image = %reference, "no checkpoint");
+                continue;
+            }
+
+            info!(image = %reference, phase = %phase.as_ref(), "resuming from checkpoint");
+            ctx.container
+                .remove()
+                .await
+                .context("failed to remove container")?;
+            let mut state = image_state.clone();
+            state.id = reference;
+            *ctx = container::spawn(build_ctx, &state, true)
+                .await
+                .context("failed to spawn container from checkpoint")?;
+            return Ok(Some(*phase));
+        }
+        Ok(None)
+    }
+    .instrument(span)
+    .await
+}
+
+/// Removes all checkpoints of the build, called once the build succeeded
+pub async fn remove(ctx: &build::Context, image_state: &ImageState) {
+    let images = ctx.docker.images();
+    for phase in Phase::ALL.iter() {
+        let reference = reference(ctx, image_state, *phase);
+        if images.get(&reference).delete().await.is_ok() {
+            debug!(image = %reference, "removed checkpoint");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_phases() {
+        assert!(Phase::Configure.is_completed(Some(Phase::Build)));
+        assert!(Phase::Build.is_completed(Some(Phase::Build)));
+        assert!(!Phase::Install.is_completed(Some(Phase::Build)));
+        assert!(!Phase::Source.is_completed(None));
+    }
+}
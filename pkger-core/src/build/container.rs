@@ -1,11 +1,15 @@
 use crate::build;
 use crate::container::{DockerContainer, ExecOpts, Output};
-use crate::docker::{api::ContainerCreateOpts, ExecContainerOpts};
+use crate::docker::api::ContainerCreateOpts;
 use crate::image::ImageState;
+use crate::labels;
+use crate::recipe::{parse_size, Device};
 use crate::{Error, Result};
 
+use serde_json::{json, Value as JsonValue};
+use std::fmt;
 use std::path::Path;
-use tracing::{info_span, trace, Instrument};
+use tracing::{info_span, trace, warn, Instrument};
 
 pub struct Context<'job> {
     pub container: DockerContainer<'job>,
@@ -17,7 +21,9 @@ impl<'job> Context<'job> {
     #[allow(clippy::too_many_arguments)]
     pub fn new(build_ctx: &'job build::Context, opts: ContainerCreateOpts) -> Context<'job> {
         Context {
-            container: DockerContainer::new(&build_ctx.docker, Some(build_ctx.is_running.clone())),
+            container: DockerContainer::new(&build_ctx.docker, Some(build_ctx.is_running.clone()))
+                .with_stall_detection(build_ctx.stall_detection.clone())
+                .with_output_limit(build_ctx.output_limit.clone()),
             opts,
             build_ctx,
         }
@@ -26,10 +32,12 @@ impl<'job> Context<'job> {
 
 // https://github.com/rust-lang/rust-clippy/issues/7271
 #[allow(clippy::needless_lifetimes)]
-/// Creates and starts a container from the given ImageState
+/// Creates and starts a container from the given ImageState. A `restricted` container has the
+/// security options of the build applied.
 pub async fn spawn<'ctx>(
     ctx: &'ctx build::Context,
     image_state: &ImageState,
+    restricted: bool,
 ) -> Result<Context<'ctx>> {
     let span = info_span!("init-container-ctx");
     async move {
@@ -40,33 +48,178 @@ pub async fn spawn<'ctx>(
         env.insert("PKGER_OUT_DIR", ctx.container_out_dir.to_string_lossy());
         env.insert("PKGER_OS", image_state.os.name());
         env.insert("PKGER_OS_VERSION", image_state.os.version());
+        if ctx.recipe.metadata.noninteractive() && !ctx.recipe.env.contains("DEBIAN_FRONTEND") {
+            env.insert("DEBIAN_FRONTEND", "noninteractive");
+        }
+        if let Some(preset) = &ctx.recipe.metadata.preset {
+            for (key, value) in preset.env() {
+                if !ctx.recipe.env.contains(key) {
+                    env.insert(*key, *value);
+                }
+            }
+        }
         trace!(env = ?env);
+        ctx.record(|transcript| transcript.env(env.iter()));
 
-        let opts = ContainerCreateOpts::builder(&image_state.id)
+        let labels = labels::for_build(ctx);
+        let mut builder = ContainerCreateOpts::builder(&image_state.id);
+        builder
             .name(&ctx.id)
+            .labels(labels::as_opts(&labels))
             .cmd(vec!["sleep infinity"])
             .entrypoint(vec!["/bin/sh", "-c"])
             .env(env.kv_vec())
-            .working_dir(ctx.container_bld_dir.to_string_lossy())
-            .build();
+            .working_dir(ctx.container_bld_dir.to_string_lossy());
+        // options without builder methods in docker-api
+        let mut overrides = json!({});
+        let mut host_config = json!({});
+
+        if let Some(network) = &ctx.recipe.metadata.network {
+            trace!(network = ?network);
+            if let Some(hostname) = &network.hostname {
+                overrides["Hostname"] = json!(hostname);
+            }
+            if !network.extra_hosts.is_empty() {
+                builder.extra_hosts(
+                    network
+                        .extra_hosts
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>(),
+                );
+            }
+            if !network.dns.is_empty() {
+                host_config["Dns"] = json!(network.dns);
+            }
+        }
+
+        let metadata = &ctx.recipe.metadata;
+        if !metadata.devices.is_empty() {
+            trace!(devices = ?metadata.devices);
+            builder.devices(metadata.devices.iter().map(Device::as_map).collect());
+        }
+        if let Some(gpus) = &metadata.gpus {
+            trace!(gpus = ?gpus);
+            host_config["DeviceRequests"] = json!([gpus.device_request()]);
+        }
+
+        if restricted {
+            let disk = ctx.disk()?;
+            if let Some(size) = &disk.size {
+                if ctx.runtime.supports_storage_opts() {
+                    trace!(size = %size, "limiting container size");
+                    host_config["StorageOpt"] = json!({ "size": size });
+                } else {
+                    warn!(size = %size, runtime = %ctx.runtime.as_ref(), "limiting container size is not supported, ignoring");
+                }
+            }
+            if let Some(shm_size) = &disk.shm_size {
+                trace!(shm_size = %shm_size, "setting size of /dev/shm");
+                host_config["ShmSize"] = json!(parse_size(shm_size)?);
+            }
+
+            let security = ctx.security();
+            if !security.is_empty() {
+                trace!(security = ?security);
+                host_config["SecurityOpt"] = json!(security.security_opts()?);
+                host_config["CapDrop"] = json!(security.cap_drop);
+                host_config["Tmpfs"] = JsonValue::Object(
+                    security
+                        .tmpfs
+                        .iter()
+                        .map(|mount| {
+                            let mount = disk.tmpfs_mount(mount);
+                            match mount.split_once(':') {
+                                Some((path, options)) => (path.to_string(), json!(options)),
+                                None => (mount, json!("")),
+                            }
+                        })
+                        .collect(),
+                );
+                if security.read_only {
+                    host_config["ReadonlyRootfs"] = json!(true);
+                    // files are copied into the build directories through the Docker API which
+                    // can only write to volumes when the root filesystem is read only
+                    overrides["Volumes"] = JsonValue::Object(
+                        [
+                            &ctx.container_bld_dir,
+                            &ctx.container_out_dir,
+                            &ctx.container_tmp_dir,
+                        ]
+                        .iter()
+                        .map(|dir| (dir.to_string_lossy().to_string(), json!({})))
+                        .collect(),
+                    );
+                }
+            }
+        }
+
+        overrides["HostConfig"] = host_config;
 
+        let opts = builder.build();
         let mut ctx = Context::new(&ctx, opts);
-        ctx.container.spawn(&ctx.opts).await.map(|_| ctx)
+        ctx.container
+            .spawn_with_overrides(&ctx.opts, &overrides)
+            .await
+            .map(|_| ctx)
     }
     .instrument(span)
     .await
 }
 
-pub async fn checked_exec(ctx: &Context<'_>, opts: &ExecContainerOpts) -> Result<Output<String>> {
+#[derive(Debug)]
+/// A command executed in a build container exited with a non zero code
+pub struct ExecError {
+    pub command: String,
+    pub container: String,
+    pub exit_code: u64,
+    pub stderr: String,
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "command `{}` exited with code {} in container {}",
+            self.command, self.exit_code, self.container
+        )?;
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, "\nstderr:\n{}", stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Executes the command in the container of this context returning an `ExecError` if it exits
+/// with a non zero code
+pub async fn checked_exec(ctx: &Context<'_>, opts: &ExecOpts<'_>) -> Result<Output<String>> {
     let span = info_span!("checked-exec");
     async move {
-        let out = ctx.container.exec(opts).await?;
+        ctx.build_ctx.record(|transcript| {
+            transcript.exec(
+                opts.get_cmd(),
+                opts.get_shell(),
+                opts.get_working_dir()
+                    .unwrap_or(&ctx.build_ctx.container_bld_dir),
+            )
+        });
+        let exec_opts = opts.clone().build();
+        let out = if opts.is_output_limited() {
+            ctx.container.exec_limited(&exec_opts).await?
+        } else {
+            ctx.container.exec(&exec_opts).await?
+        };
         if out.exit_code != 0 {
-            Err(Error::msg(format!(
-                "command failed with exit code {}\nError:\n{}",
-                out.exit_code,
-                out.stderr.join("\n")
-            )))
+            Err(ExecError {
+                command: opts.get_cmd().to_string(),
+                container: ctx.container.id().to_string(),
+                exit_code: out.exit_code,
+                stderr: out.stderr.join(""),
+            }
+            .into())
         } else {
             Ok(out)
         }
@@ -75,6 +228,17 @@ pub async fn checked_exec(ctx: &Context<'_>, opts: &ExecContainerOpts) -> Result
     .await
 }
 
+/// Copies `data` to a file at `path` inside of the container
+pub async fn copy_file_into(ctx: &Context<'_>, path: &Path, data: &[u8]) -> Result<()> {
+    ctx.build_ctx
+        .record(|transcript| transcript.copy(path, data.len()));
+    ctx.container
+        .inner()
+        .copy_file_into(path, data)
+        .await
+        .map_err(Error::from)
+}
+
 pub async fn create_dirs<P: AsRef<Path>>(ctx: &Context<'_>, dirs: &[P]) -> Result<()> {
     let span = info_span!("create-dirs");
     async move {
@@ -90,9 +254,7 @@ pub async fn create_dirs<P: AsRef<Path>>(ctx: &Context<'_>, dirs: &[P]) -> Resul
 
         checked_exec(
             ctx,
-            &ExecOpts::default()
-                .cmd(&format!("mkdir -pv {}", dirs_joined))
-                .build(),
+            &ExecOpts::default().cmd(&format!("mkdir -pv {}", dirs_joined)),
         )
         .await
         .map(|_| ())
@@ -0,0 +1,276 @@
+//! Credentials of private sources. Each entry of the configuration applies to sources on a host,
+//! optionally limited to a path prefix, and holds a username with a password, a token or a path
+//! to a private SSH key. Values are passed to commands in the build container through the
+//! environment of the command so they never end up in the image, the transcript or the logs.
+use crate::{secrets, Error, Result};
+
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+static USERNAME_VAR: &str = "PKGER_SOURCE_USERNAME";
+static PASSWORD_VAR: &str = "PKGER_SOURCE_PASSWORD";
+static TOKEN_VAR: &str = "PKGER_SOURCE_TOKEN";
+/// Username sent with a token to git servers when none is set
+static DEFAULT_TOKEN_USERNAME: &str = "oauth2";
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct SourceCredentials {
+    /// Host of the sources, optionally followed by a path prefix like `github.com/org`
+    pub host: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sent as a bearer token to HTTP sources and as the password to git servers
+    pub token: Option<String>,
+    /// Path on the host to a private key used to clone git sources over SSH
+    pub ssh_key: Option<PathBuf>,
+    /// Path on the host to the known hosts verified when cloning over SSH, defaults to
+    /// `~/.ssh/known_hosts`
+    pub known_hosts: Option<PathBuf>,
+}
+
+impl fmt::Debug for SourceCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hidden = |value: &Option<String>| value.as_ref().map(|_| secrets::REDACTED);
+        f.debug_struct("SourceCredentials")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .field("password", &hidden(&self.password))
+            .field("token", &hidden(&self.token))
+            .field("ssh_key", &self.ssh_key)
+            .field("known_hosts", &self.known_hosts)
+            .finish()
+    }
+}
+
+/// Scheme of `url` and its location without the scheme, user info, query and fragment. Locations
+/// of scp-like urls such as `git@github.com:org/repo.git` are `github.com/org/repo.git` with the
+/// `ssh` scheme. Returns `None` for paths.
+fn location(url: &str) -> Option<(&str, String)> {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    if let Some((scheme, rest)) = url.split_once("://") {
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        return Some((scheme, format!("{}{}", host, path)));
+    }
+    match url.split_once(':') {
+        Some((authority, path)) if !authority.contains('/') && !authority.is_empty() => {
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            Some(("ssh", format!("{}/{}", host, path.trim_start_matches('/'))))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the credentials with the longest host and path prefix matching `url`
+pub fn find<'c>(credentials: &'c [SourceCredentials], url: &str) -> Option<&'c SourceCredentials> {
+    let (_, location) = location(url)?;
+    credentials
+        .iter()
+        .filter(|credentials| {
+            let prefix = credentials.host.trim_end_matches('/');
+            location
+                .strip_prefix(prefix)
+                .map(|rest| rest.is_empty() || rest.starts_with('/') || rest.starts_with(':'))
+                .unwrap_or(false)
+        })
+        .max_by_key(|credentials| credentials.host.trim_end_matches('/').len())
+}
+
+impl SourceCredentials {
+    pub fn validate(&self) -> Result<()> {
+        if self.host.trim_end_matches('/').is_empty() {
+            return Err(Error::msg("host of source credentials can't be empty"));
+        }
+        if self.password.is_some() && self.token.is_some() {
+            return Err(Error::msg(format!(
+                "only one of `password` and `token` can be set in credentials of `{}`",
+                self.host
+            )));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(Error::msg(format!(
+                "a password requires a username in credentials of `{}`",
+                self.host
+            )));
+        }
+        if self.password.is_none() && self.token.is_none() && self.ssh_key.is_none() {
+            return Err(Error::msg(format!(
+                "one of `password`, `token` or `ssh_key` has to be set in credentials of `{}`",
+                self.host
+            )));
+        }
+        Ok(())
+    }
+
+    /// Remembers the password and the token so that they are redacted from output
+    pub fn register_secrets(&self) {
+        for secret in self.password.iter().chain(self.token.iter()) {
+            secrets::register(secret.as_str());
+        }
+    }
+
+    /// Environment of commands authenticating with these credentials
+    pub fn env(&self) -> Vec<String> {
+        let mut env = vec![];
+        let username = match (&self.username, &self.token) {
+            (Some(username), _) => Some(username.as_str()),
+            (None, Some(_)) => Some(DEFAULT_TOKEN_USERNAME),
+            (None, None) => None,
+        };
+        if let Some(username) = username {
+            env.push(format!("{}={}", USERNAME_VAR, username));
+        }
+        if let Some(password) = self.password.as_ref().or(self.token.as_ref()) {
+            env.push(format!("{}={}", PASSWORD_VAR, password));
+        }
+        if let Some(token) = &self.token {
+            env.push(format!("{}={}", TOKEN_VAR, token));
+        }
+        env
+    }
+
+    /// Arguments of curl authenticating a download, credentials are expanded from the
+    /// environment returned by [`env`](SourceCredentials::env). curl only sends them to the host
+    /// of the url, not to hosts it is redirected to.
+    pub fn curl_args(&self) -> String {
+        if self.token.is_some() {
+            format!(" --oauth2-bearer \"${}\"", TOKEN_VAR)
+        } else if self.password.is_some() {
+            format!(" -u \"${}:${}\"", USERNAME_VAR, PASSWORD_VAR)
+        } else {
+            String::new()
+        }
+    }
+
+    /// Path of the SSH key used to clone the git repository at `url`, only set for SSH urls
+    pub fn ssh_key_for(&self, url: &str) -> Option<&Path> {
+        match location(url) {
+            Some(("ssh", _)) | Some(("git+ssh", _)) => self.ssh_key.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Path of the known hosts verified when cloning over SSH
+    pub fn known_hosts_path(&self) -> Option<PathBuf> {
+        self.known_hosts.clone().or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"))
+        })
+    }
+
+    /// Git invocation and its environment authenticating access to the repository at `url`.
+    /// `ssh` are the locations of the key and of the known hosts copied into the container.
+    pub fn git_command(&self, url: &str, ssh: Option<(&Path, &Path)>) -> (String, Vec<String>) {
+        let mut env = vec!["GIT_TERMINAL_PROMPT=0".to_string()];
+        if let Some((ssh_key, known_hosts)) = ssh {
+            env.push(format!(
+                "GIT_SSH_COMMAND=ssh -i {} -o IdentitiesOnly=yes -o StrictHostKeyChecking=yes -o UserKnownHostsFile={}",
+                ssh_key.display(),
+                known_hosts.display()
+            ));
+            return ("git".to_string(), env);
+        }
+        match location(url) {
+            Some((scheme, location))
+                if (scheme == "http" || scheme == "https")
+                    && (self.password.is_some() || self.token.is_some()) =>
+            {
+                let host = location.split('/').next().unwrap_or_default();
+                env.extend(self.env());
+                // the helper is scoped to the host so that submodules on other hosts don't get
+                // the credentials
+                let git = format!(
+                    "git -c 'credential.{}://{}.helper=!f() {{ test \"$1\" = get && echo \"username=${}\" && echo \"password=${}\"; }}; f'",
+                    scheme, host, USERNAME_VAR, PASSWORD_VAR
+                );
+                (git, env)
+            }
+            _ => ("git".to_string(), env),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(host: &str, token: Option<&str>, ssh_key: Option<&str>) -> SourceCredentials {
+        SourceCredentials {
+            host: host.to_string(),
+            username: None,
+            password: None,
+            token: token.map(str::to_string),
+            ssh_key: ssh_key.map(PathBuf::from),
+            known_hosts: None,
+        }
+    }
+
+    #[test]
+    fn authenticates_sources() {
+        let all = vec![
+            credentials("github.com", Some("t0ken"), None),
+            credentials(
+                "github.com/private-org/",
+                None,
+                Some("/home/pkger/.ssh/id_ed25519"),
+            ),
+            credentials("example.com", Some("other"), None),
+        ];
+        let host = |url: &str| find(&all, url).map(|c| c.host.as_str());
+        assert_eq!(host("https://github.com/org/repo.git"), Some("github.com"));
+        assert_eq!(
+            host("git@github.com:private-org/repo.git"),
+            Some("github.com/private-org/")
+        );
+        assert_eq!(
+            host("https://user@example.com:8443/foo.tar.gz?x=1"),
+            Some("example.com")
+        );
+        assert_eq!(host("https://github.community/foo"), None);
+        assert_eq!(host("/home/pkger/foo.tar.gz"), None);
+
+        let token = &all[0];
+        assert_eq!(
+            token.curl_args(),
+            " --oauth2-bearer \"$PKGER_SOURCE_TOKEN\""
+        );
+        let (git, env) = token.git_command("https://github.com/org/repo.git", None);
+        assert!(git.starts_with("git -c 'credential.https://github.com.helper=!f()"));
+        assert!(!git.contains("t0ken"));
+        assert!(env.contains(&"PKGER_SOURCE_USERNAME=oauth2".to_string()));
+        assert!(env.contains(&"PKGER_SOURCE_PASSWORD=t0ken".to_string()));
+        assert!(format!("{:?}", token).contains(secrets::REDACTED));
+        assert!(!format!("{:?}", token).contains("t0ken"));
+
+        let ssh = &all[1];
+        assert_eq!(
+            ssh.ssh_key_for("https://github.com/private-org/repo.git"),
+            None
+        );
+        let key = ssh
+            .ssh_key_for("ssh://git@github.com/private-org/repo.git")
+            .unwrap();
+        let (git, env) =
+            ssh.git_command("", Some((Path::new("/tmp/key"), Path::new("/tmp/hosts"))));
+        assert_eq!(key, Path::new("/home/pkger/.ssh/id_ed25519"));
+        assert_eq!(git, "git");
+        assert!(env[1].starts_with("GIT_SSH_COMMAND=ssh -i /tmp/key "));
+        assert!(env[1].ends_with(" -o StrictHostKeyChecking=yes -o UserKnownHostsFile=/tmp/hosts"));
+
+        assert!(credentials("", Some("t"), None).validate().is_err());
+        assert!(credentials("github.com", None, None).validate().is_err());
+        let mut password = credentials("github.com", None, None);
+        password.password = Some("p".to_string());
+        assert!(password.validate().is_err());
+        password.username = Some("u".to_string());
+        assert!(password.validate().is_ok());
+        assert_eq!(
+            password.curl_args(),
+            " -u \"$PKGER_SOURCE_USERNAME:$PKGER_SOURCE_PASSWORD\""
+        );
+    }
+}
@@ -1,18 +1,37 @@
 use crate::build::container::Context;
+use crate::container::ExecOpts;
 use crate::image::ImageState;
-use crate::recipe::{BuildTarget, Recipe};
+use crate::recipe::{BuildTarget, PackageManager, PatchMethod, Preset, Recipe};
+use crate::{Error, Result};
 
 use std::collections::HashSet;
+use std::fs;
+use tracing::{info_span, trace, Instrument};
+
+/// Attempts of installing dependencies, failures other than missing packages are usually caused
+/// by unreachable mirrors
+const INSTALL_ATTEMPTS: u32 = 3;
+/// Prefix of lines printed by the install script for every package missing from the repositories
+static MISSING_PACKAGE_MARKER: &str = "pkger-missing-package:";
+static INSTALL_LOG: &str = "/tmp/pkger-deps.log";
+/// Prefix of lines printed by the resolve script for every dependency that can't be installed
+static UNRESOLVABLE_MARKER: &str = "pkger-unresolvable:";
 
 pub fn recipe_deps<'ctx>(ctx: &Context<'ctx>, state: &ImageState) -> HashSet<&'ctx str> {
     if let Some(deps) = &ctx.build_ctx.recipe.metadata.build_depends {
-        deps.resolve_names(&state.image)
+        deps.resolve_names(&state.image, ctx.build_ctx.arch())
     } else {
         HashSet::new()
     }
 }
 
-pub fn pkger_deps(target: &BuildTarget, recipe: &Recipe) -> HashSet<&'static str> {
+/// Returns dependencies pkger needs to build `target`, `source_package` adds the ones needed to
+/// also build a source package
+pub fn pkger_deps(
+    target: &BuildTarget,
+    recipe: &Recipe,
+    source_package: bool,
+) -> HashSet<&'static str> {
     let mut deps = HashSet::new();
     deps.insert("tar");
     match target {
@@ -22,6 +41,14 @@ pub fn pkger_deps(target: &BuildTarget, recipe: &Recipe) -> HashSet<&'static str
         }
         BuildTarget::Deb => {
             deps.insert("dpkg");
+            if source_package {
+                deps.insert("dpkg-dev");
+            }
+            if recipe.uses_native_packaging(target) {
+                deps.insert("dpkg-dev");
+                deps.insert("debhelper");
+                deps.insert("fakeroot");
+            }
         }
         BuildTarget::Gzip => {
             deps.insert("gzip");
@@ -29,10 +56,20 @@ pub fn pkger_deps(target: &BuildTarget, recipe: &Recipe) -> HashSet<&'static str
         BuildTarget::Pkg => {
             deps.insert("base-devel");
         }
+        BuildTarget::Apk => {
+            deps.insert("alpine-sdk");
+        }
     }
     if recipe.metadata.git.is_some() {
         deps.insert("git");
-    } else if let Some(src) = &recipe.metadata.source {
+    }
+    let sources = recipe
+        .metadata
+        .source
+        .iter()
+        .filter(|_| recipe.metadata.git.is_none())
+        .chain(&recipe.metadata.extra_sources);
+    for src in sources {
         if src.starts_with("http") {
             deps.insert("curl");
         }
@@ -41,9 +78,251 @@ pub fn pkger_deps(target: &BuildTarget, recipe: &Recipe) -> HashSet<&'static str
         }
     }
 
-    if recipe.metadata.patches.is_some() {
-        deps.insert("patch");
+    if recipe.metadata.signature.is_some() {
+        deps.insert("gnupg");
+        if recipe.metadata.git.is_none() {
+            deps.insert("curl");
+        }
+    }
+
+    if let Some(patches) = &recipe.metadata.patches {
+        if patches.uses_method(PatchMethod::Patch) {
+            deps.insert("patch");
+        }
+        if patches.uses_method(PatchMethod::Git) {
+            deps.insert("git");
+        }
+    }
+
+    if recipe.metadata.rpath.is_some() {
+        deps.insert("patchelf");
+    }
+    if recipe.metadata.symbols.is_some() {
+        deps.insert("binutils");
+    }
+    if recipe.metadata.preset == Some(Preset::Static) {
+        deps.insert("file");
     }
 
     deps
 }
+
+/// Returns a shell script updating the repositories and installing `deps` in one transaction with
+/// `pkg_mngr` invoked as `cmd`, versions of `deps` are converted to the syntax of the package manager. Failed installs are retried unless a package is missing from the
+/// repositories, in which case the script prints the missing packages and fails right away.
+pub fn install_script(pkg_mngr: &PackageManager, cmd: &str, deps: &[&str]) -> String {
+    let update = format!("{} {}", cmd, pkg_mngr.update_repos_args().join(" "));
+    let mut install_args = pkg_mngr.install_args();
+    install_args.extend(pkg_mngr.install_resilience_args());
+    let deps = deps
+        .iter()
+        .map(|dep| pkg_mngr.install_spec(dep))
+        .collect::<Vec<_>>();
+    let install = format!("{} {} {}", cmd, install_args.join(" "), deps.join(" "));
+
+    let patterns = pkg_mngr.missing_package_patterns();
+    let find_missing = if patterns.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "missing=$(sed -n {} {}); if [ -n \"$missing\" ]; then for package in $missing; do echo \"{} $package\"; done; exit 1; fi; ",
+            patterns
+                .iter()
+                .map(|pattern| format!("-e 's/{}/\\1/p'", pattern))
+                .collect::<Vec<_>>()
+                .join(" "),
+            INSTALL_LOG,
+            MISSING_PACKAGE_MARKER
+        )
+    };
+
+    format!(
+        "for attempt in {0}; do {{ {1} && {2}; }} >{3} 2>&1 && exit 0; {4}echo \"installing dependencies failed, attempt $attempt of {5}:\"; tail -n 20 {3}; [ $attempt -lt {5} ] && sleep $((attempt * 5)); done; exit 1",
+        (1..=INSTALL_ATTEMPTS)
+            .map(|attempt| attempt.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+        update,
+        install,
+        INSTALL_LOG,
+        find_missing,
+        INSTALL_ATTEMPTS,
+    )
+}
+
+/// Returns the packages reported as missing by the script from `install_script` in `output`
+pub fn missing_packages(output: &str) -> Vec<String> {
+    marked_lines(output, MISSING_PACKAGE_MARKER)
+}
+
+fn marked_lines(output: &str, marker: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(marker))
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Returns a shell script checking with `pkg_mngr` whether each of `deps` can be installed from
+/// the repositories. A dependency with alternatives like `foo | bar` is resolvable if any of them
+/// is, every unresolvable dependency is printed on a marked line.
+pub fn resolve_script(pkg_mngr: &PackageManager, deps: &[&str]) -> String {
+    let query = format!(
+        "{} {}",
+        pkg_mngr.as_ref(),
+        pkg_mngr.resolve_args().join(" ")
+    );
+    let checks = deps
+        .iter()
+        .map(|dep| {
+            let alternatives = dep
+                .split('|')
+                .map(|alternative| format!("{} {}", query, pkg_mngr.install_spec(alternative)))
+                .collect::<Vec<_>>()
+                .join(" || ");
+            format!(
+                "{{ {}; }} >/dev/null 2>&1 || echo '{} {}'; ",
+                alternatives,
+                UNRESOLVABLE_MARKER,
+                dep.replace('\'', "")
+            )
+        })
+        .collect::<String>();
+    // the script fails only if the shell can't run it
+    format!("{}true", checks)
+}
+
+/// Whether a dependency named `name` is a package built by pkger, the recipe itself, a package
+/// in the output directory of the image or one of the fetched artifacts
+fn is_built_locally(ctx: &Context<'_>, name: &str) -> bool {
+    let build_ctx = ctx.build_ctx;
+    let is_package_of = |file_name: &str| {
+        file_name.starts_with(&format!("{}-", name)) || file_name.starts_with(&format!("{}_", name))
+    };
+    if build_ctx.recipe.metadata.name == name
+        || build_ctx
+            .recipe
+            .metadata
+            .fetch_artifacts
+            .iter()
+            .any(|artifact| is_package_of(artifact.file_name()))
+    {
+        return true;
+    }
+    fs::read_dir(build_ctx.out_dir.join(build_ctx.target.image()))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .any(|entry| is_package_of(&entry.file_name().to_string_lossy()))
+        })
+        .unwrap_or_default()
+}
+
+/// Returns runtime dependencies of the recipe that can't be installed from the repositories of
+/// the image and aren't built locally. Substitution variables and rich dependencies of RPM are
+/// not checked.
+pub async fn unresolvable_deps(ctx: &Context<'_>, state: &ImageState) -> Result<Vec<String>> {
+    let build_ctx = ctx.build_ctx;
+    let depends = match &build_ctx.recipe.metadata.depends {
+        Some(depends) => depends,
+        None => return Ok(vec![]),
+    };
+    let span = info_span!("resolve-deps");
+    async move {
+        let mut deps = depends
+            .resolve_names(&state.image, build_ctx.arch())
+            .into_iter()
+            .filter(|dep| !dep.contains('$') && !dep.trim_start().starts_with('('))
+            .filter(|dep| {
+                !dep.split('|').any(|alternative| {
+                    let name = alternative
+                        .trim()
+                        .split(|c: char| c.is_whitespace() || c == '(' || c == ':')
+                        .next()
+                        .unwrap_or_default();
+                    is_built_locally(ctx, name)
+                })
+            })
+            .collect::<Vec<_>>();
+        if deps.is_empty() {
+            return Ok(vec![]);
+        }
+        deps.sort_unstable();
+
+        let pkg_mngr = state.os.package_manager();
+        let script = resolve_script(&pkg_mngr, &deps);
+        trace!(script = %script);
+        let out = ctx
+            .container
+            .exec(&ExecOpts::default().cmd(&script).build())
+            .await?;
+        if out.exit_code != 0 {
+            return Err(Error::msg(format!(
+                "resolving dependencies exited with code {}",
+                out.exit_code
+            )));
+        }
+        Ok(marked_lines(&out.stdout.join(""), UNRESOLVABLE_MARKER))
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_install_script() {
+        let script = install_script(&PackageManager::Apt, "apt-get", &["curl", "gcc"]);
+        assert!(script.starts_with("for attempt in 1 2 3; do { apt-get update -y && apt-get install -y -o Acquire::Retries=3 curl gcc; } >/tmp/pkger-deps.log 2>&1 && exit 0; missing=$(sed -n -e 's/^E: Unable to locate package \\(.*\\)$/\\1/p'"));
+
+        let script = install_script(
+            &PackageManager::Apk,
+            "apk",
+            &["curl (>= 7.0)", "gcc", "libfoo:any"],
+        );
+        assert!(script.contains("{ apk update && apk add 'curl>=7.0' gcc libfoo; }"));
+        let script = install_script(&PackageManager::Pacman, "pacman", &["gcc (= 11.1.0)"]);
+        assert!(
+            script.contains("{ pacman -Sy --noconfirm && pacman -S --noconfirm 'gcc=11.1.0'; }")
+        );
+        assert_eq!(
+            PackageManager::Dnf.install_spec("gcc (<< 12)"),
+            "'gcc < 12'"
+        );
+        assert_eq!(
+            PackageManager::Apt.install_spec("gcc:amd64 (>= 10)"),
+            "gcc:amd64"
+        );
+        assert_eq!(
+            PackageManager::Apt.install_spec("gcc (= 10.2-1)"),
+            "gcc=10.2-1"
+        );
+
+        assert_eq!(
+            missing_packages(
+                "Step 3/3\npkger-missing-package: libfoo-dev\npkger-missing-package: bar\n"
+            ),
+            vec!["libfoo-dev", "bar"]
+        );
+        assert!(missing_packages("E: Unable to locate package foo").is_empty());
+    }
+
+    #[test]
+    fn creates_resolve_script() {
+        let script = resolve_script(&PackageManager::Apt, &["libfoo | libbar (>= 1.0)", "curl"]);
+        assert_eq!(
+            script,
+            "{ apt-get install -s -qq libfoo || apt-get install -s -qq libbar; } >/dev/null 2>&1 || echo 'pkger-unresolvable: libfoo | libbar (>= 1.0)'; { apt-get install -s -qq curl; } >/dev/null 2>&1 || echo 'pkger-unresolvable: curl'; true"
+        );
+        assert_eq!(
+            marked_lines(
+                "pkger-unresolvable: libfoo | libbar (>= 1.0)\n",
+                UNRESOLVABLE_MARKER
+            ),
+            vec!["libfoo | libbar (>= 1.0)"]
+        );
+    }
+}
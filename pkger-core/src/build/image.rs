@@ -1,14 +1,16 @@
 use crate::build::{container, deps, Context};
 use crate::docker::{
     api::{BuildOpts, ImageBuildChunk},
-    Docker,
+    Docker, Runtime,
 };
 use crate::image::{ImageState, ImagesState};
-use crate::recipe::RecipeTarget;
+use crate::labels;
+use crate::recipe::{RecipeTarget, RepoSnapshot};
 use crate::{Error, Result};
 
 use futures::StreamExt;
 use std::collections::HashSet;
+use std::fmt;
 use std::fs;
 use std::path::Path;
 use std::sync::{Arc, RwLock};
@@ -19,17 +21,53 @@ use tracing::{debug, info, info_span, trace, warn, Instrument};
 pub static CACHED: &str = "cached";
 pub static LATEST: &str = "latest";
 
+#[derive(Debug)]
+/// Docker reported an error while building an image
+pub struct ImageBuildError {
+    pub image: String,
+    pub message: String,
+}
+
+impl fmt::Display for ImageBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "building image `{}` failed: {}",
+            self.image,
+            self.message.trim()
+        )
+    }
+}
+
+impl std::error::Error for ImageBuildError {}
+
 pub async fn build(ctx: &mut Context) -> Result<ImageState> {
     let span = info_span!("image-build");
     let cloned_span = span.clone();
 
     async move {
+        ctx.progress.enter("image");
         let mut deps = if let Some(deps) = &ctx.recipe.metadata.build_depends {
-            deps.resolve_names(&ctx.target.image())
+            deps.resolve_names(
+                ctx.target.image(),
+                ctx.recipe
+                    .metadata
+                    .arch_for(ctx.target.image(), *ctx.target.build_target()),
+            )
         } else {
             Default::default()
         };
-        deps.extend(deps::pkger_deps(ctx.target.build_target(), &ctx.recipe));
+        if !ctx
+            .recipe
+            .metadata
+            .skip_default_deps_for(ctx.target.image(), *ctx.target.build_target())
+        {
+            deps.extend(deps::pkger_deps(
+                ctx.target.build_target(),
+                &ctx.recipe,
+                ctx.source_package,
+            ));
+        }
         trace!(resolved_deps = ?deps);
 
         let result = cloned_span.in_scope(|| {
@@ -42,8 +80,15 @@ pub async fn build(ctx: &mut Context) -> Result<ImageState> {
                 .iter()
                 .map(|s| s.as_str())
                 .collect::<HashSet<_>>();
+            let snapshot = ctx
+                .recipe
+                .metadata
+                .snapshot_for(ctx.target.image(), *ctx.target.build_target())
+                .map(RepoSnapshot::id);
             if deps != state_deps {
                 info!(old = ?state.deps, new = ?deps, "dependencies changed");
+            } else if state.tag == CACHED && snapshot != state.snapshot {
+                info!(old = ?state.snapshot, new = ?snapshot, "repository snapshot changed");
             } else {
                 trace!("unchanged");
 
@@ -58,11 +103,15 @@ pub async fn build(ctx: &mut Context) -> Result<ImageState> {
 
         debug!(image = %ctx.target.image(), "building from scratch");
         let images = ctx.docker.images();
+        let tag = format!("{}:{}", &ctx.target.image(), LATEST);
+        let labels = labels::for_build(ctx);
         let opts = BuildOpts::builder(&ctx.image.path)
-            .tag(&format!("{}:{}", &ctx.target.image(), LATEST))
+            .tag(&tag)
+            .labels(labels::as_opts(&labels))
             .build();
 
         let mut stream = images.build(&opts);
+        let mut id = None;
 
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
@@ -71,34 +120,41 @@ pub async fn build(ctx: &mut Context) -> Result<ImageState> {
                     error,
                     error_detail: _,
                 } => {
-                    return Err(Error::msg(error));
+                    return Err(ImageBuildError {
+                        image: ctx.target.image().to_string(),
+                        message: error,
+                    }
+                    .into());
                 }
                 ImageBuildChunk::Update { stream } => {
                     info!("{}", stream);
                 }
                 ImageBuildChunk::Digest { aux } => {
-                    let state = ImageState::new(
-                        &aux.id,
-                        &ctx.target,
-                        LATEST,
-                        &SystemTime::now(),
-                        &ctx.docker,
-                        &Default::default(),
-                        ctx.simple,
-                    )
-                    .await?;
-
-                    if let Ok(mut image_state) = ctx.image_state.write() {
-                        (*image_state).update(&ctx.target, &state)
-                    }
-
-                    return Ok(state);
+                    id = Some(aux.id);
+                    break;
                 }
                 _ => {}
             }
         }
 
-        Err(Error::msg("stream ended before image id was received"))
+        let id = built_image_id(ctx.runtime, &ctx.docker, &tag, id).await?;
+        let state = ImageState::new(
+            &id,
+            &ctx.target,
+            LATEST,
+            &SystemTime::now(),
+            &ctx.docker,
+            &ctx.image_state,
+            &Default::default(),
+            ctx.simple,
+        )
+        .await?;
+
+        if let Ok(mut image_state) = ctx.image_state.write() {
+            (*image_state).update(&ctx.target, &state)
+        }
+
+        Ok(state)
     }
     .instrument(span)
     .await
@@ -118,22 +174,48 @@ pub async fn cache_image(
 
         if pkg_mngr_name.is_empty() {
             return Err(Error::msg(format!(
-                "caching image failed - no package manger found for os `{}`",
+                "no package manager found for os `{}`",
                 state.os.name()
             )));
         }
 
-        let deps_joined = deps.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let names = &ctx.build_ctx.recipe.metadata.dependency_names;
+        let distro = state.os.distribution();
+        let mut deps_resolved = deps
+            .iter()
+            .filter_map(|dep| names.resolve(dep, distro))
+            .collect::<Vec<_>>();
+        // sorted so that the Dockerfile and the layers cached by Docker stay the same
+        deps_resolved.sort_unstable();
+        deps_resolved.dedup();
+        let deps_sorted = deps_resolved
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&str>>();
 
-        #[rustfmt::skip]
-            let dockerfile = format!(
-r#"FROM {}
-RUN {} {}
-RUN {} {} {} >/dev/null"#,
-                tag,
-                pkg_mngr_name, pkg_mngr.update_repos_args().join(" "),
-                pkg_mngr_name, pkg_mngr.install_args().join(" "), deps_joined.join(" ")
-            );
+        let snapshot = ctx.build_ctx.recipe.metadata.snapshot_for(
+            ctx.build_ctx.target.image(),
+            *ctx.build_ctx.target.build_target(),
+        );
+        let mut setup = String::new();
+        let mut pkg_mngr_name = pkg_mngr_name.to_string();
+        if let Some(snapshot) = snapshot {
+            info!(snapshot = %snapshot.id(), "installing dependencies from repository snapshot");
+            if let Some(cmd) = snapshot.setup_command(&pkg_mngr)? {
+                setup = format!("\nRUN {}", cmd);
+            }
+            for arg in snapshot.package_manager_args(&pkg_mngr) {
+                pkg_mngr_name.push(' ');
+                pkg_mngr_name.push_str(&arg);
+            }
+        }
+
+        let dockerfile = format!(
+            "FROM {}{}\nRUN {}",
+            tag,
+            setup,
+            deps::install_script(&pkg_mngr, &pkg_mngr_name, &deps_sorted)
+        );
 
         trace!(dockerfile = %dockerfile);
 
@@ -151,9 +233,15 @@ RUN {} {} {} >/dev/null"#,
         fs::write(temp_path.join("Dockerfile"), dockerfile)?;
 
         let images = docker.images();
-        let opts = BuildOpts::builder(&temp_path).tag(tag).build();
+        let labels = labels::for_build(ctx.build_ctx);
+        let opts = BuildOpts::builder(temp_path)
+            .tag(&tag)
+            .labels(labels::as_opts(&labels))
+            .build();
 
         let mut stream = images.build(&opts);
+        let mut id = None;
+        let mut missing = vec![];
 
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
@@ -162,33 +250,80 @@ RUN {} {} {} >/dev/null"#,
                     error,
                     error_detail: _,
                 } => {
-                    return Err(Error::msg(error));
+                    if !missing.is_empty() {
+                        return Err(Error::msg(format!(
+                            "packages not found in the repositories of `{}`: {}",
+                            state.image,
+                            missing.join(", ")
+                        )));
+                    }
+                    return Err(ImageBuildError {
+                        image: tag,
+                        message: error,
+                    }
+                    .into());
                 }
                 ImageBuildChunk::Update { stream } => {
+                    missing.extend(deps::missing_packages(&stream));
                     info!("{}", stream);
                 }
                 ImageBuildChunk::Digest { aux } => {
-                    return ImageState::new(
-                        &aux.id,
-                        &ctx.build_ctx.target,
-                        CACHED,
-                        &SystemTime::now(),
-                        &docker,
-                        deps,
-                        ctx.build_ctx.simple,
-                    )
-                    .await
+                    id = Some(aux.id);
+                    break;
                 }
                 _ => {}
             }
         }
 
-        Err(Error::msg("id of image not received"))
+        let id = built_image_id(ctx.build_ctx.runtime, docker, &tag, id).await?;
+        // installing dependencies doesn't change the os so the new image isn't probed
+        if let Ok(mut images) = ctx.build_ctx.image_state.write() {
+            images.cache_os(&id, &state.os);
+        }
+        ImageState::new(
+            &id,
+            &ctx.build_ctx.target,
+            CACHED,
+            &SystemTime::now(),
+            docker,
+            &ctx.build_ctx.image_state,
+            deps,
+            ctx.build_ctx.simple,
+        )
+        .await
+        .map(|mut state| {
+            state.snapshot = snapshot.map(RepoSnapshot::id);
+            state
+        })
     }
     .instrument(span)
     .await
 }
 
+/// Returns the id of an image built with `tag`. Podman doesn't report the id while building
+/// through the Docker API so there it is looked up by the tag.
+async fn built_image_id(
+    runtime: Runtime,
+    docker: &Docker,
+    tag: &str,
+    reported: Option<String>,
+) -> Result<String> {
+    match reported {
+        Some(id) => Ok(id),
+        None if !runtime.reports_built_image_id() => {
+            trace!(tag = %tag, "looking up id of built image");
+            docker
+                .images()
+                .get(tag)
+                .inspect()
+                .await
+                .map(|details| details.id)
+                .map_err(|e| Error::msg(format!("failed to find built image `{}` - {}", tag, e)))
+        }
+        None => Err(Error::msg(format!("id of image `{}` not received", tag))),
+    }
+}
+
 /// Checks whether any of the files located at the path of this Image changed since last build.
 /// If shouldn't be rebuilt returns previous `ImageState`.
 pub fn find_cached_state(
@@ -0,0 +1,62 @@
+//! Checks that ELF files in the build output of the `static` preset are statically linked, so
+//! that the package doesn't depend on the C library of the image it was built on.
+use crate::build::container::{checked_exec, Context};
+use crate::container::ExecOpts;
+use crate::{Error, Result};
+
+use tracing::{info_span, trace, Instrument};
+
+/// Prints the path and the output of `file` for every ELF file separated by a tab
+static SCAN_COMMAND: &str = r#"find . -type f | while IFS= read -r f; do t=$(file -b "$f"); case "$t" in ELF*) printf '%s\t%s\n' "$f" "$t";; esac; done"#;
+
+/// Returns paths of dynamically linked files from the output of the scan
+fn dynamically_linked(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, kind)| kind.contains("dynamically linked"))
+        .map(|(file, _)| file.trim_start_matches('.'))
+        .collect()
+}
+
+pub async fn check_static(ctx: &Context<'_>) -> Result<()> {
+    let span = info_span!("check-static-linkage");
+    async move {
+        let output = checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(SCAN_COMMAND)
+                .working_dir(&ctx.build_ctx.container_out_dir),
+        )
+        .await?
+        .stdout
+        .join("");
+        trace!(output = %output);
+
+        let dynamic = dynamically_linked(&output);
+        if dynamic.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "files built with the `static` preset are dynamically linked: {}",
+                dynamic.join(", ")
+            )))
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_dynamically_linked_files() {
+        let output = "./usr/bin/foo\tELF 64-bit LSB executable, x86-64, version 1 (SYSV), statically linked, stripped
+./usr/bin/bar\tELF 64-bit LSB pie executable, x86-64, version 1 (SYSV), dynamically linked, interpreter /lib/ld-musl-x86_64.so.1, stripped
+./usr/bin/baz\tELF 64-bit LSB pie executable, x86-64, version 1 (SYSV), static-pie linked, stripped
+";
+        assert_eq!(dynamically_linked(output), vec!["/usr/bin/bar"]);
+    }
+}
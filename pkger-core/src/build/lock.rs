@@ -0,0 +1,196 @@
+//! Pins of built recipes. Every successful build records a hash of the recipe directory and a
+//! digest of the fetched source under the version of the recipe, so that release processes can
+//! refuse to build anything that drifted since the pins were made.
+use crate::build::cache::hash_dir;
+use crate::build::container::{checked_exec, Context};
+use crate::container::ExecOpts;
+use crate::recipe::Recipe;
+use crate::{ErrContext, Error, Result};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, trace, Instrument};
+
+/// Default name of the lockfile
+pub static LOCKFILE: &str = "pkger.lock";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RecipePin {
+    /// Hash of all files in the recipe directory
+    pub recipe: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Digest of the source, `sha256:<hex>` of a downloaded or local source or `git:<commit>` of a
+    /// git repository
+    pub source: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Lockfile {
+    #[serde(default)]
+    /// Pins by the name of the recipe and its version
+    pub recipes: BTreeMap<String, BTreeMap<String, RecipePin>>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Lockfile {
+    /// Loads the lockfile at `path` returning an empty one if it doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut lockfile = if path.exists() {
+            let contents = fs::read(path)?;
+            serde_yaml::from_slice::<Lockfile>(&contents)
+                .context(format!("failed to parse lockfile `{}`", path.display()))?
+        } else {
+            Lockfile::default()
+        };
+        lockfile.path = path.to_path_buf();
+        Ok(lockfile)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents = serde_yaml::to_string(&self).context("failed to serialize lockfile")?;
+        fs::write(&self.path, contents)
+            .context(format!("failed to save lockfile `{}`", self.path.display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, recipe: &str, version: &str) -> Option<&RecipePin> {
+        self.recipes.get(recipe).and_then(|pins| pins.get(version))
+    }
+
+    /// Pins the version of `recipe` keeping the previously pinned source if `pin` has none
+    pub fn pin(&mut self, recipe: &str, version: &str, pin: RecipePin) {
+        let pins = self.recipes.entry(recipe.to_string()).or_default();
+        let source = pin
+            .source
+            .or_else(|| pins.get(version).and_then(|old| old.source.clone()));
+        pins.insert(version.to_string(), RecipePin { source, ..pin });
+    }
+
+    /// Returns an error describing why `recipe` doesn't match its pin
+    pub fn verify(&self, recipe: &Recipe) -> Result<()> {
+        let metadata = &recipe.metadata;
+        let pin = self.get(&metadata.name, &metadata.version).ok_or_else(|| {
+            Error::msg(format!(
+                "recipe `{}` version `{}` is not pinned in the lockfile",
+                metadata.name, metadata.version
+            ))
+        })?;
+        let hash = recipe_hash(&recipe.recipe_dir)?;
+        if hash != pin.recipe {
+            return Err(Error::msg(format!(
+                "recipe `{}` version `{}` changed since it was pinned, expected hash `{}` found `{}`",
+                metadata.name, metadata.version, pin.recipe, hash
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hash of all files in the recipe directory
+pub fn recipe_hash(recipe_dir: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_dir(&mut hasher, recipe_dir, recipe_dir).context(format!(
+        "failed to hash recipe directory `{}`",
+        recipe_dir.display()
+    ))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the digest from the output of `sha256sum`, files are joined in a stable order
+fn parse_checksums(output: &str) -> Option<String> {
+    let mut sums = output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect::<Vec<_>>();
+    if sums.is_empty() {
+        return None;
+    }
+    sums.sort_unstable();
+    Some(format!("sha256:{}", sums.join(",")))
+}
+
+/// Returns the digest of the fetched source of the build or `None` if the recipe has no source
+pub async fn source_digest(ctx: &Context<'_>) -> Result<Option<String>> {
+    let span = info_span!("source-digest");
+    async move {
+        let metadata = &ctx.build_ctx.recipe.metadata;
+        let digest = if metadata.git.is_some() {
+            let output = checked_exec(
+                ctx,
+                &ExecOpts::default()
+                    .cmd("git rev-parse HEAD")
+                    .working_dir(&ctx.build_ctx.container_bld_dir),
+            )
+            .await?
+            .stdout
+            .join("");
+            Some(format!("git:{}", output.trim()))
+        } else if metadata.source.is_some() {
+            let output = checked_exec(
+                ctx,
+                &ExecOpts::default()
+                    .cmd("sha256sum *")
+                    .working_dir(&ctx.build_ctx.container_tmp_dir)
+                    .shell("/bin/bash"),
+            )
+            .await?
+            .stdout
+            .join("");
+            parse_checksums(&output)
+        } else {
+            None
+        };
+        trace!(digest = ?digest);
+        Ok(digest)
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_recipes() {
+        assert_eq!(
+            parse_checksums("bbb  foo-1.0.tar.gz\naaa  foo.patch\n"),
+            Some("sha256:aaa,bbb".to_string())
+        );
+        assert_eq!(parse_checksums(""), None);
+
+        let mut lockfile = Lockfile::default();
+        lockfile.pin(
+            "foo",
+            "1.0",
+            RecipePin {
+                recipe: "1".to_string(),
+                source: Some("sha256:aaa".to_string()),
+            },
+        );
+        lockfile.pin(
+            "foo",
+            "1.0",
+            RecipePin {
+                recipe: "2".to_string(),
+                source: None,
+            },
+        );
+        assert_eq!(
+            lockfile.get("foo", "1.0"),
+            Some(&RecipePin {
+                recipe: "2".to_string(),
+                source: Some("sha256:aaa".to_string()),
+            })
+        );
+        assert!(lockfile.get("foo", "1.1").is_none());
+    }
+}
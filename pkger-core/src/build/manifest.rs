@@ -0,0 +1,112 @@
+//! Metadata describing a created artifact. It is saved next to the package so that tools consuming
+//! the output don't have to know anything about the recipe that produced it.
+use crate::build::container::Context;
+use crate::build::snapshot::EnvironmentSnapshot;
+use crate::image::ImageState;
+use crate::recipe::BuildTarget;
+use crate::{ErrContext, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, trace};
+
+pub static MANIFEST_EXTENSION: &str = "json";
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ArtifactManifest {
+    /// Id of the build that created the artifact
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub release: String,
+    /// Architecture named the way the target package format expects it
+    pub arch: String,
+    pub target: String,
+    pub image: String,
+    #[serde(default)]
+    /// Id of the Docker image the artifact was built on
+    pub image_id: String,
+    pub os: String,
+    pub os_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// First paragraph of the documentation of the recipe describing what the package does
+    pub summary: Option<String>,
+    /// Path to the artifact relative to the output directory
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Path to the detached signature of the artifact relative to the output directory
+    pub signature: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Environment variables, tool versions and installed packages before each script phase
+    pub environment: Vec<EnvironmentSnapshot>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    /// Ids under which publishers distribute the artifact, like an IPFS CID or the info hash of
+    /// a torrent, by the name of the publisher
+    pub content_ids: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Snapshot of the package repositories the dependencies of the image were installed from
+    pub repo_snapshot: Option<String>,
+}
+
+impl ArtifactManifest {
+    pub fn new(ctx: &Context<'_>, image_state: &ImageState, package: &Path) -> Self {
+        let metadata = &ctx.build_ctx.recipe.metadata;
+        let target = ctx.build_ctx.target.build_target();
+        let arch = match target {
+            BuildTarget::Deb => ctx.build_ctx.arch().deb_name(),
+            BuildTarget::Pkg => ctx.build_ctx.arch().pkg_name(),
+            BuildTarget::Apk => ctx.build_ctx.arch().apk_name(),
+            BuildTarget::Rpm | BuildTarget::Gzip => ctx.build_ctx.arch().rpm_name(),
+        };
+
+        Self {
+            id: ctx.build_ctx.id.clone(),
+            name: metadata.name.clone(),
+            version: metadata.version.clone(),
+            release: ctx.build_ctx.release(),
+            arch: arch.to_string(),
+            target: target.as_ref().to_string(),
+            image: image_state.image.clone(),
+            image_id: image_state.id.clone(),
+            os: image_state.os.name().to_string(),
+            os_version: image_state.os.version().to_string(),
+            summary: ctx.build_ctx.recipe.summary(),
+            path: package
+                .strip_prefix(&ctx.build_ctx.out_dir)
+                .unwrap_or(package)
+                .to_path_buf(),
+            signature: None,
+            environment: ctx.build_ctx.snapshots(),
+            content_ids: BTreeMap::new(),
+            repo_snapshot: image_state.snapshot.clone(),
+        }
+    }
+
+    pub fn with_signature(mut self, signature: &Path) -> Self {
+        self.signature = Some(signature.to_path_buf());
+        self
+    }
+
+    /// Returns the location of the manifest file of the given package
+    pub fn path_for(package: &Path) -> PathBuf {
+        let mut filename = package.file_name().unwrap_or_default().to_os_string();
+        filename.push(".");
+        filename.push(MANIFEST_EXTENSION);
+        package.with_file_name(filename)
+    }
+
+    /// Saves this manifest next to the artifact located in `output_dir`
+    pub fn save(&self, output_dir: &Path) -> Result<PathBuf> {
+        let path = Self::path_for(&output_dir.join(&self.path));
+        let span = info_span!("save-manifest", path = %path.display());
+        let _enter = span.enter();
+
+        trace!(manifest = ?self);
+        let data = serde_json::to_vec_pretty(&self).context("failed to serialize manifest")?;
+        fs::write(&path, data)
+            .map(|_| path)
+            .context("failed to save manifest")
+    }
+}
@@ -1,28 +1,58 @@
+pub mod artifacts;
+pub mod cache;
+pub mod checkpoint;
 pub mod container;
+pub mod credentials;
 pub mod deps;
 pub mod image;
+pub mod linkage;
+pub mod lock;
+pub mod manifest;
 pub mod package;
+pub mod progress;
+pub mod publish;
 pub mod remote;
+pub mod rpath;
 pub mod scripts;
+pub mod sign;
+pub mod signature;
+pub mod snapshot;
+pub mod symbols;
+pub mod transcript;
 
-use crate::container::ExecOpts;
-use crate::docker::Docker;
+use crate::container::{ExecOpts, OutputLimit, StallDetection};
+use crate::docker::{Docker, Runtime};
+use crate::failure::FailureKind;
 use crate::image::{Image, ImageState, ImagesState};
-use crate::recipe::{ImageTarget, Patch, Patches, Recipe, RecipeTarget};
+use crate::labels;
+use crate::recipe::{
+    parse_size, BuildArch, DiskOptions, ImageTarget, PackageManager, Patch, Patches, Preset,
+    Recipe, RecipeTarget, SecurityOptions,
+};
+use crate::units::HumanSize;
 use crate::{ErrContext, Error, Result};
+use cache::ArtifactCache;
+use checkpoint::Phase;
+use credentials::SourceCredentials;
+use lock::{Lockfile, RecipePin};
+use progress::Progress;
+use publish::ArtifactPublisher;
+use sign::SigningConfig;
+use snapshot::EnvironmentSnapshot;
+use transcript::Transcript;
 
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 use tracing::{debug, info, info_span, trace, warn, Instrument};
 
 macro_rules! cleanup {
     ($ctx:ident) => {
         if !$ctx.container.is_running().await? {
-            return Err(Error::msg("job interrupted by ctrl-c signal"));
+            return Err(Error::msg("job interrupted").context(FailureKind::Runtime));
         }
     };
 }
@@ -34,6 +64,8 @@ pub struct Context {
     recipe: Arc<Recipe>,
     image: Image,
     docker: Docker,
+    /// Container engine behind `docker`
+    runtime: Runtime,
     container_bld_dir: PathBuf,
     container_out_dir: PathBuf,
     container_tmp_dir: PathBuf,
@@ -42,85 +74,559 @@ pub struct Context {
     image_state: Arc<RwLock<ImagesState>>,
     is_running: Arc<AtomicBool>,
     simple: bool,
+    /// Output of a build stage that should be packaged instead of running the scripts
+    prebuilt_output: Option<Arc<Vec<u8>>>,
+    /// State of the image built ahead of the build with `prepare_image`
+    prepared_image: Option<ImageState>,
+    /// Directory where the transcript of this build is saved and the transcript itself
+    transcript: Option<(PathBuf, Mutex<Transcript>)>,
+    /// Key used to sign the built package
+    signing: Option<SigningConfig>,
+    /// Credentials of private sources
+    credentials: Arc<Vec<SourceCredentials>>,
+    /// Restrictions of the build container that recipes can't relax
+    security: SecurityOptions,
+    /// Disk limits of the build that recipes can only lower
+    disk: DiskOptions,
+    /// Detection of commands that stall
+    stall_detection: Option<StallDetection>,
+    /// Limits of the output of commands kept in memory and printed
+    output_limit: Option<OutputLimit>,
+    /// Environment of the container before each script phase
+    snapshots: Mutex<Vec<EnvironmentSnapshot>>,
+    /// Cache from which finished artifacts are pulled instead of building them
+    cache: Option<Arc<dyn ArtifactCache>>,
+    /// Whether built artifacts are pushed to the cache
+    cache_push: bool,
+    /// Publishers distributing finished artifacts
+    publishers: Vec<Arc<dyn ArtifactPublisher>>,
+    /// Whether steps of the recipe may run with extended privileges
+    allow_privileged: bool,
+    /// Lockfile in which the build is pinned and whether the build must match its pin instead
+    lockfile: Option<(Arc<Mutex<Lockfile>>, bool)>,
+    /// Digest of the source fetched by this build
+    source_digest: Mutex<Option<String>>,
+    /// Phase the build is currently in
+    progress: Progress,
+    /// Whether the container is committed to an image after each phase of the build
+    checkpoints: bool,
+    /// Whether a source package is built next to the binary package
+    source_package: bool,
 }
 
 pub async fn run(ctx: &mut Context) -> Result<PathBuf> {
     let span = info_span!("build", recipe = %ctx.recipe.metadata.name, image = %ctx.target.image(), target = %ctx.target.build_target().as_ref());
-    async move {
+    let result = async {
         info!(id = %ctx.id, "running job" );
-        let image_state = image::build(ctx).await.context("failed to build image")?;
+        ctx.check_free_space().context(FailureKind::Runtime)?;
+
+        let image_state = match ctx.prepared_image.take() {
+            Some(state) => state,
+            None => image::build(ctx)
+                .await
+                .context("failed to build image")
+                .context(FailureKind::Runtime)?,
+        };
+        let ctx = &*ctx;
+
+        let out_dir = ctx
+            .create_out_dir(&image_state.image)
+            .await
+            .context(FailureKind::Runtime)?;
 
-        let out_dir = ctx.create_out_dir(&image_state).await?;
+        let (mut container_ctx, image_state) = init_container(ctx, image_state).await?;
 
-        let mut container_ctx = container::spawn(&ctx, &image_state).await?;
+        let mut cache_key = None;
+        if let Some(output) = &ctx.prebuilt_output {
+            // artifacts packaged from the output of a build stage depend on more than the recipe so
+            // they are not cached
+            ctx.enter_phase("unpack output of the build stage");
+            unpack_prebuilt_output(&container_ctx, output)
+                .await
+                .context(FailureKind::Runtime)?;
+        } else {
+            let resumed = resume(&mut container_ctx, &image_state).await?;
+            let source_digest = fetch_source(&container_ctx, &image_state, resumed).await?;
+
+            if let Some(cache) = &ctx.cache {
+                match cache::cache_key(ctx, &image_state, source_digest.as_deref()).await {
+                    Ok(None) => {}
+                    Ok(Some(key)) => {
+                        match cache::restore(cache, &key, &out_dir).await {
+                            Ok(Some(manifest)) => {
+                                let package = ctx.out_dir.join(&manifest.path);
+                                ctx.finish_artifact(manifest, &package).await?;
+                                container_ctx
+                                    .container
+                                    .remove()
+                                    .await
+                                    .context(FailureKind::Runtime)?;
+                                if ctx.checkpoints_enabled() {
+                                    checkpoint::remove(ctx, &image_state).await;
+                                }
+                                return Ok(package);
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!(reason = %e, "failed to restore artifact from cache"),
+                        }
+                        cache_key = Some(key);
+                    }
+                    Err(e) => warn!(reason = %e, "failed to compute cache key"),
+                }
+            }
+
+            build_output(&container_ctx, &image_state, resumed).await?;
+        }
 
         cleanup!(container_ctx);
 
-        let image_state = if image_state.tag != image::CACHED {
-            let mut deps = deps::pkger_deps(ctx.target.build_target(), &ctx.recipe);
-            deps.extend(deps::recipe_deps(&container_ctx, &image_state));
-            let new_state =
-                image::cache_image(&container_ctx, &ctx.docker, &image_state, &deps).await?;
-            info!(id = %new_state.id, image = %new_state.image, "successfully cached image");
+        ctx.enter_phase("package");
+        let package = package::create_package(&container_ctx, &image_state, out_dir.as_path())
+            .await
+            .context(FailureKind::Packaging)?;
 
-            if let Ok(mut state) = ctx.image_state.write() {
-                trace!("saving image state");
-                (*state).update(&ctx.target, &new_state)
+        let manifest = manifest::ArtifactManifest::new(&container_ctx, &image_state, &package);
+        if let (Some(cache), Some(key), true) = (&ctx.cache, &cache_key, ctx.cache_push) {
+            if let Err(e) = cache::store(cache, key, &package, &manifest).await {
+                warn!(reason = %e, "failed to store artifact in cache");
             }
+        }
+        ctx.finish_artifact(manifest, &package).await?;
+        ctx.pin();
+
+        container_ctx
+            .container
+            .remove()
+            .await
+            .context(FailureKind::Runtime)?;
+        if ctx.checkpoints_enabled() {
+            checkpoint::remove(ctx, &image_state).await;
+        }
+
+        Ok(package)
+    }
+    .instrument(span)
+    .await;
 
-            container_ctx.container.remove().await?;
-            container_ctx = container::spawn(&ctx, &new_state).await?;
+    ctx.save_transcript();
+    result
+}
 
-            new_state
-        } else {
-            image_state
+/// Runs only the first stage of a two-stage build. The configure, build and install scripts are
+/// executed once in the image of this context and the contents of `$PKGER_OUT_DIR` are returned as
+/// a tar archive ready to be packaged on other images.
+pub async fn run_build_stage(ctx: &mut Context) -> Result<Vec<u8>> {
+    let span =
+        info_span!("build-stage", recipe = %ctx.recipe.metadata.name, image = %ctx.target.image());
+    let result = async {
+        info!(id = %ctx.id, "running build stage");
+        let image_state = match ctx.prepared_image.take() {
+            Some(state) => state,
+            None => image::build(ctx)
+                .await
+                .context("failed to build image")
+                .context(FailureKind::Runtime)?,
         };
+        let ctx = &*ctx;
+
+        let (mut container_ctx, image_state) = init_container(ctx, image_state).await?;
+
+        let resumed = resume(&mut container_ctx, &image_state).await?;
+        fetch_source(&container_ctx, &image_state, resumed).await?;
+        build_output(&container_ctx, &image_state, resumed).await?;
 
         cleanup!(container_ctx);
 
-        let dirs = vec![
-            &ctx.container_out_dir,
-            &ctx.container_bld_dir,
-            &ctx.container_tmp_dir,
-        ];
+        let output = container_ctx
+            .container
+            .copy_from(&ctx.container_out_dir)
+            .await
+            .context("failed to copy build output")
+            .context(FailureKind::Runtime)?;
+        ctx.pin();
 
-        container::create_dirs(&container_ctx, &dirs[..]).await?;
+        container_ctx
+            .container
+            .remove()
+            .await
+            .context(FailureKind::Runtime)?;
+        if ctx.checkpoints_enabled() {
+            checkpoint::remove(ctx, &image_state).await;
+        }
 
-        cleanup!(container_ctx);
+        Ok(output)
+    }
+    .instrument(span)
+    .await;
 
-        remote::fetch_source(&container_ctx).await?;
+    ctx.save_transcript();
+    result
+}
 
-        cleanup!(container_ctx);
+/// Builds the image of this context ahead of running the build, so that the image can be built
+/// once for all builds sharing it.
+pub async fn prepare_image(ctx: &mut Context) -> Result<()> {
+    let span = info_span!("prepare-image", recipe = %ctx.recipe.metadata.name, image = %ctx.target.image());
+    async move {
+        let state = image::build(ctx)
+            .await
+            .context("failed to build image")
+            .context(FailureKind::Runtime)?;
+        ctx.prepared_image = Some(state);
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
+// https://github.com/rust-lang/rust-clippy/issues/7271
+#[allow(clippy::needless_lifetimes)]
+/// Spawns a container for the build caching the image with all dependencies installed if
+/// necessary. Returns the context of the spawned container and the final state of the image.
+async fn init_container<'ctx>(
+    ctx: &'ctx Context,
+    image_state: ImageState,
+) -> Result<(container::Context<'ctx>, ImageState)> {
+    // images without a package manager, like the fixture of the sandbox mode, are used as they are
+    let has_package_manager = !matches!(
+        image_state.os.package_manager(),
+        PackageManager::Unavailable
+    );
+    let install_deps = image_state.tag != image::CACHED && has_package_manager;
+
+    // dependencies are installed in an unrestricted container, only the container executing the
+    // scripts is locked down
+    let mut container_ctx = container::spawn(ctx, &image_state, !install_deps)
+        .await
+        .context(FailureKind::Runtime)?;
+
+    cleanup!(container_ctx);
+
+    if !has_package_manager && !deps::recipe_deps(&container_ctx, &image_state).is_empty() {
+        warn!(os = %image_state.os.name(), "image has no package manager, skipping dependencies");
+    }
+
+    if install_deps && ctx.recipe.metadata.noninteractive() {
+        // configured before installing dependencies so that the cached image keeps it
+        if let Err(e) = disable_prompts(&container_ctx, &image_state).await {
+            warn!(reason = %e, "failed to configure package manager to assume yes");
+        }
+    }
+
+    let image_state = if install_deps {
+        let mut deps = deps::recipe_deps(&container_ctx, &image_state);
+        if !ctx
+            .recipe
+            .metadata
+            .skip_default_deps_for(ctx.target.image(), *ctx.target.build_target())
+        {
+            deps.extend(deps::pkger_deps(
+                ctx.target.build_target(),
+                &ctx.recipe,
+                ctx.source_package,
+            ));
+        }
+        let new_state = image::cache_image(&container_ctx, &ctx.docker, &image_state, &deps)
+            .await
+            .context("failed to cache image with dependencies")
+            .context(FailureKind::Dependencies)?;
+        info!(id = %new_state.id, image = %new_state.image, "successfully cached image");
+
+        if let Ok(mut state) = ctx.image_state.write() {
+            trace!("saving image state");
+            (*state).update(&ctx.target, &new_state)
+        }
+
+        container_ctx
+            .container
+            .remove()
+            .await
+            .context(FailureKind::Runtime)?;
+        container_ctx = container::spawn(ctx, &new_state, true)
+            .await
+            .context(FailureKind::Runtime)?;
+
+        new_state
+    } else {
+        image_state
+    };
+
+    if let Ok(mut state) = ctx.image_state.write() {
+        (*state).mark_used(&ctx.target);
+    }
 
+    // repositories of images without installed dependencies were never updated
+    if has_package_manager && !image_state.deps.is_empty() {
+        match deps::unresolvable_deps(&container_ctx, &image_state).await {
+            Ok(unresolvable) if !unresolvable.is_empty() => warn!(
+                deps = %unresolvable.join(", "),
+                "runtime dependencies can't be installed from the repositories of the image"
+            ),
+            Ok(_) => {}
+            Err(e) => warn!(reason = %e, "failed to check runtime dependencies"),
+        }
+    }
+    ctx.record(|transcript| transcript.image(&image_state, &ctx.recipe.metadata.dependency_names));
+
+    cleanup!(container_ctx);
+
+    let dirs = [
+        &ctx.container_out_dir,
+        &ctx.container_bld_dir,
+        &ctx.container_tmp_dir,
+    ];
+
+    container::create_dirs(&container_ctx, &dirs[..])
+        .await
+        .context(FailureKind::Runtime)?;
+
+    Ok((container_ctx, image_state))
+}
+
+/// Configures the package manager of the image to answer yes to all prompts, so that commands of
+/// recipes installing packages without `-y` don't hang waiting for input.
+async fn disable_prompts(ctx: &container::Context<'_>, image_state: &ImageState) -> Result<()> {
+    let (path, line) = match image_state.os.package_manager().assume_yes_config() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    trace!(path = %path, line = %line, "disabling prompts");
+
+    container::checked_exec(
+        ctx,
+        &ExecOpts::default().cmd(&format!(
+            "grep -qxF '{line}' {path} 2>/dev/null || echo '{line}' >> {path}",
+            line = line,
+            path = path
+        )),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Replaces the container of the build with one created from its latest checkpoint if
+/// checkpoints are enabled. Returns the phase from which the build is resumed.
+async fn resume(
+    container_ctx: &mut container::Context<'_>,
+    image_state: &ImageState,
+) -> Result<Option<Phase>> {
+    let ctx = container_ctx.build_ctx;
+    if !ctx.checkpoints_enabled() {
+        if ctx.checkpoints {
+            warn!("checkpoints are disabled, the root filesystem of the container is read only");
+        }
+        return Ok(None);
+    }
+    checkpoint::resume(container_ctx, image_state)
+        .await
+        .context(FailureKind::Runtime)
+}
+
+/// Fetches and unpacks the source and installs fetched artifacts unless the build was `resumed`
+/// after the source phase. Returns the digest of the source when the build is pinned or cached.
+async fn fetch_source(
+    container_ctx: &container::Context<'_>,
+    image_state: &ImageState,
+    resumed: Option<Phase>,
+) -> Result<Option<String>> {
+    let ctx = container_ctx.build_ctx;
+
+    cleanup!(container_ctx);
+
+    if !Phase::Source.is_completed(resumed) {
+        ctx.enter_phase("fetch source");
+        remote::fetch_source(container_ctx)
+            .await
+            // a source not matching its checksum is already classified
+            .map_err(|e| match FailureKind::of(&e) {
+                Some(_) => e,
+                None => e.context(FailureKind::Runtime),
+            })?;
+
+        if let Some(signature) = &ctx.recipe.metadata.signature {
+            ctx.enter_phase("verify signature");
+            signature::verify(container_ctx, signature)
+                .await
+                .context(FailureKind::Verification)?;
+            ctx.enter_phase("unpack source");
+        }
+        remote::unpack_source(container_ctx)
+            .await
+            .context(FailureKind::Runtime)?;
+
+        if !ctx.recipe.metadata.fetch_artifacts.is_empty() {
+            ctx.enter_phase("install fetched artifacts");
+            artifacts::fetch_and_install(container_ctx, image_state)
+                .await
+                // a fetched artifact not matching its checksum is already classified
+                .map_err(|e| match FailureKind::of(&e) {
+                    Some(_) => e,
+                    None => e.context(FailureKind::Dependencies),
+                })?;
+        }
+    }
+
+    if ctx.lockfile.is_none() && ctx.cache.is_none() {
+        return Ok(None);
+    }
+    // the fetched source is kept in checkpoints so it's pinned even when resuming
+    let digest = lock::source_digest(container_ctx)
+        .await
+        .context("failed to compute digest of the source")
+        .context(FailureKind::Runtime)?;
+    if ctx.lockfile.is_some() {
+        ctx.lock_source(digest.clone())
+            .context(FailureKind::Verification)?;
+    }
+    Ok(digest)
+}
+
+/// Applies patches and executes all scripts leaving the final files in `$PKGER_OUT_DIR` of the
+/// container. When the package is built from native packaging files the scripts are skipped and
+/// the patched source is left in the build directory. Phases completed before the checkpoint the
+/// build was `resumed` from are skipped.
+async fn build_output(
+    container_ctx: &container::Context<'_>,
+    image_state: &ImageState,
+    resumed: Option<Phase>,
+) -> Result<()> {
+    let ctx = container_ctx.build_ctx;
+
+    cleanup!(container_ctx);
+
+    if !Phase::Source.is_completed(resumed) {
         if let Some(patches) = &ctx.recipe.metadata.patches {
-            let patches = collect_patches(&container_ctx, &patches).await?;
+            let patches = collect_patches(container_ctx, patches)
+                .await
+                .context(FailureKind::Runtime)?;
 
             cleanup!(container_ctx);
 
-            apply_patches(&container_ctx, patches).await?;
+            ctx.enter_phase("apply patches");
+            apply_patches(container_ctx, patches)
+                .await
+                .context(FailureKind::Runtime)?;
         }
 
+        if ctx.checkpoints_enabled() {
+            checkpoint::save(container_ctx, image_state, Phase::Source).await;
+        }
+    }
+
+    cleanup!(container_ctx);
+
+    if ctx.recipe.uses_native_packaging(ctx.target.build_target()) {
+        info!("using native packaging files, skipping scripts");
+        return Ok(());
+    }
+
+    scripts::execute_scripts(container_ctx, image_state, resumed)
+        .await
+        .context(FailureKind::Script)?;
+
+    cleanup!(container_ctx);
+
+    ctx.enter_phase("exclude paths");
+    exclude_paths(container_ctx)
+        .await
+        .context(FailureKind::Packaging)?;
+
+    if let Some(opts) = &ctx.recipe.metadata.rpath {
         cleanup!(container_ctx);
 
-        scripts::execute_scripts(&container_ctx).await?;
+        ctx.enter_phase("check rpaths");
+        rpath::check(container_ctx, opts)
+            .await
+            .context(FailureKind::Verification)?;
+    }
 
+    if let Some(opts) = &ctx.recipe.metadata.symbols {
         cleanup!(container_ctx);
 
-        exclude_paths(&container_ctx).await?;
+        ctx.enter_phase("check symbols");
+        symbols::check(container_ctx, opts)
+            .await
+            .context(FailureKind::Verification)?;
+    }
 
+    if ctx.recipe.metadata.preset == Some(Preset::Static) {
         cleanup!(container_ctx);
 
-        let package =
-            package::create_package(&container_ctx, &image_state, out_dir.as_path()).await?;
+        ctx.enter_phase("check static linkage");
+        linkage::check_static(container_ctx)
+            .await
+            .context(FailureKind::Verification)?;
+    }
 
-        container_ctx.container.remove().await?;
+    Ok(())
+}
 
-        Ok(package)
+/// Copies the output of a build stage into `$PKGER_OUT_DIR` of the container.
+async fn unpack_prebuilt_output(ctx: &container::Context<'_>, output: &[u8]) -> Result<()> {
+    let span = info_span!("unpack-prebuilt-output");
+    async move {
+        let archive = ctx.build_ctx.container_tmp_dir.join("prebuilt-output.tar");
+        info!(archive = %archive.display(), "copying output of the build stage");
+
+        let output = strip_output_root(output).context("invalid output of the build stage")?;
+        container::copy_file_into(ctx, archive.as_path(), &output)
+            .await
+            .context("failed to copy output of the build stage")?;
+
+        container::checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "tar xf {} -C {}",
+                archive.display(),
+                ctx.build_ctx.container_out_dir.display()
+            )),
+        )
+        .await
+        .map(|_| ())
     }
     .instrument(span)
     .await
 }
 
+/// Rewrites the output of a build stage so that it can be extracted directly into the output
+/// directory. The output is an archive of the output directory as returned by Docker, with the
+/// directory itself as the only top level entry, so the first component of every path and of
+/// every hard link target is removed.
+fn strip_output_root(output: &[u8]) -> Result<Vec<u8>> {
+    fn strip(path: &Path) -> PathBuf {
+        path.components().skip(1).collect()
+    }
+
+    let mut archive = tar::Archive::new(output);
+    let mut builder = tar::Builder::new(vec![]);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = strip(&entry.path()?);
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let mut header = entry.header().clone();
+        match header.entry_type() {
+            tar::EntryType::Symlink | tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| {
+                        Error::msg(format!("link `{}` without a target", path.display()))
+                    })?
+                    .into_owned();
+                let target = if header.entry_type() == tar::EntryType::Link {
+                    strip(&target)
+                } else {
+                    target
+                };
+                builder.append_link(&mut header, &path, &target)?;
+            }
+            _ => builder.append_data(&mut header, &path, &mut entry)?,
+        }
+    }
+    builder.into_inner().map_err(Error::from)
+}
+
 impl Context {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -137,10 +643,13 @@ impl Context {
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
-        let id = format!(
-            "pkger-{}-{}-{}",
-            &recipe.metadata.name, &target.image, &timestamp,
-        );
+        // builds of each package format of the image run at the same time
+        let id = if recipe.metadata.build_targets_of(&target.image).len() > 1 {
+            let image = format!("{}-{}", target.image, target.build_target.as_ref());
+            labels::container_name(&recipe.metadata.name, &image, timestamp)
+        } else {
+            labels::container_name(&recipe.metadata.name, &target.image, timestamp)
+        };
         let container_bld_dir = PathBuf::from(format!(
             "/tmp/{}-build-{}",
             &recipe.metadata.name, &timestamp,
@@ -159,6 +668,7 @@ impl Context {
             recipe,
             image,
             docker,
+            runtime: Runtime::default(),
             container_bld_dir,
             container_out_dir,
             container_tmp_dir,
@@ -167,17 +677,364 @@ impl Context {
             image_state,
             is_running,
             simple,
+            prebuilt_output: None,
+            prepared_image: None,
+            transcript: None,
+            signing: None,
+            credentials: Arc::new(vec![]),
+            security: SecurityOptions::default(),
+            disk: DiskOptions::default(),
+            stall_detection: None,
+            output_limit: None,
+            snapshots: Mutex::new(vec![]),
+            cache: None,
+            cache_push: false,
+            publishers: vec![],
+            allow_privileged: false,
+            lockfile: None,
+            source_digest: Mutex::new(None),
+            progress: Progress::default(),
+            checkpoints: false,
+            source_package: false,
+        }
+    }
+
+    /// Signs the built package with the given key creating a detached signature next to it
+    pub fn with_signing(mut self, signing: SigningConfig) -> Self {
+        self.signing = Some(signing);
+        self
+    }
+
+    /// Authenticates fetching of private git repositories and HTTP sources with the matching
+    /// entry of `credentials`
+    pub fn with_credentials(mut self, credentials: Arc<Vec<SourceCredentials>>) -> Self {
+        credentials
+            .iter()
+            .for_each(SourceCredentials::register_secrets);
+        self.credentials = credentials;
+        self
+    }
+
+    /// Applies `security` to the build container in addition to the security options of the
+    /// recipe. Profiles set here take precedence over the ones of the recipe.
+    pub fn with_security(mut self, security: SecurityOptions) -> Self {
+        self.security = security;
+        self
+    }
+
+    /// Applies the disk limits in `disk` to the build, recipes can only lower them
+    pub fn with_disk(mut self, disk: DiskOptions) -> Self {
+        self.disk = disk;
+        self
+    }
+
+    /// Warns about commands executed in the container that produce no output and use no CPU for
+    /// the configured time, optionally killing them.
+    pub fn with_stall_detection(mut self, stall_detection: StallDetection) -> Self {
+        self.stall_detection = Some(stall_detection);
+        self
+    }
+
+    /// Truncates the output of commands executed in the container to its first and last lines,
+    /// saving the full output to a file.
+    pub fn with_output_limit(mut self, output_limit: OutputLimit) -> Self {
+        self.output_limit = Some(output_limit);
+        self
+    }
+
+    /// Pulls the artifact of this build from `cache` if it contains one built from the same recipe
+    /// and image. Otherwise the artifact is built and pushed to the cache if `push` is set.
+    pub fn with_cache(mut self, cache: Arc<dyn ArtifactCache>, push: bool) -> Self {
+        self.cache = Some(cache);
+        self.cache_push = push;
+        self
+    }
+
+    /// Publishes the finished artifact with `publisher` recording its content id in the manifest
+    pub fn with_publisher(mut self, publisher: Arc<dyn ArtifactPublisher>) -> Self {
+        self.publishers.push(publisher);
+        self
+    }
+
+    /// Sets the container engine serving the API of the connected daemon
+    pub fn with_runtime(mut self, runtime: Runtime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Commits the container to an image after each phase of the build so that a failed build is
+    /// resumed from the last phase that succeeded. The build directories don't contain a timestamp
+    /// so that their paths stay the same across retries.
+    pub fn with_checkpoints(mut self, checkpoints: bool) -> Self {
+        self.checkpoints = checkpoints;
+        if checkpoints {
+            let name = &self.recipe.metadata.name;
+            self.container_bld_dir = PathBuf::from(format!("/tmp/{}-build", name));
+            self.container_out_dir = PathBuf::from(format!("/tmp/{}-out", name));
+            self.container_tmp_dir = PathBuf::from(format!("/tmp/{}-tmp", name));
         }
+        self
+    }
+
+    /// Builds an SRPM or a Debian source package next to RPM and DEB packages
+    pub fn with_source_package(mut self, source_package: bool) -> Self {
+        self.source_package = source_package;
+        self
+    }
+
+    /// Allows steps of the recipe marked as `privileged` to run with extended privileges. Builds
+    /// with privileged steps fail unless this is set.
+    pub fn with_privileged(mut self, allow: bool) -> Self {
+        self.allow_privileged = allow;
+        self
+    }
+
+    /// Pins the recipe and its source in `lockfile` once the build succeeds. If `locked` is set
+    /// nothing is pinned and the build fails if the fetched source doesn't match the pinned one.
+    pub fn with_lockfile(mut self, lockfile: Arc<Mutex<Lockfile>>, locked: bool) -> Self {
+        self.lockfile = Some((lockfile, locked));
+        self
+    }
+
+    /// Records every command executed in the container and saves it as a shell script named
+    /// after the id of this build in `dir` once the build finishes, even if it fails.
+    pub fn with_transcript(mut self, dir: &Path) -> Self {
+        self.transcript = Some((dir.to_path_buf(), Mutex::new(Transcript::new(&self.id))));
+        self
+    }
+
+    /// Skips fetching the source and running the scripts in favour of packaging the output of a
+    /// previously executed build stage.
+    pub fn with_prebuilt_output(mut self, output: Arc<Vec<u8>>) -> Self {
+        self.prebuilt_output = Some(output);
+        self
     }
 
     pub fn id(&self) -> &str {
         self.id.as_str()
     }
 
-    async fn create_out_dir(&self, image: &ImageState) -> Result<PathBuf> {
+    pub fn recipe(&self) -> &Recipe {
+        &self.recipe
+    }
+
+    pub fn target(&self) -> &RecipeTarget {
+        &self.target
+    }
+
+    /// Credentials used to fetch the source at `url`
+    pub fn source_credentials(&self, url: &str) -> Option<&SourceCredentials> {
+        credentials::find(&self.credentials, url)
+    }
+
+    /// Architecture of the package built by this job
+    pub fn arch(&self) -> &BuildArch {
+        self.recipe
+            .metadata
+            .arch_for(self.target.image(), *self.target.build_target())
+    }
+
+    /// Release of the package built by this job
+    pub fn release(&self) -> String {
+        self.recipe
+            .metadata
+            .release_for(self.target.image(), *self.target.build_target())
+    }
+
+    /// Security options of the build container combining the global ones with those of the recipe
+    pub fn security(&self) -> SecurityOptions {
+        match &self.recipe.metadata.security {
+            Some(security) => self
+                .security
+                .merge(&security.clone().relative_to(&self.recipe.recipe_dir)),
+            None => self.security.clone(),
+        }
+    }
+
+    /// Disk limits of the build combining the global ones with those of the recipe
+    pub fn disk(&self) -> Result<DiskOptions> {
+        match &self.recipe.metadata.disk {
+            Some(disk) => self.disk.merge(disk),
+            None => Ok(self.disk.clone()),
+        }
+    }
+
+    /// Returns an error if the output directory has less free space than required
+    fn check_free_space(&self) -> Result<()> {
+        let min_free = match self.disk()?.min_free {
+            Some(min_free) => min_free,
+            None => return Ok(()),
+        };
+        let required = parse_size(&min_free)?;
+        // the output directory might not be created yet
+        let dir = self
+            .out_dir
+            .ancestors()
+            .find(|dir| dir.exists())
+            .unwrap_or_else(|| Path::new("/"));
+        let available = available_space(dir)
+            .context(format!("failed to check free space of `{}`", dir.display()))?;
+        trace!(dir = %dir.display(), available = %HumanSize(available), required = %HumanSize(required));
+
+        if available < required {
+            return Err(Error::msg(format!(
+                "not enough free space in `{}`, {} available but {} are required",
+                dir.display(),
+                HumanSize(available),
+                HumanSize(required)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` with the transcript of this build if one is recorded
+    pub(crate) fn record<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Transcript),
+    {
+        if let Some((_, transcript)) = &self.transcript {
+            if let Ok(mut transcript) = transcript.lock() {
+                f(&mut transcript)
+            }
+        }
+    }
+
+    /// Marks the start of `phase` in the progress and the transcript of this build
+    pub(crate) fn enter_phase(&self, phase: &str) {
+        self.progress.enter(phase);
+        self.record(|transcript| transcript.phase(phase));
+    }
+
+    /// Returns a handle to the phase this build is in that stays valid while the build runs
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    pub(crate) fn add_snapshot(&self, snapshot: EnvironmentSnapshot) {
+        if let Ok(mut snapshots) = self.snapshots.lock() {
+            snapshots.push(snapshot);
+        }
+    }
+
+    /// Whether checkpoints are saved, they can't be used with a read only root filesystem as
+    /// the build directories are then volumes which aren't committed
+    fn checkpoints_enabled(&self) -> bool {
+        self.checkpoints && !self.security().read_only
+    }
+
+    /// Returns snapshots of the environment taken before each script phase
+    pub fn snapshots(&self) -> Vec<EnvironmentSnapshot> {
+        self.snapshots
+            .lock()
+            .map(|snapshots| snapshots.clone())
+            .unwrap_or_default()
+    }
+
+    /// Verifies the digest of the fetched source against the lockfile if the build is locked,
+    /// otherwise saves it to be pinned once the build succeeds
+    fn lock_source(&self, digest: Option<String>) -> Result<()> {
+        let metadata = &self.recipe.metadata;
+        match &self.lockfile {
+            Some((lockfile, true)) => {
+                let lockfile = lockfile
+                    .lock()
+                    .map_err(|e| Error::msg(format!("failed to access lockfile - {}", e)))?;
+                let pinned = lockfile
+                    .get(&metadata.name, &metadata.version)
+                    .and_then(|pin| pin.source.clone());
+                if pinned != digest {
+                    return Err(Error::msg(format!(
+                        "source of recipe `{}` version `{}` changed since it was pinned, expected `{}` found `{}`",
+                        metadata.name,
+                        metadata.version,
+                        pinned.as_deref().unwrap_or("none"),
+                        digest.as_deref().unwrap_or("none"),
+                    )));
+                }
+            }
+            Some((_, false)) => {
+                if let Ok(mut source_digest) = self.source_digest.lock() {
+                    *source_digest = digest;
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Pins the recipe of this build in the lockfile unless the build is locked
+    fn pin(&self) {
+        let lockfile = match &self.lockfile {
+            Some((lockfile, false)) => lockfile,
+            _ => return,
+        };
+        let metadata = &self.recipe.metadata;
+        let recipe = match lock::recipe_hash(&self.recipe.recipe_dir) {
+            Ok(hash) => hash,
+            Err(e) => {
+                warn!(reason = %e, "failed to pin recipe");
+                return;
+            }
+        };
+        let source = self
+            .source_digest
+            .lock()
+            .map(|digest| digest.clone())
+            .unwrap_or_default();
+        if let Ok(mut lockfile) = lockfile.lock() {
+            trace!(recipe = %recipe, source = ?source, "pinning recipe");
+            lockfile.pin(
+                &metadata.name,
+                &metadata.version,
+                RecipePin { recipe, source },
+            );
+        }
+    }
+
+    fn save_transcript(&self) {
+        if let Some((dir, transcript)) = &self.transcript {
+            let path = dir.join(format!("{}.sh", self.id));
+            let script = match transcript.lock() {
+                Ok(transcript) => transcript.render(),
+                Err(_) => return,
+            };
+            match fs::create_dir_all(dir).and_then(|_| fs::write(&path, script)) {
+                Ok(_) => info!(path = %path.display(), "saved transcript"),
+                Err(e) => warn!(path = %path.display(), reason = %e, "failed to save transcript"),
+            }
+        }
+    }
+
+    /// Publishes and signs the `package` if configured and saves its manifest
+    async fn finish_artifact(
+        &self,
+        mut manifest: manifest::ArtifactManifest,
+        package: &Path,
+    ) -> Result<()> {
+        if !self.publishers.is_empty() {
+            manifest
+                .content_ids
+                .extend(publish::publish_all(&self.publishers, package).await);
+        }
+        if let Some(signing) = &self.signing {
+            let signature = sign::sign_package(signing, package)
+                .await
+                .context("failed to sign package")
+                .context(FailureKind::Packaging)?;
+            manifest = manifest
+                .with_signature(signature.strip_prefix(&self.out_dir).unwrap_or(&signature));
+        }
+        manifest
+            .save(&self.out_dir)
+            .map(|_| ())
+            .context(FailureKind::Packaging)
+    }
+
+    async fn create_out_dir(&self, image: &str) -> Result<PathBuf> {
         let span = info_span!("create-out-dir");
         async move {
-            let out_dir = self.out_dir.join(&image.image);
+            let out_dir = self.out_dir.join(image);
 
             if out_dir.exists() {
                 trace!(dir = %out_dir.display(), "already exists, skipping");
@@ -194,6 +1051,22 @@ impl Context {
     }
 }
 
+/// Returns the number of bytes available to unprivileged users on the filesystem of `path`
+pub fn available_space(path: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid nul terminated string and `stat` is only read if the call succeeds
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
 pub async fn exclude_paths(ctx: &container::Context<'_>) -> Result<()> {
     let span = info_span!("exclude-paths");
     async move {
@@ -217,8 +1090,7 @@ pub async fn exclude_paths(ctx: &container::Context<'_>) -> Result<()> {
                 &ctx,
                 &ExecOpts::default()
                     .cmd(&format!("rm -rvf {}", exclude_paths.join(" ")))
-                    .working_dir(&ctx.build_ctx.container_out_dir)
-                    .build(),
+                    .working_dir(&ctx.build_ctx.container_out_dir),
             )
             .await?;
         }
@@ -241,13 +1113,8 @@ pub async fn apply_patches(
             if let Err(e) = container::checked_exec(
                 &ctx,
                 &ExecOpts::default()
-                    .cmd(&format!(
-                        "patch -p{} < {}",
-                        patch.strip_level(),
-                        location.display()
-                    ))
-                    .working_dir(&ctx.build_ctx.container_bld_dir)
-                    .build(),
+                    .cmd(&patch.apply_command(&location))
+                    .working_dir(&ctx.build_ctx.container_bld_dir),
             )
             .await
             {
@@ -309,13 +1176,11 @@ pub async fn collect_patches(
 
         container::checked_exec(
             ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "tar xf {} -C {}",
-                    patches_archive.display(),
-                    patch_dir.display()
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "tar xf {} -C {}",
+                patches_archive.display(),
+                patch_dir.display()
+            )),
         )
         .await
         .map(|_| out)
@@ -323,3 +1188,56 @@ pub async fn collect_patches(
     .instrument(span)
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpacks_prebuilt_output() {
+        let dir = tempdir::TempDir::new("pkger-prebuilt").unwrap();
+        let out_dir = dir.path().join("pkger-out");
+        fs::create_dir(&out_dir).unwrap();
+
+        // the layout of an archive of `/tmp/out` copied from a container
+        let mut builder = tar::Builder::new(vec![]);
+        for path in ["out", "out/usr", "out/usr/bin"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder
+                .append_data(&mut header, path, std::io::empty())
+                .unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o755);
+        header.set_size(3);
+        builder
+            .append_data(&mut header, "out/usr/bin/foo", &b"foo"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "out/usr/bin/bar", "out/usr/bin/foo")
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "out/usr/bin/baz", "foo")
+            .unwrap();
+
+        let output = strip_output_root(&builder.into_inner().unwrap()).unwrap();
+        tar::Archive::new(&output[..]).unpack(&out_dir).unwrap();
+
+        assert_eq!(fs::read(out_dir.join("usr/bin/foo")).unwrap(), b"foo");
+        assert_eq!(fs::read(out_dir.join("usr/bin/bar")).unwrap(), b"foo");
+        assert_eq!(
+            fs::read_link(out_dir.join("usr/bin/baz")).unwrap(),
+            Path::new("foo")
+        );
+        assert!(!out_dir.join("out").exists());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::archive::create_tarball;
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::container::{DownloadFilter, ExecOpts};
+use crate::image::ImageState;
+use crate::{ErrContext, Result};
+
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, info_span, trace, Instrument};
+
+/// Creates a final APK package and saves it to `output_dir`
+pub(crate) async fn build_apk(
+    ctx: &Context<'_>,
+    image_state: &ImageState,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let name = format!(
+        "{}-{}",
+        &ctx.build_ctx.recipe.metadata.name, &ctx.build_ctx.recipe.metadata.version,
+    );
+    let package_name = format!("{}-r{}", &name, &ctx.build_ctx.release());
+
+    let span = info_span!("APK", package = %package_name);
+    let cloned_span = span.clone();
+    async move {
+        info!("building APK package");
+
+        let tmp_dir = PathBuf::from(format!("/tmp/{}", package_name));
+        let src_dir = tmp_dir.join("src");
+        let bld_dir = tmp_dir.join("bld");
+        // abuild saves packages to `<REPODEST>/<name of the parent of bld_dir>/<arch>`
+        let repo_dir = tmp_dir.join("repo");
+
+        let source_tar_name = [&name, ".tar.gz"].join("");
+        let source_tar_path = bld_dir.join(&source_tar_name);
+
+        let dirs = [tmp_dir.as_path(), bld_dir.as_path(), src_dir.as_path()];
+
+        create_dirs(ctx, &dirs[..])
+            .await
+            .context("failed to create dirs")?;
+
+        trace!("copy source files to temporary location");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!("cp -rv . {}", src_dir.display()))
+                .working_dir(&ctx.build_ctx.container_out_dir),
+        )
+        .await
+        .context("failed to copy source files to temp directory")?;
+
+        trace!("prepare archived source files");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!("tar -zcvf {} .", source_tar_path.display()))
+                .working_dir(src_dir.as_path()),
+        )
+        .await?;
+
+        trace!("calculate source SHA512 checksum");
+        let sum = checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!("sha512sum {}", source_tar_name))
+                .working_dir(bld_dir.as_path()),
+        )
+        .await
+        .map(|out| out.stdout.join(""))?;
+        let sum = sum
+            .lines()
+            .next()
+            .map(|s| s.trim().to_string())
+            .context("failed to calculate SHA512 checksum of source")?;
+
+        let sources = vec![source_tar_name];
+        let checksums = vec![sum];
+        static BUILD_USER: &str = "builduser";
+
+        let recipe = &ctx.build_ctx.recipe;
+        let apkbuild = recipe
+            .as_apkbuild(&image_state.image, &sources, &checksums)
+            .render();
+        debug!(APKBUILD = %apkbuild);
+
+        let scripts = recipe
+            .metadata
+            .apk
+            .as_ref()
+            .map(|apk| {
+                apk.install_scripts()
+                    .into_iter()
+                    .map(|(suffix, script)| {
+                        let script = if script.starts_with("#!") {
+                            script.to_string()
+                        } else {
+                            format!("#!/bin/sh\n{}", script)
+                        };
+                        (format!("{}.{}", recipe.metadata.name, suffix), script)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut entries = vec![("APKBUILD".to_string(), apkbuild.as_bytes())];
+        entries.extend(
+            scripts
+                .iter()
+                .map(|(name, script)| (name.clone(), script.as_bytes())),
+        );
+        let apkbuild_tar = cloned_span.in_scope(|| create_tarball(entries.into_iter()))?;
+        let apkbuild_tar_path = tmp_dir.join("APKBUILD.tar");
+
+        trace!("copy APKBUILD archive to container");
+        copy_file_into(ctx, apkbuild_tar_path.as_path(), &apkbuild_tar)
+            .await
+            .context("failed to copy archive with APKBUILD to container")?;
+
+        trace!("extract APKBUILD archive");
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "tar -xvf {} -C {}",
+                apkbuild_tar_path.display(),
+                bld_dir.display(),
+            )),
+        )
+        .await?;
+
+        trace!("create build user");
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "adduser -D {0} && addgroup {0} abuild",
+                BUILD_USER
+            )),
+        )
+        .await?;
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "chown -R {0}:{0} {1}",
+                BUILD_USER,
+                tmp_dir.display()
+            )),
+        )
+        .await?;
+
+        trace!("generate signing key");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd("abuild-keygen -a -n")
+                .working_dir(bld_dir.as_path())
+                .user(BUILD_USER),
+        )
+        .await
+        .context("failed to generate key signing the package")?;
+
+        trace!("abuild");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!("abuild -d -P {}", repo_dir.display()))
+                .working_dir(bld_dir.as_path())
+                .user(BUILD_USER),
+        )
+        .await
+        .context("failed to build APK package")?;
+
+        let apk = format!("{}.apk", package_name);
+        let apk_path = checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!("find {} -name {}", repo_dir.display(), apk)),
+        )
+        .await
+        .map(|out| out.stdout.join(""))?;
+        let apk_path = apk_path
+            .lines()
+            .next()
+            .map(|path| PathBuf::from(path.trim()))
+            .context("failed to find built package")?;
+
+        ctx.container
+            .download_files_matching(
+                &apk_path,
+                output_dir,
+                &DownloadFilter::default().expect(apk.as_str()),
+            )
+            .await
+            .map(|_| output_dir.join(apk))
+            .context("failed to download finished package")
+    }
+    .instrument(span)
+    .await
+}
@@ -1,9 +1,15 @@
-use crate::archive::create_tarball;
-use crate::build::container::{checked_exec, create_dirs, Context};
-use crate::container::ExecOpts;
+use crate::archive::{
+    create_tarball,
+    flate2::{write::GzEncoder, Compression},
+};
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::build::package::dpkg_deb_built_packages;
+use crate::build::remote::copy_dir_into;
+use crate::container::{DownloadFilter, ExecOpts};
 use crate::image::ImageState;
-use crate::{ErrContext, Result};
+use crate::{ErrContext, Error, Result};
 
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use tracing::{debug, info, info_span, trace, Instrument};
 
@@ -13,13 +19,24 @@ pub async fn build_deb(
     image_state: &ImageState,
     output_dir: &Path,
 ) -> Result<PathBuf> {
+    if let Some(debian_dir) = ctx
+        .build_ctx
+        .recipe
+        .metadata
+        .deb
+        .as_ref()
+        .and_then(|deb| deb.debian_dir.as_ref())
+    {
+        return build_deb_from_debian_dir(ctx, debian_dir, output_dir).await;
+    }
+
     let name = [
         &ctx.build_ctx.recipe.metadata.name,
         "-",
         &ctx.build_ctx.recipe.metadata.version,
     ]
     .join("");
-    let arch = ctx.build_ctx.recipe.metadata.arch.deb_name();
+    let arch = ctx.build_ctx.arch().deb_name();
     let package_name = [&name, ".", &arch].join("");
 
     let span = info_span!("DEB", package = %package_name);
@@ -44,27 +61,28 @@ pub async fn build_deb(
             .render();
         debug!(control = %control);
 
-        let entries = vec![("./control", control.as_bytes())];
+        let conffiles = ctx.build_ctx.recipe.deb_conffiles();
+        let mut entries = vec![("./control", control.as_bytes())];
+        if let Some(conffiles) = &conffiles {
+            debug!(conffiles = %conffiles);
+            entries.push(("./conffiles", conffiles.as_bytes()));
+        }
         let control_tar = cloned_span.in_scope(|| create_tarball(entries.into_iter()))?;
         let control_tar_path = tmp_dir.join([&name, "-control.tar"].join(""));
 
         trace!("copy control archive to container");
-        ctx.container
-            .inner()
-            .copy_file_into(control_tar_path.as_path(), &control_tar)
+        copy_file_into(ctx, control_tar_path.as_path(), &control_tar)
             .await
             .context("failed to copy archive with control file")?;
 
         trace!("extract control archive");
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "tar -xvf {} -C {}",
-                    control_tar_path.display(),
-                    deb_dir.display(),
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "tar -xvf {} -C {}",
+                control_tar_path.display(),
+                deb_dir.display(),
+            )),
         )
         .await
         .context("failed to extract archive with control file")?;
@@ -74,38 +92,147 @@ pub async fn build_deb(
             &ctx,
             &ExecOpts::default()
                 .cmd(&format!("cp -rv . {}", base_dir.display()))
-                .working_dir(&ctx.build_ctx.container_out_dir)
-                .build(),
+                .working_dir(&ctx.build_ctx.container_out_dir),
         )
         .await
         .context("failed to copy source files to build directory")?;
 
+        if let Some(changelog) = ctx.build_ctx.recipe.debian_changelog(&image_state.image) {
+            trace!("copy changelog to build dir");
+            let doc_dir = base_dir
+                .join("usr/share/doc")
+                .join(&ctx.build_ctx.recipe.metadata.name);
+            create_dirs(ctx, &[doc_dir.as_path()])
+                .await
+                .context("failed to create dirs")?;
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(changelog.as_bytes())?;
+            copy_file_into(
+                ctx,
+                &doc_dir.join("changelog.Debian.gz"),
+                &encoder.finish()?,
+            )
+            .await
+            .context("failed to copy changelog")?;
+        }
+
         let dpkg_deb_opts = if image_state.os.version().parse::<u8>().unwrap_or_default() < 10 {
             "--build"
         } else {
             "--build --root-owner-group"
         };
 
-        checked_exec(
+        let out = checked_exec(
             &ctx,
+            &ExecOpts::default().cmd(&format!(
+                "dpkg-deb {} {}",
+                dpkg_deb_opts,
+                base_dir.display()
+            )),
+        )
+        .await
+        .context("failed to build deb package")?;
+
+        let packages = dpkg_deb_built_packages(&out.stdout.join(""));
+        debug!(packages = ?packages);
+        let package = packages
+            .first()
+            .ok_or_else(|| Error::msg("dpkg-deb didn't report the built package"))?;
+        // a relative path is relative to the working directory of dpkg-deb
+        let package = ctx.build_ctx.container_bld_dir.join(package);
+        let file_name = package.file_name().unwrap_or_default();
+
+        ctx.container
+            .download_files_matching(
+                package.as_path(),
+                output_dir,
+                &DownloadFilter::default().expect(file_name.to_string_lossy()),
+            )
+            .await
+            .map(|_| output_dir.join(file_name))
+            .context("failed to download finished package")
+    }
+    .instrument(span)
+    .await
+}
+
+/// Creates DEB packages with `dpkg-buildpackage` using an existing `debian/` directory from the
+/// recipe directory.
+async fn build_deb_from_debian_dir(
+    ctx: &Context<'_>,
+    debian_dir: &str,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let span = info_span!("DEB", debian_dir = %debian_dir);
+    async move {
+        info!("building DEB package from debian directory");
+
+        let recipe = &ctx.build_ctx.recipe;
+        let bld_dir = &ctx.build_ctx.container_bld_dir;
+        let debbld_dir = PathBuf::from("/root/debbuild");
+
+        create_dirs(ctx, &[debbld_dir.as_path()])
+            .await
+            .context("failed to create dirs")?;
+
+        copy_dir_into(
+            ctx,
+            &recipe.recipe_dir.join(debian_dir),
+            &bld_dir.join("debian"),
+        )
+        .await
+        .context("failed to copy debian directory")?;
+
+        trace!("dpkg-buildpackage");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd("chmod +x debian/rules && dpkg-buildpackage -us -uc -b")
+                .working_dir(bld_dir.as_path())
+                .shell("/bin/bash"),
+        )
+        .await
+        .context("failed to build deb package")?;
+
+        // dpkg-buildpackage saves the packages in the parent directory of the source
+        let parent_dir = bld_dir.parent().unwrap_or_else(|| Path::new("/"));
+        let packages = checked_exec(
+            ctx,
             &ExecOpts::default()
                 .cmd(&format!(
-                    "dpkg-deb {} {}",
-                    dpkg_deb_opts,
-                    base_dir.display()
+                    "for deb in *.deb; do mv $deb {} && echo $deb; done",
+                    debbld_dir.display()
                 ))
-                .build(),
+                .working_dir(parent_dir)
+                .shell("/bin/bash"),
         )
         .await
-        .context("failed to build deb package")?;
+        .map(|out| {
+            out.stdout
+                .join("")
+                .split_ascii_whitespace()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        })
+        .context("failed to collect built packages")?;
+        debug!(packages = ?packages);
 
-        let deb_name = [&package_name, ".deb"].join("");
+        let package = packages
+            .iter()
+            .find(|p| p.starts_with(&format!("{}_", recipe.metadata.name)))
+            .or_else(|| packages.first())
+            .ok_or_else(|| Error::msg("no packages were built"))?
+            .to_string();
 
+        let filter = packages.iter().fold(
+            DownloadFilter::default().patterns(vec!["*.deb"]),
+            |filter, package| filter.expect(package.as_str()),
+        );
         ctx.container
-            .download_files(debbld_dir.join(&deb_name).as_path(), output_dir)
+            .download_files_matching(debbld_dir.as_path(), output_dir, &filter)
             .await
-            .map(|_| output_dir.join(deb_name))
-            .context("failed to download finished package")
+            .map(|_| output_dir.join(package))
+            .context("failed to download finished packages")
     }
     .instrument(span)
     .await
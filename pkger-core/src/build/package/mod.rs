@@ -1,24 +1,114 @@
+pub mod apk;
 pub mod deb;
 pub mod gzip;
 pub mod pkg;
 pub mod rpm;
+pub mod source;
 
 use crate::build::container::Context;
 use crate::image::ImageState;
 use crate::recipe::BuildTarget;
-use crate::Result;
+use crate::{ErrContext, Result};
 
 use std::path::{Path, PathBuf};
+use tracing::info;
 
 pub async fn create_package(
     ctx: &Context<'_>,
     image_state: &ImageState,
     output_dir: &Path,
 ) -> Result<PathBuf> {
-    match ctx.build_ctx.target.build_target() {
+    let package = match ctx.build_ctx.target.build_target() {
         BuildTarget::Rpm => rpm::build_rpm(&ctx, &image_state, &output_dir).await,
         BuildTarget::Gzip => gzip::build_gzip(&ctx, &output_dir).await,
         BuildTarget::Deb => deb::build_deb(&ctx, &image_state, &output_dir).await,
         BuildTarget::Pkg => pkg::build_pkg(&ctx, &image_state, &output_dir).await,
+        BuildTarget::Apk => apk::build_apk(ctx, image_state, output_dir).await,
+    }?;
+
+    if ctx.build_ctx.source_package {
+        let files = source::build_source_package(ctx, image_state, output_dir)
+            .await
+            .context("failed to build source package")?;
+        for file in files {
+            info!(file = %file.display(), "saved source package");
+        }
+    }
+
+    Ok(package)
+}
+
+/// Returns the paths of packages reported by `rpmbuild` in lines like
+/// `Wrote: /root/rpmbuild/RPMS/x86_64/foo-1.0-1.el8.x86_64.rpm`
+pub(crate) fn rpmbuild_written_packages(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Wrote:"))
+        .map(|path| PathBuf::from(path.trim()))
+        .collect()
+}
+
+/// Returns the paths of packages reported by `dpkg-deb` in lines like
+/// `dpkg-deb: building package 'foo' in '/root/debbuild/foo-1.0.amd64.deb'.`
+pub(crate) fn dpkg_deb_built_packages(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("dpkg-deb: building package"))
+        .filter_map(|line| line.rsplit(" in ").next())
+        .map(|path| PathBuf::from(path.trim_end_matches('.').trim_matches('\'')))
+        .collect()
+}
+
+/// Returns the main package out of `packages` built from one recipe. Subpackages like
+/// `foo-devel` also start with the name of the recipe, so the package is chosen by the file name
+/// starting with the name and version separated with `separator`, falling back to the first one.
+pub(crate) fn main_package<'p>(
+    packages: &'p [PathBuf],
+    name: &str,
+    version: &str,
+    separator: &str,
+) -> Option<&'p PathBuf> {
+    let prefix = [name, separator, version].join("");
+    packages
+        .iter()
+        .find(|package| {
+            package
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy().starts_with(&prefix))
+                .unwrap_or_default()
+        })
+        .or_else(|| packages.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_built_packages() {
+        let out = "Processing files: foo-devel-1.0-1.el8.x86_64\nWrote: /root/rpmbuild/RPMS/x86_64/foo-devel-1.0-1.el8.x86_64.rpm\nWrote: /root/rpmbuild/RPMS/x86_64/foo-1.0-1.el8.x86_64.rpm\nExecuting(%clean)\n";
+        let packages = rpmbuild_written_packages(out);
+        assert_eq!(
+            packages,
+            vec![
+                PathBuf::from("/root/rpmbuild/RPMS/x86_64/foo-devel-1.0-1.el8.x86_64.rpm"),
+                PathBuf::from("/root/rpmbuild/RPMS/x86_64/foo-1.0-1.el8.x86_64.rpm"),
+            ]
+        );
+        assert_eq!(
+            main_package(&packages, "foo", "1.0", "-"),
+            Some(&packages[1])
+        );
+        assert_eq!(
+            main_package(&packages, "bar", "1.0", "-"),
+            Some(&packages[0])
+        );
+
+        let out = "dpkg-deb: building package 'foo' in '/root/debbuild/foo-1.0.amd64.deb'.\n";
+        assert_eq!(
+            dpkg_deb_built_packages(out),
+            vec![PathBuf::from("/root/debbuild/foo-1.0.amd64.deb")]
+        );
+        assert!(rpmbuild_written_packages("").is_empty());
     }
 }
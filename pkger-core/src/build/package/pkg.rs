@@ -1,6 +1,6 @@
 use crate::archive::create_tarball;
-use crate::build::container::{checked_exec, create_dirs, Context};
-use crate::container::ExecOpts;
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::container::{DownloadFilter, ExecOpts};
 use crate::image::ImageState;
 use crate::{ErrContext, Result};
 
@@ -17,13 +17,20 @@ pub(crate) async fn build_pkg(
         "{}-{}",
         &ctx.build_ctx.recipe.metadata.name, &ctx.build_ctx.recipe.metadata.version,
     );
-    let arch = ctx.build_ctx.recipe.metadata.arch.pkg_name();
-    let package_name = format!(
-        "{}-{}-{}",
-        &name,
-        &ctx.build_ctx.recipe.metadata.release(),
-        &arch
-    );
+    let arch = ctx.build_ctx.arch().pkg_name();
+    let package_name = format!("{}-{}-{}", &name, &ctx.build_ctx.release(), &arch);
+    // makepkg prefixes the version in the file name of the package with the epoch
+    let pkg = match &ctx.build_ctx.recipe.metadata.epoch {
+        Some(epoch) => format!(
+            "{}-{}:{}-{}-{}.pkg.tar.zst",
+            &ctx.build_ctx.recipe.metadata.name,
+            epoch,
+            &ctx.build_ctx.recipe.metadata.version,
+            &ctx.build_ctx.release(),
+            &arch
+        ),
+        None => format!("{}.pkg.tar.zst", package_name),
+    };
 
     let span = info_span!("PKG", package = %package_name);
     let cloned_span = span.clone();
@@ -48,8 +55,7 @@ pub(crate) async fn build_pkg(
             &ctx,
             &ExecOpts::default()
                 .cmd(&format!("cp -rv . {}", src_dir.display()))
-                .working_dir(&ctx.build_ctx.container_out_dir)
-                .build(),
+                .working_dir(&ctx.build_ctx.container_out_dir),
         )
         .await
         .context("failed to copy source files to temp directory")?;
@@ -59,17 +65,14 @@ pub(crate) async fn build_pkg(
             &ctx,
             &ExecOpts::default()
                 .cmd(&format!("tar -zcvf {} .", source_tar_path.display()))
-                .working_dir(src_dir.as_path())
-                .build(),
+                .working_dir(src_dir.as_path()),
         )
         .await?;
 
         trace!("calculate source MD5 checksum");
         let sum = checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!("md5sum {}", source_tar_path.display()))
-                .build(),
+            &ExecOpts::default().cmd(&format!("md5sum {}", source_tar_path.display())),
         )
         .await
         .map(|out| out.stdout.join(""))?;
@@ -95,54 +98,65 @@ pub(crate) async fn build_pkg(
         let pkgbuild_tar_path = tmp_dir.join("PKGBUILD.tar");
 
         trace!("copy PKGBUILD archive to container");
-        ctx.container
-            .inner()
-            .copy_file_into(pkgbuild_tar_path.as_path(), &pkgbuild_tar)
+        copy_file_into(ctx, pkgbuild_tar_path.as_path(), &pkgbuild_tar)
             .await
             .context("failed to copy archive with PKGBUILD to container")?;
 
         trace!("extract PKGBUILD archive");
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "tar -xvf {} -C {}",
-                    pkgbuild_tar_path.display(),
-                    bld_dir.display(),
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "tar -xvf {} -C {}",
+                pkgbuild_tar_path.display(),
+                bld_dir.display(),
+            )),
         )
         .await?;
 
+        if let Some(install) = ctx
+            .build_ctx
+            .recipe
+            .metadata
+            .pkg
+            .as_ref()
+            .and_then(|pkg| pkg.install.as_ref())
+        {
+            trace!(install = %install, "copy install script next to PKGBUILD");
+            checked_exec(
+                ctx,
+                &ExecOpts::default().cmd(&format!(
+                    "cp {} {}",
+                    src_dir.join(install).display(),
+                    bld_dir.display()
+                )),
+            )
+            .await
+            .context(format!("failed to copy install script `{}`", install))?;
+        }
+
         trace!("create build user");
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!("useradd -m {}", BUILD_USER))
-                .build(),
+            &ExecOpts::default().cmd(&format!("useradd -m {}", BUILD_USER)),
         )
         .await?;
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!("passwd -d {}", BUILD_USER))
-                .build(),
+            &ExecOpts::default().cmd(&format!("passwd -d {}", BUILD_USER)),
         )
         .await?;
         checked_exec(
             &ctx,
             &ExecOpts::default()
                 .cmd(&format!("chown -Rv {0}:{0} .", BUILD_USER))
-                .working_dir(bld_dir.as_path())
-                .build(),
+                .working_dir(bld_dir.as_path()),
         )
         .await?;
         checked_exec(
             &ctx,
             &ExecOpts::default()
                 .cmd("chmod 644 PKGBUILD")
-                .working_dir(bld_dir.as_path())
-                .build(),
+                .working_dir(bld_dir.as_path()),
         )
         .await?;
 
@@ -152,17 +166,19 @@ pub(crate) async fn build_pkg(
             &ExecOpts::default()
                 .cmd("makepkg")
                 .working_dir(bld_dir.as_path())
-                .user(BUILD_USER)
-                .build(),
+                .user(BUILD_USER),
         )
         .await
         .context("failed to build PKG package")?;
 
-        let pkg = format!("{}.pkg.tar.zst", package_name);
         let pkg_path = bld_dir.join(&pkg);
 
         ctx.container
-            .download_files(&pkg_path, output_dir)
+            .download_files_matching(
+                &pkg_path,
+                output_dir,
+                &DownloadFilter::default().expect(pkg.as_str()),
+            )
             .await
             .map(|_| output_dir.join(pkg))
             .context("failed to download finished package")
@@ -1,8 +1,10 @@
 use crate::archive::create_tarball;
-use crate::build::container::{checked_exec, create_dirs, Context};
-use crate::container::ExecOpts;
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::build::package::{main_package, rpmbuild_written_packages};
+use crate::build::remote::copy_dir_into;
+use crate::container::{DownloadFilter, ExecOpts};
 use crate::image::ImageState;
-use crate::{ErrContext, Result};
+use crate::{ErrContext, Error, Result};
 
 use std::path::Path;
 use std::path::PathBuf;
@@ -14,14 +16,25 @@ pub(crate) async fn build_rpm(
     image_state: &ImageState,
     output_dir: &Path,
 ) -> Result<PathBuf> {
+    if let Some(spec_file) = ctx
+        .build_ctx
+        .recipe
+        .metadata
+        .rpm
+        .as_ref()
+        .and_then(|rpm| rpm.spec_file.as_ref())
+    {
+        return build_rpm_from_spec(ctx, spec_file, output_dir).await;
+    }
+
     let name = [
         &ctx.build_ctx.recipe.metadata.name,
         "-",
         &ctx.build_ctx.recipe.metadata.version,
     ]
     .join("");
-    let release = ctx.build_ctx.recipe.metadata.release();
-    let arch = ctx.build_ctx.recipe.metadata.arch.rpm_name();
+    let release = ctx.build_ctx.release();
+    let arch = ctx.build_ctx.arch().rpm_name();
     let buildroot_name = [&name, "-", &release, ".", &arch].join("");
     let source_tar = [&name, ".tar.gz"].join("");
 
@@ -54,13 +67,11 @@ pub(crate) async fn build_rpm(
         trace!("copy source files to temporary location");
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "cp -rv {} {}",
-                    ctx.build_ctx.container_out_dir.display(),
-                    tmp_buildroot.display(),
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "cp -rv {} {}",
+                ctx.build_ctx.container_out_dir.display(),
+                tmp_buildroot.display(),
+            )),
         )
         .await
         .context("failed to copy source files to temp directory")?;
@@ -70,29 +81,14 @@ pub(crate) async fn build_rpm(
             &ctx,
             &ExecOpts::default()
                 .cmd(&format!("tar -zcvf {} .", source_tar_path.display(),))
-                .working_dir(tmp_buildroot.as_path())
-                .build(),
+                .working_dir(tmp_buildroot.as_path()),
         )
         .await?;
 
         trace!("find source file paths");
-        let files = checked_exec(
-            &ctx,
-            &ExecOpts::default()
-                .cmd(r#"find . -type f -o -type l -name "*""#)
-                .working_dir(&ctx.build_ctx.container_out_dir)
-                .build(),
-        )
-        .await
-        .map(|out| {
-            out.stdout
-                .join("")
-                .split_ascii_whitespace()
-                .filter(|s| !s.is_empty())
-                .map(|s| s.trim_start_matches('.').to_string())
-                .collect::<Vec<_>>()
-        })
-        .context("failed to find source files")?;
+        let files = output_files(ctx)
+            .await
+            .context("failed to find source files")?;
         trace!(source_files = ?files);
 
         let spec = cloned_span.in_scope(|| {
@@ -111,45 +107,169 @@ pub(crate) async fn build_rpm(
         let spec_tar_path = specs.join([&name, "-spec.tar"].join(""));
 
         trace!("copy spec archive to container");
-        ctx.container
-            .inner()
-            .copy_file_into(spec_tar_path.as_path(), &spec_tar)
+        copy_file_into(ctx, spec_tar_path.as_path(), &spec_tar)
             .await
             .context("failed to copy archive with spec")?;
 
         trace!("extract spec archive");
         checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "tar -xvf {} -C {}",
-                    spec_tar_path.display(),
-                    specs.display(),
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "tar -xvf {} -C {}",
+                spec_tar_path.display(),
+                specs.display(),
+            )),
         )
         .await?;
 
         trace!("rpmbuild");
-        checked_exec(
+        let out = checked_exec(
             &ctx,
-            &ExecOpts::default()
-                .cmd(&format!(
-                    "setarch {0} rpmbuild -bb --target {0} {1}",
-                    ctx.build_ctx.recipe.metadata.arch.rpm_name(),
-                    specs.join(spec_file).display()
-                ))
-                .build(),
+            &ExecOpts::default().cmd(&format!(
+                "setarch {0} rpmbuild -bb --target {0} {1}",
+                ctx.build_ctx.arch().rpm_name(),
+                specs.join(spec_file).display()
+            )),
         )
         .await
         .context("failed to build rpm package")?;
 
-        ctx.container
-            .download_files(rpms.join(&arch).as_path(), output_dir)
+        download_written_packages(ctx, &out.stdout.join(""), output_dir).await
+    }
+    .instrument(span)
+    .await
+}
+
+/// Returns absolute paths of all files and symlinks installed to the output directory of the build
+pub(crate) async fn output_files(ctx: &Context<'_>) -> Result<Vec<String>> {
+    checked_exec(
+        ctx,
+        &ExecOpts::default()
+            .cmd(r#"find . -type f -o -type l -name "*""#)
+            .working_dir(&ctx.build_ctx.container_out_dir),
+    )
+    .await
+    .map(|out| {
+        out.stdout
+            .join("")
+            .split_ascii_whitespace()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.trim_start_matches('.').to_string())
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Creates RPM packages using an existing spec file from the recipe directory. All files located
+/// next to the spec file are treated as additional sources.
+async fn build_rpm_from_spec(
+    ctx: &Context<'_>,
+    spec_file: &str,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let span = info_span!("RPM", spec = %spec_file);
+    async move {
+        info!("building RPM package from spec file");
+
+        let recipe = &ctx.build_ctx.recipe;
+        let spec_path = recipe.recipe_dir.join(spec_file);
+        let spec_name = spec_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| Error::msg(format!("invalid spec file `{}`", spec_file)))?;
+        let spec_dir = spec_path.parent().unwrap_or(&recipe.recipe_dir);
+
+        let base_path = PathBuf::from("/root/rpmbuild");
+        let specs = base_path.join("SPECS");
+        let sources = base_path.join("SOURCES");
+        let rpms = base_path.join("RPMS");
+        let srpms = base_path.join("SRPMS");
+
+        let dirs = [
+            specs.as_path(),
+            sources.as_path(),
+            rpms.as_path(),
+            srpms.as_path(),
+        ];
+
+        create_dirs(ctx, &dirs[..])
             .await
-            .map(|_| output_dir.join(format!("{}.rpm", buildroot_name)))
-            .context("failed to download finished package")
+            .context("failed to create directories")?;
+
+        copy_dir_into(ctx, spec_dir, &sources)
+            .await
+            .context("failed to copy spec directory")?;
+
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "mv {} {}",
+                sources.join(&spec_name).display(),
+                specs.display()
+            )),
+        )
+        .await?;
+
+        trace!("copy sources");
+        let source_name = [&recipe.metadata.name, "-", &recipe.metadata.version].join("");
+        let cmd = if recipe.metadata.git.is_some() {
+            // a cloned repository has no archive, create one like `git archive` would
+            format!(
+                "cp -r {0} /tmp/{1} && tar -czf {2}/{1}.tar.gz -C /tmp {1}",
+                ctx.build_ctx.container_bld_dir.display(),
+                source_name,
+                sources.display(),
+            )
+        } else {
+            format!(
+                "cp -rv {}/. {}",
+                ctx.build_ctx.container_tmp_dir.display(),
+                sources.display()
+            )
+        };
+        checked_exec(ctx, &ExecOpts::default().cmd(&cmd))
+            .await
+            .context("failed to copy sources")?;
+
+        trace!("rpmbuild");
+        let out = checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "setarch {0} rpmbuild -bb --target {0} {1}",
+                ctx.build_ctx.arch().rpm_name(),
+                specs.join(&spec_name).display()
+            )),
+        )
+        .await
+        .context("failed to build rpm package")?;
+
+        download_written_packages(ctx, &out.stdout.join(""), output_dir).await
     }
     .instrument(span)
     .await
 }
+
+/// Downloads all packages that `rpmbuild` reported as written in `output`, including
+/// subpackages, and returns the path of the main package
+pub(crate) async fn download_written_packages(
+    ctx: &Context<'_>,
+    output: &str,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let packages = rpmbuild_written_packages(output);
+    debug!(packages = ?packages);
+
+    let metadata = &ctx.build_ctx.recipe.metadata;
+    let package = main_package(&packages, &metadata.name, &metadata.version, "-")
+        .ok_or_else(|| Error::msg("rpmbuild didn't report any written packages"))?;
+
+    for path in &packages {
+        let filter = DownloadFilter::default()
+            .expect(path.file_name().unwrap_or_default().to_string_lossy());
+        ctx.container
+            .download_files_matching(path, output_dir, &filter)
+            .await
+            .context(format!("failed to download package `{}`", path.display()))?;
+    }
+
+    Ok(output_dir.join(package.file_name().unwrap_or_default()))
+}
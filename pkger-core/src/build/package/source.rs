@@ -0,0 +1,307 @@
+//! Source packages built next to the binary package for distribution build services. SRPMs are
+//! built with `rpmbuild -bs` and Debian source packages with `dpkg-source -b` from the pristine
+//! source of the recipe and either the native packaging files of the recipe or the ones exported
+//! from it.
+use crate::archive::create_tarball;
+use crate::build::container::{checked_exec, copy_file_into, create_dirs, Context};
+use crate::build::package::rpm::{download_written_packages, output_files};
+use crate::build::remote::copy_dir_into;
+use crate::container::{DownloadFilter, ExecOpts};
+use crate::image::ImageState;
+use crate::recipe::BuildTarget;
+use crate::{ErrContext, Error, Result};
+
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+/// Directory in the container where source packages are built
+static SOURCE_DIR: &str = "/root/pkger-source";
+
+/// Builds the source package of the target of the build and saves it to `output_dir`. Returns the
+/// paths of the saved files.
+pub(crate) async fn build_source_package(
+    ctx: &Context<'_>,
+    image_state: &ImageState,
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    match ctx.build_ctx.target.build_target() {
+        BuildTarget::Rpm => build_srpm(ctx, image_state, output_dir)
+            .await
+            .map(|srpm| vec![srpm]),
+        BuildTarget::Deb => build_debian_source(ctx, image_state, output_dir).await,
+        target => {
+            warn!(target = %target.as_ref(), "source packages can only be built for RPM and DEB targets, skipping");
+            Ok(vec![])
+        }
+    }
+}
+
+/// Name of the directory with the pristine source, like `foo-1.0`
+fn source_dir_name(ctx: &Context<'_>) -> String {
+    let metadata = &ctx.build_ctx.recipe.metadata;
+    format!("{}-{}", metadata.name, metadata.version)
+}
+
+/// Extracts the fetched source to `dest` as it was before patches were applied and scripts ran.
+/// A git repository is exported with `git archive`.
+async fn extract_pristine_source(ctx: &Context<'_>, dest: &Path) -> Result<()> {
+    let build_ctx = ctx.build_ctx;
+    create_dirs(ctx, &[dest]).await?;
+    let cmd = if build_ctx.recipe.metadata.git.is_some() {
+        format!(
+            "git -C {} archive --format=tar HEAD | tar -x -C {}",
+            build_ctx.container_bld_dir.display(),
+            dest.display()
+        )
+    } else if build_ctx.recipe.metadata.source.is_some() {
+        format!(
+            r#"
+            for file in $(find . -maxdepth 1 -type f ! -name patches.tar);
+            do
+                if [[ $file == *.tar* ]]
+                then
+                    tar xf $file -C {0}
+                elif [[ $file == *.zip ]]
+                then
+                    unzip -q $file -d {0}
+                else
+                    cp $file {0}
+                fi
+            done"#,
+            dest.display()
+        )
+    } else {
+        return Ok(());
+    };
+    checked_exec(
+        ctx,
+        &ExecOpts::default()
+            .cmd(&cmd)
+            .working_dir(&build_ctx.container_tmp_dir)
+            .shell("/bin/bash"),
+    )
+    .await
+    .map(|_| ())
+    .context("failed to extract pristine source")
+}
+
+async fn build_srpm(
+    ctx: &Context<'_>,
+    image_state: &ImageState,
+    output_dir: &Path,
+) -> Result<PathBuf> {
+    let span = info_span!("SRPM");
+    async move {
+        info!("building source RPM package");
+        let build_ctx = ctx.build_ctx;
+        let recipe = &build_ctx.recipe;
+
+        if let Some(spec_file) = recipe
+            .metadata
+            .rpm
+            .as_ref()
+            .and_then(|rpm| rpm.spec_file.as_ref())
+        {
+            // the spec file and its sources were already prepared for the binary package
+            let spec_name = Path::new(spec_file).file_name().unwrap_or_default();
+            let out = checked_exec(
+                ctx,
+                &ExecOpts::default().cmd(&format!(
+                    "rpmbuild -bs /root/rpmbuild/SPECS/{}",
+                    spec_name.to_string_lossy()
+                )),
+            )
+            .await
+            .context("failed to build source rpm package")?;
+            return download_written_packages(ctx, &out.stdout.join(""), output_dir).await;
+        }
+
+        // a separate tree so that sources of the binary package don't end up in the SRPM
+        let top_dir = Path::new(SOURCE_DIR).join("rpmbuild");
+        let specs = top_dir.join("SPECS");
+        let sources = top_dir.join("SOURCES");
+        create_dirs(ctx, &[specs.as_path(), sources.as_path()])
+            .await
+            .context("failed to create directories")?;
+
+        trace!("copy sources");
+        let mut cmds = vec![];
+        if recipe.metadata.git.is_some() {
+            let pristine = Path::new(SOURCE_DIR).join(source_dir_name(ctx));
+            extract_pristine_source(ctx, &pristine).await?;
+            cmds.push(format!(
+                "tar -czf {}/{}.tar.gz -C {} .",
+                sources.display(),
+                source_dir_name(ctx),
+                pristine.display()
+            ));
+        } else if let Some(source) = &recipe.metadata.source {
+            let file_name = source.rsplit('/').next().unwrap_or_default();
+            cmds.push(format!(
+                "cp {} {}",
+                build_ctx.container_tmp_dir.join(file_name).display(),
+                sources.display()
+            ));
+        }
+        for source in &recipe.metadata.extra_sources {
+            let file_name = source.rsplit('/').next().unwrap_or_default();
+            cmds.push(format!(
+                "cp {} {}",
+                build_ctx.container_tmp_dir.join(file_name).display(),
+                sources.display()
+            ));
+        }
+        let patch_dir = build_ctx.container_tmp_dir.join("patches");
+        for patch in recipe
+            .metadata
+            .patches
+            .as_ref()
+            .map(|patches| patches.resolve_names(&image_state.image))
+            .unwrap_or_default()
+        {
+            let src = patch.patch();
+            let file_name = src.rsplit('/').next().unwrap_or_default();
+            // collected patches keep relative paths from the recipe directory
+            let location = if src.starts_with("http") || Path::new(src).is_absolute() {
+                patch_dir.join(file_name)
+            } else {
+                patch_dir.join(src)
+            };
+            cmds.push(format!(
+                "cp {} {}",
+                location.display(),
+                sources.join(file_name).display()
+            ));
+        }
+        if !cmds.is_empty() {
+            checked_exec(ctx, &ExecOpts::default().cmd(&cmds.join(" && ")))
+                .await
+                .context("failed to copy sources")?;
+        }
+
+        let files = output_files(ctx)
+            .await
+            .context("failed to find installed files")?;
+        let spec = recipe.source_rpm_spec(&image_state.image, &files).render();
+        debug!(spec = %spec);
+        let spec_path = specs.join(format!("{}.spec", recipe.metadata.name));
+        copy_file_into(ctx, &spec_path, spec.as_bytes())
+            .await
+            .context("failed to copy spec")?;
+
+        trace!("rpmbuild");
+        let out = checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "rpmbuild -bs --define '_topdir {}' {}",
+                top_dir.display(),
+                spec_path.display()
+            )),
+        )
+        .await
+        .context("failed to build source rpm package")?;
+
+        download_written_packages(ctx, &out.stdout.join(""), output_dir).await
+    }
+    .instrument(span)
+    .await
+}
+
+async fn build_debian_source(
+    ctx: &Context<'_>,
+    image_state: &ImageState,
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let span = info_span!("DSC");
+    async move {
+        info!("building Debian source package");
+        let recipe = &ctx.build_ctx.recipe;
+        let source_dir = PathBuf::from(SOURCE_DIR).join("debian");
+        let dir_name = source_dir_name(ctx);
+        let pristine = source_dir.join(&dir_name);
+        extract_pristine_source(ctx, &pristine).await?;
+
+        trace!("create orig archive");
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!(
+                    "tar -czf {}_{}.orig.tar.gz {}",
+                    recipe.metadata.name, recipe.metadata.version, dir_name
+                ))
+                .working_dir(&source_dir),
+        )
+        .await
+        .context("failed to create orig archive")?;
+
+        let debian_dir = pristine.join("debian");
+        match recipe.metadata.deb.as_ref().and_then(|deb| deb.debian_dir.as_ref()) {
+            Some(dir) => copy_dir_into(ctx, &recipe.recipe_dir.join(dir), &debian_dir)
+                .await
+                .context("failed to copy debian directory")?,
+            None => {
+                let entries = recipe.export_debian_dir(&image_state.image).entries()?;
+                let archive = create_tarball(
+                    entries
+                        .iter()
+                        .map(|(path, content)| (path, content.as_slice())),
+                )?;
+                let archive_path = source_dir.join("debian.tar");
+                copy_file_into(ctx, &archive_path, &archive)
+                    .await
+                    .context("failed to copy debian directory")?;
+                create_dirs(ctx, &[debian_dir.as_path()]).await?;
+                checked_exec(
+                    ctx,
+                    &ExecOpts::default().cmd(&format!(
+                        "tar -xf {} -C {} && rm {0}",
+                        archive_path.display(),
+                        debian_dir.display()
+                    )),
+                )
+                .await
+                .context("failed to extract debian directory")?;
+            }
+        }
+
+        trace!("dpkg-source");
+        let out = checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!(
+                    "chmod +x {0}/debian/rules && dpkg-source -b {0} >&2 && ls *.dsc *.orig.tar.* *.debian.tar.* 2>/dev/null",
+                    dir_name
+                ))
+                .working_dir(&source_dir)
+                .shell("/bin/bash"),
+        )
+        .await
+        .context("failed to build debian source package")?;
+
+        let files = out
+            .stdout
+            .join("")
+            .split_ascii_whitespace()
+            .map(|file| file.to_string())
+            .collect::<Vec<_>>();
+        debug!(files = ?files);
+        if !files.iter().any(|file| file.ends_with(".dsc")) {
+            return Err(Error::msg("dpkg-source didn't create a .dsc file"));
+        }
+
+        for file in &files {
+            ctx.container
+                .download_files_matching(
+                    &source_dir.join(file),
+                    output_dir,
+                    &DownloadFilter::default().expect(file.as_str()),
+                )
+                .await
+                .context(format!("failed to download `{}`", file))?;
+        }
+
+        Ok(files.iter().map(|file| output_dir.join(file)).collect())
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,21 @@
+//! Progress of a running build observed from outside of it, for example to report which phase a
+//! long build is in.
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Default)]
+/// Shared handle to the phase a build is currently in
+pub struct Progress(Arc<Mutex<String>>);
+
+impl Progress {
+    /// Returns the phase the build is in or an empty string if it hasn't started yet
+    pub fn phase(&self) -> String {
+        self.0.lock().map(|phase| phase.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn enter(&self, phase: &str) {
+        if let Ok(mut current) = self.0.lock() {
+            current.clear();
+            current.push_str(phase);
+        }
+    }
+}
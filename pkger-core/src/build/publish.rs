@@ -0,0 +1,58 @@
+//! Distribution of finished artifacts without a central server, like creating torrents or pinning
+//! the artifacts on IPFS. The content ids returned by publishers are recorded in the manifest.
+use crate::build::cache::blocking;
+use crate::{Error, Result};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, info_span, warn, Instrument};
+
+/// Publishes artifacts returning an id under which the content can be retrieved
+pub trait ArtifactPublisher: fmt::Debug + Send + Sync {
+    /// Name under which the content id is recorded in the manifest, like `ipfs`
+    fn name(&self) -> &str;
+
+    /// Publishes the package at `package` returning its content id
+    fn publish(&self, package: &Path) -> Result<String>;
+
+    /// Retrieves the contents of a package published under `id`
+    fn fetch(&self, id: &str) -> Result<Vec<u8>> {
+        Err(Error::msg(format!(
+            "publisher `{}` can't fetch `{}`, fetching artifacts is not supported",
+            self.name(),
+            id
+        )))
+    }
+}
+
+/// Publishes the package at `package` with all `publishers` returning the content ids mapped by
+/// the names of the publishers. Failures only produce a warning as the package itself is fine.
+pub async fn publish_all(
+    publishers: &[Arc<dyn ArtifactPublisher>],
+    package: &Path,
+) -> BTreeMap<String, String> {
+    let span = info_span!("publish", package = %package.display());
+    async move {
+        let mut ids = BTreeMap::new();
+        for publisher in publishers {
+            let name = publisher.name().to_string();
+            let result = {
+                let publisher = publisher.clone();
+                let package = package.to_path_buf();
+                blocking(move || publisher.publish(&package)).await
+            };
+            match result {
+                Ok(id) => {
+                    info!(publisher = %name, id = %id, "published artifact");
+                    ids.insert(name, id);
+                }
+                Err(e) => warn!(publisher = %name, reason = %e, "failed to publish artifact"),
+            }
+        }
+        ids
+    }
+    .instrument(span)
+    .await
+}
@@ -1,43 +1,93 @@
 use crate::archive::create_tarball;
-use crate::build::container::{checked_exec, Context};
+use crate::build::container::{checked_exec, copy_file_into, Context};
 use crate::container::ExecOpts;
-use crate::recipe::GitSource;
-use crate::Result;
+use crate::failure::FailureKind;
+use crate::recipe::{Checksum, GitSource};
+use crate::{ErrContext, Error, Result};
 
 use std::fs;
 use std::path::{Path, PathBuf};
-use tracing::{debug, info, info_span, Instrument};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 pub async fn clone_git_to_bld_dir(ctx: &Context<'_>, repo: &GitSource) -> Result<()> {
     let span = info_span!("clone-git");
     async move {
-                info!(repo = %repo.url(), branch = %repo.branch(), out_dir = %ctx.build_ctx.container_bld_dir.display(), "cloning git source repository to build directory");
-                checked_exec(
-                    &ctx,
-                    &ExecOpts::default().cmd(
-                    &format!(
-                    "git clone -j 8 --single-branch --branch {} --recurse-submodules -- {} {}",
-                    repo.branch(),
+                info!(repo = %repo.url(), reference = %repo.reference(), commit = ?repo.commit(), depth = ?repo.depth(), out_dir = %ctx.build_ctx.container_bld_dir.display(), "cloning git source repository to build directory");
+                let bld_dir = &ctx.build_ctx.container_bld_dir;
+                let credentials = match ctx.build_ctx.source_credentials(repo.url()) {
+                    Some(credentials) => credentials,
+                    None => {
+                        return checked_exec(
+                            ctx,
+                            &ExecOpts::default().cmd(&repo.clone_command(bld_dir, "git")),
+                        )
+                        .await
+                        .map(|_| ())
+                    }
+                };
+                info!(host = %credentials.host, "authenticating with source credentials");
+
+                let ssh = match credentials.ssh_key_for(repo.url()) {
+                    Some(key) => {
+                        let data = fs::read(key)
+                            .context(format!("failed to read ssh key `{}`", key.display()))?;
+                        let known_hosts = credentials.known_hosts_path().ok_or_else(|| {
+                            Error::msg("no known hosts to verify the host of the repository, set `known_hosts` of the credentials")
+                        })?;
+                        let hosts = fs::read(&known_hosts).context(format!(
+                            "failed to read known hosts `{}`",
+                            known_hosts.display()
+                        ))?;
+                        let key_path = ctx.build_ctx.container_tmp_dir.join(".ssh-key");
+                        let hosts_path = ctx.build_ctx.container_tmp_dir.join(".ssh-known-hosts");
+                        copy_file_into(ctx, &key_path, &data).await?;
+                        copy_file_into(ctx, &hosts_path, &hosts).await?;
+                        Some((key_path, hosts_path))
+                    }
+                    None => None,
+                };
+                let (git, env) = credentials.git_command(
                     repo.url(),
-                    ctx.build_ctx.container_bld_dir.display()
-                )).build())
-                .await
-                .map(|_| ())
+                    ssh.as_ref()
+                        .map(|(key, hosts)| (key.as_path(), hosts.as_path())),
+                );
+                let cmd = match &ssh {
+                    // the key is removed in the same command so that it never ends up in an image
+                    Some((key, hosts)) => format!(
+                        "chmod 600 {0} && ( {2} ); status=$?; rm -f {0} {1}; exit $status",
+                        key.display(),
+                        hosts.display(),
+                        repo.clone_command(bld_dir, &git)
+                    ),
+                    None => repo.clone_command(bld_dir, &git),
+                };
+                checked_exec(ctx, &ExecOpts::default().cmd(&cmd).env(&env))
+                    .await
+                    .map(|_| ())
         }
         .instrument(span)
         .await
 }
 
+/// Arguments of curl and the environment authenticating a download of `url`
+fn curl_auth(ctx: &Context<'_>, url: &str) -> (String, Vec<String>) {
+    match ctx.build_ctx.source_credentials(url) {
+        Some(credentials) => (credentials.curl_args(), credentials.env()),
+        None => (String::new(), vec![]),
+    }
+}
+
 pub async fn get_http_source(ctx: &Context<'_>, source: &str, dest: &Path) -> Result<()> {
     let span = info_span!("download-http");
     async move {
         info!(url = %source, destination = %dest.display(), "fetching");
+        let (auth, env) = curl_auth(ctx, source);
         checked_exec(
             &ctx,
             &ExecOpts::default()
-                .cmd(&format!("curl -LO {}", source))
+                .cmd(&format!("curl{} -LO {}", auth, source))
                 .working_dir(dest)
-                .build(),
+                .env(&env),
         )
         .await
         .map(|_| ())
@@ -46,6 +96,127 @@ pub async fn get_http_source(ctx: &Context<'_>, source: &str, dest: &Path) -> Re
     .await
 }
 
+/// Downloads the file at `url` as `file` in `dest` verifying it against `checksum`. A file that
+/// doesn't match is removed. Returns whether the download failed because of a mismatch.
+async fn download_verified(
+    ctx: &Context<'_>,
+    url: &str,
+    file: &str,
+    checksum: Option<&Checksum>,
+    dest: &Path,
+) -> std::result::Result<(), (Error, bool)> {
+    info!(url = %url, destination = %dest.display(), "fetching");
+    let (auth, env) = curl_auth(ctx, url);
+    checked_exec(
+        ctx,
+        &ExecOpts::default()
+            .cmd(&format!("curl{} -fL -o {} {}", auth, file, url))
+            .working_dir(dest)
+            .env(&env),
+    )
+    .await
+    .map_err(|e| (e, false))?;
+
+    let checksum = match checksum {
+        Some(checksum) => checksum,
+        None => return Ok(()),
+    };
+    let out = checked_exec(
+        ctx,
+        &ExecOpts::default()
+            .cmd(&format!("{} {}", checksum.command(), file))
+            .working_dir(dest),
+    )
+    .await
+    .map_err(|e| (e, false))?;
+    match checksum.mismatch(&out.stdout.join("")) {
+        None => Ok(()),
+        Some(actual) => {
+            let _ = checked_exec(
+                ctx,
+                &ExecOpts::default()
+                    .cmd(&format!("rm -f {}", file))
+                    .working_dir(dest),
+            )
+            .await;
+            Err((
+                Error::msg(format!(
+                    "checksum mismatch, expected {} but got {}",
+                    checksum, actual
+                )),
+                true,
+            ))
+        }
+    }
+}
+
+/// Downloads the source of the recipe to `dest` trying its mirrors in order when a download
+/// fails or doesn't match the checksum of the source. If every mirror served a file with a
+/// different checksum the error is classified as a verification failure.
+pub async fn download_source(ctx: &Context<'_>, source: &str, dest: &Path) -> Result<()> {
+    let span = info_span!("download-source");
+    async move {
+        let metadata = &ctx.build_ctx.recipe.metadata;
+        let file = file_name(source);
+        let urls = std::iter::once(source)
+            .chain(metadata.mirrors.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+
+        let mut errors = vec![];
+        let mut all_mismatched = true;
+        for url in &urls {
+            match download_verified(ctx, url, file, metadata.checksum.as_ref(), dest).await {
+                Ok(()) => return Ok(()),
+                Err((e, mismatch)) => {
+                    if urls.len() > 1 {
+                        warn!(url = %url, reason = %e, "failed to fetch source");
+                    }
+                    all_mismatched &= mismatch;
+                    errors.push(format!("{} - {}", url, e));
+                }
+            }
+        }
+
+        let err = Error::msg(errors.join("\n")).context(if urls.len() > 1 {
+            "failed to fetch source from all mirrors"
+        } else {
+            "failed to fetch source"
+        });
+        Err(if all_mismatched {
+            err.context(FailureKind::Verification)
+        } else {
+            err
+        })
+    }
+    .instrument(span)
+    .await
+}
+
+/// Returns the name of the file `source` is saved as in the container
+pub fn file_name(source: &str) -> &str {
+    let path = source.split(['?', '#']).next().unwrap_or(source);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Fetches a single file from an http/https `url` or a path on the host to `dest` in the container
+pub async fn fetch_file(ctx: &Context<'_>, url: &str, dest: &Path) -> Result<()> {
+    if url.starts_with("http") {
+        info!(url = %url, destination = %dest.display(), "fetching");
+        let (auth, env) = curl_auth(ctx, url);
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!("curl{} -fLo {} {}", auth, dest.display(), url))
+                .env(&env),
+        )
+        .await
+        .map(|_| ())
+    } else {
+        let data = fs::read(url).context(format!("failed to read `{}`", url))?;
+        copy_file_into(ctx, dest, &data).await
+    }
+}
+
 pub async fn copy_files_into(ctx: &Context<'_>, files: &[&Path], dest: &Path) -> Result<()> {
     let span = info_span!("copy-files-into");
     let mut entries = Vec::new();
@@ -60,28 +231,98 @@ pub async fn copy_files_into(ctx: &Context<'_>, files: &[&Path], dest: &Path) ->
 
     let archive = span.in_scope(|| create_tarball(entries.iter().map(|(p, b)| (p, &b[..]))))?;
 
-    ctx.container
-        .inner()
-        .copy_file_into(dest, &archive)
+    copy_file_into(ctx, dest, &archive)
         .instrument(span.clone())
         .await?;
 
     Ok(())
 }
 
+/// Recursively reads all files from `dir` returning their paths relative to `base` and contents
+fn read_dir_files(base: &Path, dir: &Path, files: &mut Vec<(PathBuf, Vec<u8>)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_dir_files(base, &path, files)?;
+        } else {
+            let relative = path.strip_prefix(base).unwrap_or(&path);
+            files.push((PathBuf::from(".").join(relative), fs::read(&path)?));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the whole directory `dir` from the host into `dest` directory of the container
+pub async fn copy_dir_into(ctx: &Context<'_>, dir: &Path, dest: &Path) -> Result<()> {
+    let span = info_span!("copy-dir-into", dir = %dir.display(), destination = %dest.display());
+    let cloned_span = span.clone();
+    async move {
+        let mut files = Vec::new();
+        read_dir_files(dir, dir, &mut files)
+            .context(format!("failed to read directory `{}`", dir.display()))?;
+
+        let archive =
+            cloned_span.in_scope(|| create_tarball(files.iter().map(|(p, b)| (p, &b[..]))))?;
+        let archive_path = PathBuf::from(format!("/tmp/{}-dir.tar", ctx.build_ctx.id));
+
+        copy_file_into(ctx, archive_path.as_path(), &archive)
+            .await
+            .context("failed to copy directory archive")?;
+
+        checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(&format!(
+                    "mkdir -p {1} && tar -xvf {0} -C {1} && rm -f {0}",
+                    archive_path.display(),
+                    dest.display(),
+                ))
+                .shell("/bin/bash"),
+        )
+        .await
+        .map(|_| ())
+    }
+    .instrument(span)
+    .await
+}
+
+/// Clones the git repository of the recipe to the build directory or saves its source archive in
+/// the temporary directory of the build. Additional sources are saved next to the source archive.
 pub async fn fetch_source(ctx: &Context<'_>) -> Result<()> {
     let span = info_span!("fetch");
     async move {
-        if let Some(repo) = &ctx.build_ctx.recipe.metadata.git {
+        let metadata = &ctx.build_ctx.recipe.metadata;
+        let tmp_dir = &ctx.build_ctx.container_tmp_dir;
+        if let Some(repo) = &metadata.git {
             clone_git_to_bld_dir(ctx, repo).await?;
-        } else if let Some(source) = &ctx.build_ctx.recipe.metadata.source {
+        } else if let Some(source) = &metadata.source {
             if source.starts_with("http") {
-                get_http_source(ctx, source.as_str(), &ctx.build_ctx.container_tmp_dir).await?;
+                download_source(ctx, source.as_str(), tmp_dir).await?;
             } else {
                 let src_path = PathBuf::from(source);
-                copy_files_into(ctx, &[src_path.as_path()], &ctx.build_ctx.container_tmp_dir)
-                    .await?;
+                copy_files_into(ctx, &[src_path.as_path()], tmp_dir).await?;
             }
+        }
+        for source in &metadata.extra_sources {
+            fetch_file(ctx, source, &tmp_dir.join(file_name(source)))
+                .await
+                .context(format!("failed to fetch source `{}`", source))?;
+        }
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
+
+/// Extracts the fetched source archive to the build directory, other files are copied as they are
+pub async fn unpack_source(ctx: &Context<'_>) -> Result<()> {
+    let span = info_span!("unpack");
+    async move {
+        let metadata = &ctx.build_ctx.recipe.metadata;
+        if (metadata.git.is_none() && metadata.source.is_some())
+            || !metadata.extra_sources.is_empty()
+        {
             checked_exec(
                 ctx,
                 &ExecOpts::default()
@@ -102,8 +343,7 @@ pub async fn fetch_source(ctx: &Context<'_>) -> Result<()> {
                         ctx.build_ctx.container_bld_dir.display(),
                     ))
                     .working_dir(&ctx.build_ctx.container_tmp_dir)
-                    .shell("/bin/bash")
-                    .build(),
+                    .shell("/bin/bash"),
             )
             .await?;
         }
@@ -0,0 +1,83 @@
+//! Checks of RPATHs of ELF binaries in the build output. Distribution policy checkers reject
+//! packages with RPATHs pointing to the build directory, so insecure entries either fail the
+//! build or get rewritten with `patchelf`.
+use crate::build::container::{checked_exec, Context};
+use crate::build::transcript::quote;
+use crate::container::ExecOpts;
+use crate::recipe::RpathOptions;
+use crate::{Error, Result};
+
+use tracing::{info, info_span, trace, Instrument};
+
+/// Prints the path and RPATH of every ELF file with an RPATH separated by a tab
+static SCAN_COMMAND: &str = r#"find . -type f | while IFS= read -r f; do r=$(patchelf --print-rpath "$f" 2>/dev/null) && [ -n "$r" ] && printf '%s\t%s\n' "$f" "$r"; done; true"#;
+
+fn parse_scan(output: &str) -> Vec<(&str, &str)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(file, rpath)| (file.trim_start_matches('.'), rpath.trim()))
+        .collect()
+}
+
+pub async fn check(ctx: &Context<'_>, opts: &RpathOptions) -> Result<()> {
+    let span = info_span!("check-rpaths");
+    async move {
+        let out_dir = &ctx.build_ctx.container_out_dir;
+        let output = checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(SCAN_COMMAND).working_dir(out_dir),
+        )
+        .await?
+        .stdout
+        .join("");
+
+        let mut insecure = vec![];
+        for (file, rpath) in parse_scan(&output) {
+            let entries = opts.insecure_entries(rpath);
+            if entries.is_empty() {
+                continue;
+            }
+            trace!(file = %file, rpath = %rpath, insecure = ?entries);
+
+            match opts.rewritten(rpath) {
+                Some(new_rpath) => {
+                    let path = out_dir.join(file.trim_start_matches('/'));
+                    let path = quote(&path.to_string_lossy());
+                    let cmd = if new_rpath.is_empty() {
+                        format!("patchelf --remove-rpath {}", path)
+                    } else {
+                        format!("patchelf --set-rpath {} {}", quote(&new_rpath), path)
+                    };
+                    checked_exec(ctx, &ExecOpts::default().cmd(&cmd)).await?;
+                    info!(file = %file, old = %rpath, new = %new_rpath, "rewrote rpath");
+                }
+                None => insecure.push(format!("{} ({})", file, entries.join(":"))),
+            }
+        }
+
+        if insecure.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "found insecure rpaths in {}, allow them in `rpath.allowed` or set `rpath.rewrite`",
+                insecure.join(", ")
+            )))
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scan_output() {
+        assert_eq!(
+            parse_scan("./usr/bin/foo\t/tmp/build/lib:$ORIGIN\nnot an elf\n"),
+            vec![("/usr/bin/foo", "/tmp/build/lib:$ORIGIN")]
+        );
+    }
+}
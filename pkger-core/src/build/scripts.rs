@@ -1,9 +1,12 @@
+use crate::build::checkpoint::{self, Phase};
 use crate::build::container::{checked_exec, Context};
+use crate::build::snapshot;
 use crate::container::ExecOpts;
-use crate::{Error, Result};
+use crate::image::ImageState;
+use crate::{ErrContext, Error, Result};
 
 use std::path::PathBuf;
-use tracing::{debug, info, info_span, trace, Instrument};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 
 macro_rules! run_script {
     ($phase:literal, $script:expr, $dir:expr,  $ctx:ident) => {{
@@ -11,6 +14,13 @@ macro_rules! run_script {
         async move {
             trace!(script = ?$script);
             info!(concat!("executing ", $phase, " scripts"));
+            $ctx.build_ctx.progress.enter($phase);
+            $ctx.build_ctx
+                .record(|transcript| transcript.phase(concat!($phase, " script")));
+            match snapshot::take(&$ctx, $phase).await {
+                Ok(snapshot) => $ctx.build_ctx.add_snapshot(snapshot),
+                Err(e) => warn!(reason = %e, "failed to snapshot environment"),
+            }
             let mut opts = ExecOpts::default();
             let mut _dir;
 
@@ -34,25 +44,38 @@ macro_rules! run_script {
             }
 
             for cmd in &$script.steps {
-                if let Some(images) = &cmd.images {
-                    trace!(images = ?images, "only execute on");
-                    if !images.contains(&$ctx.build_ctx.target.image().to_owned()) {
-                        trace!(image = %$ctx.build_ctx.target.image(), "not found in images");
-                        if !cmd.has_target_specified() {
-                            debug!(command = %cmd.cmd, "skipping, excluded by image filter");
-                            continue;
-                        }
-                    }
+                if !cmd.should_run(
+                    $ctx.build_ctx.target.image(),
+                    $ctx.build_ctx.target.build_target(),
+                    $ctx.build_ctx.arch(),
+                ) {
+                    debug!(command = %cmd.cmd, "skipping, shouldn't run on image, target or arch");
+                    continue;
                 }
-
-                if !cmd.should_run_on($ctx.build_ctx.target.build_target()) {
-                    debug!(command = %cmd.cmd, "skipping, shouldn't run on target");
+                if !cmd.should_run_with(&$ctx.build_ctx.recipe.metadata.enabled_features) {
+                    debug!(command = %cmd.cmd, "skipping, shouldn't run with enabled features");
                     continue;
                 }
 
-                debug!(command = %cmd.cmd, "running");
-                checked_exec(&$ctx, &opts.clone().cmd(&cmd.cmd).build())
-                    .await?;
+                if cmd.is_privileged() {
+                    if !$ctx.build_ctx.allow_privileged {
+                        return Err(Error::msg(format!(
+                            "recipe `{}` is not allowed to run privileged steps, add it to `privileged_recipes` in the configuration",
+                            $ctx.build_ctx.recipe.metadata.name
+                        )));
+                    }
+                    warn!(command = %cmd.cmd, "running with extended privileges");
+                } else {
+                    debug!(command = %cmd.cmd, "running");
+                }
+                let cmd_opts = opts
+                    .clone()
+                    .cmd(&cmd.cmd)
+                    .privileged(cmd.is_privileged())
+                    .limit_output(true);
+                checked_exec(&$ctx, &cmd_opts)
+                    .await
+                    .context(concat!("failed to run ", $phase, " script"))?;
             }
 
             Ok::<_, Error>(())
@@ -62,30 +85,55 @@ macro_rules! run_script {
     }};
 }
 
-pub async fn execute_scripts(ctx: &Context<'_>) -> Result<()> {
+/// Executes the scripts of the recipe skipping phases completed before the checkpoint the build
+/// was `resumed` from. A checkpoint is saved after each phase if checkpoints are enabled.
+pub async fn execute_scripts(
+    ctx: &Context<'_>,
+    image_state: &ImageState,
+    resumed: Option<Phase>,
+) -> Result<()> {
     let span = info_span!("exec-scripts");
     async move {
-        if let Some(config_script) = &ctx.build_ctx.recipe.configure_script {
+        let checkpoints = ctx.build_ctx.checkpoints_enabled();
+
+        if Phase::Configure.is_completed(resumed) {
+            info!("configure phase completed in checkpoint");
+        } else if let Some(config_script) = &ctx.build_ctx.recipe.configure_script {
             run_script!(
                 "configure",
                 config_script,
                 &ctx.build_ctx.container_bld_dir,
                 ctx
             );
+            if checkpoints {
+                checkpoint::save(ctx, image_state, Phase::Configure).await;
+            }
         } else {
             info!("no configure steps to run");
         }
 
-        let build_script = &ctx.build_ctx.recipe.build_script;
-        run_script!("build", build_script, &ctx.build_ctx.container_bld_dir, ctx);
+        if Phase::Build.is_completed(resumed) {
+            info!("build phase completed in checkpoint");
+        } else {
+            let build_script = &ctx.build_ctx.recipe.build_script;
+            run_script!("build", build_script, &ctx.build_ctx.container_bld_dir, ctx);
+            if checkpoints {
+                checkpoint::save(ctx, image_state, Phase::Build).await;
+            }
+        }
 
-        if let Some(install_script) = &ctx.build_ctx.recipe.install_script {
+        if Phase::Install.is_completed(resumed) {
+            info!("install phase completed in checkpoint");
+        } else if let Some(install_script) = &ctx.build_ctx.recipe.install_script {
             run_script!(
                 "install",
                 install_script,
                 &ctx.build_ctx.container_out_dir,
                 ctx
             );
+            if checkpoints {
+                checkpoint::save(ctx, image_state, Phase::Install).await;
+            }
         } else {
             info!("no install steps to run");
         }
@@ -0,0 +1,138 @@
+//! Signing of built packages with GnuPG of the host running pkger. The private key stays in the
+//! gpg-agent, or a keychain it is backed by, and the passphrase is asked for by its pinentry, so
+//! the key never has to be exported to a file on the build host.
+use crate::{ErrContext, Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use tracing::{info, info_span, trace};
+
+pub static DEFAULT_GPG: &str = "gpg";
+pub static SIGNATURE_EXTENSION: &str = "asc";
+
+/// Held while gpg runs so that concurrent builds don't prompt for the passphrase at once
+static SIGNING: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SigningConfig {
+    /// Id, fingerprint or user id of the key used for signing
+    pub key: String,
+    /// Path to the gpg binary, defaults to `gpg` from `$PATH`
+    pub gpg: Option<PathBuf>,
+    /// GnuPG home directory, for example one with the socket of an agent forwarded from another
+    /// machine. Defaults to the home directory of the current user.
+    pub homedir: Option<PathBuf>,
+}
+
+/// Returns the location of the detached signature of the given package
+pub fn signature_path(package: &Path) -> PathBuf {
+    let mut filename = package.file_name().unwrap_or_default().to_os_string();
+    filename.push(".");
+    filename.push(SIGNATURE_EXTENSION);
+    package.with_file_name(filename)
+}
+
+/// Creates an ASCII armored detached signature next to `package` and returns its path. gpg runs
+/// on a blocking thread.
+pub async fn sign_package(config: &SigningConfig, package: &Path) -> Result<PathBuf> {
+    let signature = signature_path(package);
+    let span = info_span!("sign-package", key = %config.key, package = %package.display());
+
+    let (config, package, output) = (config.clone(), package.to_path_buf(), signature.clone());
+    let sign_span = span.clone();
+    tokio::task::spawn_blocking(move || {
+        let _enter = sign_span.enter();
+        sign_file(&config, &package, &output, false)
+    })
+    .await
+    .context("signing was interrupted")??;
+
+    let _enter = span.enter();
+    info!(signature = %signature.display(), "signed package");
+    Ok(signature)
+}
+
+/// Signs `file` saving the ASCII armored signature to `output`. With `clearsign` the output
+/// contains the signed text of the file, otherwise the signature is detached. Only one file is
+/// signed at a time.
+pub fn sign_file(
+    config: &SigningConfig,
+    file: &Path,
+    output: &Path,
+    clearsign: bool,
+) -> Result<()> {
+    let mut cmd = gpg_command(config);
+    cmd.args(["--local-user", config.key.as_str()])
+        .arg("--armor")
+        .arg(if clearsign {
+            "--clearsign"
+        } else {
+            "--detach-sign"
+        })
+        .args(["--yes", "--output"])
+        .arg(output)
+        .arg(file)
+        // pinentry may need the terminal to ask for the passphrase
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::null());
+
+    let _signing = SIGNING.lock().unwrap_or_else(|e| e.into_inner());
+    run_gpg(cmd)
+}
+
+/// Saves the ASCII armored public key used for signing to `output` so that clients can import it
+/// to verify signatures
+pub fn export_public_key(config: &SigningConfig, output: &Path) -> Result<()> {
+    if output.exists() {
+        fs::remove_file(output).context(format!("failed to remove `{}`", output.display()))?;
+    }
+    let mut cmd = gpg_command(config);
+    cmd.args(["--armor", "--export", "--yes", "--output"])
+        .arg(output)
+        .arg(&config.key)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    run_gpg(cmd)?;
+
+    // gpg only warns and writes nothing when the key is not in the keyring
+    if fs::metadata(output)
+        .map(|meta| meta.len() == 0)
+        .unwrap_or(true)
+    {
+        return Err(Error::msg(format!(
+            "public key `{}` was not found in the keyring",
+            config.key
+        )));
+    }
+    Ok(())
+}
+
+fn gpg_command(config: &SigningConfig) -> Command {
+    let mut cmd = Command::new(
+        config
+            .gpg
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_GPG)),
+    );
+    if let Some(homedir) = &config.homedir {
+        cmd.arg("--homedir").arg(homedir);
+    }
+    cmd
+}
+
+fn run_gpg(mut cmd: Command) -> Result<()> {
+    trace!(command = ?cmd);
+    let output = cmd.output().context("failed to run gpg")?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "gpg exited with {}\nstderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Verification of upstream GPG signatures of sources. The trusted keys are fetched into a
+//! separate keyring in the container so that only they can make a signature valid.
+use crate::build::container::{checked_exec, Context};
+use crate::build::remote;
+use crate::container::ExecOpts;
+use crate::recipe::SourceSignature;
+use crate::{ErrContext, Error, Result};
+
+use std::path::PathBuf;
+use tracing::{info, info_span, trace, Instrument};
+
+/// Verifies the signature of the fetched source of the build. The source archive must be in the
+/// temporary directory of the build and a git repository in its build directory.
+pub async fn verify(ctx: &Context<'_>, signature: &SourceSignature) -> Result<()> {
+    let span = info_span!("verify-signature");
+    async move {
+        let gnupg_home = PathBuf::from(format!("/tmp/{}-gnupg", ctx.build_ctx.id));
+        let gpg = format!("gpg --homedir {} --batch", gnupg_home.display());
+
+        info!(keys = %signature.fingerprints().join(", "), keyserver = %signature.keyserver(), "fetching trusted keys");
+        checked_exec(
+            ctx,
+            &ExecOpts::default().cmd(&format!(
+                "mkdir -m 700 -p {} && {} --keyserver {} --recv-keys {}",
+                gnupg_home.display(),
+                gpg,
+                signature.keyserver(),
+                signature.fingerprints().join(" ")
+            )),
+        )
+        .await
+        .context("failed to fetch trusted keys")?;
+
+        let status = if let Some(repo) = &ctx.build_ctx.recipe.metadata.git {
+            info!(tag = %repo.reference(), "verifying signature of tag");
+            let out = checked_exec(
+                ctx,
+                &ExecOpts::default()
+                    .cmd(&format!(
+                        "GNUPGHOME={} git verify-tag --raw {} 2>&1",
+                        gnupg_home.display(),
+                        repo.reference()
+                    ))
+                    .working_dir(&ctx.build_ctx.container_bld_dir),
+            )
+            .await
+            .context(format!("failed to verify signature of tag `{}`", repo.reference()))?;
+            out.stdout.join("")
+        } else {
+            let source = ctx.build_ctx.recipe.metadata.source.as_deref().unwrap_or_default();
+            let url = signature
+                .url
+                .as_deref()
+                .ok_or_else(|| Error::msg("missing url of the signature"))?;
+            let sig_path = gnupg_home.join("source.sig");
+            remote::fetch_file(ctx, url, &sig_path)
+                .await
+                .context(format!("failed to fetch signature `{}`", url))?;
+
+            let archive = ctx.build_ctx.container_tmp_dir.join(remote::file_name(source));
+            info!(archive = %archive.display(), "verifying signature of source");
+            let out = checked_exec(
+                ctx,
+                &ExecOpts::default().cmd(&format!(
+                    "{} --status-fd 1 --verify {} {}",
+                    gpg,
+                    sig_path.display(),
+                    archive.display()
+                )),
+            )
+            .await
+            .context("failed to verify signature of source")?;
+            out.stdout.join("")
+        };
+        trace!(status = %status);
+
+        match signature.trusted_signer(&status) {
+            Some(fingerprint) => {
+                info!(key = %fingerprint, "source signed by a trusted key");
+                Ok(())
+            }
+            None => Err(Error::msg("source is not signed by any of the trusted keys")),
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,163 @@
+//! Snapshots of the environment in which the scripts of a build are executed. They are saved in
+//! the artifact manifest so that differences between two builds of a package can be investigated.
+use crate::build::container::Context;
+use crate::container::ExecOpts;
+use crate::{secrets, Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{info_span, trace, Instrument};
+
+static TOOLS_MARKER: &str = "--pkger-tools--";
+static PACKAGES_MARKER: &str = "--pkger-packages--";
+
+/// Build tools whose versions are recorded together with the command printing the version
+static TOOLS: &[(&str, &str)] = &[
+    ("gcc", "gcc --version"),
+    ("g++", "g++ --version"),
+    ("clang", "clang --version"),
+    ("rustc", "rustc --version"),
+    ("cargo", "cargo --version"),
+    ("go", "go version"),
+    ("make", "make --version"),
+    ("cmake", "cmake --version"),
+    ("python3", "python3 --version"),
+    ("perl", "perl -e 'print $^V'"),
+];
+
+static LIST_PACKAGES: &str = r#"if command -v dpkg-query >/dev/null 2>&1; then dpkg-query -W -f='${Package} ${Version}\n'; elif command -v rpm >/dev/null 2>&1; then rpm -qa --qf '%{NAME} %{EVR}\n'; elif command -v pacman >/dev/null 2>&1; then pacman -Q; fi"#;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct EnvironmentSnapshot {
+    /// Script phase before which the snapshot was taken
+    pub phase: String,
+    /// Environment variables with secrets redacted
+    pub env: BTreeMap<String, String>,
+    /// Versions of build tools available in the container
+    pub tools: BTreeMap<String, String>,
+    /// Installed packages and their versions
+    pub packages: BTreeMap<String, String>,
+}
+
+fn snapshot_command() -> String {
+    let tools = TOOLS
+        .iter()
+        .map(|(tool, version)| {
+            format!(
+                r#"command -v {tool} >/dev/null 2>&1 && echo "{tool} $({version} 2>&1 | head -n1)""#,
+                tool = tool,
+                version = version
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    format!(
+        "env; echo {}; {}; echo {}; {}",
+        TOOLS_MARKER, tools, PACKAGES_MARKER, LIST_PACKAGES
+    )
+}
+
+impl EnvironmentSnapshot {
+    /// Parses the output of the snapshot command
+    fn parse(phase: &str, output: &str) -> Self {
+        let mut snapshot = EnvironmentSnapshot {
+            phase: phase.to_string(),
+            ..Default::default()
+        };
+        let mut section = None;
+
+        for line in output.lines() {
+            if line == TOOLS_MARKER || line == PACKAGES_MARKER {
+                section = Some(line);
+                continue;
+            }
+            match section {
+                None => {
+                    if let Some((key, value)) = line.split_once('=') {
+                        snapshot
+                            .env
+                            .insert(key.to_string(), secrets::redact(value).into_owned());
+                    }
+                }
+                Some(marker) => {
+                    if let Some((name, version)) = line.split_once(' ') {
+                        let map = if marker == TOOLS_MARKER {
+                            &mut snapshot.tools
+                        } else {
+                            &mut snapshot.packages
+                        };
+                        map.insert(name.to_string(), version.trim().to_string());
+                    }
+                }
+            }
+        }
+
+        snapshot
+    }
+}
+
+/// Records the environment variables, versions of build tools and installed packages of the
+/// container
+pub async fn take(ctx: &Context<'_>, phase: &str) -> Result<EnvironmentSnapshot> {
+    let span = info_span!("snapshot-environment", phase = %phase);
+    async move {
+        let cmd = snapshot_command();
+        let opts = ExecOpts::default()
+            .cmd(&cmd)
+            .working_dir(&ctx.build_ctx.container_bld_dir)
+            .build();
+        let out = ctx.container.exec_quiet(&opts).await?;
+        if out.exit_code != 0 {
+            return Err(Error::msg(format!(
+                "snapshot command exited with code {}",
+                out.exit_code
+            )));
+        }
+
+        let snapshot = EnvironmentSnapshot::parse(phase, &out.stdout.join(""));
+        trace!(
+            env = %snapshot.env.len(),
+            tools = %snapshot.tools.len(),
+            packages = %snapshot.packages.len()
+        );
+        Ok(snapshot)
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_snapshot() {
+        let snapshot = EnvironmentSnapshot::parse(
+            "build",
+            r#"PATH=/usr/bin:/bin
+PKGER_OS=debian
+--pkger-tools--
+gcc gcc (Debian 8.3.0-6) 8.3.0
+make GNU Make 4.2.1
+--pkger-packages--
+bash 5.0-4
+libc6 2.28-10
+"#,
+        );
+
+        assert_eq!(snapshot.phase, "build");
+        assert_eq!(
+            snapshot.env.get("PKGER_OS").map(String::as_str),
+            Some("debian")
+        );
+        assert_eq!(
+            snapshot.tools.get("gcc").map(String::as_str),
+            Some("gcc (Debian 8.3.0-6) 8.3.0")
+        );
+        assert_eq!(snapshot.packages.len(), 2);
+        assert_eq!(
+            snapshot.packages.get("libc6").map(String::as_str),
+            Some("2.28-10")
+        );
+    }
+}
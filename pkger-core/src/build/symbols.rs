@@ -0,0 +1,190 @@
+//! Tracking of symbols exported by shared libraries in the build output. The symbols are compared
+//! against a baseline checked in next to the recipe so that removed symbols, which break the ABI
+//! of the library, fail the build before the package ships.
+use crate::build::container::{checked_exec, Context};
+use crate::container::ExecOpts;
+use crate::recipe::SymbolsOptions;
+use crate::{ErrContext, Error, Result};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use tracing::{info, info_span, trace, warn, Instrument};
+
+static LIBRARY_MARKER: &str = "--pkger-library--";
+
+/// Prints the SONAME of every shared library followed by its exported symbols
+static SCAN_COMMAND: &str = r#"find . -type f -name 'lib*.so*' | sort | while IFS= read -r f; do so=$(objdump -p "$f" 2>/dev/null | awk '/SONAME/ {print $2}'); [ -n "$so" ] || continue; echo "--pkger-library-- $so"; nm -D --defined-only "$f" | awk '{print $NF}'; done"#;
+
+/// Exported symbols by SONAME of the library
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols(BTreeMap<String, BTreeSet<String>>);
+
+impl Symbols {
+    /// Parses symbols in the format of the baseline file, SONAMEs of libraries on separate lines
+    /// followed by their symbols indented with a space
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = BTreeMap::new();
+        let mut library = None;
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with(' ') {
+                if let Some(library) = &library {
+                    symbols
+                        .entry(String::clone(library))
+                        .or_insert_with(BTreeSet::new)
+                        .insert(line.trim().to_string());
+                }
+            } else {
+                let name = line.trim().to_string();
+                symbols.entry(name.clone()).or_insert_with(BTreeSet::new);
+                library = Some(name);
+            }
+        }
+        Self(symbols)
+    }
+
+    fn parse_scan(output: &str) -> Self {
+        let mut symbols = BTreeMap::new();
+        let mut library = None;
+        for line in output.lines() {
+            if let Some(name) = line.strip_prefix(LIBRARY_MARKER) {
+                let name = name.trim().to_string();
+                symbols.entry(name.clone()).or_insert_with(BTreeSet::new);
+                library = Some(name);
+            } else if let (Some(library), false) = (&library, line.trim().is_empty()) {
+                symbols
+                    .entry(String::clone(library))
+                    .or_insert_with(BTreeSet::new)
+                    .insert(line.trim().to_string());
+            }
+        }
+        Self(symbols)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (library, symbols) in &self.0 {
+            out.push_str(library);
+            out.push('\n');
+            for symbol in symbols {
+                out.push(' ');
+                out.push_str(symbol);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Returns symbols of `baseline` missing from these symbols by library, skipping libraries
+    /// that are not part of these symbols as their SONAME changed
+    pub fn removed<'a>(&self, baseline: &'a Symbols) -> Vec<(&'a str, &'a str)> {
+        baseline
+            .0
+            .iter()
+            .filter_map(|(library, symbols)| self.0.get(library).map(|new| (library, symbols, new)))
+            .flat_map(|(library, symbols, new)| {
+                symbols
+                    .iter()
+                    .filter(move |symbol| !new.contains(*symbol))
+                    .map(move |symbol| (library.as_str(), symbol.as_str()))
+            })
+            .collect()
+    }
+
+    /// Returns the number of symbols not present in `baseline`
+    pub fn added(&self, baseline: &Symbols) -> usize {
+        self.0
+            .iter()
+            .map(|(library, symbols)| match baseline.0.get(library) {
+                Some(old) => symbols.difference(old).count(),
+                None => symbols.len(),
+            })
+            .sum()
+    }
+}
+
+pub async fn check(ctx: &Context<'_>, opts: &SymbolsOptions) -> Result<()> {
+    let span = info_span!("check-symbols");
+    async move {
+        let output = checked_exec(
+            ctx,
+            &ExecOpts::default()
+                .cmd(SCAN_COMMAND)
+                .working_dir(&ctx.build_ctx.container_out_dir),
+        )
+        .await?
+        .stdout
+        .join("");
+        let symbols = Symbols::parse_scan(&output);
+        trace!(libraries = %symbols.0.len());
+
+        let path = ctx.build_ctx.recipe.recipe_dir.join(&opts.baseline);
+        if !path.exists() {
+            fs::write(&path, symbols.render())
+                .context(format!("failed to save symbols to `{}`", path.display()))?;
+            info!(path = %path.display(), "created symbols baseline");
+            return Ok(());
+        }
+        let baseline = Symbols::parse(
+            &fs::read_to_string(&path)
+                .context(format!("failed to read symbols from `{}`", path.display()))?,
+        );
+
+        for library in baseline.0.keys() {
+            if !symbols.0.contains_key(library) {
+                warn!(library = %library, "library of the baseline not found, update the baseline if its SONAME changed");
+            }
+        }
+        let added = symbols.added(&baseline);
+        if added > 0 {
+            info!(added = %added, "found new symbols, update the baseline to track them");
+        }
+
+        let removed = symbols
+            .removed(&baseline)
+            .into_iter()
+            .filter(|(_, symbol)| !opts.allow_removed.iter().any(|allowed| allowed == symbol))
+            .map(|(library, symbol)| format!("{} ({})", symbol, library))
+            .collect::<Vec<_>>();
+        if removed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "symbols removed since the baseline break the ABI: {}, list them in `symbols.allow_removed` if the removal was announced",
+                removed.join(", ")
+            )))
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_symbols_with_baseline() {
+        let baseline = Symbols::parse(
+            r#"# symbols of libfoo
+libfoo.so.1
+ foo_free
+ foo_init
+libbar.so.2
+ bar
+"#,
+        );
+        let symbols = Symbols::parse_scan(
+            "--pkger-library-- libfoo.so.1\nfoo_init\nfoo_new\n--pkger-library-- libbar.so.3\nbar\n",
+        );
+
+        assert_eq!(
+            symbols.removed(&baseline),
+            vec![("libfoo.so.1", "foo_free")]
+        );
+        assert_eq!(symbols.added(&baseline), 2);
+        assert_eq!(Symbols::parse(&symbols.render()), symbols);
+    }
+}
@@ -0,0 +1,199 @@
+//! Record of everything a build executed in its container. It is rendered as a standalone shell
+//! script so that a build can be reproduced and inspected without pkger.
+use crate::image::ImageState;
+use crate::recipe::DependencyNames;
+use crate::secrets;
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+enum Entry {
+    Phase(String),
+    Exec {
+        command: String,
+        shell: String,
+        working_dir: PathBuf,
+    },
+    Copy {
+        path: PathBuf,
+        size: usize,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Transcript {
+    id: String,
+    image: Option<String>,
+    deps: Vec<String>,
+    env: Vec<(String, String)>,
+    entries: Vec<Entry>,
+}
+
+/// Quotes `s` so that it is passed to the shell as a single word
+pub(crate) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+impl Transcript {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Records the image the build ran on together with commands installing its dependencies
+    pub fn image(&mut self, state: &ImageState, names: &DependencyNames) {
+        self.image = Some(format!("{}:{} ({})", state.image, state.tag, state.id));
+
+        let pkg_mngr = state.os.package_manager();
+        let pkg_mngr_name = pkg_mngr.as_ref();
+        self.deps.clear();
+        if pkg_mngr_name.is_empty() || state.deps.is_empty() {
+            return;
+        }
+        let distro = state.os.distribution();
+        let mut deps = state
+            .deps
+            .iter()
+            .filter_map(|dep| names.resolve(dep, distro))
+            .map(|dep| pkg_mngr.install_spec(&dep))
+            .collect::<Vec<_>>();
+        deps.sort_unstable();
+        deps.dedup();
+
+        self.deps.push(format!(
+            "{} {}",
+            pkg_mngr_name,
+            pkg_mngr.update_repos_args().join(" ")
+        ));
+        let mut install_args = pkg_mngr.install_args();
+        install_args.extend(pkg_mngr.install_resilience_args());
+        self.deps.push(format!(
+            "{} {} {}",
+            pkg_mngr_name,
+            install_args.join(" "),
+            deps.join(" ")
+        ));
+    }
+
+    /// Records the environment of the build container
+    pub fn env<K, V>(&mut self, env: impl IntoIterator<Item = (K, V)>)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env = env.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        self.env.sort();
+    }
+
+    pub fn phase(&mut self, name: impl Into<String>) {
+        self.entries.push(Entry::Phase(name.into()));
+    }
+
+    pub fn exec(&mut self, command: &str, shell: &str, working_dir: &Path) {
+        self.entries.push(Entry::Exec {
+            command: command.to_string(),
+            shell: shell.to_string(),
+            working_dir: working_dir.to_path_buf(),
+        });
+    }
+
+    /// Records a file created by pkger inside of the container
+    pub fn copy(&mut self, path: &Path, size: usize) {
+        self.entries.push(Entry::Copy {
+            path: path.to_path_buf(),
+            size,
+        });
+    }
+
+    /// Renders this transcript as a shell script with secrets resolved from the recipe redacted
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "#!/bin/sh");
+        let _ = writeln!(out, "# Commands executed by pkger in build `{}`", self.id);
+        if let Some(image) = &self.image {
+            let _ = writeln!(out, "# Image: {}", image);
+        }
+        let _ = writeln!(out, "set -e");
+
+        if !self.deps.is_empty() {
+            let _ = writeln!(out, "\n# dependencies");
+            self.deps.iter().for_each(|cmd| {
+                let _ = writeln!(out, "{}", cmd);
+            });
+        }
+
+        if !self.env.is_empty() {
+            let _ = writeln!(out, "\n# environment");
+            self.env.iter().for_each(|(k, v)| {
+                let _ = writeln!(out, "export {}={}", k, quote(v));
+            });
+        }
+
+        for entry in &self.entries {
+            match entry {
+                Entry::Phase(name) => {
+                    let _ = writeln!(out, "\n# {}", name);
+                }
+                Entry::Exec {
+                    command,
+                    shell,
+                    working_dir,
+                } => {
+                    let _ = writeln!(
+                        out,
+                        "cd {} && {} -c {}",
+                        quote(&working_dir.to_string_lossy()),
+                        shell,
+                        quote(command)
+                    );
+                }
+                Entry::Copy { path, size } => {
+                    let _ = writeln!(
+                        out,
+                        "# pkger copied a file of {} bytes to `{}`",
+                        size,
+                        path.display()
+                    );
+                }
+            }
+        }
+
+        secrets::redact(&out).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_script() {
+        let mut transcript = Transcript::new("pkger-test-centos8-1");
+        transcript.env(vec![
+            ("PKGER_OUT_DIR", "/tmp/out"),
+            ("PKGER_BLD_DIR", "/tmp/bld"),
+        ]);
+        transcript.phase("build");
+        transcript.exec("echo 'it works'", "/bin/bash", Path::new("/tmp/bld"));
+        transcript.copy(Path::new("/tmp/spec.tar"), 1024);
+
+        assert_eq!(
+            transcript.render(),
+            r#"#!/bin/sh
+# Commands executed by pkger in build `pkger-test-centos8-1`
+set -e
+
+# environment
+export PKGER_BLD_DIR='/tmp/bld'
+export PKGER_OUT_DIR='/tmp/out'
+
+# build
+cd '/tmp/bld' && /bin/bash -c 'echo '\''it works'\'''
+# pkger copied a file of 1024 bytes to `/tmp/spec.tar`
+"#
+        );
+    }
+}
@@ -1,17 +1,26 @@
-use crate::archive::unpack_tarball;
-use crate::{ErrContext, Result};
+use crate::archive::{glob_match, unpack_tarball_matching};
+use crate::docker::{raw, Docker};
+use crate::secrets;
+use crate::{ErrContext, Error, Result};
 
 use docker_api::{
     api::{ContainerCreateOpts, ExecContainerOpts, LogsOpts, RmContainerOpts},
     conn::TtyChunk,
-    Container, Docker, Exec,
+    Container, Exec,
 };
 use futures::{StreamExt, TryStreamExt};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tracing::{error, info, info_span, trace, Instrument};
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, trace, warn, Instrument};
 
 /// Length of significant characters of a container ID.
 static CONTAINER_ID_LEN: usize = 12;
@@ -21,6 +30,190 @@ pub fn convert_id(id: &str) -> &str {
     &id[..CONTAINER_ID_LEN]
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Detection of commands that neither produce output nor use the CPU, like a configure script
+/// waiting for input.
+pub struct StallDetection {
+    /// Number of seconds without output and CPU activity after which a command is stalled
+    pub after: u64,
+    #[serde(default)]
+    /// Whether to kill stalled commands instead of only warning about them
+    pub kill: bool,
+}
+
+impl StallDetection {
+    /// How often the CPU usage of a container is checked when a command produces no output
+    fn interval(&self) -> Duration {
+        Duration::from_secs((self.after / 4).clamp(1, 30))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Limits of the output of executed commands kept in memory and printed. Output streams with more
+/// lines are truncated to their first and last lines and the full output is saved to a file.
+pub struct OutputLimit {
+    #[serde(default = "default_head")]
+    /// Number of lines from the start of each output stream that are kept
+    pub head: usize,
+    #[serde(default = "default_tail")]
+    /// Number of lines from the end of each output stream that are kept
+    pub tail: usize,
+    /// Directory where the full output of containers is saved, defaults to `pkger-logs` in the
+    /// temporary directory
+    pub log_dir: Option<PathBuf>,
+}
+
+fn default_head() -> usize {
+    1000
+}
+
+fn default_tail() -> usize {
+    1000
+}
+
+impl OutputLimit {
+    /// Path of the file with the full output of the container with `id`
+    pub fn log_path(&self, id: &str) -> PathBuf {
+        self.log_dir
+            .clone()
+            .unwrap_or_else(|| env::temp_dir().join("pkger-logs"))
+            .join(format!("{}.log", id))
+    }
+}
+
+/// Lines of one output stream of a command limited to the first `head` and last `tail` lines
+struct LimitedLines {
+    head: usize,
+    tail: usize,
+    first: Vec<String>,
+    last: VecDeque<String>,
+    omitted: usize,
+}
+
+impl LimitedLines {
+    fn new(limit: &OutputLimit) -> Self {
+        Self {
+            head: limit.head,
+            tail: limit.tail,
+            first: vec![],
+            last: VecDeque::new(),
+            omitted: 0,
+        }
+    }
+
+    /// Adds a line returning whether it is one of the first lines that can be printed right away
+    fn push(&mut self, line: &str) -> bool {
+        if self.first.len() < self.head {
+            self.first.push(line.to_string());
+            return true;
+        }
+        self.last.push_back(line.to_string());
+        if self.last.len() > self.tail {
+            self.last.pop_front();
+            self.omitted += 1;
+        }
+        false
+    }
+
+    /// Returns the kept lines with a line pointing to `log` in place of the omitted ones
+    fn into_output(self, log: &Path) -> Vec<String> {
+        let mut output = self
+            .first
+            .into_iter()
+            .map(|line| line + "\n")
+            .collect::<Vec<_>>();
+        if self.omitted > 0 {
+            output.push(format!(
+                "... {} lines omitted, full output in {} ...\n",
+                self.omitted,
+                log.display()
+            ));
+        }
+        output.extend(self.last.into_iter().map(|line| line + "\n"));
+        output
+    }
+}
+
+/// Parses the cumulative CPU time reported by `ps` like `01:02:03` or `1-01:02:03` into seconds
+fn parse_cpu_time(time: &str) -> Option<u64> {
+    let (days, time) = match time.split_once('-') {
+        Some((days, time)) => (days.parse::<u64>().ok()?, time),
+        None => (0, time),
+    };
+    let seconds = time.split(':').try_fold(0, |total, part| {
+        part.parse::<u64>().ok().map(|n| total * 60 + n)
+    })?;
+    Some(days * 24 * 60 * 60 + seconds)
+}
+
+/// Opens the file with the full output of a container for appending
+fn open_log(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Selects the files downloaded from a container by their names
+#[derive(Clone, Debug, Default)]
+pub struct DownloadFilter {
+    patterns: Vec<String>,
+    expected: Vec<String>,
+}
+
+impl DownloadFilter {
+    /// Downloads only files matching one of the glob `patterns`, all files are downloaded when
+    /// there are no patterns
+    pub fn patterns<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.patterns.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Fails the download if no file named `name` was downloaded
+    pub fn expect(mut self, name: impl Into<String>) -> Self {
+        self.expected.push(name.into());
+        self
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_match(pattern, name))
+    }
+
+    /// Returns an error listing the expected files missing from `downloaded`
+    pub fn check(&self, downloaded: &[PathBuf]) -> Result<()> {
+        let missing = self
+            .expected
+            .iter()
+            .filter(|name| {
+                !downloaded.iter().any(|path| {
+                    path.file_name()
+                        .map(|file_name| file_name.to_string_lossy() == name.as_str())
+                        .unwrap_or_default()
+                })
+            })
+            .map(|name| format!("`{}`", name))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "expected artifacts were not found in the container: {}",
+                missing.join(", ")
+            )))
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Output<T> {
     pub stdout: Vec<T>,
@@ -39,6 +232,7 @@ pub struct ExecOpts<'opts> {
     user: Option<&'opts str>,
     working_dir: Option<&'opts Path>,
     env: Option<&'opts [String]>,
+    limit_output: bool,
 }
 
 impl<'opts> Default for ExecOpts<'opts> {
@@ -53,6 +247,7 @@ impl<'opts> Default for ExecOpts<'opts> {
             user: None,
             working_dir: None,
             env: None,
+            limit_output: false,
         }
     }
 }
@@ -105,6 +300,35 @@ impl<'opts> ExecOpts<'opts> {
         self
     }
 
+    /// Variables in the `KEY=VALUE` form set only in the environment of the command
+    pub fn env(mut self, env: &'opts [String]) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Whether the output of the command is truncated by the output limit of the container.
+    /// Commands whose output is parsed must not be limited.
+    pub fn limit_output(mut self, limit: bool) -> Self {
+        self.limit_output = limit;
+        self
+    }
+
+    pub fn is_output_limited(&self) -> bool {
+        self.limit_output
+    }
+
+    pub fn get_cmd(&self) -> &str {
+        self.cmd
+    }
+
+    pub fn get_shell(&self) -> &str {
+        self.shell
+    }
+
+    pub fn get_working_dir(&self) -> Option<&Path> {
+        self.working_dir
+    }
+
     pub fn build(self) -> ExecContainerOpts {
         let mut builder = ExecContainerOpts::builder();
         let mut mut_builder = &mut builder;
@@ -140,6 +364,8 @@ pub struct DockerContainer<'job> {
     is_running: Arc<AtomicBool>,
     container: Container<'job>,
     docker: &'job Docker,
+    stall_detection: Option<StallDetection>,
+    output_limit: Option<OutputLimit>,
 }
 
 impl<'job> DockerContainer<'job> {
@@ -152,9 +378,23 @@ impl<'job> DockerContainer<'job> {
             },
             container: docker.containers().get(""),
             docker,
+            stall_detection: None,
+            output_limit: None,
         }
     }
 
+    /// Warns about, or kills, executed commands that stall
+    pub fn with_stall_detection(mut self, stall_detection: Option<StallDetection>) -> Self {
+        self.stall_detection = stall_detection;
+        self
+    }
+
+    /// Truncates the output of executed commands saving the full output to a file
+    pub fn with_output_limit(mut self, output_limit: Option<OutputLimit>) -> Self {
+        self.output_limit = output_limit;
+        self
+    }
+
     pub fn inner(&self) -> &Container<'job> {
         &self.container
     }
@@ -164,15 +404,27 @@ impl<'job> DockerContainer<'job> {
     }
 
     pub async fn spawn(&mut self, opts: &ContainerCreateOpts) -> Result<()> {
+        self.spawn_with_overrides(opts, &JsonValue::Null).await
+    }
+
+    /// Creates and starts a container like [`spawn`](DockerContainer::spawn) with the fields of
+    /// `overrides` merged into the configuration of the container, used for options that
+    /// `ContainerCreateOpts` has no builder methods for like most of `HostConfig`
+    pub async fn spawn_with_overrides(
+        &mut self,
+        opts: &ContainerCreateOpts,
+        overrides: &JsonValue,
+    ) -> Result<()> {
         let span = info_span!("container-spawn");
         async move {
-            let container = self
-                .docker
-                .containers()
-                .create(&opts)
-                .await?
-                .id()
-                .to_owned();
+            let container = if overrides.is_null() {
+                self.docker.containers().create(opts).await?.id().to_owned()
+            } else {
+                let config = serde_json::from_str(&opts.serialize()?)?;
+                raw::create_container(self.docker, opts.name.as_deref(), &config, overrides)
+                    .await
+                    .context("failed to create container")?
+            };
 
             self.container = self.docker.containers().get(container);
             info!(id = %self.id(), "created container");
@@ -197,7 +449,7 @@ impl<'job> DockerContainer<'job> {
 
             info!("deleting container");
             self.container
-                .remove(&RmContainerOpts::builder().force(true).build())
+                .remove(&RmContainerOpts::builder().force(true).volumes(true).build())
                 .await
                 .context("failed to delete container")?;
 
@@ -223,34 +475,138 @@ impl<'job> DockerContainer<'job> {
     }
 
     pub async fn exec<'cmd>(&self, opts: &ExecContainerOpts) -> Result<Output<String>> {
+        self.run_exec(opts, true, false).await
+    }
+
+    /// Executes a command like [`exec`](DockerContainer::exec) without logging its output
+    pub async fn exec_quiet(&self, opts: &ExecContainerOpts) -> Result<Output<String>> {
+        self.run_exec(opts, false, false).await
+    }
+
+    /// Executes a command like [`exec`](DockerContainer::exec) truncating its output if an output
+    /// limit is set
+    pub async fn exec_limited(&self, opts: &ExecContainerOpts) -> Result<Output<String>> {
+        self.run_exec(opts, true, true).await
+    }
+
+    async fn run_exec(
+        &self,
+        opts: &ExecContainerOpts,
+        log_output: bool,
+        limit_output: bool,
+    ) -> Result<Output<String>> {
         let span = info_span!("container-exec", id = %self.id());
         async move {
             let exec = Exec::create(&self.docker, self.id(), &opts).await?;
             let mut stream = exec.start();
 
             let mut output = Output::default();
+            let print = |line: &str, is_stderr: bool| match (log_output, is_stderr) {
+                (true, false) => info!("{}", line.trim()),
+                (true, true) => error!("{}", line.trim()),
+                (false, _) => trace!("{}", line.trim()),
+            };
+            let output_limit = self.output_limit.as_ref().filter(|_| limit_output);
+            let mut limited =
+                output_limit.map(|limit| (LimitedLines::new(limit), LimitedLines::new(limit)));
+            let log_path = output_limit.map(|limit| limit.log_path(self.id()));
+            let mut log_file = log_path.as_deref().and_then(|path| match open_log(path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    warn!(path = %path.display(), reason = %e, "failed to open output log");
+                    None
+                }
+            });
+            let mut last_activity = Instant::now();
+            let mut last_cpu_time = None;
+            let mut stalled = false;
+
+            loop {
+                let result = match &self.stall_detection {
+                    Some(stall) => {
+                        match tokio::time::timeout(stall.interval(), stream.next()).await {
+                            Ok(result) => result,
+                            Err(_) => {
+                                self.check_ctrlc().await?;
+                                let cpu_time = self.cpu_time().await;
+                                if cpu_time.is_none() || cpu_time != last_cpu_time {
+                                    last_cpu_time = cpu_time;
+                                    last_activity = Instant::now();
+                                } else if last_activity.elapsed().as_secs() >= stall.after {
+                                    if stall.kill {
+                                        error!(idle = %stall.after, "command stalled, killing it");
+                                        self.kill_commands().await?;
+                                        stalled = true;
+                                    } else {
+                                        warn!(idle = %stall.after, "command produced no output and used no CPU, it might be waiting for input");
+                                    }
+                                    last_activity = Instant::now();
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    None => stream.next().await,
+                };
+                let result = match result {
+                    Some(result) => result,
+                    None => break,
+                };
+                last_activity = Instant::now();
 
-            while let Some(result) = stream.next().await {
                 self.check_ctrlc().await?;
-                match result? {
-                    TtyChunk::StdOut(chunk) => {
-                        let chunk = str::from_utf8(&chunk)?;
+                let (chunk, is_stderr) = match result? {
+                    TtyChunk::StdOut(chunk) => (chunk, false),
+                    TtyChunk::StdErr(chunk) => (chunk, true),
+                    _ => unreachable!(),
+                };
+                let chunk = str::from_utf8(&chunk)?;
+                if let Some(file) = &mut log_file {
+                    if let Err(e) = file.write_all(secrets::redact(chunk).as_bytes()) {
+                        warn!(reason = %e, "failed to save output");
+                        log_file = None;
+                    }
+                }
+                match (&mut limited, is_stderr) {
+                    (Some((stdout, _)), false) => chunk.lines().for_each(|line| {
+                        if stdout.push(line) {
+                            print(line, false);
+                        }
+                    }),
+                    (Some((_, stderr)), true) => chunk.lines().for_each(|line| {
+                        if stderr.push(line) {
+                            print(line, true);
+                        }
+                    }),
+                    (None, false) => {
                         output.stdout.push(chunk.to_string());
-                        chunk.lines().for_each(|line| {
-                            info!("{}", line.trim());
-                        })
+                        chunk.lines().for_each(|line| print(line, false));
                     }
-                    TtyChunk::StdErr(chunk) => {
-                        let chunk = str::from_utf8(&chunk)?;
+                    (None, true) => {
                         output.stderr.push(chunk.to_string());
-                        chunk.lines().for_each(|line| {
-                            error!("{}", line.trim());
-                        })
+                        chunk.lines().for_each(|line| print(line, true));
                     }
-                    _ => unreachable!(),
                 }
             }
 
+            if let (Some((stdout, stderr)), Some(log_path)) = (limited, &log_path) {
+                for (lines, is_stderr) in [(&stdout, false), (&stderr, true)] {
+                    if lines.omitted > 0 {
+                        warn!(omitted = %lines.omitted, log = %log_path.display(), "output truncated");
+                    }
+                    lines.last.iter().for_each(|line| print(line, is_stderr));
+                }
+                output.stdout = stdout.into_output(log_path);
+                output.stderr = stderr.into_output(log_path);
+            }
+
+            if let (true, Some(stall)) = (stalled, &self.stall_detection) {
+                return Err(anyhow!(
+                    "command was killed after {}s without output and CPU activity",
+                    stall.after
+                ));
+            }
+
             output.exit_code = exec
                 .inspect()
                 .await
@@ -301,27 +657,82 @@ impl<'job> DockerContainer<'job> {
         .await
     }
 
+    /// Commits the filesystem of the container to an image tagged `repo:tag`
+    pub async fn commit(&self, repo: &str, tag: &str) -> Result<()> {
+        let span = info_span!("container-commit", id = %self.id(), repo = %repo, tag = %tag);
+        async move {
+            trace!("committing");
+            raw::commit_container(self.docker, self.container.id(), repo, tag)
+                .await
+                .map(|_| ())
+                .context("failed to commit container")
+        }
+        .instrument(span)
+        .await
+    }
+
     pub async fn download_files(&self, source: &Path, dest: &Path) -> Result<()> {
+        self.download_files_matching(source, dest, &DownloadFilter::default())
+            .await
+            .map(|_| ())
+    }
+
+    /// Downloads the files from `source` in the container selected by `filter` to `dest`
+    /// returning their paths
+    pub async fn download_files_matching(
+        &self,
+        source: &Path,
+        dest: &Path,
+        filter: &DownloadFilter,
+    ) -> Result<Vec<PathBuf>> {
         let span = info_span!("container-download-files", id = %self.id(), source = %source.display(), destination = %dest.display());
         let cloned_span = span.clone();
 
         async move {
-            trace!("fetching");
+            trace!(filter = ?filter, "fetching");
             let files = self.copy_from(source).await?;
 
             let mut archive = tar::Archive::new(&files[..]);
 
-            cloned_span.in_scope(|| unpack_tarball(&mut archive, dest))
+            let downloaded = cloned_span.in_scope(|| {
+                unpack_tarball_matching(&mut archive, dest, |name| filter.matches(name))
+            })?;
+            filter.check(&downloaded)?;
+            Ok(downloaded)
         }
         .instrument(span)
         .await
     }
 
+    /// Returns the total CPU time in seconds used by all processes of the container
+    async fn cpu_time(&self) -> Option<u64> {
+        let top = match self.container.top(None).await {
+            Ok(top) => top,
+            Err(e) => {
+                trace!(reason = %e, "failed to list processes");
+                return None;
+            }
+        };
+        let column = top.titles.iter().position(|title| title == "TIME")?;
+        top.processes
+            .iter()
+            .map(|process| process.get(column).and_then(|time| parse_cpu_time(time)))
+            .sum()
+    }
+
+    /// Kills all processes of the container except the init process keeping it running
+    async fn kill_commands(&self) -> Result<()> {
+        let opts = ExecOpts::default().cmd("kill -KILL -1").build();
+        let exec = Exec::create(self.docker, self.id(), &opts).await?;
+        exec.start().try_collect::<Vec<_>>().await?;
+        Ok(())
+    }
+
     async fn check_ctrlc(&self) -> Result<()> {
         let span = info_span!("check-ctrlc");
         async move {
             if !self.is_running().await? {
-                Err(anyhow!("container execution interrupted by ctrl-c signal"))
+                Err(anyhow!("container execution interrupted"))
             } else {
                 Ok(())
             }
@@ -330,3 +741,70 @@ impl<'job> DockerContainer<'job> {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_time() {
+        assert_eq!(parse_cpu_time("00:00:05"), Some(5));
+        assert_eq!(parse_cpu_time("01:02:03"), Some(3723));
+        assert_eq!(parse_cpu_time("2-00:00:01"), Some(172801));
+        assert_eq!(parse_cpu_time("12:34"), Some(754));
+        assert_eq!(parse_cpu_time("-"), None);
+    }
+
+    #[test]
+    fn filters_downloaded_files() {
+        let filter = DownloadFilter::default()
+            .patterns(vec!["*.deb", "foo-?.rpm"])
+            .expect("foo_1.0_amd64.deb");
+        assert!(filter.matches("foo_1.0_amd64.deb"));
+        assert!(filter.matches("foo-1.rpm"));
+        assert!(!filter.matches("foo-10.rpm"));
+        assert!(!filter.matches("foo_1.0_amd64.buildinfo"));
+        assert!(DownloadFilter::default().matches("anything"));
+
+        assert!(filter
+            .check(&[PathBuf::from("/out/foo_1.0_amd64.deb")])
+            .is_ok());
+        let err = filter
+            .check(&[PathBuf::from("/out/foo-dbg_1.0_amd64.deb")])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected artifacts were not found in the container: `foo_1.0_amd64.deb`"
+        );
+
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbc"));
+        assert!(!glob_match("a*b", "ab/c"));
+    }
+
+    #[test]
+    fn truncates_output() {
+        let limit = OutputLimit {
+            head: 2,
+            tail: 1,
+            log_dir: Some(PathBuf::from("/tmp/logs")),
+        };
+        let mut lines = LimitedLines::new(&limit);
+        let printed = (1..=5)
+            .map(|i| lines.push(&format!("line {}", i)))
+            .collect::<Vec<_>>();
+        assert_eq!(printed, vec![true, true, false, false, false]);
+
+        let log = limit.log_path("3f2a1b9c8d7e");
+        assert_eq!(log, PathBuf::from("/tmp/logs/3f2a1b9c8d7e.log"));
+        assert_eq!(
+            lines.into_output(&log),
+            vec![
+                "line 1\n",
+                "line 2\n",
+                "... 2 lines omitted, full output in /tmp/logs/3f2a1b9c8d7e.log ...\n",
+                "line 5\n"
+            ]
+        );
+    }
+}
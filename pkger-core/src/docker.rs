@@ -1,43 +1,314 @@
-use crate::Result;
+pub mod raw;
+pub mod trace;
 
 pub use docker_api::*;
 
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::env;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
 
 static RUN_DOCKER_SOCK: &str = "/run/docker.sock";
 static VAR_RUN_DOCKER_SOCK: &str = "/var/run/docker.sock";
+static RUN_PODMAN_SOCK: &str = "/run/podman/podman.sock";
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+/// Container engine serving the Docker API that builds run on. Podman is used through its Docker
+/// compatible API with the differences to Docker handled by pkger.
+pub enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl FromStr for Runtime {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s {
+            "docker" => Ok(Runtime::Docker),
+            "podman" => Ok(Runtime::Podman),
+            _ => Err(crate::Error::msg(format!(
+                "unknown container runtime `{}`, expected `docker` or `podman`",
+                s
+            ))),
+        }
+    }
+}
+
+impl AsRef<str> for Runtime {
+    fn as_ref(&self) -> &str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+        }
+    }
+}
+
+impl Runtime {
+    /// Unix sockets the runtime listens on by default in order of preference. Rootless Podman
+    /// listens in `$XDG_RUNTIME_DIR`.
+    fn default_sockets(&self) -> Vec<PathBuf> {
+        match self {
+            Runtime::Docker => vec![RUN_DOCKER_SOCK.into(), VAR_RUN_DOCKER_SOCK.into()],
+            Runtime::Podman => env::var_os("XDG_RUNTIME_DIR")
+                .map(|dir| PathBuf::from(dir).join("podman/podman.sock"))
+                .into_iter()
+                .chain(Some(RUN_PODMAN_SOCK.into()))
+                .collect(),
+        }
+    }
+
+    /// Whether building an image reports the id of the built image
+    pub fn reports_built_image_id(&self) -> bool {
+        matches!(self, Runtime::Docker)
+    }
+
+    /// Whether the size of the writable layer of containers can be limited. Rootless Podman
+    /// doesn't support storage options of containers.
+    pub fn supports_storage_opts(&self) -> bool {
+        matches!(self, Runtime::Docker)
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Connection to a Docker daemon. Keeps the uri of the daemon so that endpoints docker-api doesn't
+/// cover can be requested through [`raw`].
+pub struct Docker {
+    inner: docker_api::Docker,
+    uri: String,
+    host: String,
+}
+
+impl Docker {
+    /// Connects to the daemon at a `unix://`, `tcp://` or `http://` uri
+    pub fn new(uri: &str) -> crate::Result<Self> {
+        Ok(Self {
+            inner: docker_api::Docker::new(uri)?,
+            uri: uri.to_string(),
+            host: uri.to_string(),
+        })
+    }
+
+    /// Connects to the daemon listening on the unix socket at `socket_path`
+    pub fn unix<P: AsRef<Path>>(socket_path: P) -> Self {
+        let socket_path = socket_path.as_ref();
+        let uri = format!("unix://{}", socket_path.display());
+        Self {
+            inner: docker_api::Docker::unix(socket_path),
+            host: uri.clone(),
+            uri,
+        }
+    }
+
+    /// Connects to the daemon at `host` through the tracing proxy at `proxy`
+    fn traced(host: &str, proxy: &str) -> crate::Result<Self> {
+        Self::new(proxy).map(|docker| Self {
+            host: host.to_string(),
+            ..docker
+        })
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Uri of the daemon as configured, unlike [`uri`](Docker::uri) it's the same when requests
+    /// are traced
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+impl Deref for Docker {
+    type Target = docker_api::Docker;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// Hands out connections to one or more Docker daemons. When multiple daemons are configured
+/// each call to [`connect`](DockerConnectionPool::connect) returns the next daemon in a smooth
+/// weighted round robin order so that daemons with higher weight receive proportionally more
+/// builds.
 pub struct DockerConnectionPool {
-    connector: Docker,
+    connectors: Vec<Docker>,
+    runtime: Runtime,
+    /// Indexes of connectors in the order they are handed out
+    schedule: Vec<usize>,
+    next: AtomicUsize,
 }
 
 impl Default for DockerConnectionPool {
     fn default() -> Self {
-        let socket_path = if PathBuf::from(RUN_DOCKER_SOCK).exists() {
-            RUN_DOCKER_SOCK
+        Self::for_runtime(Runtime::default())
+    }
+}
+
+impl DockerConnectionPool {
+    /// Connects to the default socket of `runtime`
+    pub fn for_runtime(runtime: Runtime) -> Self {
+        let sockets = runtime.default_sockets();
+        let socket_path = sockets
+            .iter()
+            .find(|socket| socket.exists())
+            .or_else(|| sockets.last())
+            .map(|socket| socket.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let docker = if trace::is_enabled() {
+            let uri = format!("unix://{}", socket_path);
+            match trace::proxy(&uri).and_then(|proxy| Docker::traced(&uri, &proxy)) {
+                Ok(docker) => docker,
+                Err(e) => {
+                    warn!(reason = %e, "failed to trace docker API, connecting directly");
+                    Docker::unix(socket_path)
+                }
+            }
         } else {
-            VAR_RUN_DOCKER_SOCK
+            Docker::unix(socket_path)
         };
 
-        Self {
-            connector: Docker::unix(socket_path),
-        }
+        Self::from_connectors(vec![(docker, 1)]).with_runtime(runtime)
     }
-}
 
-impl DockerConnectionPool {
-    pub fn new<S>(uri: S) -> Result<Self>
+    /// Sets the runtime serving the API on all hosts of this pool
+    pub fn with_runtime(mut self, runtime: Runtime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn runtime(&self) -> Runtime {
+        self.runtime
+    }
+
+    pub fn new<S>(uri: S) -> crate::Result<Self>
     where
         S: Into<String>,
     {
-        let uri = uri.into();
+        Self::with_hosts(vec![(uri, 1)])
+    }
 
-        Ok(Self {
-            connector: Docker::new(&uri)?,
-        })
+    /// Creates a pool of connections to multiple Docker daemons. Each host consists of an uri and
+    /// a weight that decides what part of builds should be run on it.
+    pub fn with_hosts<I, S>(hosts: I) -> crate::Result<Self>
+    where
+        I: IntoIterator<Item = (S, u32)>,
+        S: Into<String>,
+    {
+        let mut connectors = Vec::new();
+        for (uri, weight) in hosts {
+            let uri = uri.into();
+            let docker = if trace::is_enabled() {
+                Docker::traced(&uri, &trace::proxy(&uri)?)?
+            } else {
+                Docker::new(&uri)?
+            };
+            connectors.push((docker, weight));
+        }
+
+        if connectors.is_empty() {
+            return Err(anyhow!("no docker hosts provided"));
+        }
+
+        Ok(Self::from_connectors(connectors))
+    }
+
+    fn from_connectors(connectors: Vec<(Docker, u32)>) -> Self {
+        let weights = connectors
+            .iter()
+            .map(|(_, weight)| *weight.max(&1) as i64)
+            .collect::<Vec<_>>();
+
+        Self {
+            connectors: connectors.into_iter().map(|(docker, _)| docker).collect(),
+            runtime: Runtime::default(),
+            schedule: weighted_schedule(&weights),
+            next: AtomicUsize::new(0),
+        }
     }
 
     pub fn connect(&self) -> Docker {
-        self.connector.clone()
+        let next = self.next.fetch_add(1, Ordering::SeqCst) % self.schedule.len();
+        self.connectors[self.schedule[next]].clone()
+    }
+
+    /// Returns the connection to `host` if it's in this pool, otherwise the next daemon like
+    /// [`connect`](DockerConnectionPool::connect)
+    pub fn connect_to(&self, host: Option<&str>) -> Docker {
+        host.and_then(|host| {
+            self.connectors
+                .iter()
+                .find(|docker| docker.host() == host)
+                .cloned()
+        })
+        .unwrap_or_else(|| self.connect())
+    }
+
+    /// Connections to all Docker daemons in this pool
+    pub fn hosts(&self) -> &[Docker] {
+        &self.connectors
+    }
+}
+
+/// Creates a smooth weighted round robin order of indexes for the given weights, for example
+/// weights `[5, 1, 1]` result in `[0, 0, 1, 0, 2, 0, 0]`.
+fn weighted_schedule(weights: &[i64]) -> Vec<usize> {
+    let total: i64 = weights.iter().sum();
+    let mut current = vec![0; weights.len()];
+    let mut schedule = Vec::with_capacity(total as usize);
+
+    for _ in 0..total {
+        let mut best = 0;
+        for (i, weight) in weights.iter().enumerate() {
+            current[i] += weight;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        schedule.push(best);
+    }
+
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn creates_weighted_schedule() {
+        assert_eq!(weighted_schedule(&[1]), vec![0]);
+        assert_eq!(weighted_schedule(&[1, 1, 1]), vec![0, 1, 2]);
+        assert_eq!(weighted_schedule(&[5, 1, 1]), vec![0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(weighted_schedule(&[2, 1]), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn pins_connections_to_hosts() {
+        let pool = DockerConnectionPool::with_hosts(vec![("tcp://a:2375", 1), ("tcp://b:2375", 1)])
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(pool.connect_to(Some("tcp://b:2375")).host(), "tcp://b:2375");
+        }
+        assert_eq!(pool.connect_to(Some("tcp://c:2375")).host(), "tcp://a:2375");
+        assert_eq!(pool.connect_to(None).host(), "tcp://b:2375");
+    }
+
+    #[test]
+    fn parses_runtime() {
+        assert_eq!("podman".parse::<Runtime>().unwrap(), Runtime::Podman);
+        assert_eq!("docker".parse::<Runtime>().unwrap(), Runtime::Docker);
+        assert!("containerd".parse::<Runtime>().is_err());
+        assert!(Runtime::Podman
+            .default_sockets()
+            .contains(&PathBuf::from(RUN_PODMAN_SOCK)));
     }
 }
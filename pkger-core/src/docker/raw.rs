@@ -0,0 +1,196 @@
+//! Endpoints of the Docker API that docker-api doesn't cover, like creating containers with
+//! options of `HostConfig` it has no builder methods for and committing containers. Every request
+//! opens a new connection to the daemon.
+use super::trace::{read_head, Conn, Head, Upstream};
+use super::Docker;
+use crate::{ErrContext, Error, Result};
+
+use serde_json::{json, Value as JsonValue};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Percent encodes a value of a query parameter
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Merges the fields of `from` into `into`, objects are merged recursively
+fn merge(into: &mut JsonValue, from: &JsonValue) {
+    match (into, from) {
+        (JsonValue::Object(into), JsonValue::Object(from)) => {
+            for (key, value) in from {
+                match into.get_mut(key) {
+                    Some(existing) if existing.is_object() && value.is_object() => {
+                        merge(existing, value)
+                    }
+                    _ => {
+                        into.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (into, from) => *into = from.clone(),
+    }
+}
+
+async fn read_body(conn: &mut Conn, head: &Head) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    if head.chunked {
+        loop {
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| Error::msg(format!("invalid chunk size `{}`", size)))?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            conn.read_exact(&mut body[start..]).await?;
+            // the line break after the chunk
+            conn.read_exact(&mut [0; 2]).await?;
+        }
+    } else if let Some(len) = head.content_length {
+        body.resize(len as usize, 0);
+        conn.read_exact(&mut body).await?;
+    } else {
+        conn.read_to_end(&mut body).await?;
+    }
+    Ok(body)
+}
+
+/// Sends a request with a JSON `body` to the daemon returning the JSON of the response
+async fn request(docker: &Docker, method: &str, path: &str, body: &JsonValue) -> Result<JsonValue> {
+    let upstream = Upstream::parse(docker.uri())?;
+    let mut conn: Conn = BufReader::new(
+        upstream
+            .connect()
+            .await
+            .context(format!("failed to connect to `{}`", docker.uri()))?,
+    );
+
+    let body = serde_json::to_vec(body)?;
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: docker\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        body.len()
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(&body).await?;
+    conn.flush().await?;
+
+    let head = read_head(&mut conn)
+        .await?
+        .ok_or_else(|| Error::msg("the daemon closed the connection without a response"))?;
+    let status = head
+        .first_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::msg(format!("invalid response `{}`", head.first_line)))?;
+    let body = read_body(&mut conn, &head).await?;
+    let body: JsonValue = if body.is_empty() {
+        JsonValue::Null
+    } else {
+        serde_json::from_slice(&body).unwrap_or_else(|_| {
+            JsonValue::String(String::from_utf8_lossy(&body).trim().to_string())
+        })
+    };
+
+    if status >= 400 {
+        let message = match &body {
+            JsonValue::Object(object) => object
+                .get("message")
+                .and_then(JsonValue::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            JsonValue::String(message) => message.clone(),
+            _ => String::new(),
+        };
+        return Err(Error::msg(format!("error {} - {}", status, message)));
+    }
+    Ok(body)
+}
+
+fn id_of(response: &JsonValue) -> Result<String> {
+    response
+        .get("Id")
+        .and_then(JsonValue::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| Error::msg(format!("missing id in response `{}`", response)))
+}
+
+/// Creates a container returning its id. `config` is the configuration of the container as
+/// created by docker-api with the fields of `overrides` merged into it.
+pub async fn create_container(
+    docker: &Docker,
+    name: Option<&str>,
+    config: &JsonValue,
+    overrides: &JsonValue,
+) -> Result<String> {
+    let mut config = config.clone();
+    merge(&mut config, overrides);
+    let path = match name {
+        Some(name) => format!("/containers/create?name={}", encode(name)),
+        None => "/containers/create".to_string(),
+    };
+    request(docker, "POST", &path, &config)
+        .await
+        .and_then(|response| id_of(&response))
+}
+
+/// Commits the filesystem of the container `id` to an image tagged `repo:tag` returning the id
+/// of the image
+pub async fn commit_container(docker: &Docker, id: &str, repo: &str, tag: &str) -> Result<String> {
+    let path = format!(
+        "/commit?container={}&repo={}&tag={}",
+        encode(id),
+        encode(repo),
+        encode(tag)
+    );
+    request(docker, "POST", &path, &json!({}))
+        .await
+        .and_then(|response| id_of(&response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_container_config() {
+        let mut config = json!({
+            "Image": "centos:8",
+            "HostConfig": { "ExtraHosts": ["foo:127.0.0.1"] },
+        });
+        merge(
+            &mut config,
+            &json!({
+                "Hostname": "builder",
+                "HostConfig": { "ShmSize": 1024, "ReadonlyRootfs": true },
+            }),
+        );
+        assert_eq!(
+            config,
+            json!({
+                "Image": "centos:8",
+                "Hostname": "builder",
+                "HostConfig": {
+                    "ExtraHosts": ["foo:127.0.0.1"],
+                    "ShmSize": 1024,
+                    "ReadonlyRootfs": true,
+                },
+            })
+        );
+        assert_eq!(encode("pkger/foo:1.0 x"), "pkger%2Ffoo%3A1.0%20x");
+    }
+}
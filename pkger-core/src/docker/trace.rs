@@ -0,0 +1,345 @@
+//! Tracing of the Docker API. When enabled, clients connect to a local proxy that forwards every
+//! request to the daemon and logs its method, path, status, duration and the beginning of both
+//! bodies. Streamed responses like build output are logged once they end and connections
+//! upgraded for attaching to containers are relayed without inspection. The proxy listens on a
+//! unix socket only accessible to the current user as access to it is access to the daemon.
+use crate::{ErrContext, Error, Result};
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tempdir::TempDir;
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of bytes of each body included in the log
+const BODY_LIMIT: usize = 512;
+
+pub(super) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+pub(super) type Conn = BufReader<Box<dyn Stream>>;
+
+/// Routes all Docker connections created from now on through a tracing proxy
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Upstream {
+    Unix(String),
+    Tcp(String),
+}
+
+impl Upstream {
+    pub(super) fn parse(uri: &str) -> Result<Self> {
+        match uri.split_once("://") {
+            Some(("unix", path)) => Ok(Upstream::Unix(path.to_string())),
+            Some(("tcp", addr)) | Some(("http", addr)) => {
+                Ok(Upstream::Tcp(addr.trim_end_matches('/').to_string()))
+            }
+            _ => Err(Error::msg(format!(
+                "unsupported Docker uri `{}`, only `unix://` and `tcp://` daemons are supported",
+                uri
+            ))),
+        }
+    }
+
+    pub(super) async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Upstream::Unix(path) => Box::new(UnixStream::connect(path).await?),
+            Upstream::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+        })
+    }
+}
+
+/// Starts a proxy in front of the daemon at `uri` returning the uri clients should connect to.
+/// The proxy listens on a socket with mode `0600` in a private temporary directory that is
+/// removed once the runtime shuts down. Must be called within a Tokio runtime.
+pub fn proxy(uri: &str) -> Result<String> {
+    let upstream = Upstream::parse(uri).context("failed to trace the Docker API")?;
+    let handle = tokio::runtime::Handle::try_current()
+        .context("tracing of the Docker API requires a runtime")?;
+    let dir = TempDir::new("pkger-docker-trace")
+        .context("failed to create a directory for the tracing proxy")?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+    let socket = dir.path().join("docker.sock");
+    let listener = {
+        let _guard = handle.enter();
+        UnixListener::bind(&socket).context("failed to bind the tracing proxy")?
+    };
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+
+    info!(upstream = %uri, proxy = %socket.display(), "tracing Docker API requests");
+    let uri = uri.to_string();
+    let proxy = format!("unix://{}", socket.display());
+    handle.spawn(async move {
+        // the directory lives as long as the proxy
+        let _dir = dir;
+        loop {
+            match listener.accept().await {
+                Ok((client, _)) => {
+                    let upstream = upstream.clone();
+                    let span = info_span!("docker-api", upstream = %uri);
+                    tokio::spawn(
+                        async move {
+                            if let Err(e) = serve(client, &upstream).await {
+                                debug!(reason = %e, "proxied connection failed");
+                            }
+                        }
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    warn!(reason = %e, "tracing proxy stopped accepting connections");
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(proxy)
+}
+
+/// Head of a request or a response with the framing of its body
+pub(super) struct Head {
+    raw: Vec<u8>,
+    pub(super) first_line: String,
+    pub(super) content_length: Option<u64>,
+    pub(super) chunked: bool,
+    upgrade: bool,
+}
+
+/// Reads the head of a message, `None` if the connection was closed before it
+pub(super) async fn read_head(conn: &mut Conn) -> io::Result<Option<Head>> {
+    let mut raw = vec![];
+    let mut head = Head {
+        raw: vec![],
+        first_line: String::new(),
+        content_length: None,
+        chunked: false,
+        upgrade: false,
+    };
+    loop {
+        let start = raw.len();
+        if conn.read_until(b'\n', &mut raw).await? == 0 {
+            return if raw.is_empty() {
+                Ok(None)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+        let line = String::from_utf8_lossy(&raw[start..]).trim().to_string();
+        if line.is_empty() {
+            break;
+        }
+        if head.first_line.is_empty() {
+            head.first_line = line;
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => head.content_length = value.parse().ok(),
+                "transfer-encoding" => {
+                    head.chunked = value.to_ascii_lowercase().contains("chunked")
+                }
+                "connection" => head.upgrade = value.to_ascii_lowercase().contains("upgrade"),
+                _ => {}
+            }
+        }
+    }
+    head.raw = raw;
+    Ok(Some(head))
+}
+
+fn capture(captured: &mut Vec<u8>, data: &[u8]) {
+    let left = BODY_LIMIT.saturating_sub(captured.len());
+    captured.extend_from_slice(&data[..data.len().min(left)]);
+}
+
+/// Relays exactly `len` bytes
+async fn relay_sized(
+    from: &mut Conn,
+    to: &mut Conn,
+    len: u64,
+    captured: &mut Vec<u8>,
+) -> io::Result<()> {
+    let mut left = len;
+    let mut buf = [0; 8192];
+    while left > 0 {
+        let n = from.read(&mut buf[..(left.min(8192) as usize)]).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        capture(captured, &buf[..n]);
+        to.write_all(&buf[..n]).await?;
+        left -= n as u64;
+    }
+    to.flush().await
+}
+
+/// Relays a chunked body forwarding every chunk as soon as it arrives
+async fn relay_chunked(from: &mut Conn, to: &mut Conn, captured: &mut Vec<u8>) -> io::Result<()> {
+    loop {
+        let mut line = vec![];
+        if from.read_until(b'\n', &mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        to.write_all(&line).await?;
+        let size = String::from_utf8_lossy(&line);
+        let size = size.trim().split(';').next().unwrap_or_default();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        if size == 0 {
+            // trailers end with an empty line
+            loop {
+                let mut line = vec![];
+                if from.read_until(b'\n', &mut line).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                to.write_all(&line).await?;
+                if line == b"\r\n" || line == b"\n" {
+                    return to.flush().await;
+                }
+            }
+        }
+        relay_sized(from, to, size, captured).await?;
+        // the line break after the chunk
+        relay_sized(from, to, 2, &mut vec![]).await?;
+    }
+}
+
+fn body_of(captured: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(captured))
+}
+
+/// Relays requests of one client connection until either side closes it
+async fn serve(client: UnixStream, upstream: &Upstream) -> io::Result<()> {
+    let mut client: Conn = BufReader::new(Box::new(client));
+    let mut daemon: Conn = BufReader::new(upstream.connect().await?);
+
+    while let Some(request) = read_head(&mut client).await? {
+        let start = Instant::now();
+        let (method, path) = {
+            let mut parts = request.first_line.split_whitespace();
+            (
+                parts.next().unwrap_or_default().to_string(),
+                parts.next().unwrap_or_default().to_string(),
+            )
+        };
+        daemon.write_all(&request.raw).await?;
+        let mut request_body = vec![];
+        if request.chunked {
+            relay_chunked(&mut client, &mut daemon, &mut request_body).await?;
+        } else if let Some(len) = request.content_length {
+            relay_sized(&mut client, &mut daemon, len, &mut request_body).await?;
+        }
+        daemon.flush().await?;
+
+        let response = match read_head(&mut daemon).await? {
+            Some(response) => response,
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        let status = response
+            .first_line
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        client.write_all(&response.raw).await?;
+        client.flush().await?;
+
+        let mut response_body = vec![];
+        let has_body = method != "HEAD" && status != "204" && status != "304";
+        let raw_stream = status == "101"
+            || (request.upgrade && status.starts_with('2'))
+            || (has_body && !response.chunked && response.content_length.is_none());
+        if raw_stream {
+            info!(method = %method, path = %path, status = %status, duration = ?start.elapsed(), request_body = %body_of(&request_body), "relaying raw stream");
+            io::copy_bidirectional(&mut client, &mut daemon).await?;
+            return Ok(());
+        }
+        if has_body {
+            if response.chunked {
+                relay_chunked(&mut daemon, &mut client, &mut response_body).await?;
+            } else if let Some(len) = response.content_length {
+                relay_sized(&mut daemon, &mut client, len, &mut response_body).await?;
+            }
+        }
+        info!(method = %method, path = %path, status = %status, duration = ?start.elapsed(), request_body = %body_of(&request_body), response_body = %body_of(&response_body));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn traces_requests() {
+        assert_eq!(
+            Upstream::parse("unix:///var/run/docker.sock").unwrap(),
+            Upstream::Unix("/var/run/docker.sock".to_string())
+        );
+        assert!(Upstream::parse("https://docker.example.com:2376").is_err());
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let daemon = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = daemon.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (conn, _) = daemon.accept().await.unwrap();
+                let mut conn: Conn = BufReader::new(Box::new(conn));
+                for _ in 0..2 {
+                    let head = read_head(&mut conn).await.unwrap().unwrap();
+                    assert!(head.first_line.starts_with("GET /"));
+                    conn.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+                        .await
+                        .unwrap();
+                    conn.flush().await.unwrap();
+                }
+            });
+
+            let proxy = proxy(&format!("tcp://{}", addr)).unwrap();
+            let socket = std::path::Path::new(proxy.trim_start_matches("unix://"));
+            let mode = |path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(socket), 0o600);
+            assert_eq!(mode(socket.parent().unwrap()), 0o700);
+            let mut client = UnixStream::connect(socket).await.unwrap();
+            let mut received = vec![];
+            for path in &["/version", "/info"] {
+                client
+                    .write_all(format!("GET {} HTTP/1.1\r\nHost: docker\r\n\r\n", path).as_bytes())
+                    .await
+                    .unwrap();
+                let mut buf = vec![0; 1024];
+                let n = client.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+                while !received.ends_with(b"0\r\n\r\n") {
+                    let n = client.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+            assert_eq!(
+                String::from_utf8_lossy(&received).matches("2\r\nok\r\n").count(),
+                2
+            );
+        });
+    }
+}
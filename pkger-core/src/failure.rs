@@ -0,0 +1,114 @@
+use crate::Error;
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Class of a failure attached to an error as context. Each class has a distinct exit code so
+/// that automation like CI pipelines can react differently to, for example, a broken recipe and
+/// a failing build script.
+pub enum FailureKind {
+    /// A recipe couldn't be read or parsed
+    Recipe,
+    /// Building an image, talking to Docker or running a container failed
+    Runtime,
+    /// Installing dependencies of a build failed
+    Dependencies,
+    /// One of the build scripts exited with an error
+    Script,
+    /// Creating the package from the build output failed
+    Packaging,
+    /// A check of a built package or a downloaded file failed
+    Verification,
+}
+
+impl FailureKind {
+    /// Exit code used when this failure ends the process. Codes start at 10 so that they don't
+    /// collide with the generic error code 1 and the code 2 of invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        use FailureKind::*;
+        match self {
+            Recipe => 10,
+            Runtime => 11,
+            Dependencies => 12,
+            Script => 13,
+            Packaging => 14,
+            Verification => 15,
+        }
+    }
+
+    /// Returns the class attached to `err` or one of its causes
+    pub fn of(err: &Error) -> Option<Self> {
+        err.downcast_ref::<FailureKind>().copied()
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use FailureKind::*;
+        let msg = match self {
+            Recipe => "invalid recipe",
+            Runtime => "runtime failure",
+            Dependencies => "failed to install dependencies",
+            Script => "build script failed",
+            Packaging => "failed to create package",
+            Verification => "verification failed",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for FailureKind {}
+
+/// Renders `err` with each of its causes on a new line indented one level deeper than the
+/// previous one. Multiline messages, like the stderr of a failed command, are kept aligned with
+/// the cause they belong to.
+pub fn report(err: &Error) -> String {
+    let mut out = String::new();
+    for (depth, cause) in err.chain().enumerate() {
+        let indent = "  ".repeat(depth);
+        let msg = cause.to_string();
+        for (i, line) in msg.lines().enumerate() {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            match (depth, i) {
+                (0, 0) => {}
+                (_, 0) => out.push_str(&format!("{}caused by: ", indent)),
+                _ => out.push_str(&format!("{}  ", indent)),
+            }
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrContext;
+
+    #[test]
+    fn finds_kind_in_context() {
+        let err = Err::<(), _>(anyhow!("exit code 1"))
+            .context(FailureKind::Script)
+            .context("job failed")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&err), Some(FailureKind::Script));
+        assert_eq!(FailureKind::of(&anyhow!("other")), None);
+    }
+
+    #[test]
+    fn reports_indented_chain() {
+        let err = Err::<(), _>(anyhow!("exit code 2\nmake: *** No targets"))
+            .context("failed to run build script")
+            .context(FailureKind::Script)
+            .unwrap_err();
+        assert_eq!(
+            report(&err),
+            "build script failed
+  caused by: failed to run build script
+    caused by: exit code 2
+      make: *** No targets"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Storage of the [`ImagesState`](crate::image::ImagesState). The state is split into entries,
+//! one for every target, unreferenced image and cached operating system, that are saved
+//! separately so that processes sharing a backend only overwrite the entries they changed. The
+//! state is kept in a file by default, a SQLite database can be safely shared by multiple pkger
+//! processes on one host and Redis by a fleet of build hosts.
+use crate::image::state;
+use crate::{ErrContext, Error, Result};
+
+use redis::{Commands, ConnectionAddr, IntoConnectionInfo};
+use rusqlite::{params, Connection, TransactionBehavior};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::trace;
+
+/// Time a write waits for other processes to release a SQLite database or for Redis to answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+static DEFAULT_REDIS_KEY: &str = "pkger:state";
+
+/// Serialized entries of the images state by key
+pub type Entries = BTreeMap<String, Vec<u8>>;
+
+/// Loads and saves the entries of the images state
+pub trait StateBackend: fmt::Debug + Send + Sync {
+    /// Returns all saved entries
+    fn load(&self) -> Result<Entries>;
+
+    /// Applies `update` to the saved entries and writes the ones it changed, atomically with
+    /// respect to other processes using the same backend. `update` may be called again if the
+    /// entries were changed concurrently. Returns the entries as saved.
+    fn update(&self, update: &mut dyn FnMut(&mut Entries)) -> Result<Entries>;
+
+    /// Whether a failed load can be recovered from by starting with an empty state. Shared
+    /// backends should not be overwritten after a connection error.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Returns the entries of `after` that differ from `before` and the keys missing from `after`
+fn changes<'a>(
+    before: &Entries,
+    after: &'a Entries,
+) -> (Vec<(&'a String, &'a Vec<u8>)>, Vec<String>) {
+    let changed = after
+        .iter()
+        .filter(|(key, value)| before.get(*key) != Some(value))
+        .collect();
+    let removed = before
+        .keys()
+        .filter(|key| !after.contains_key(*key))
+        .cloned()
+        .collect();
+    (changed, removed)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// Backend of the images state selected in the configuration
+pub enum StateBackendConfig {
+    /// A file, defaults to the state file in the state directory
+    File { path: Option<PathBuf> },
+    /// A SQLite database
+    Sqlite { path: PathBuf },
+    /// A Redis server at `url` like `redis://:password@localhost:6379/0`, the state is saved in a
+    /// hash under `key`
+    Redis { url: String, key: Option<String> },
+}
+
+impl StateBackendConfig {
+    /// Creates the configured backend, `state_file` is used by a file backend without a path
+    pub fn backend(&self, state_file: &Path) -> Result<Arc<dyn StateBackend>> {
+        Ok(match self {
+            StateBackendConfig::File { path } => {
+                Arc::new(FileBackend::new(path.as_deref().unwrap_or(state_file)))
+            }
+            StateBackendConfig::Sqlite { path } => Arc::new(SqliteBackend::new(path)),
+            StateBackendConfig::Redis { url, key } => Arc::new(RedisBackend::new(
+                url,
+                key.as_deref().unwrap_or(DEFAULT_REDIS_KEY),
+            )?),
+        })
+    }
+}
+
+//####################################################################################################
+
+#[derive(Debug)]
+/// Keeps the whole state serialized in one file
+pub struct FileBackend {
+    path: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl StateBackend for FileBackend {
+    fn load(&self) -> Result<Entries> {
+        if !self.path.exists() {
+            return Ok(Entries::new());
+        }
+        let data =
+            fs::read(&self.path).context(format!("failed to read `{}`", self.path.display()))?;
+        // older versions created an empty file before saving anything
+        if data.is_empty() {
+            return Ok(Entries::new());
+        }
+        state::decode(&data)
+    }
+
+    fn update(&self, update: &mut dyn FnMut(&mut Entries)) -> Result<Entries> {
+        let mut entries = self.load()?;
+        update(&mut entries);
+        trace!(state_file = %self.path.display(), "saving state");
+        fs::write(&self.path, state::encode(&entries)?).context("failed to save state file")?;
+        Ok(entries)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+//####################################################################################################
+
+#[derive(Debug)]
+/// Keeps every entry in a separate row of a SQLite database
+pub struct SqliteBackend {
+    path: PathBuf,
+}
+
+impl SqliteBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .context(format!("failed to open `{}`", self.path.display()))?;
+        // concurrent writers wait for each other instead of failing right away
+        conn.busy_timeout(TIMEOUT)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pkger_state_entries (key TEXT PRIMARY KEY, data BLOB NOT NULL)",
+            [],
+        )
+        .context("failed to create state table")?;
+        Ok(conn)
+    }
+}
+
+fn load_rows(conn: &Connection) -> Result<Entries> {
+    let mut stmt = conn.prepare("SELECT key, data FROM pkger_state_entries")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect::<rusqlite::Result<Entries>>()
+        .context("failed to read state")
+}
+
+impl StateBackend for SqliteBackend {
+    fn load(&self) -> Result<Entries> {
+        load_rows(&self.connect()?)
+    }
+
+    fn update(&self, update: &mut dyn FnMut(&mut Entries)) -> Result<Entries> {
+        trace!(database = %self.path.display(), "saving state");
+        let mut conn = self.connect()?;
+        // the write lock is taken right away so that no other process changes the entries
+        // between reading and writing them
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("failed to lock state")?;
+        let before = load_rows(&tx)?;
+        let mut entries = before.clone();
+        update(&mut entries);
+        let (changed, removed) = changes(&before, &entries);
+        for (key, data) in changed {
+            tx.execute(
+                "INSERT INTO pkger_state_entries (key, data) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET data = excluded.data",
+                params![key, data],
+            )
+            .context("failed to save state")?;
+        }
+        for key in removed {
+            tx.execute(
+                "DELETE FROM pkger_state_entries WHERE key = ?1",
+                params![key],
+            )
+            .context("failed to save state")?;
+        }
+        tx.commit().context("failed to save state")?;
+        Ok(entries)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+//####################################################################################################
+
+/// Keeps every entry in a separate field of a Redis hash
+pub struct RedisBackend {
+    client: redis::Client,
+    key: String,
+}
+
+impl fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.client.get_connection_info();
+        f.debug_struct("RedisBackend")
+            .field("addr", &info.addr.to_string())
+            .field("db", &info.redis.db)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl RedisBackend {
+    /// Connects to the server at `url` like `redis://[[user]:password@]host[:port][/db]`
+    pub fn new(url: &str, key: &str) -> Result<Self> {
+        let mut info = url
+            .into_connection_info()
+            .map_err(|e| Error::msg(format!("invalid redis url - {}", e)))?;
+        // IPv6 addresses are kept in brackets which can't be resolved
+        if let ConnectionAddr::Tcp(host, _) = &mut info.addr {
+            if let Some(ip) = host.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+                *host = ip.to_string();
+            }
+        }
+        let client = redis::Client::open(info)?;
+        Ok(Self {
+            client,
+            key: key.to_string(),
+        })
+    }
+
+    fn connect(&self) -> Result<redis::Connection> {
+        let addr = self.client.get_connection_info().addr.to_string();
+        let conn = self
+            .client
+            .get_connection_with_timeout(TIMEOUT)
+            .context(format!("failed to connect to redis at `{}`", addr))?;
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        conn.set_write_timeout(Some(TIMEOUT))?;
+        Ok(conn)
+    }
+}
+
+impl StateBackend for RedisBackend {
+    fn load(&self) -> Result<Entries> {
+        self.connect()?
+            .hgetall(&self.key)
+            .context(format!("failed to read `{}` from redis", self.key))
+    }
+
+    fn update(&self, update: &mut dyn FnMut(&mut Entries)) -> Result<Entries> {
+        trace!(key = %self.key, "saving state");
+        let mut conn = self.connect()?;
+        let key = self.key.as_str();
+        // the transaction is retried if another process changes the hash in the meantime
+        redis::transaction(&mut conn, &[key], |conn, pipe| {
+            let before: Entries = conn.hgetall(key)?;
+            let mut entries = before.clone();
+            update(&mut entries);
+            let (changed, removed) = changes(&before, &entries);
+            for (field, data) in changed {
+                pipe.hset(key, field, data).ignore();
+            }
+            if !removed.is_empty() {
+                pipe.hdel(key, removed).ignore();
+            }
+            pipe.query::<Option<()>>(conn)
+                .map(|saved| saved.map(|_| entries))
+        })
+        .context(format!("failed to save `{}` to redis", self.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_entries_separately() {
+        let dir = tempdir::TempDir::new("pkger-state").unwrap();
+        let path = dir.path().join("state.db");
+        let first = SqliteBackend::new(&path);
+        let second = SqliteBackend::new(&path);
+        first
+            .update(&mut |entries| {
+                entries.insert("a".into(), vec![1]);
+                entries.insert("b".into(), vec![1]);
+            })
+            .unwrap();
+
+        // the second process only sees the change of the first one while saving
+        first
+            .update(&mut |entries| {
+                entries.insert("a".into(), vec![2]);
+            })
+            .unwrap();
+        let saved = second
+            .update(&mut |entries| {
+                entries.remove("b");
+                entries.insert("c".into(), vec![1]);
+            })
+            .unwrap();
+        assert_eq!(first.load().unwrap(), saved);
+        assert_eq!(
+            saved.into_iter().collect::<Vec<_>>(),
+            vec![("a".to_string(), vec![2]), ("c".to_string(), vec![1])]
+        );
+
+        let redis = RedisBackend::new("redis://:secret@[::1]:6380/2", "pkger").unwrap();
+        assert_eq!(
+            format!("{:?}", redis),
+            r#"RedisBackend { addr: "::1:6380", db: 2, key: "pkger" }"#
+        );
+        assert!(RedisBackend::new("rediss://localhost", "pkger").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::docker::{self, Docker};
+use crate::image::ImagesState;
+use crate::recipe::RecipeTarget;
+use crate::units::HumanSize;
+
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+const SECONDS_IN_DAY: u64 = 24 * 60 * 60;
+const BYTES_IN_MB: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+/// Decides which images created by pkger should be removed from Docker.
+pub struct GcPolicy {
+    /// Images that were not used by any build for this many days are removed
+    pub max_age_days: Option<u64>,
+    /// If the total size of images in megabytes exceeds this value least recently used images
+    /// are removed until it doesn't
+    pub max_size_mb: Option<u64>,
+}
+
+impl GcPolicy {
+    /// Evicts images that violate this policy from the state. Evicted images are moved to the
+    /// unreferenced set of the state and removed with the next [`collect`](collect).
+    pub fn apply(&self, state: &mut ImagesState) {
+        let now = SystemTime::now();
+
+        if let Some(max_age) = self.max_age_days {
+            let max_age = Duration::from_secs(max_age * SECONDS_IN_DAY);
+            let expired = state
+                .images
+                .iter()
+                .filter(|(_, image)| {
+                    let last_used = image.last_used.unwrap_or(image.timestamp);
+                    now.duration_since(last_used).unwrap_or_default() > max_age
+                })
+                .map(|(target, _)| target.clone())
+                .collect::<Vec<_>>();
+
+            for target in expired {
+                if let Some(image) = state.evict(&target) {
+                    info!(image = %image.image, id = %image.id, "image expired");
+                }
+            }
+        }
+
+        if let Some(max_size) = self.max_size_mb {
+            let max_size = max_size * BYTES_IN_MB;
+            let mut by_usage = state
+                .images
+                .iter()
+                .map(|(target, image)| {
+                    (
+                        image.last_used.unwrap_or(image.timestamp),
+                        image.details.size as u64,
+                        target.clone(),
+                    )
+                })
+                .collect::<Vec<(SystemTime, u64, RecipeTarget)>>();
+            by_usage.sort_by_key(|(last_used, _, _)| *last_used);
+
+            let mut total_size = by_usage.iter().map(|(_, size, _)| size).sum::<u64>();
+            trace!(total_size = %HumanSize(total_size), max_size = %HumanSize(max_size));
+
+            for (_, size, target) in by_usage {
+                if total_size <= max_size {
+                    break;
+                }
+                if let Some(image) = state.evict(&target) {
+                    info!(image = %image.image, id = %image.id, "evicting image, size limit exceeded");
+                }
+                total_size = total_size.saturating_sub(size);
+            }
+        }
+    }
+}
+
+/// Whether removing an image failed because other images are based on it
+fn has_children(error: &docker::Error) -> bool {
+    matches!(error, docker::Error::Fault { code, message } if code.as_u16() == 409 && message.contains("dependent child"))
+}
+
+/// Applies the `policy` to the state and removes all unreferenced images from every Docker
+/// daemon in `docker`. Images that fail to be removed stay in the state so that removing them
+/// can be retried later.
+pub async fn collect(state: &Arc<RwLock<ImagesState>>, docker: &[Docker], policy: &GcPolicy) {
+    let span = info_span!("image-gc");
+    async move {
+        let unreferenced = match state.write() {
+            Ok(mut state) => {
+                policy.apply(&mut state);
+                state.unreferenced.iter().cloned().collect::<Vec<_>>()
+            }
+            Err(e) => {
+                warn!(reason = %e, "failed to access image state");
+                return;
+            }
+        };
+
+        let mut removed = Vec::new();
+        for id in unreferenced {
+            for docker in docker {
+                match docker.images().get(&id).delete().await {
+                    Ok(_) => {
+                        info!(id = %id, "removed image");
+                        removed.push(id.clone());
+                    }
+                    // a parent of a live image, like the image cached images are built on,
+                    // can't be removed before its children. Docker prunes it together with the
+                    // last of them so retrying is pointless.
+                    Err(e) if has_children(&e) => {
+                        debug!(id = %id, reason = %e, "image has dependent children, not retrying");
+                        removed.push(id.clone());
+                    }
+                    Err(e) => debug!(id = %id, reason = %e, "failed to remove image"),
+                }
+            }
+        }
+
+        if let Ok(mut state) = state.write() {
+            removed.iter().for_each(|id| {
+                state.unreferenced.remove(id);
+                state.os_cache.remove(id);
+            });
+        }
+    }
+    .instrument(span)
+    .await
+}
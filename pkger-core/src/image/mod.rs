@@ -1,5 +1,8 @@
+pub mod backend;
+pub mod gc;
 pub mod os;
 pub mod state;
+pub mod transfer;
 
 pub use os::find_os;
 pub use state::{ImageState, ImagesState};
@@ -11,6 +14,22 @@ use std::convert::AsRef;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// Name of the fixture image of the sandbox mode
+pub static SANDBOX_IMAGE: &str = "pkger-sandbox";
+
+/// A busybox image without a package manager. Scripts executed by pkger expect bash, which the
+/// shell of busybox can stand in for.
+static SANDBOX_DOCKERFILE: &str = r#"FROM busybox:latest
+RUN printf '#!/bin/sh\nexec /bin/sh "$@"\n' > /bin/bash && chmod +x /bin/bash
+"#;
+
+/// Name of the musl based image on which recipes with the `static` preset are built
+pub static MUSL_IMAGE: &str = "pkger-musl";
+
+static MUSL_DOCKERFILE: &str = r#"FROM alpine:latest
+RUN apk add --no-cache bash
+"#;
+
 #[derive(Clone, Debug)]
 /// A representation of an image on the filesystem
 pub struct Image {
@@ -28,6 +47,7 @@ impl Image {
             BuildTarget::Rpm => ("centos:latest", "pkger-rpm"),
             BuildTarget::Deb => ("ubuntu:latest", "pkger-deb"),
             BuildTarget::Pkg => ("archlinux", "pkger-pkg"),
+            BuildTarget::Apk => ("alpine:latest", "pkger-apk"),
             BuildTarget::Gzip => ("ubuntu:latest", "pkger-gzip"),
         }
     }
@@ -44,6 +64,26 @@ impl Image {
         Image::try_from_path(image_dir)
     }
 
+    /// Creates the fixture image of the sandbox mode in `images_dir`. Recipes built on it are
+    /// packaged as gzip archives without installing any dependencies, which is enough to quickly
+    /// validate changes of the build pipeline.
+    pub fn sandbox(images_dir: &Path) -> Result<Image> {
+        let image_dir = images_dir.join(SANDBOX_IMAGE);
+        fs::create_dir_all(&image_dir)?;
+        fs::write(image_dir.join("Dockerfile"), SANDBOX_DOCKERFILE)?;
+
+        Image::try_from_path(image_dir)
+    }
+
+    /// Creates the musl based image of the `static` preset in `images_dir`
+    pub fn musl(images_dir: &Path) -> Result<Image> {
+        let image_dir = images_dir.join(MUSL_IMAGE);
+        fs::create_dir_all(&image_dir)?;
+        fs::write(image_dir.join("Dockerfile"), MUSL_DOCKERFILE)?;
+
+        Image::try_from_path(image_dir)
+    }
+
     pub fn get_or_create(images_dir: &Path, target: BuildTarget) -> Result<Image> {
         let (_, name) = Self::simple_image(target);
 
@@ -1,4 +1,5 @@
 use crate::docker::{api::ContainerCreateOpts, Docker};
+use crate::labels;
 use crate::oneshot::{self, OneShotCtx};
 use crate::recipe::Os;
 use crate::{ErrContext, Error, Result};
@@ -40,6 +41,7 @@ async fn os_from_osrelease(image_id: &str, docker: &Docker) -> Result<Os> {
         docker,
         &ContainerCreateOpts::builder(&image_id)
             .cmd(vec!["cat", "/etc/os-release"])
+            .labels(labels::as_opts(&labels::common()))
             .build(),
         true,
         true,
@@ -90,6 +92,7 @@ async fn os_from_rhrelease(image_id: &str, docker: &Docker) -> Result<Os> {
         docker,
         &ContainerCreateOpts::builder(&image_id)
             .cmd(vec!["cat", "/etc/redhat-release"])
+            .labels(labels::as_opts(&labels::common()))
             .build(),
         true,
         true,
@@ -111,6 +114,7 @@ async fn os_from_issue(image_id: &str, docker: &Docker) -> Result<Os> {
         docker,
         &ContainerCreateOpts::builder(&image_id)
             .cmd(vec!["cat", "/etc/issue"])
+            .labels(labels::as_opts(&labels::common()))
             .build(),
         true,
         true,
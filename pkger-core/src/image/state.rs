@@ -1,20 +1,26 @@
-use crate::image::find_os;
+use crate::image::backend::{Entries, FileBackend, StateBackend};
+use crate::image::{find_os, MUSL_IMAGE};
 
-use crate::docker::{image::ImageDetails, Docker};
-use crate::recipe::{Os, RecipeTarget};
-use crate::{ErrContext, Result};
+use crate::docker::{self, image::ImageDetails, Docker};
+use crate::recipe::{Os, Recipe, RecipeTarget};
+use crate::{ErrContext, Error, Result};
 
 use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
-use std::fs::{self, File};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info, info_span, trace, Instrument};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 
 pub static DEFAULT_STATE_FILE: &str = ".pkger.state";
 
+/// Prefixes of keys of entries of the state
+static IMAGE_ENTRY: &str = "image:";
+static UNREFERENCED_ENTRY: &str = "unreferenced:";
+static OS_ENTRY: &str = "os:";
+
 #[derive(Deserialize, Clone, Debug, Serialize)]
 /// Saved state of an image that contains all the metadata of the image
 pub struct ImageState {
@@ -26,15 +32,26 @@ pub struct ImageState {
     pub details: ImageDetails,
     pub deps: HashSet<String>,
     pub simple: bool,
+    #[serde(default)]
+    /// Last time a build used this image
+    pub last_used: Option<SystemTime>,
+    #[serde(default)]
+    /// Id of the snapshot of the package repositories the dependencies were installed from
+    pub snapshot: Option<String>,
+    #[serde(default)]
+    /// Docker daemon the image was built on, images without one exist on all daemons
+    pub host: Option<String>,
 }
 
 impl ImageState {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         id: &str,
         target: &RecipeTarget,
         tag: &str,
         timestamp: &SystemTime,
         docker: &Docker,
+        images: &RwLock<ImagesState>,
         deps: &HashSet<&str>,
         simple: bool,
     ) -> Result<ImageState> {
@@ -51,7 +68,7 @@ impl ImageState {
             let os = if let Some(os) = target.image_os() {
                 os.clone()
             } else {
-                find_os(id, docker).await?
+                find_os_cached(images, id, docker).await?
             };
             debug!(os = ?os, "parsed image info");
 
@@ -67,16 +84,24 @@ impl ImageState {
                 details,
                 deps: deps.iter().map(|s| s.to_string()).collect(),
                 simple,
+                last_used: Some(*timestamp),
+                snapshot: None,
+                host: Some(docker.host().to_string()),
             })
         }
         .instrument(span)
         .await
     }
 
-    /// Verifies if a given image exists in docker, on connection error returns false
+    /// Verifies if a given image exists in docker, on connection error returns false. Images built
+    /// on another daemon don't exist in this one.
     pub async fn exists(&self, docker: &Docker) -> bool {
         let span = info_span!("check-image-exists", image = %self.image, id = %self.id);
         async move {
+            if let Some(host) = self.host.as_deref().filter(|host| *host != docker.host()) {
+                info!(host = %host, "image was built on another Docker daemon");
+                return false;
+            }
             info!("checking if image exists in Docker");
             docker.images().get(&self.id).inspect().await.is_ok()
         }
@@ -85,6 +110,105 @@ impl ImageState {
     }
 }
 
+/// Returns the operating system of the image with `id`, only images missing from the cache of
+/// `images` are probed. Ids are digests of the images so a rebuilt image is always probed again.
+pub async fn find_os_cached(images: &RwLock<ImagesState>, id: &str, docker: &Docker) -> Result<Os> {
+    if let Some(os) = images
+        .read()
+        .ok()
+        .and_then(|images| images.cached_os(id).cloned())
+    {
+        trace!(id = %id, os = ?os, "using cached os");
+        return Ok(os);
+    }
+    let os = find_os(id, docker).await?;
+    if let Ok(mut images) = images.write() {
+        images.cache_os(id, &os);
+    }
+    Ok(os)
+}
+
+/// Whether `recipe` still builds `target`, the image of the build stage included
+fn has_target(recipe: &Recipe, target: &RecipeTarget) -> bool {
+    let metadata = &recipe.metadata;
+    let image = target.image();
+    metadata.images.iter().flatten().any(|image_target| {
+        image_target.image == image && &image_target.build_target == target.build_target()
+    }) || metadata.build_image.as_deref() == Some(image)
+        || (metadata.has_build_stage() && metadata.build_image.is_none() && image == MUSL_IMAGE)
+}
+
+/// Returns true only if every daemon in `docker` reports that the image with `id` doesn't exist,
+/// daemons that fail to answer are assumed to still have it
+async fn is_missing(id: &str, docker: &[Docker]) -> bool {
+    for docker in docker {
+        match docker.images().get(id).inspect().await {
+            Err(docker::Error::Fault { code, .. }) if code.as_u16() == 404 => {}
+            Ok(_) => return false,
+            Err(e) => {
+                debug!(id = %id, reason = %e, "failed to inspect image");
+                return false;
+            }
+        }
+    }
+    !docker.is_empty()
+}
+
+/// Drops entries of images that no longer exist on any of the Docker daemons in `docker`, for
+/// example because they were removed by hand, so that builds don't reference stale image ids.
+/// Operating systems cached for missing images are dropped as well. Returns the number of dropped
+/// entries.
+pub async fn prune_missing(images: &RwLock<ImagesState>, docker: &[Docker]) -> usize {
+    let span = info_span!("prune-missing-images");
+    async move {
+        let ids = match images.read() {
+            Ok(images) => images
+                .images
+                .values()
+                .map(|state| state.id.clone())
+                .chain(images.unreferenced.iter().cloned())
+                .chain(images.os_cache.keys().cloned())
+                .collect::<HashSet<_>>(),
+            Err(e) => {
+                warn!(reason = %e, "failed to access image state");
+                return 0;
+            }
+        };
+
+        let mut missing = HashSet::new();
+        for id in ids {
+            if is_missing(&id, docker).await {
+                trace!(id = %id, "image doesn't exist");
+                missing.insert(id);
+            }
+        }
+        if missing.is_empty() {
+            return 0;
+        }
+
+        let mut images = match images.write() {
+            Ok(images) => images,
+            Err(e) => {
+                warn!(reason = %e, "failed to access image state");
+                return 0;
+            }
+        };
+        let before = images.images.len();
+        images.images.retain(|target, state| {
+            let exists = !missing.contains(&state.id);
+            if !exists {
+                info!(image = %target.image(), recipe = %target.recipe(), id = %state.id, "dropping state of removed image");
+            }
+            exists
+        });
+        images.unreferenced.retain(|id| !missing.contains(id));
+        images.os_cache.retain(|id, _| !missing.contains(id));
+        before - images.images.len()
+    }
+    .instrument(span)
+    .await
+}
+
 //####################################################################################################
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -92,51 +216,215 @@ pub struct ImagesState {
     /// Contains historical build data of images. Each key-value pair contains an image name and
     /// [ImageState](ImageState) struct representing the state of the image.
     pub images: HashMap<RecipeTarget, ImageState>,
-    /// Path to a file containing image state
-    pub state_file: PathBuf,
+    #[serde(skip, default = "default_backend")]
+    /// Where the state is loaded from and saved to
+    pub backend: Arc<dyn StateBackend>,
+    #[serde(default)]
+    /// Ids of images created by pkger that are no longer referenced by any target and can be
+    /// removed from Docker.
+    pub unreferenced: HashSet<String>,
+    #[serde(default)]
+    /// Operating systems of images found by probing them, by image id
+    pub os_cache: HashMap<String, Os>,
+    #[serde(skip)]
+    /// Entries as last loaded from or saved to the backend
+    saved: Entries,
+}
+
+/// Parses a state serialized as a whole into entries
+pub(crate) fn decode(data: &[u8]) -> Result<Entries> {
+    serde_cbor::from_slice::<ImagesState>(data)
+        .context("failed to parse image state")?
+        .entries()
+}
+
+/// Serializes entries of a state as a whole
+pub(crate) fn encode(entries: &Entries) -> Result<Vec<u8>> {
+    let state = ImagesState::from_entries(entries, default_backend())?;
+    serde_cbor::to_vec(&state).context("failed to serialize image state")
+}
+
+fn default_backend() -> Arc<dyn StateBackend> {
+    Arc::new(FileBackend::new(DEFAULT_STATE_FILE))
 }
 
 impl Default for ImagesState {
     fn default() -> Self {
+        ImagesState::empty(default_backend())
+    }
+}
+
+impl ImagesState {
+    /// Creates an empty state saved to `backend`
+    pub fn empty(backend: Arc<dyn StateBackend>) -> Self {
         ImagesState {
             images: HashMap::new(),
-            state_file: PathBuf::from(DEFAULT_STATE_FILE),
+            backend,
+            unreferenced: HashSet::new(),
+            os_cache: HashMap::new(),
+            saved: Entries::new(),
         }
     }
-}
 
-impl ImagesState {
+    /// Loads the images state from `backend`, a backend without a saved state gives an empty
+    /// state
+    pub fn load(backend: Arc<dyn StateBackend>) -> Result<Self> {
+        let entries = backend.load()?;
+        Self::from_entries(&entries, backend)
+    }
+
+    fn from_entries(entries: &Entries, backend: Arc<dyn StateBackend>) -> Result<Self> {
+        let mut state = ImagesState::empty(backend);
+        for (key, data) in entries {
+            if let Some(target) = key.strip_prefix(IMAGE_ENTRY) {
+                let target = serde_json::from_str(target)
+                    .context(format!("invalid target of image state `{}`", target))?;
+                let image = serde_cbor::from_slice(data)
+                    .context(format!("failed to parse image state of `{}`", key))?;
+                state.images.insert(target, image);
+            } else if let Some(id) = key.strip_prefix(UNREFERENCED_ENTRY) {
+                state.unreferenced.insert(id.to_string());
+            } else if let Some(id) = key.strip_prefix(OS_ENTRY) {
+                let os = serde_cbor::from_slice(data)
+                    .context(format!("failed to parse cached os of `{}`", id))?;
+                state.os_cache.insert(id.to_string(), os);
+            } else {
+                return Err(Error::msg(format!(
+                    "unknown entry of image state `{}`",
+                    key
+                )));
+            }
+        }
+        state.saved = entries.clone();
+        Ok(state)
+    }
+
+    /// Splits the state into entries saved separately
+    fn entries(&self) -> Result<Entries> {
+        let mut entries = Entries::new();
+        for (target, image) in &self.images {
+            entries.insert(
+                format!("{}{}", IMAGE_ENTRY, serde_json::to_string(target)?),
+                serde_cbor::to_vec(image).context("failed to serialize image state")?,
+            );
+        }
+        for id in &self.unreferenced {
+            entries.insert(format!("{}{}", UNREFERENCED_ENTRY, id), vec![]);
+        }
+        for (id, os) in &self.os_cache {
+            entries.insert(
+                format!("{}{}", OS_ENTRY, id),
+                serde_cbor::to_vec(os).context("failed to serialize cached os")?,
+            );
+        }
+        Ok(entries)
+    }
+
     /// Tries to initialize images state from the given path
     pub fn try_from_path<P: AsRef<Path>>(state_file: P) -> Result<Self> {
-        if !state_file.as_ref().exists() {
-            File::create(state_file.as_ref())?;
+        Self::load(Arc::new(FileBackend::new(state_file)))
+    }
 
-            return Ok(ImagesState {
-                images: HashMap::new(),
-                state_file: state_file.as_ref().to_path_buf(),
-            });
+    /// Updates the target image with a new state. The old image is not released when the new one
+    /// is based on it, like a cached image, as it can't be removed before the new one.
+    pub fn update(&mut self, target: &RecipeTarget, state: &ImageState) {
+        self.unreferenced.remove(&state.id);
+        if let Some(old) = self.images.insert(target.clone(), state.clone()) {
+            if old.id != state.details.parent {
+                self.release(old.id);
+            }
         }
-        let contents = fs::read(state_file.as_ref())?;
-        Ok(serde_cbor::from_slice(&contents)?)
     }
 
-    /// Updates the target image with a new state
-    pub fn update(&mut self, target: &RecipeTarget, state: &ImageState) {
-        self.images.insert(target.clone(), state.clone());
-    }
-
-    /// Saves the images state to the filesystem
-    pub fn save(&self) -> Result<()> {
-        if !Path::new(&self.state_file).exists() {
-            trace!(state_file = %self.state_file.display(), "doesn't exist, creating");
-            fs::File::create(&self.state_file)
-                .context("failed to save state file")
-                .map(|_| ())
-        } else {
-            trace!(state_file = %self.state_file.display(), "file exists, overwriting");
-            serde_cbor::to_vec(&self)
-                .context("failed to deserialize image state")
-                .and_then(|d| fs::write(&self.state_file, d).context("failed to save state file"))
+    /// Docker daemon the image of the target was built on, builds of the target should run on it
+    /// so that the image is reused
+    pub fn host_of(&self, target: &RecipeTarget) -> Option<&str> {
+        self.images
+            .get(target)
+            .and_then(|state| state.host.as_deref())
+    }
+
+    /// Marks the image of the target as used by a build right now
+    pub fn mark_used(&mut self, target: &RecipeTarget) {
+        if let Some(state) = self.images.get_mut(target) {
+            state.last_used = Some(SystemTime::now());
         }
     }
+
+    /// Removes the image of the target from the state marking it as unreferenced
+    pub fn evict(&mut self, target: &RecipeTarget) -> Option<ImageState> {
+        let state = self.images.remove(target)?;
+        self.release(state.id.clone());
+        Some(state)
+    }
+
+    /// Evicts the images of targets that none of `recipes` builds anymore, because the recipe was
+    /// deleted or it no longer has the target. `recipes` must contain all recipes. Images of simple
+    /// targets are only evicted with their recipe. Returns the evicted targets.
+    pub fn prune_targets(&mut self, recipes: &[Recipe]) -> Vec<RecipeTarget> {
+        let stale = self
+            .images
+            .iter()
+            .filter(|(target, state)| {
+                !recipes.iter().any(|recipe| {
+                    recipe.metadata.name == target.recipe()
+                        && (state.simple || has_target(recipe, target))
+                })
+            })
+            .map(|(target, _)| target.clone())
+            .collect::<Vec<_>>();
+
+        for target in &stale {
+            if let Some(state) = self.evict(target) {
+                info!(image = %target.image(), recipe = %target.recipe(), id = %state.id, "evicting image of removed target");
+            }
+        }
+        stale
+    }
+
+    pub fn cached_os(&self, id: &str) -> Option<&Os> {
+        self.os_cache.get(id)
+    }
+
+    /// Saves the operating system of the image with `id` so that the image isn't probed again
+    pub fn cache_os(&mut self, id: &str, os: &Os) {
+        self.os_cache.insert(id.to_string(), os.clone());
+    }
+
+    /// Marks the image as unreferenced unless some other target still uses it
+    fn release(&mut self, id: String) {
+        if !self.images.values().any(|state| state.id == id) {
+            self.unreferenced.insert(id);
+        }
+    }
+
+    /// Saves the entries changed since the state was loaded to its backend and merges in the
+    /// entries saved by other processes in the meantime. Entries removed here are only removed
+    /// from the backend if nobody changed them since, and images referenced by any target are
+    /// never left unreferenced.
+    pub fn save(&mut self) -> Result<()> {
+        let entries = self.entries()?;
+        let saved = &self.saved;
+        let merged = self.backend.update(&mut |stored| {
+            for (key, data) in &entries {
+                if saved.get(key) != Some(data) {
+                    stored.insert(key.clone(), data.clone());
+                }
+            }
+            for (key, data) in saved {
+                if !entries.contains_key(key) && stored.get(key) == Some(data) {
+                    stored.remove(key);
+                }
+            }
+            let referenced = stored
+                .iter()
+                .filter(|(key, _)| key.starts_with(IMAGE_ENTRY))
+                .filter_map(|(_, data)| serde_cbor::from_slice::<ImageState>(data).ok())
+                .map(|image| format!("{}{}", UNREFERENCED_ENTRY, image.id))
+                .collect::<HashSet<_>>();
+            stored.retain(|key, _| !referenced.contains(key));
+        })?;
+        *self = Self::from_entries(&merged, self.backend.clone())?;
+        Ok(())
+    }
 }
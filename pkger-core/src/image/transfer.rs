@@ -0,0 +1,110 @@
+//! Transfer of cached images between machines. An exported archive contains the images saved
+//! from Docker together with their state entries, so that ephemeral runners can restore a warm
+//! cache from artifact storage.
+use crate::docker::{api::ImageBuildChunk, Docker};
+use crate::image::ImageState;
+use crate::recipe::RecipeTarget;
+use crate::{ErrContext, Error, Result};
+
+use futures::StreamExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use tempdir::TempDir;
+use tracing::{info_span, trace, warn, Instrument};
+
+static STATE_ENTRY: &str = "state.cbor";
+static IMAGES_ENTRY: &str = "images.tar";
+
+/// Saves images of `entries` that exist in Docker together with their state to a tar archive at
+/// `path`. Returns the number of exported entries.
+pub async fn export(
+    docker: &Docker,
+    entries: &[(RecipeTarget, ImageState)],
+    path: &Path,
+) -> Result<usize> {
+    let span = info_span!("export-images", path = %path.display());
+    async move {
+        let mut exported = vec![];
+        for (target, state) in entries {
+            if state.exists(docker).await {
+                exported.push((target.clone(), state.clone()));
+            } else {
+                warn!(image = %state.image, id = %state.id, "skipping, image doesn't exist in docker");
+            }
+        }
+        let mut names = exported
+            .iter()
+            .map(|(_, state)| format!("{}:{}", state.image, state.tag))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        trace!(images = ?names);
+
+        let tmp = TempDir::new("pkger-export")?;
+        let images_path = tmp.path().join(IMAGES_ENTRY);
+        let mut file = File::create(&images_path)?;
+        let images = docker.images();
+        let mut stream = Box::pin(images.export(names.iter().map(String::as_str).collect()));
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk.context("failed to save images")?)?;
+        }
+        file.flush()?;
+
+        let state = serde_cbor::to_vec(&exported).context("failed to serialize image state")?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(state.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let mut archive = tar::Builder::new(
+            File::create(path).context(format!("failed to create `{}`", path.display()))?,
+        );
+        archive.append_data(&mut header, STATE_ENTRY, state.as_slice())?;
+        archive.append_path_with_name(&images_path, IMAGES_ENTRY)?;
+        archive.finish()?;
+
+        Ok(exported.len())
+    }
+    .instrument(span)
+    .await
+}
+
+/// Loads images from an archive created by [`export`](export) into Docker returning their state
+/// entries
+pub async fn import(docker: &Docker, path: &Path) -> Result<Vec<(RecipeTarget, ImageState)>> {
+    let span = info_span!("import-images", path = %path.display());
+    async move {
+        let tmp = TempDir::new("pkger-import")?;
+        let mut archive = tar::Archive::new(
+            File::open(path).context(format!("failed to open `{}`", path.display()))?,
+        );
+        archive
+            .unpack(tmp.path())
+            .context("failed to unpack the archive")?;
+
+        let mut entries: Vec<(RecipeTarget, ImageState)> = serde_cbor::from_slice(
+            &fs::read(tmp.path().join(STATE_ENTRY))
+                .context("the archive doesn't contain the state of images")?,
+        )
+        .context("failed to deserialize image state")?;
+        trace!(entries = %entries.len());
+        // the images are loaded into every daemon they are imported to
+        entries.iter_mut().for_each(|(_, state)| state.host = None);
+
+        let images = docker.images();
+        let mut stream = images.import(File::open(tmp.path().join(IMAGES_ENTRY))?);
+        while let Some(chunk) = stream.next().await {
+            match chunk.context("failed to load images")? {
+                ImageBuildChunk::Error { error, .. } => {
+                    return Err(Error::msg(format!("failed to load images - {}", error)))
+                }
+                chunk => trace!(chunk = ?chunk),
+            }
+        }
+
+        Ok(entries)
+    }
+    .instrument(span)
+    .await
+}
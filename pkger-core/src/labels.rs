@@ -0,0 +1,113 @@
+//! Labels of containers and images created by pkger. They let operators correlate leftover
+//! resources with the build that created them and let external tooling clean them up with filters
+//! like `docker ps -a --filter label=pkger.recipe=foo`.
+use crate::build::Context;
+
+use std::collections::HashMap;
+use std::env;
+use std::process;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// Set on everything created by pkger
+pub static MANAGED: &str = "pkger.managed";
+/// Version of pkger that created the resource
+pub static VERSION: &str = "pkger.version";
+/// Id of the pkger invocation, shared by all builds it runs
+pub static RUN: &str = "pkger.run";
+/// Id of the build, the same as the name of its container
+pub static BUILD: &str = "pkger.build";
+pub static RECIPE: &str = "pkger.recipe";
+/// Image of the build target
+pub static TARGET: &str = "pkger.target";
+/// Package format of the build target
+pub static BUILD_TARGET: &str = "pkger.build-target";
+
+/// Overrides the generated run id, for example with the id of a CI pipeline
+static RUN_ID_ENV: &str = "PKGER_RUN_ID";
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Id of this invocation of pkger, `PKGER_RUN_ID` or the start time and pid of the process
+pub fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        env::var(RUN_ID_ENV)
+            .ok()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| {
+                let timestamp = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                format!("{}-{}", timestamp, process::id())
+            })
+    })
+}
+
+/// Labels of resources that don't belong to a single build
+pub fn common() -> HashMap<&'static str, String> {
+    vec![
+        (MANAGED, "true".to_string()),
+        (VERSION, env!("CARGO_PKG_VERSION").to_string()),
+        (RUN, run_id().to_string()),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// Labels of the container and images of a build
+pub fn for_build(ctx: &Context) -> HashMap<&'static str, String> {
+    let mut labels = common();
+    let target = ctx.target();
+    labels.insert(BUILD, ctx.id().to_string());
+    labels.insert(RECIPE, target.recipe().to_string());
+    labels.insert(TARGET, target.image().to_string());
+    labels.insert(BUILD_TARGET, target.build_target().as_ref().to_string());
+    labels
+}
+
+/// Borrows labels in the form taken by the Docker API options
+pub fn as_opts<'a>(labels: &'a HashMap<&'static str, String>) -> HashMap<&'a str, &'a str> {
+    labels
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect()
+}
+
+/// Name of the container of a build, `pkger-<recipe>-<image>-<timestamp>` with characters Docker
+/// doesn't allow in names replaced by `-`
+pub fn container_name(recipe: &str, image: &str, timestamp: u64) -> String {
+    format!("pkger-{}-{}-{}", recipe, image, timestamp)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_resources() {
+        assert_eq!(
+            container_name("foo", "centos:8", 1638200000),
+            "pkger-foo-centos-8-1638200000"
+        );
+        assert_eq!(
+            container_name("libfoo++", "debian10", 1),
+            "pkger-libfoo---debian10-1"
+        );
+
+        let labels = common();
+        assert_eq!(labels[MANAGED], "true");
+        assert_eq!(labels[RUN], run_id());
+        assert_eq!(run_id(), run_id());
+        assert_eq!(as_opts(&labels)[VERSION], env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -5,8 +5,13 @@ pub mod archive;
 pub mod build;
 pub mod container;
 pub mod docker;
+pub mod failure;
 pub mod image;
+pub mod labels;
 pub mod oneshot;
 pub mod recipe;
+pub mod repo;
+pub mod secrets;
+pub mod units;
 
 pub use anyhow::{anyhow, Context as ErrContext, Error, Result};
@@ -1,4 +1,4 @@
-use crate::recipe::BuildTarget;
+use crate::recipe::{BuildArch, BuildTarget};
 
 use serde::{Deserialize, Serialize};
 
@@ -12,13 +12,27 @@ use serde::{Deserialize, Serialize};
 /// { cmd = "echo 123", images = ["centos8", "debian10"] }
 ///
 /// { cmd = "echo 321", rpm = true } # execute only when building rpm target
+///
+/// { cmd = "echo 456", arch = ["armhf", "arm64"] } # execute only when building for these architectures
+///
+/// { cmd = "mount -o loop disk.img /mnt", privileged = true } # execute with extended privileges
+///
+/// { cmd = "./configure --with-tls", features = ["tls"] } # execute only with these features enabled
 pub struct Command {
     pub cmd: String,
     pub images: Option<Vec<String>>,
+    pub arch: Option<Vec<String>>,
     pub rpm: Option<bool>,
     pub deb: Option<bool>,
     pub pkg: Option<bool>,
+    pub apk: Option<bool>,
     pub gzip: Option<bool>,
+    /// Runs the step with extended privileges, allowed only for recipes listed in the
+    /// configuration
+    pub privileged: Option<bool>,
+    /// Features that must be enabled for the step to run, features prefixed with `!` must be
+    /// disabled
+    pub features: Option<Vec<String>>,
 }
 
 impl From<&str> for Command {
@@ -26,17 +40,29 @@ impl From<&str> for Command {
         Self {
             cmd: s.to_string(),
             images: None,
+            arch: None,
             rpm: None,
             deb: None,
             pkg: None,
+            apk: None,
             gzip: None,
+            privileged: None,
+            features: None,
         }
     }
 }
 
 impl Command {
+    pub fn is_privileged(&self) -> bool {
+        self.privileged.unwrap_or_default()
+    }
+
     pub fn has_target_specified(&self) -> bool {
-        self.rpm.is_some() || self.deb.is_some() || self.pkg.is_some() || self.gzip.is_some()
+        self.rpm.is_some()
+            || self.deb.is_some()
+            || self.pkg.is_some()
+            || self.apk.is_some()
+            || self.gzip.is_some()
     }
     pub fn should_run_on(&self, target: &BuildTarget) -> bool {
         if !self.has_target_specified() {
@@ -46,8 +72,49 @@ impl Command {
             BuildTarget::Rpm => self.rpm,
             BuildTarget::Deb => self.deb,
             BuildTarget::Pkg => self.pkg,
+            BuildTarget::Apk => self.apk,
             BuildTarget::Gzip => self.gzip,
         }
         .unwrap_or_default()
     }
+
+    /// Checks whether this command should be executed for the architecture `arch`
+    pub fn should_run_for(&self, arch: &BuildArch) -> bool {
+        self.arch
+            .as_ref()
+            .map(|archs| archs.iter().any(|a| BuildArch::from(a.as_str()) == *arch))
+            .unwrap_or(true)
+    }
+
+    /// Checks whether this command should be executed with the `enabled` features
+    pub fn should_run_with(&self, enabled: &[String]) -> bool {
+        self.features
+            .as_ref()
+            .map(|features| {
+                features
+                    .iter()
+                    .all(|feature| match feature.strip_prefix('!') {
+                        Some(feature) => !enabled.iter().any(|f| f == feature),
+                        None => enabled.iter().any(|f| f == feature),
+                    })
+            })
+            .unwrap_or(true)
+    }
+
+    /// Checks whether this command should be executed when building `target` for `arch` on
+    /// `image`. A command excluded by its image filter still runs if it explicitly specifies the
+    /// target.
+    pub fn should_run(&self, image: &str, target: &BuildTarget, arch: &BuildArch) -> bool {
+        if !self.should_run_for(arch) {
+            return false;
+        }
+
+        if let Some(images) = &self.images {
+            if !images.iter().any(|i| i == image) && !self.has_target_specified() {
+                return false;
+            }
+        }
+
+        self.should_run_on(target)
+    }
 }
@@ -0,0 +1,81 @@
+//! Documentation of recipes describing what the package does. It is either set inline in the
+//! `docs` field, read from a markdown file the field points to or from `README.md` next to the
+//! recipe.
+use crate::recipe::Recipe;
+
+use std::fs;
+
+const README_FILE: &str = "README.md";
+
+impl Recipe {
+    /// Returns the markdown documentation of this recipe if it has any
+    pub fn docs(&self) -> Option<String> {
+        match &self.metadata.docs {
+            Some(docs) => {
+                let path = self.recipe_dir.join(docs);
+                if docs.ends_with(".md") && path.is_file() {
+                    fs::read_to_string(path).ok()
+                } else {
+                    Some(docs.clone())
+                }
+            }
+            None => fs::read_to_string(self.recipe_dir.join(README_FILE)).ok(),
+        }
+    }
+
+    /// Returns the first paragraph of the documentation of this recipe
+    pub fn summary(&self) -> Option<String> {
+        self.docs().and_then(|docs| summary(&docs))
+    }
+}
+
+/// Returns the first paragraph of `markdown` skipping headings, images and html
+pub fn summary(markdown: &str) -> Option<String> {
+    let mut paragraph = vec![];
+    for line in markdown.lines().map(str::trim) {
+        if line.is_empty() {
+            if paragraph.is_empty() {
+                continue;
+            }
+            break;
+        }
+        if paragraph.is_empty()
+            && (line.starts_with('#')
+                || line.starts_with('!')
+                || line.starts_with("[!")
+                || line.starts_with('<'))
+        {
+            continue;
+        }
+        paragraph.push(line);
+    }
+
+    if paragraph.is_empty() {
+        None
+    } else {
+        Some(paragraph.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_summary() {
+        let readme = r#"# pkger
+
+![logo](assets/logo.svg)
+
+Creates RPM, DEB and other packages
+using Docker.
+
+## Installation
+"#;
+        assert_eq!(
+            summary(readme),
+            Some("Creates RPM, DEB and other packages using Docker.".to_string())
+        );
+        assert_eq!(summary("# Title\n\n"), None);
+    }
+}
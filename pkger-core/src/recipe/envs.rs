@@ -37,6 +37,13 @@ impl Env {
         self.0.insert(key.into(), value.into())
     }
 
+    pub fn contains<K>(&self, key: K) -> bool
+    where
+        K: AsRef<str>,
+    {
+        self.0.contains_key(key.as_ref())
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
@@ -49,6 +56,10 @@ impl Env {
         self.0.remove(key.as_ref())
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+
     pub fn kv_vec(self) -> Vec<String> {
         self.0
             .into_iter()
@@ -0,0 +1,531 @@
+use crate::recipe::metadata::rpm_files_entries;
+use crate::recipe::{
+    strip_arch_qualifier, BuildArch, BuildTarget, Command, Patch, Recipe, UNKNOWN_MAINTAINER,
+};
+use crate::{secrets, ErrContext, Result};
+
+use chrono::Local;
+use rpmspec::RpmSpec;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, trace, warn};
+
+static DEBHELPER_COMPAT: &str = "debhelper-compat (= 12)";
+static DEB_STANDARDS_VERSION: &str = "4.5.1";
+
+/// Renders the steps of a script that should run when building `target` for `arch` on `image` as
+/// shell commands. The commands are executed in `working_dir` if the script specifies it or in
+/// `default_dir` otherwise.
+fn render_script(
+    steps: &[Command],
+    working_dir: Option<&PathBuf>,
+    default_dir: &str,
+    image: &str,
+    target: &BuildTarget,
+    arch: &BuildArch,
+) -> String {
+    let dir = working_dir
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_else(|| default_dir.to_string());
+
+    let mut script = format!("mkdir -p {0}\ncd {0}\n", dir);
+    steps
+        .iter()
+        .filter(|cmd| cmd.should_run(image, target, arch))
+        .for_each(|cmd| {
+            script.push_str(&cmd.cmd);
+            script.push('\n');
+        });
+
+    script
+}
+
+/// File name of a patch that is either a path or an url
+fn patch_name(patch: &Patch) -> String {
+    patch
+        .patch()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl Recipe {
+    fn sorted_env(&self) -> Vec<String> {
+        let mut env = self.env.clone().kv_vec();
+        env.sort();
+        env
+    }
+
+    fn exported_patches(&self, image: &str) -> Vec<&Patch> {
+        self.metadata
+            .patches
+            .as_ref()
+            .map(|patches| patches.resolve_names(image))
+            .unwrap_or_default()
+    }
+
+    fn source_archive_name(&self) -> String {
+        format!("{}-{}.tar.gz", self.metadata.name, self.metadata.version)
+    }
+
+    /// Renders all scripts of this recipe as the `%prep`, `%build` and `%install` sections of a
+    /// standalone spec file that can be built with `rpmbuild` without pkger.
+    pub fn export_rpm_spec(&self, image: &str) -> RpmSpec {
+        let mut files = vec!["# list the files installed to %{buildroot} here".to_string()];
+        files.extend(self.metadata.rpm.iter().flat_map(|rpm| {
+            rpm.file_attributes
+                .iter()
+                .map(|(path, attributes)| attributes.render(path))
+        }));
+        self.exported_rpm_spec(image, files)
+    }
+
+    /// Same as `export_rpm_spec` but with `files` installed by a build of the recipe in `%files`,
+    /// so that the spec of a source RPM can be rebuilt as it is
+    pub fn source_rpm_spec(&self, image: &str, files: &[String]) -> RpmSpec {
+        let files = match &self.metadata.rpm {
+            Some(rpm) => rpm_files_entries(files, &rpm.file_attributes),
+            None => files.to_vec(),
+        };
+        self.exported_rpm_spec(image, files)
+    }
+
+    fn exported_rpm_spec(&self, image: &str, files: Vec<String>) -> RpmSpec {
+        let target = BuildTarget::Rpm;
+        let source = match &self.metadata.source {
+            Some(source) if self.metadata.git.is_none() => source.clone(),
+            // a git repository has to be archived manually
+            _ => self.source_archive_name(),
+        };
+
+        let patches = self.exported_patches(image);
+        let mut prep = String::from("%setup -q -c\n");
+        patches.iter().enumerate().for_each(|(i, patch)| {
+            prep.push_str(&format!("%patch{} -p{}\n", i, patch.strip_level()));
+        });
+
+        let mut env = vec![
+            "export PKGER_BLD_DIR=%{_builddir}/%{name}-%{version}".to_string(),
+            "export PKGER_OUT_DIR=%{buildroot}".to_string(),
+        ];
+        env.extend(
+            self.sorted_env()
+                .into_iter()
+                .map(|kv| format!("export {}", kv)),
+        );
+        let env = env.join("\n") + "\n";
+
+        let mut build = env.clone();
+        if let Some(configure) = &self.configure_script {
+            build.push_str(&render_script(
+                &configure.steps,
+                configure.working_dir.as_ref(),
+                "$PKGER_BLD_DIR",
+                image,
+                &target,
+                self.metadata.arch_for(image, target),
+            ));
+        }
+        build.push_str(&render_script(
+            &self.build_script.steps,
+            self.build_script.working_dir.as_ref(),
+            "$PKGER_BLD_DIR",
+            image,
+            &target,
+            self.metadata.arch_for(image, target),
+        ));
+
+        let mut install = env;
+        if let Some(script) = &self.install_script {
+            install.push_str(&render_script(
+                &script.steps,
+                script.working_dir.as_ref(),
+                "$PKGER_OUT_DIR",
+                image,
+                &target,
+                self.metadata.arch_for(image, target),
+            ));
+        }
+
+        let mut builder = self
+            .rpm_spec_builder(image)
+            .add_sources_entries(
+                Some(source)
+                    .into_iter()
+                    .chain(self.metadata.extra_sources.iter().cloned()),
+            )
+            .add_patches_entries(patches.iter().map(|p| p.patch().to_string()))
+            .add_files_entries(files)
+            .prep_script(prep)
+            .build_script(build)
+            .install_script(install);
+
+        if let Some(deps) = &self.metadata.build_depends {
+            let mut deps = deps
+                .resolve_names(image, self.metadata.arch_for(image, target))
+                .into_iter()
+                .collect::<Vec<_>>();
+            deps.sort_unstable();
+            builder =
+                builder.add_build_requires_entries(deps.into_iter().map(strip_arch_qualifier));
+        }
+
+        builder.build()
+    }
+
+    /// Renders a `debian/` directory that builds this recipe with `dpkg-buildpackage`
+    pub fn export_debian_dir(&self, image: &str) -> DebianDir {
+        let target = BuildTarget::Deb;
+        let name = &self.metadata.name;
+        let maintainer = self
+            .metadata
+            .maintainer
+            .clone()
+            .unwrap_or_else(|| UNKNOWN_MAINTAINER.to_string());
+
+        let mut build_depends = vec![DEBHELPER_COMPAT];
+        if let Some(deps) = &self.metadata.build_depends {
+            let mut deps = deps
+                .resolve_names(image, self.metadata.arch_for(image, target))
+                .into_iter()
+                .collect::<Vec<_>>();
+            deps.sort_unstable();
+            build_depends.extend(deps);
+        }
+
+        let mut control = format!(
+            "Source: {}\nMaintainer: {}\nBuild-Depends: {}\nStandards-Version: {}\n",
+            name,
+            maintainer,
+            build_depends.join(", "),
+            DEB_STANDARDS_VERSION
+        );
+        if let Some(group) = &self.metadata.group {
+            control.push_str(&format!("Section: {}\n", group));
+        }
+        if let Some(url) = &self.metadata.url {
+            control.push_str(&format!("Homepage: {}\n", url));
+        }
+        control.push('\n');
+
+        // reuse the binary control fields leaving out the ones generated by debhelper
+        let binary = self.as_deb_control(image).render();
+        binary
+            .lines()
+            .filter(|line| {
+                !["Version:", "Installed-Size:", "Maintainer:", "Homepage:"]
+                    .iter()
+                    .any(|field| line.starts_with(field))
+            })
+            .for_each(|line| {
+                control.push_str(line);
+                control.push('\n');
+            });
+
+        let version = match &self.metadata.epoch {
+            Some(epoch) => format!(
+                "{}:{}-{}",
+                epoch,
+                self.metadata.version,
+                self.metadata.release_for(image, target)
+            ),
+            None => format!(
+                "{}-{}",
+                self.metadata.version,
+                self.metadata.release_for(image, target)
+            ),
+        };
+        let changelog = self.debian_changelog(image).unwrap_or_else(|| {
+            format!(
+                "{} ({}) unstable; urgency=medium\n\n  * Exported from pkger recipe.\n\n -- {}  {}\n",
+                name,
+                version,
+                maintainer,
+                Local::now().to_rfc2822()
+            )
+        });
+
+        let mut env = vec![
+            "export PKGER_BLD_DIR := $(CURDIR)".to_string(),
+            format!("export PKGER_OUT_DIR := $(CURDIR)/debian/{}", name),
+        ];
+        env.extend(
+            self.sorted_env()
+                .into_iter()
+                .map(|kv| format!("export {}", kv.replacen('=', " := ", 1))),
+        );
+        let shell = |shell: Option<&String>| shell.map(String::as_str).unwrap_or("sh").to_string();
+        let rules = format!(
+            "#!/usr/bin/make -f\n\n{}\n\n%:\n\tdh $@\n\noverride_dh_auto_configure:\n\t{} -e debian/pkger-configure.sh\n\noverride_dh_auto_build:\n\t{} -e debian/pkger-build.sh\n\noverride_dh_auto_install:\n\t{} -e debian/pkger-install.sh\n",
+            env.join("\n"),
+            shell(self.configure_script.as_ref().and_then(|s| s.shell.as_ref())),
+            shell(self.build_script.shell.as_ref()),
+            shell(self.install_script.as_ref().and_then(|s| s.shell.as_ref())),
+        );
+
+        let mut files = vec![
+            (PathBuf::from("control"), control),
+            (PathBuf::from("changelog"), changelog),
+            (PathBuf::from("rules"), rules),
+            (PathBuf::from("source/format"), "3.0 (quilt)\n".to_string()),
+        ];
+        if let Some(conffiles) = self.deb_conffiles() {
+            files.push((PathBuf::from("conffiles"), conffiles));
+        }
+
+        let configure = self
+            .configure_script
+            .as_ref()
+            .map(|script| {
+                render_script(
+                    &script.steps,
+                    script.working_dir.as_ref(),
+                    "$PKGER_BLD_DIR",
+                    image,
+                    &target,
+                    self.metadata.arch_for(image, target),
+                )
+            })
+            .unwrap_or_default();
+        let build = render_script(
+            &self.build_script.steps,
+            self.build_script.working_dir.as_ref(),
+            "$PKGER_BLD_DIR",
+            image,
+            &target,
+            self.metadata.arch_for(image, target),
+        );
+        let install = self
+            .install_script
+            .as_ref()
+            .map(|script| {
+                render_script(
+                    &script.steps,
+                    script.working_dir.as_ref(),
+                    "$PKGER_OUT_DIR",
+                    image,
+                    &target,
+                    self.metadata.arch_for(image, target),
+                )
+            })
+            .unwrap_or_default();
+        files.push((PathBuf::from("pkger-configure.sh"), configure));
+        files.push((PathBuf::from("pkger-build.sh"), build));
+        files.push((PathBuf::from("pkger-install.sh"), install));
+
+        let mut patches = Vec::new();
+        let mut series = String::new();
+        for patch in self.exported_patches(image) {
+            if patch.patch().starts_with("http") {
+                warn!(patch = %patch.patch(), "remote patches can't be exported, skipping");
+                continue;
+            }
+            let name = patch_name(patch);
+            series.push_str(&format!("{} -p{}\n", name, patch.strip_level()));
+            patches.push((
+                self.recipe_dir.join(patch.patch()),
+                PathBuf::from("patches").join(name),
+            ));
+        }
+        if !series.is_empty() {
+            files.push((PathBuf::from("patches/series"), series));
+        }
+
+        DebianDir { files, patches }
+    }
+
+    /// Renders everything pkger generates from this recipe for `target` on `image` without
+    /// running a container: the environment, scripts with filtered steps, resolved build
+    /// dependencies and the packaging file. The output is stable so it can be compared against
+    /// a snapshot saved in the repository.
+    pub fn render_snapshot(&self, image: &str, target: &BuildTarget) -> String {
+        let arch = self.metadata.arch_for(image, *target);
+        let mut sections = vec![];
+
+        let env = self
+            .sorted_env()
+            .iter()
+            .map(|kv| format!("{}\n", secrets::redact(kv)))
+            .collect::<String>();
+        sections.push(("env", env));
+
+        if let Some(script) = &self.configure_script {
+            sections.push((
+                "configure",
+                render_script(
+                    &script.steps,
+                    script.working_dir.as_ref(),
+                    "$PKGER_BLD_DIR",
+                    image,
+                    target,
+                    arch,
+                ),
+            ));
+        }
+        sections.push((
+            "build",
+            render_script(
+                &self.build_script.steps,
+                self.build_script.working_dir.as_ref(),
+                "$PKGER_BLD_DIR",
+                image,
+                target,
+                arch,
+            ),
+        ));
+        if let Some(script) = &self.install_script {
+            sections.push((
+                "install",
+                render_script(
+                    &script.steps,
+                    script.working_dir.as_ref(),
+                    "$PKGER_OUT_DIR",
+                    image,
+                    target,
+                    arch,
+                ),
+            ));
+        }
+
+        if let Some(deps) = &self.metadata.build_depends {
+            let mut deps = deps
+                .resolve_names(image, arch)
+                .into_iter()
+                .collect::<Vec<_>>();
+            deps.sort_unstable();
+            sections.push((
+                "build_depends",
+                deps.into_iter().map(|dep| format!("{}\n", dep)).collect(),
+            ));
+        }
+
+        if !self.uses_native_packaging(target) {
+            match target {
+                BuildTarget::Rpm => {
+                    sections.push(("spec", self.as_rpm_spec(&[], &[], image).render()))
+                }
+                BuildTarget::Deb => sections.push(("control", self.as_deb_control(image).render())),
+                BuildTarget::Pkg => {
+                    sections.push(("PKGBUILD", self.as_pkgbuild(image, &[], &[]).render()))
+                }
+                BuildTarget::Apk => {
+                    sections.push(("APKBUILD", self.as_apkbuild(image, &[], &[]).render()))
+                }
+                BuildTarget::Gzip => {}
+            }
+        }
+
+        sections
+            .into_iter()
+            .map(|(name, content)| {
+                let mut section = format!("### {}\n{}", name, content);
+                if !section.ends_with('\n') {
+                    section.push('\n');
+                }
+                section
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Contents of a `debian/` directory exported from a recipe
+pub struct DebianDir {
+    /// Generated files with paths relative to the `debian/` directory
+    files: Vec<(PathBuf, String)>,
+    /// Patch files to copy from the recipe directory with their destination paths
+    patches: Vec<(PathBuf, PathBuf)>,
+}
+
+impl DebianDir {
+    /// Returns all files of the directory with paths relative to it, patches are read from the
+    /// recipe directory
+    pub fn entries(&self) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        let mut entries = self
+            .files
+            .iter()
+            .map(|(path, content)| (path.clone(), content.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        for (src, dest) in &self.patches {
+            entries.push((
+                dest.clone(),
+                fs::read(src).context(format!("failed to read patch `{}`", src.display()))?,
+            ));
+        }
+        Ok(entries)
+    }
+
+    /// Saves all files to the `debian/` directory created in `output_dir`. Returns the path of
+    /// the created directory.
+    pub fn save_to<P: AsRef<Path>>(&self, output_dir: P) -> Result<PathBuf> {
+        let debian_dir = output_dir.as_ref().join("debian");
+        let span = info_span!("save-debian-dir", path = %debian_dir.display());
+        let _enter = span.enter();
+
+        for (path, content) in &self.files {
+            let path = debian_dir.join(path);
+            trace!(file = %path.display(), "saving");
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, content).context(format!("failed to save `{}`", path.display()))?;
+        }
+
+        for (src, dest) in &self.patches {
+            let dest = debian_dir.join(dest);
+            trace!(patch = %src.display(), destination = %dest.display(), "copying");
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(src, &dest).context(format!("failed to copy patch `{}`", src.display()))?;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let rules = debian_dir.join("rules");
+            fs::set_permissions(&rules, fs::Permissions::from_mode(0o755))?;
+        }
+
+        Ok(debian_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recipe::{Recipe, RecipeRep};
+    use std::path::PathBuf;
+
+    const TEST_RECIPE: &[u8] = include_bytes!("../../../example/recipes/test/recipe.yml");
+
+    #[test]
+    fn exports_scripts_for_image() {
+        let rep = RecipeRep::from_yaml_bytes(TEST_RECIPE).unwrap();
+        let recipe = Recipe::new(rep, PathBuf::new()).unwrap();
+
+        let spec = recipe.export_rpm_spec("debian10").render();
+        assert!(spec.contains("%setup -q -c"));
+        assert!(spec.contains("export ENV_VAR_TEST=test.com:1010"));
+        assert!(spec.contains("cd /var/lib"));
+        assert!(spec.contains("touch /tmp/pkger_group"));
+        assert!(!spec.contains("touch /tmp/only_centos"));
+        assert!(spec.contains("# list the files installed to %{buildroot} here"));
+
+        let spec = recipe
+            .source_rpm_spec("debian10", &["/usr/bin/test".to_string()])
+            .render();
+        assert!(spec.contains("%files\n/usr/bin/test\n"));
+        assert!(spec.contains("%setup -q -c"));
+
+        let debian = recipe.export_debian_dir("centos8");
+        let rules = debian
+            .files
+            .iter()
+            .find(|(path, _)| path == &PathBuf::from("rules"))
+            .map(|(_, rules)| rules)
+            .unwrap();
+        assert!(rules.contains("/bin/bash -e debian/pkger-build.sh"));
+        assert!(rules.contains("export PKGER_OUT_DIR := $(CURDIR)/debian/test-pkger"));
+    }
+}
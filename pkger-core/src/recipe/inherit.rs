@@ -0,0 +1,189 @@
+//! Inheritance of recipes. A recipe can extend one or more recipes of the same recipes directory
+//! with `extends`, it then starts with their merged contents and overrides only the fields it
+//! defines. Mappings are merged recursively while any other value, including lists, replaces the
+//! inherited one. Recipes marked as `abstract` only serve as a base and are never built.
+use crate::recipe::recipe_file;
+use crate::{ErrContext, Error, Result};
+
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static EXTENDS_KEY: &str = "extends";
+static ABSTRACT_KEY: &str = "abstract";
+
+/// Contents of a recipe merged with all recipes it extends
+pub(crate) struct Inherited {
+    pub value: Value,
+    /// Directories of the extended recipes, including the ones they extend
+    pub bases: Vec<PathBuf>,
+    pub is_abstract: bool,
+}
+
+/// Loads the recipe file at `path` resolving its bases
+pub(crate) fn load(path: &Path) -> Result<Inherited> {
+    let mut bases = vec![];
+    let mut value = load_chain(path, &mut vec![], &mut bases)?;
+    let is_abstract = take_abstract(&mut value)?;
+    Ok(Inherited {
+        value,
+        bases,
+        is_abstract,
+    })
+}
+
+/// Whether the recipe file at `path` is marked as abstract, without resolving its bases
+pub(crate) fn is_abstract(path: &Path) -> bool {
+    fs::read(path)
+        .ok()
+        .and_then(|data| serde_yaml::from_slice::<Value>(&data).ok())
+        .and_then(|value| value.get(ABSTRACT_KEY).and_then(Value::as_bool))
+        .unwrap_or(false)
+}
+
+fn take_abstract(value: &mut Value) -> Result<bool> {
+    match value
+        .as_mapping_mut()
+        .and_then(|map| map.remove(&Value::from(ABSTRACT_KEY)))
+    {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(is_abstract)) => Ok(is_abstract),
+        Some(_) => Err(Error::msg(format!("`{}` must be a boolean", ABSTRACT_KEY))),
+    }
+}
+
+/// Name of the directory of a recipe, used in messages
+fn recipe_name(dir: &Path) -> String {
+    dir.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Loads the recipe file at `path` merged with its bases. `chain` are the directories of recipes
+/// currently being resolved.
+fn load_chain(path: &Path, chain: &mut Vec<PathBuf>, bases: &mut Vec<PathBuf>) -> Result<Value> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    if chain.contains(&dir) {
+        let cycle = chain
+            .iter()
+            .skip_while(|recipe| **recipe != dir)
+            .chain(Some(&dir))
+            .map(|recipe| recipe_name(recipe))
+            .collect::<Vec<_>>();
+        return Err(Error::msg(format!(
+            "recipes extend each other in a cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+
+    let data = fs::read(path).context(format!("failed to read `{}`", path.display()))?;
+    let mut value: Value =
+        serde_yaml::from_slice(&data).context(format!("failed to parse `{}`", path.display()))?;
+    let extends = match value
+        .as_mapping_mut()
+        .and_then(|map| map.remove(&Value::from(EXTENDS_KEY)))
+    {
+        None | Some(Value::Null) => return Ok(value),
+        Some(Value::String(name)) => vec![name],
+        Some(Value::Sequence(names)) => names
+            .into_iter()
+            .map(|name| match name {
+                Value::String(name) => Ok(name),
+                _ => Err(Error::msg(format!(
+                    "`{}` must be a name of a recipe or a list of them",
+                    EXTENDS_KEY
+                ))),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => {
+            return Err(Error::msg(format!(
+                "`{}` must be a name of a recipe or a list of them",
+                EXTENDS_KEY
+            )))
+        }
+    };
+
+    chain.push(dir.clone());
+    let recipes_dir = dir.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = Value::Mapping(Mapping::new());
+    for name in extends {
+        let base_dir = recipes_dir.join(&name);
+        let mut base = recipe_file(&base_dir)
+            .and_then(|file| load_chain(&file, chain, bases))
+            .context(format!("failed to load base recipe `{}`", name))?;
+        // only the recipe marked as abstract is abstract, not the ones extending it
+        take_abstract(&mut base)?;
+        merge(&mut merged, base);
+        if !bases.contains(&base_dir) {
+            bases.push(base_dir);
+        }
+    }
+    chain.pop();
+
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merges `other` into `value`, mappings are merged recursively and any other value of `other`
+/// replaces the one in `value`
+fn merge(value: &mut Value, other: Value) {
+    match (value, other) {
+        (Value::Mapping(map), Value::Mapping(other)) => {
+            for (key, other) in other {
+                match map.get_mut(&key) {
+                    Some(value) => merge(value, other),
+                    None => {
+                        map.insert(key, other);
+                    }
+                }
+            }
+        }
+        (value, other) => *value = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extends_recipes() {
+        let tmp = tempdir::TempDir::new("pkger-inherit").unwrap();
+        let recipes = fs::canonicalize(tmp.path()).unwrap();
+        let recipes = recipes.as_path();
+        let write = |name: &str, recipe: &str| {
+            fs::create_dir_all(recipes.join(name)).unwrap();
+            fs::write(recipes.join(name).join("recipe.yml"), recipe).unwrap();
+        };
+        write(
+            "base",
+            "abstract: true\nmetadata:\n  license: MIT\n  images: [centos8, debian10]\n  build_depends:\n    all: [gcc]\nconfigure:\n  steps:\n    - cmd: ./configure\n",
+        );
+        write(
+            "rust",
+            "extends: base\nmetadata:\n  build_depends:\n    all: [cargo]\n",
+        );
+        write(
+            "foo",
+            "extends: [rust]\nmetadata:\n  name: foo\n  images: [debian10]\n  description: ~\n",
+        );
+
+        let foo = load(&recipes.join("foo/recipe.yml")).unwrap();
+        assert!(!foo.is_abstract);
+        assert_eq!(foo.bases, vec![recipes.join("base"), recipes.join("rust")]);
+        let expected: Value = serde_yaml::from_str(
+            "metadata:\n  license: MIT\n  images: [debian10]\n  build_depends:\n    all: [cargo]\n  name: foo\n  description: ~\nconfigure:\n  steps:\n    - cmd: ./configure\n",
+        )
+        .unwrap();
+        assert_eq!(foo.value, expected);
+        assert!(load(&recipes.join("base/recipe.yml")).unwrap().is_abstract);
+        assert!(is_abstract(&recipes.join("base/recipe.yml")));
+
+        write("a", "extends: b\n");
+        write("b", "extends: a\n");
+        let err = load(&recipes.join("a/recipe.yml")).err().unwrap();
+        assert!(format!("{:#}", err).contains("cycle: a -> b -> a"));
+    }
+}
@@ -1,24 +1,62 @@
 mod arch;
+mod artifacts;
+mod changelog;
+mod checksum;
+mod condition;
+mod deprecation;
 mod deps;
+mod devices;
+mod disk;
+mod features;
+mod files;
 mod git;
 mod image;
+mod network;
 mod os;
 mod patches;
+mod preset;
+mod repo_snapshot;
+mod rpath;
+mod security;
+mod signature;
+mod source;
+mod symbols;
 mod target;
 
 pub use arch::BuildArch;
-pub use deps::Dependencies;
+pub use artifacts::FetchedArtifact;
+pub use changelog::{load_changelog, Changelog, ChangelogEntry, DEFAULT_CHANGELOG_FILE};
+pub use checksum::Checksum;
+pub use condition::{compare_versions, VersionCondition};
+pub use deprecation::{Deprecation, DeprecationRep};
+pub use deps::{strip_arch_qualifier, Dependencies, DependencyNames};
+pub use devices::{Device, Gpus};
+pub use disk::{parse_size, DiskOptions};
+pub use features::{
+    feature_env_var, requested_features, validate_feature_name, Feature, FeatureRep,
+};
+pub use files::{rpm_files_entries, RpmFileAttributes};
 pub use git::GitSource;
-pub use image::ImageTarget;
+pub use image::{BuildTargetRep, ImageOptions, ImageTarget, ImageTargetOpts, ImageTargetRep};
+pub use network::NetworkOptions;
 pub use os::{Distro, Os, PackageManager};
-pub use patches::{Patch, Patches};
+pub use patches::{Patch, PatchMethod, Patches};
+pub use preset::Preset;
+pub use repo_snapshot::RepoSnapshot;
+pub use rpath::RpathOptions;
+pub use security::SecurityOptions;
+pub use signature::SourceSignature;
+pub use source::SourceRep;
+pub use symbols::SymbolsOptions;
 pub use target::BuildTarget;
 
 use crate::{Error, Result};
 
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value as YamlValue;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::str::FromStr;
 
 macro_rules! if_let_some_ty {
     ($from:expr, $ty:tt) => {
@@ -38,17 +76,38 @@ pub struct MetadataRep {
     pub description: String,
     pub license: String,
 
-    pub images: Option<Vec<YamlValue>>,
+    pub images: Option<Vec<ImageTargetRep>>,
+    /// Name of an image on which the scripts are executed only once. The output is then packaged
+    /// separately on each of the image targets.
+    pub build_image: Option<String>,
+    /// Predefined build setup like `static`
+    pub preset: Option<Preset>,
 
     // Common optional
     pub maintainer: Option<String>,
+    /// People or teams responsible for the recipe, notified when its builds fail
+    pub owners: Option<Vec<String>>,
+    /// Markdown documentation of the package or a path to a markdown file relative to the recipe
+    pub docs: Option<String>,
     /// The URL of the web site for this package
     pub url: Option<String>,
     pub arch: Option<String>,
-    /// http/https or file system source pointing to a tar.gz or tar.xz package
-    pub source: Option<String>,
+    /// http/https or file system source pointing to a tar.gz or tar.xz package or a list of them
+    pub source: Option<SourceRep>,
+    /// URLs of mirrors of the source tried in order when downloading it fails
+    pub mirrors: Option<Vec<String>>,
+    /// Expected digest of the downloaded source like `sha256:<hex>`
+    pub checksum: Option<Checksum>,
+    /// Expected sha256 digest of the downloaded source, a shorthand for `checksum`
+    pub sha256: Option<String>,
+    /// Expected sha512 digest of the downloaded source, a shorthand for `checksum`
+    pub sha512: Option<String>,
     /// Git repository as source
     pub git: Option<YamlValue>,
+    /// GPG signature of the source verified before the build
+    pub signature: Option<SourceSignature>,
+    /// Previously built packages installed in the build container before the scripts run
+    pub fetch_artifacts: Option<Vec<FetchedArtifact>>,
     /// Whether to install default dependencies before build
     pub skip_default_deps: Option<bool>,
     /// Directories to exclude when creating the package
@@ -59,8 +118,34 @@ pub struct MetadataRep {
     pub release: Option<String>,
     /// Used to force the package to be seen as newer than any previous version with a lower epoch
     pub epoch: Option<String>,
+    /// Builds of recipes with higher priority are started before others
+    pub priority: Option<i32>,
+    /// Marks the recipe as deprecated, either `true` or a map with a message and a replacement
+    pub deprecated: Option<DeprecationRep>,
+    /// Optional parts of the recipe enabled with `--features`, by name
+    pub features: Option<BTreeMap<String, FeatureRep>>,
+    /// Entries of the changelog or a path to a YAML file with them relative to the recipe
+    pub changelog: Option<Changelog>,
+    /// Restrictions of the container in which the scripts are executed
+    pub security: Option<SecurityOptions>,
+    /// Limits of the disk space used by the build
+    pub disk: Option<DiskOptions>,
+    /// Hostname, hosts entries and DNS servers of the build container
+    pub network: Option<NetworkOptions>,
+    /// Devices of the host available in the build container, like `/dev/fuse`
+    pub devices: Option<Vec<String>>,
+    /// GPUs available in the build container, `all`, a number of GPUs or `device=0,1`
+    pub gpus: Option<YamlValue>,
+    /// Checks of RPATHs of binaries in the build output
+    pub rpath: Option<RpathOptions>,
+    /// Tracking of symbols exported by libraries in the build output
+    pub symbols: Option<SymbolsOptions>,
+    /// Whether commands should be prevented from prompting for input, defaults to true
+    pub noninteractive: Option<bool>,
 
     pub build_depends: Option<YamlValue>,
+    /// Names of dependencies on distributions where they differ from the names in the recipe
+    pub dependency_names: Option<DependencyNames>,
     pub depends: Option<YamlValue>,
     pub conflicts: Option<YamlValue>,
     pub provides: Option<YamlValue>,
@@ -77,6 +162,9 @@ pub struct MetadataRep {
 
     // Only PKG
     pub pkg: Option<PkgRep>,
+
+    // Only APK
+    pub apk: Option<ApkRep>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -116,12 +204,171 @@ impl TryFrom<PkgRep> for PkgInfo {
     }
 }
 
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct ApkRep {
+    /// Packages whose files this package is allowed to overwrite
+    pub replaces: Option<YamlValue>,
+    /// The package is installed automatically once all of these packages are installed
+    pub install_if: Option<Vec<String>>,
+    /// Priority of this package among packages providing the same name
+    pub provider_priority: Option<u32>,
+    pub pre_install_script: Option<String>,
+    pub post_install_script: Option<String>,
+    pub pre_deinstall_script: Option<String>,
+    pub post_deinstall_script: Option<String>,
+    pub pre_upgrade_script: Option<String>,
+    pub post_upgrade_script: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApkInfo {
+    /// Packages whose files this package is allowed to overwrite
+    pub replaces: Option<Dependencies>,
+    /// The package is installed automatically once all of these packages are installed
+    pub install_if: Vec<String>,
+    /// Priority of this package among packages providing the same name
+    pub provider_priority: Option<u32>,
+    pub pre_install_script: Option<String>,
+    pub post_install_script: Option<String>,
+    pub pre_deinstall_script: Option<String>,
+    pub post_deinstall_script: Option<String>,
+    pub pre_upgrade_script: Option<String>,
+    pub post_upgrade_script: Option<String>,
+}
+
+impl TryFrom<ApkRep> for ApkInfo {
+    type Error = Error;
+
+    fn try_from(rep: ApkRep) -> Result<Self> {
+        Ok(Self {
+            replaces: if_let_some_ty!(rep.replaces, Dependencies),
+            install_if: rep.install_if.unwrap_or_default(),
+            provider_priority: rep.provider_priority,
+            pre_install_script: rep.pre_install_script,
+            post_install_script: rep.post_install_script,
+            pre_deinstall_script: rep.pre_deinstall_script,
+            post_deinstall_script: rep.post_deinstall_script,
+            pre_upgrade_script: rep.pre_upgrade_script,
+            post_upgrade_script: rep.post_upgrade_script,
+        })
+    }
+}
+
+impl ApkInfo {
+    /// Returns the install scripts of the package by their suffix, like `post-install`
+    pub fn install_scripts(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("pre-install", &self.pre_install_script),
+            ("post-install", &self.post_install_script),
+            ("pre-deinstall", &self.pre_deinstall_script),
+            ("post-deinstall", &self.post_deinstall_script),
+            ("pre-upgrade", &self.pre_upgrade_script),
+            ("post-upgrade", &self.post_upgrade_script),
+        ]
+        .iter()
+        .filter_map(|(suffix, script)| script.as_deref().map(|script| (*suffix, script)))
+        .collect()
+    }
+}
+
+/// Values of the `Multi-Arch` field of DEB packages
+static MULTI_ARCH_VALUES: &[&str] = &["same", "foreign", "allowed", "no"];
+
+/// Fields of the control file rendered from the recipe, these can't be passed as `extra_fields`
+static DEB_MODELED_FIELDS: &[&str] = &[
+    "Package",
+    "Version",
+    "Architecture",
+    "Description",
+    "Essential",
+    "Maintainer",
+    "Source",
+    "Section",
+    "Priority",
+    "Installed-Size",
+    "Homepage",
+    "Built-Using",
+    "Multi-Arch",
+    "Pre-Depends",
+    "Depends",
+    "Recommends",
+    "Suggests",
+    "Breaks",
+    "Conflicts",
+    "Provides",
+    "Replaces",
+    "Enchances",
+];
+
+/// Tags of the spec file rendered from the recipe, these can't be passed as `extra_tags`.
+/// `Packager` is left out so that it can override the one derived from `maintainer`.
+static RPM_MODELED_TAGS: &[&str] = &[
+    "Name",
+    "Version",
+    "Release",
+    "Summary",
+    "Epoch",
+    "Vendor",
+    "URL",
+    "Group",
+    "Icon",
+    "License",
+    "BuildArch",
+    "Conflicts",
+    "Obsoletes",
+    "Provides",
+    "Requires",
+    "BuildRequires",
+    "Source",
+    "Patch",
+];
+
+/// Verifies that fields passed verbatim to generated packaging files are well formed and don't
+/// duplicate the ones rendered from the recipe
+fn validate_extra_fields(
+    key: &str,
+    fields: &BTreeMap<String, String>,
+    modeled: &[&str],
+) -> Result<()> {
+    for (name, value) in fields {
+        if name.is_empty()
+            || name
+                .chars()
+                .any(|c| c == ':' || c.is_whitespace() || c.is_control())
+        {
+            return Err(anyhow!("invalid name `{}` in `{}`", name, key));
+        }
+        if value.contains('\n') {
+            return Err(anyhow!(
+                "value of `{}` in `{}` must be a single line",
+                name,
+                key
+            ));
+        }
+        // numbered tags like `Source0` are modeled as well
+        let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        if let Some(field) = modeled
+            .iter()
+            .find(|field| field.eq_ignore_ascii_case(name) || field.eq_ignore_ascii_case(base))
+        {
+            return Err(anyhow!(
+                "`{}` in `{}` is generated by pkger from the recipe",
+                field,
+                key
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct DebRep {
     pub priority: Option<String>,
     pub installed_size: Option<String>,
     pub built_using: Option<String>,
     pub essential: Option<bool>,
+    /// Value of the `Multi-Arch` field, one of `same`, `foreign`, `allowed` or `no`
+    pub multi_arch: Option<String>,
 
     pub pre_depends: Option<YamlValue>,
     pub recommends: Option<YamlValue>,
@@ -129,6 +376,16 @@ pub struct DebRep {
     pub breaks: Option<YamlValue>,
     pub replaces: Option<YamlValue>,
     pub enchances: Option<YamlValue>,
+
+    /// Fields without a dedicated option rendered verbatim into the control file, like
+    /// `XB-Custom-Field`
+    pub extra_fields: Option<BTreeMap<String, String>>,
+
+    /// Absolute paths of configuration files that dpkg keeps when they were changed locally
+    pub conffiles: Option<Vec<String>>,
+
+    /// Path to an existing `debian/` directory relative to the recipe directory
+    pub debian_dir: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -137,6 +394,7 @@ pub struct DebInfo {
     pub installed_size: Option<String>,
     pub built_using: Option<String>,
     pub essential: Option<bool>,
+    pub multi_arch: Option<String>,
 
     pub pre_depends: Option<Dependencies>,
     pub recommends: Option<Dependencies>,
@@ -144,17 +402,49 @@ pub struct DebInfo {
     pub breaks: Option<Dependencies>,
     pub replaces: Option<Dependencies>,
     pub enchances: Option<Dependencies>,
+
+    pub extra_fields: BTreeMap<String, String>,
+
+    pub conffiles: Vec<String>,
+
+    pub debian_dir: Option<String>,
 }
 
 impl TryFrom<DebRep> for DebInfo {
     type Error = Error;
 
     fn try_from(rep: DebRep) -> Result<Self> {
+        if let Some(multi_arch) = &rep.multi_arch {
+            if !MULTI_ARCH_VALUES.contains(&multi_arch.as_str()) {
+                return Err(anyhow!(
+                    "invalid multi_arch value `{}`, expected one of: {}",
+                    multi_arch,
+                    MULTI_ARCH_VALUES.join(", ")
+                ));
+            }
+        }
+        let extra_fields = rep.extra_fields.unwrap_or_default();
+        validate_extra_fields("deb.extra_fields", &extra_fields, DEB_MODELED_FIELDS)?;
+        let conffiles = rep.conffiles.unwrap_or_default();
+        for conffile in &conffiles {
+            if !conffile.starts_with('/')
+                || conffile
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control())
+            {
+                return Err(anyhow!(
+                    "invalid conffile `{}`, expected an absolute path without whitespace",
+                    conffile
+                ));
+            }
+        }
+
         Ok(Self {
             priority: rep.priority,
             installed_size: rep.installed_size,
             built_using: rep.built_using,
             essential: rep.essential,
+            multi_arch: rep.multi_arch,
 
             pre_depends: if_let_some_ty!(rep.pre_depends, Dependencies),
             recommends: if_let_some_ty!(rep.recommends, Dependencies),
@@ -162,6 +452,12 @@ impl TryFrom<DebRep> for DebInfo {
             breaks: if_let_some_ty!(rep.breaks, Dependencies),
             replaces: if_let_some_ty!(rep.replaces, Dependencies),
             enchances: if_let_some_ty!(rep.enchances, Dependencies),
+
+            extra_fields,
+
+            conffiles,
+
+            debian_dir: rep.debian_dir,
         })
     }
 }
@@ -177,12 +473,26 @@ pub struct RpmRep {
     pub preun_script: Option<String>,
     pub postun_script: Option<String>,
     pub config_noreplace: Option<String>,
+    /// `%ghost`, `%verify` and `%caps` attributes of files by absolute path
+    pub file_attributes: Option<BTreeMap<String, RpmFileAttributes>>,
+    /// Tags without a dedicated option rendered verbatim into the spec file, like `Packager`
+    pub extra_tags: Option<BTreeMap<String, String>>,
+
+    /// Path to an existing spec file relative to the recipe directory
+    pub spec_file: Option<String>,
 }
 
 impl TryFrom<RpmRep> for RpmInfo {
     type Error = Error;
 
     fn try_from(rep: RpmRep) -> Result<Self> {
+        let file_attributes = rep.file_attributes.unwrap_or_default();
+        for (path, attributes) in &file_attributes {
+            attributes.validate(path)?;
+        }
+        let extra_tags = rep.extra_tags.unwrap_or_default();
+        validate_extra_fields("rpm.extra_tags", &extra_tags, RPM_MODELED_TAGS)?;
+
         Ok(Self {
             obsoletes: if_let_some_ty!(rep.obsoletes, Dependencies),
             vendor: rep.vendor,
@@ -193,6 +503,10 @@ impl TryFrom<RpmRep> for RpmInfo {
             preun_script: rep.preun_script,
             postun_script: rep.postun_script,
             config_noreplace: rep.config_noreplace,
+            file_attributes,
+            extra_tags,
+
+            spec_file: rep.spec_file,
         })
     }
 }
@@ -208,6 +522,10 @@ pub struct RpmInfo {
     pub preun_script: Option<String>,
     pub postun_script: Option<String>,
     pub config_noreplace: Option<String>,
+    pub file_attributes: BTreeMap<String, RpmFileAttributes>,
+    pub extra_tags: BTreeMap<String, String>,
+
+    pub spec_file: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -220,13 +538,34 @@ pub struct Metadata {
     pub arch: BuildArch,
 
     pub images: Option<Vec<ImageTarget>>,
+    /// Options of image targets by image name and build target
+    pub image_options: HashMap<(String, BuildTarget), ImageOptions>,
+    /// Name of an image on which the scripts are executed only once. The output is then packaged
+    /// separately on each of the image targets.
+    pub build_image: Option<String>,
+    /// Predefined build setup like `static`
+    pub preset: Option<Preset>,
     pub maintainer: Option<String>,
+    /// People or teams responsible for the recipe, notified when its builds fail
+    pub owners: Vec<String>,
+    /// Markdown documentation of the package or a path to a markdown file relative to the recipe
+    pub docs: Option<String>,
     /// The URL of the web site for this package
     pub url: Option<String>,
     /// http/https or file system source pointing to a tar.gz or tar.xz package
     pub source: Option<String>,
+    /// Sources fetched and unpacked next to the main `source`
+    pub extra_sources: Vec<String>,
+    /// URLs of mirrors of the source tried in order when downloading it fails
+    pub mirrors: Vec<String>,
+    /// Expected digest of the downloaded source
+    pub checksum: Option<Checksum>,
     /// Git repository as source
     pub git: Option<GitSource>,
+    /// GPG signature of the source verified before the build
+    pub signature: Option<SourceSignature>,
+    /// Previously built packages installed in the build container before the scripts run
+    pub fetch_artifacts: Vec<FetchedArtifact>,
     /// Whether default dependencies should be installed before the build
     pub skip_default_deps: Option<bool>,
     /// Directories to exclude when creating the package
@@ -238,8 +577,36 @@ pub struct Metadata {
     pub release: Option<String>,
     /// Used to force the package to be seen as newer than any previous version with a lower epoch
     pub epoch: Option<String>,
+    /// Builds of recipes with higher priority are started before others
+    pub priority: Option<i32>,
+    /// Set if the recipe is deprecated
+    pub deprecated: Option<Deprecation>,
+    /// Optional parts of the recipe enabled with `--features`, by name
+    pub features: BTreeMap<String, Feature>,
+    /// Names of the features enabled in this build of the recipe
+    pub enabled_features: Vec<String>,
+    /// Entries of the changelog, the newest first. They are read when the recipe is created as
+    /// the changelog may be a file relative to the recipe directory.
+    pub changelog: Vec<ChangelogEntry>,
+    /// Restrictions of the container in which the scripts are executed
+    pub security: Option<SecurityOptions>,
+    /// Limits of the disk space used by the build
+    pub disk: Option<DiskOptions>,
+    /// Hostname, hosts entries and DNS servers of the build container
+    pub network: Option<NetworkOptions>,
+    /// Devices of the host available in the build container
+    pub devices: Vec<Device>,
+    /// GPUs available in the build container
+    pub gpus: Option<Gpus>,
+    /// Checks of RPATHs of binaries in the build output
+    pub rpath: Option<RpathOptions>,
+    /// Tracking of symbols exported by libraries in the build output
+    pub symbols: Option<SymbolsOptions>,
+    /// Whether commands should be prevented from prompting for input, defaults to true
+    pub noninteractive: Option<bool>,
 
     pub build_depends: Option<Dependencies>,
+    pub dependency_names: DependencyNames,
 
     pub depends: Option<Dependencies>,
     pub conflicts: Option<Dependencies>,
@@ -252,6 +619,8 @@ pub struct Metadata {
     pub rpm: Option<RpmInfo>,
 
     pub pkg: Option<PkgInfo>,
+
+    pub apk: Option<ApkInfo>,
 }
 
 impl Metadata {
@@ -263,44 +632,243 @@ impl Metadata {
             "0"
         }
     }
+
+    fn image_options_of(&self, image: &str, target: BuildTarget) -> Option<&ImageOptions> {
+        self.image_options.get(&(image.to_string(), target))
+    }
+
+    /// Returns the architecture of the package of `target` built on `image`
+    pub fn arch_for(&self, image: &str, target: BuildTarget) -> &BuildArch {
+        self.image_options_of(image, target)
+            .and_then(|options| options.arch.as_ref())
+            .unwrap_or(&self.arch)
+    }
+
+    /// Returns the release number of the package of `target` built on `image` with the release
+    /// suffix of the image appended
+    pub fn release_for(&self, image: &str, target: BuildTarget) -> String {
+        let suffix = self
+            .image_options_of(image, target)
+            .and_then(|options| options.release_suffix.as_deref())
+            .unwrap_or_default();
+        format!("{}{}", self.release(), suffix)
+    }
+
+    /// Whether default dependencies should be installed on `image` building `target`
+    pub fn skip_default_deps_for(&self, image: &str, target: BuildTarget) -> bool {
+        self.image_options_of(image, target)
+            .and_then(|options| options.skip_default_deps)
+            .or(self.skip_default_deps)
+            .unwrap_or_default()
+    }
+
+    /// Returns the snapshot of the package repositories used on `image` building `target`
+    pub fn snapshot_for(&self, image: &str, target: BuildTarget) -> Option<&RepoSnapshot> {
+        self.image_options_of(image, target)
+            .and_then(|options| options.snapshot.as_ref())
+    }
+
+    /// Whether package managers and other tools should be prevented from prompting for input
+    pub fn noninteractive(&self) -> bool {
+        self.noninteractive.unwrap_or(true)
+    }
+
+    /// Returns the priority of builds of this package, defaults to 0
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or_default()
+    }
+
+    /// Returns the description of the package with a note appended if the recipe is deprecated,
+    /// so that the deprecation shows up in the metadata of repositories
+    pub fn package_description(&self) -> String {
+        match &self.deprecated {
+            Some(deprecation) => format!("{} ({})", self.description, deprecation.note()),
+            None => self.description.clone(),
+        }
+    }
+
+    /// Package formats built on `image` in the order they are listed
+    pub fn build_targets_of(&self, image: &str) -> Vec<BuildTarget> {
+        self.images
+            .iter()
+            .flatten()
+            .filter(|target| target.image == image)
+            .map(|target| target.build_target)
+            .collect()
+    }
+
+    /// Whether the scripts are executed once in a build stage, either on the `build_image` or on
+    /// the musl image of the `static` preset
+    pub fn has_build_stage(&self) -> bool {
+        self.build_image.is_some() || self.preset == Some(Preset::Static)
+    }
 }
 
 impl TryFrom<MetadataRep> for Metadata {
     type Error = Error;
 
     fn try_from(rep: MetadataRep) -> Result<Self> {
+        let mut image_options = HashMap::new();
         let images = if let Some(rep_images) = rep.images {
             let mut images = vec![];
-            for image in rep_images.into_iter().map(ImageTarget::try_from) {
-                images.push(image?);
+            for image in rep_images {
+                if let ImageTargetRep::Full(ImageTargetOpts {
+                    when: Some(when), ..
+                }) = &image
+                {
+                    if !VersionCondition::from_str(when)?.matches(&rep.version) {
+                        continue;
+                    }
+                }
+                let (targets, options) = <(Vec<ImageTarget>, ImageOptions)>::try_from(image)?;
+                for target in &targets {
+                    image_options
+                        .insert((target.image.clone(), target.build_target), options.clone());
+                }
+                images.extend(targets);
             }
             Some(images)
         } else {
             None
         };
 
+        if let Some(disk) = &rep.disk {
+            for size in [&disk.size, &disk.tmpfs_size, &disk.min_free, &disk.shm_size]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                parse_size(size)?;
+            }
+        }
+
+        if let Some(network) = &rep.network {
+            network.validate()?;
+        }
+
+        let (source, extra_sources) = rep.source.map(SourceRep::split).unwrap_or_default();
+        let mut checksums = rep.checksum.into_iter().collect::<Vec<_>>();
+        if let Some(digest) = &rep.sha256 {
+            checksums.push(Checksum::sha256(digest)?);
+        }
+        if let Some(digest) = &rep.sha512 {
+            checksums.push(Checksum::sha512(digest)?);
+        }
+        if checksums.len() > 1 {
+            return Err(Error::msg(
+                "only one of `checksum`, `sha256` and `sha512` can be set",
+            ));
+        }
+        let checksum = checksums.pop();
+        if let Some(mirrors) = &rep.mirrors {
+            let is_http = |url: &str| url.starts_with("http");
+            if !source.as_deref().map(is_http).unwrap_or(false) {
+                return Err(Error::msg("`mirrors` require an http/https `source`"));
+            }
+            if let Some(mirror) = mirrors.iter().find(|mirror| !is_http(mirror)) {
+                return Err(Error::msg(format!(
+                    "mirror `{}` is not an http/https url",
+                    mirror
+                )));
+            }
+            if !mirrors.is_empty() && checksum.is_none() {
+                return Err(Error::msg(
+                    "`mirrors` require a `checksum` of the source to verify downloads against",
+                ));
+            }
+        }
+        if checksum.is_some() && source.is_none() {
+            return Err(Error::msg("`checksum` requires a `source`"));
+        }
+
+        if let Some(signature) = &rep.signature {
+            if signature.keys.is_empty() {
+                return Err(Error::msg("`signature` requires at least one trusted key"));
+            }
+            match (&source, &rep.git, &signature.url) {
+                (_, Some(_), _) | (Some(_), None, Some(_)) => {}
+                (Some(_), None, None) => {
+                    return Err(Error::msg(
+                        "`signature` of a source archive requires the `url` of the signature",
+                    ))
+                }
+                (None, None, _) => {
+                    return Err(Error::msg(
+                        "`signature` requires a `source` or `git` source",
+                    ))
+                }
+            }
+        }
+
+        if let Some(artifacts) = &rep.fetch_artifacts {
+            for artifact in artifacts {
+                artifact.validate()?;
+            }
+        }
+
         Ok(Self {
             name: rep.name,
             version: rep.version,
             description: rep.description,
             license: rep.license,
             images,
+            image_options,
+            build_image: rep.build_image,
+            preset: rep.preset,
 
             arch: rep
                 .arch
                 .map(|arch| BuildArch::from(arch.as_str()))
                 .unwrap_or_else(|| BuildArch::All),
             maintainer: rep.maintainer,
+            owners: rep.owners.unwrap_or_default(),
+            docs: rep.docs,
             url: rep.url,
-            source: rep.source,
+            source,
+            extra_sources,
+            mirrors: rep.mirrors.unwrap_or_default(),
+            checksum,
             git: if_let_some_ty!(rep.git, GitSource),
+            signature: rep.signature,
+            fetch_artifacts: rep.fetch_artifacts.unwrap_or_default(),
             skip_default_deps: rep.skip_default_deps,
             exclude: rep.exclude,
             group: rep.group,
             release: rep.release,
             epoch: rep.epoch,
+            priority: rep.priority,
+            deprecated: rep.deprecated.and_then(Into::into),
+            features: rep
+                .features
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, feature)| {
+                    validate_feature_name(&name)?;
+                    Ok((name, Feature::try_from(feature)?))
+                })
+                .collect::<Result<_>>()?,
+            enabled_features: vec![],
+            changelog: vec![],
+            security: rep.security,
+            disk: rep.disk,
+            network: rep.network,
+            devices: rep
+                .devices
+                .unwrap_or_default()
+                .iter()
+                .map(|device| Device::try_from(device.as_str()))
+                .collect::<Result<_>>()?,
+            gpus: if_let_some_ty!(rep.gpus, Gpus),
+            rpath: rep.rpath,
+            symbols: rep.symbols,
+            noninteractive: rep.noninteractive,
 
             build_depends: if_let_some_ty!(rep.build_depends, Dependencies),
+            dependency_names: {
+                let names = rep.dependency_names.unwrap_or_default();
+                names.validate()?;
+                names
+            },
             depends: if_let_some_ty!(rep.depends, Dependencies),
             conflicts: if_let_some_ty!(rep.conflicts, Dependencies),
             provides: if_let_some_ty!(rep.provides, Dependencies),
@@ -310,6 +878,7 @@ impl TryFrom<MetadataRep> for Metadata {
             deb: if_let_some_ty!(rep.deb, DebInfo),
             rpm: if_let_some_ty!(rep.rpm, RpmInfo),
             pkg: if_let_some_ty!(rep.pkg, PkgInfo),
+            apk: if_let_some_ty!(rep.apk, ApkInfo),
         })
     }
 }
@@ -0,0 +1,105 @@
+use crate::recipe::{BuildTarget, Checksum};
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Previously built package fetched into the build container and installed before the scripts
+/// run, either from a URL or path or from one of the configured publishers
+pub struct FetchedArtifact {
+    /// http/https URL or file system path of the package, relative paths are resolved against
+    /// the recipe directory
+    pub url: Option<String>,
+    /// Name of the publisher from which the package is fetched, like `ipfs`
+    pub publisher: Option<String>,
+    /// Content id of the package recorded by the publisher
+    pub id: Option<String>,
+    /// File name of the package, defaults to the last segment of `url`
+    pub name: Option<String>,
+    /// Expected digest of the fetched package like `sha256:<hex>`
+    pub checksum: Option<Checksum>,
+    /// Targets on which the package is installed like `rpm`, defaults to all targets
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+impl FetchedArtifact {
+    pub fn validate(&self) -> Result<()> {
+        for target in &self.targets {
+            BuildTarget::try_from(target.as_str())?;
+        }
+        match (&self.url, &self.publisher, &self.id) {
+            (Some(_), None, None) => Ok(()),
+            (None, Some(_), Some(_)) => {
+                if self.name.is_none() {
+                    return Err(Error::msg(
+                        "artifacts fetched from a publisher require a `name`",
+                    ));
+                }
+                Ok(())
+            }
+            (None, Some(_), None) => Err(Error::msg(
+                "artifacts fetched from a publisher require an `id`",
+            )),
+            _ => Err(Error::msg(
+                "fetched artifacts require either a `url` or a `publisher` with an `id`",
+            )),
+        }
+    }
+
+    /// File name under which the package is saved in the container
+    pub fn file_name(&self) -> &str {
+        self.name.as_deref().unwrap_or_else(|| {
+            self.url
+                .as_deref()
+                .map(|url| url.rsplit('/').next().unwrap_or(url))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Whether the package is installed in builds of `target`
+    pub fn applies_to(&self, target: BuildTarget) -> bool {
+        self.targets.is_empty()
+            || self
+                .targets
+                .iter()
+                .any(|t| BuildTarget::try_from(t.as_str()).ok() == Some(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_fetched_artifacts() {
+        let from_url: FetchedArtifact = serde_yaml::from_str(
+            "url: https://example.com/pkgs/libfoo-1.0-1.x86_64.rpm\ntargets: [rpm]",
+        )
+        .unwrap();
+        assert!(from_url.validate().is_ok());
+        assert_eq!(from_url.file_name(), "libfoo-1.0-1.x86_64.rpm");
+        assert!(from_url.applies_to(BuildTarget::Rpm));
+        assert!(!from_url.applies_to(BuildTarget::Deb));
+
+        let from_publisher: FetchedArtifact = serde_yaml::from_str(
+            "publisher: ipfs\nid: bafybeigdyrzt\nname: libfoo_1.0-1_amd64.deb",
+        )
+        .unwrap();
+        assert!(from_publisher.validate().is_ok());
+        assert_eq!(from_publisher.file_name(), "libfoo_1.0-1_amd64.deb");
+        assert!(from_publisher.applies_to(BuildTarget::Deb));
+
+        let unnamed: FetchedArtifact =
+            serde_yaml::from_str("publisher: ipfs\nid: bafybeigdyrzt").unwrap();
+        assert!(unnamed.validate().is_err());
+        let both: FetchedArtifact =
+            serde_yaml::from_str("url: libfoo.rpm\npublisher: ipfs\nid: bafybeigdyrzt").unwrap();
+        assert!(both.validate().is_err());
+        let unknown_target: FetchedArtifact =
+            serde_yaml::from_str("url: libfoo.rpm\ntargets: [msi]").unwrap();
+        assert!(unknown_target.validate().is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{ErrContext, Error, Result};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static DATE_FORMAT: &str = "%Y-%m-%d";
+/// Changelog file in the recipe directory used when a recipe doesn't set `changelog`
+pub static DEFAULT_CHANGELOG_FILE: &str = "changelog.yml";
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+/// An entry of the changelog of a recipe, the newest entries come first
+pub struct ChangelogEntry {
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Release of the version, defaults to the release of the recipe
+    pub release: Option<String>,
+    /// Date of the entry like `2021-07-01`
+    pub date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Author of the entry like `John Doe <john@example.com>`, defaults to the maintainer
+    pub author: Option<String>,
+    pub changes: Vec<String>,
+}
+
+impl ChangelogEntry {
+    pub fn parsed_date(&self) -> Result<NaiveDate> {
+        NaiveDate::parse_from_str(&self.date, DATE_FORMAT).map_err(|_| {
+            Error::msg(format!(
+                "invalid date `{}` of changelog entry `{}`, expected a date like `2021-07-01`",
+                self.date, self.version
+            ))
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.parsed_date()?;
+        if self.changes.is_empty() {
+            return Err(Error::msg(format!(
+                "changelog entry `{}` has no changes",
+                self.version
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+/// Changelog of a recipe, either inline entries or a path to a YAML file with entries relative to
+/// the recipe directory
+pub enum Changelog {
+    Entries(Vec<ChangelogEntry>),
+    File(PathBuf),
+}
+
+impl Changelog {
+    /// Returns the validated entries of the changelog reading them from the file relative to
+    /// `recipe_dir` if necessary
+    pub fn entries(&self, recipe_dir: &Path) -> Result<Vec<ChangelogEntry>> {
+        let entries = match self {
+            Changelog::Entries(entries) => entries.clone(),
+            Changelog::File(path) => load_changelog(&recipe_dir.join(path))?,
+        };
+        for entry in &entries {
+            entry.validate()?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Reads the entries of a changelog file, a missing file is an empty changelog
+pub fn load_changelog(path: &Path) -> Result<Vec<ChangelogEntry>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    serde_yaml::from_slice(&fs::read(path)?)
+        .context(format!("failed to parse changelog `{}`", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_changelog() {
+        let changelog: Changelog = serde_yaml::from_str(
+            "- version: 1.1.0\n  date: 2021-07-01\n  changes:\n    - Update to version 1.1.0\n",
+        )
+        .unwrap();
+        let entries = changelog.entries(Path::new("/")).unwrap();
+        assert_eq!(entries[0].release, None);
+        assert_eq!(
+            entries[0].parsed_date().unwrap(),
+            NaiveDate::from_ymd(2021, 7, 1)
+        );
+
+        let changelog: Changelog = serde_yaml::from_str("changelog.yml").unwrap();
+        assert_eq!(changelog, Changelog::File(PathBuf::from("changelog.yml")));
+
+        let changelog: Changelog =
+            serde_yaml::from_str("- version: 1.0.0\n  date: 01/07/2021\n  changes: [init]\n")
+                .unwrap();
+        assert!(changelog.entries(Path::new("/")).is_err());
+    }
+}
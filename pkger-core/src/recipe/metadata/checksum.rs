@@ -0,0 +1,163 @@
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Number of hexadecimal characters of a digest
+    fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+/// Expected digest of a downloaded file like `sha256:<hex>` or `sha512:<hex>`
+pub struct Checksum {
+    algorithm: Algorithm,
+    digest: String,
+}
+
+impl TryFrom<String> for Checksum {
+    type Error = Error;
+
+    fn try_from(checksum: String) -> Result<Self> {
+        let (algorithm, digest) = [Algorithm::Sha256, Algorithm::Sha512]
+            .iter()
+            .find_map(|algorithm| {
+                checksum
+                    .strip_prefix(algorithm.name())
+                    .and_then(|digest| digest.strip_prefix(':'))
+                    .map(|digest| (*algorithm, digest))
+            })
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "invalid checksum `{}`, expected `sha256:<hex>` or `sha512:<hex>`",
+                    checksum
+                ))
+            })?;
+        Self::new(algorithm, digest)
+    }
+}
+
+impl From<Checksum> for String {
+    fn from(checksum: Checksum) -> Self {
+        checksum.to_string()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.digest)
+    }
+}
+
+impl Checksum {
+    fn new(algorithm: Algorithm, digest: &str) -> Result<Self> {
+        if digest.len() != algorithm.hex_len() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::msg(format!(
+                "invalid {} digest `{}`, expected {} hexadecimal characters",
+                algorithm.name(),
+                digest,
+                algorithm.hex_len()
+            )));
+        }
+        Ok(Self {
+            algorithm,
+            digest: digest.to_lowercase(),
+        })
+    }
+
+    /// Creates a checksum from a bare sha256 digest
+    pub fn sha256(digest: &str) -> Result<Self> {
+        Self::new(Algorithm::Sha256, digest)
+    }
+
+    /// Creates a checksum from a bare sha512 digest
+    pub fn sha512(digest: &str) -> Result<Self> {
+        Self::new(Algorithm::Sha512, digest)
+    }
+
+    /// Command computing the digest of a file in the container
+    pub fn command(&self) -> &'static str {
+        match self.algorithm {
+            Algorithm::Sha256 => "sha256sum",
+            Algorithm::Sha512 => "sha512sum",
+        }
+    }
+
+    /// Returns the digest from the output of [`command`](Checksum::command) if it differs from the
+    /// expected one
+    pub fn mismatch(&self, output: &str) -> Option<String> {
+        let actual = output
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        if actual == self.digest {
+            None
+        } else {
+            Some(actual)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_compares_checksums() {
+        let digest = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+        let checksum = Checksum::try_from(format!("sha256:{}", digest.to_uppercase())).unwrap();
+        assert_eq!(checksum.to_string(), format!("sha256:{}", digest));
+        assert_eq!(
+            checksum.mismatch(&format!("{}  foo-1.0.tar.gz\n", digest)),
+            None
+        );
+        assert_eq!(
+            checksum.mismatch("d3b07384d113edec49eaa6238ad5ff00  foo-1.0.tar.gz\n"),
+            Some("d3b07384d113edec49eaa6238ad5ff00".to_string())
+        );
+
+        assert!(Checksum::try_from(format!("md5:{}", digest)).is_err());
+        assert!(Checksum::try_from("sha256:abc".to_string()).is_err());
+    }
+
+    #[test]
+    fn parses_sha512_checksums() {
+        let digest = "0cf9180a764aba863a67b6d72f0918bc131c6772642cb2dce5a34f0a702f9470ddc2bf125c12198b1995c233c34b4afd346c54a2334c350a948a51b6e8b4e6b6";
+        let checksum = Checksum::try_from(format!("sha512:{}", digest)).unwrap();
+        assert_eq!(checksum, Checksum::sha512(digest).unwrap());
+        assert_eq!(checksum.command(), "sha512sum");
+        assert_eq!(checksum.to_string(), format!("sha512:{}", digest));
+        assert_eq!(
+            checksum.mismatch(&format!("{}  foo.tar.gz\n", digest)),
+            None
+        );
+
+        assert!(Checksum::sha512(&digest[..64]).is_err());
+        assert!(Checksum::sha256(digest).is_err());
+        assert_eq!(
+            Checksum::sha256(&digest[..64]).unwrap().command(),
+            "sha256sum"
+        );
+    }
+}
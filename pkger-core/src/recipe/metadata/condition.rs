@@ -0,0 +1,159 @@
+use crate::{Error, Result};
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Operator {
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// Condition on the version of a recipe like `version >= 1.2, version < 2.0`. All comparisons
+/// separated by a comma must hold.
+pub struct VersionCondition {
+    comparisons: Vec<(Operator, String)>,
+}
+
+impl FromStr for VersionCondition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let comparisons = s
+            .split(',')
+            .map(|comparison| {
+                let rest = comparison
+                    .trim()
+                    .strip_prefix("version")
+                    .ok_or_else(|| {
+                        Error::msg(format!(
+                            "invalid condition `{}`, expected a comparison like `version < 2.0`",
+                            comparison.trim()
+                        ))
+                    })?
+                    .trim_start();
+                // two character operators first so that `<=` isn't parsed as `<`
+                let (op, version) = [
+                    ("<=", Operator::Le),
+                    (">=", Operator::Ge),
+                    ("==", Operator::Eq),
+                    ("!=", Operator::Ne),
+                    ("<", Operator::Lt),
+                    (">", Operator::Gt),
+                ]
+                .iter()
+                .find_map(|(token, op)| rest.strip_prefix(token).map(|version| (*op, version)))
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "invalid operator in condition `{}`, expected one of <, <=, >, >=, ==, !=",
+                        comparison.trim()
+                    ))
+                })?;
+                let version = version.trim();
+                if version.is_empty() {
+                    return Err(Error::msg(format!(
+                        "missing version in condition `{}`",
+                        comparison.trim()
+                    )));
+                }
+                Ok((op, version.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { comparisons })
+    }
+}
+
+impl VersionCondition {
+    pub fn matches(&self, version: &str) -> bool {
+        self.comparisons
+            .iter()
+            .all(|(op, other)| op.holds(compare_versions(version, other)))
+    }
+}
+
+/// Compares versions segment by segment, numeric segments are compared as numbers and others
+/// lexically so that `1.10` is newer than `1.9`
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn segments(version: &str) -> Vec<&str> {
+        let mut segments = vec![];
+        let mut start = None;
+        let mut numeric = false;
+        for (i, c) in version.char_indices() {
+            if !c.is_ascii_alphanumeric() {
+                if let Some(s) = start.take() {
+                    segments.push(&version[s..i]);
+                }
+                continue;
+            }
+            match start {
+                Some(s) if c.is_ascii_digit() != numeric => {
+                    segments.push(&version[s..i]);
+                    start = Some(i);
+                }
+                Some(_) => {}
+                None => start = Some(i),
+            }
+            numeric = c.is_ascii_digit();
+        }
+        if let Some(s) = start {
+            segments.push(&version[s..]);
+        }
+        segments
+    }
+
+    let (a, b) = (segments(a), segments(b));
+    for (a, b) in a.iter().zip(b.iter()) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_version_conditions() {
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("2.0", "2.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.0rc1", "1.0rc2"), Ordering::Less);
+        assert_eq!(compare_versions("1.0-1", "1.0.1"), Ordering::Equal);
+
+        let condition = VersionCondition::from_str("version >= 1.2, version < 2.0").unwrap();
+        assert!(condition.matches("1.2"));
+        assert!(condition.matches("1.10.3"));
+        assert!(!condition.matches("2.0"));
+        assert!(!condition.matches("1.1"));
+        assert!(VersionCondition::from_str("version != 1.5")
+            .unwrap()
+            .matches("1.6"));
+
+        assert!(VersionCondition::from_str("release < 2").is_err());
+        assert!(VersionCondition::from_str("version ~ 2").is_err());
+        assert!(VersionCondition::from_str("version <").is_err());
+    }
+}
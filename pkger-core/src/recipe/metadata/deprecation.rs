@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+/// The `deprecated` field of a recipe, either a flag or a map with details.
+///
+/// Examples:
+/// true
+///
+/// { message = "no longer maintained upstream", replacement = "bar" }
+pub enum DeprecationRep {
+    Flag(bool),
+    Details(Deprecation),
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Deprecation {
+    /// Why the recipe is deprecated
+    pub message: Option<String>,
+    /// Name of the package that should be used instead
+    pub replacement: Option<String>,
+}
+
+impl From<DeprecationRep> for Option<Deprecation> {
+    fn from(rep: DeprecationRep) -> Self {
+        match rep {
+            DeprecationRep::Flag(true) => Some(Deprecation::default()),
+            DeprecationRep::Flag(false) => None,
+            DeprecationRep::Details(deprecation) => Some(deprecation),
+        }
+    }
+}
+
+impl Deprecation {
+    /// Returns a short note like `deprecated, use bar instead: no longer maintained upstream`
+    pub fn note(&self) -> String {
+        let mut note = "deprecated".to_string();
+        if let Some(replacement) = &self.replacement {
+            note.push_str(&format!(", use {} instead", replacement));
+        }
+        if let Some(message) = &self.message {
+            note.push_str(&format!(": {}", message));
+        }
+        note
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_deprecation() {
+        let parse = |yaml: &str| -> Option<Deprecation> {
+            serde_yaml::from_str::<DeprecationRep>(yaml).unwrap().into()
+        };
+        assert_eq!(parse("false"), None);
+        assert_eq!(parse("true").unwrap().note(), "deprecated");
+        assert_eq!(
+            parse("message: no longer maintained upstream\nreplacement: bar")
+                .unwrap()
+                .note(),
+            "deprecated, use bar instead: no longer maintained upstream"
+        );
+        assert_eq!(
+            parse("replacement: bar").unwrap().note(),
+            "deprecated, use bar instead"
+        );
+    }
+}
@@ -1,14 +1,134 @@
 #![allow(dead_code)]
+use crate::recipe::{BuildArch, Distro};
 use crate::Result;
 
+use serde::{Deserialize, Serialize};
 use serde_yaml::{Mapping, Sequence, Value as YamlValue};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 
 pub static COMMON_DEPS_KEY: &str = "all";
+/// Prefix of keys that specify dependencies for an architecture like `arch:armhf`
+pub static ARCH_DEPS_PREFIX: &str = "arch:";
 
 type DepsMap = HashMap<String, HashSet<String>>;
 
+/// Splits a dependency like `libfoo:any (>= 1.0)` into the name, the architecture qualifier used
+/// on multiarch Debian systems and the rest of the expression
+fn split_arch_qualifier(dep: &str) -> (&str, Option<&str>, &str) {
+    let end = dep
+        .find(|c: char| c.is_whitespace() || c == '(')
+        .unwrap_or(dep.len());
+    let (name, rest) = dep.split_at(end);
+    match name.split_once(':') {
+        Some((name, qualifier)) => (name, Some(qualifier), rest),
+        None => (name, None, rest),
+    }
+}
+
+/// Returns `dep` without its architecture qualifier, for package formats that don't support them
+pub fn strip_arch_qualifier(dep: &str) -> Cow<'_, str> {
+    match split_arch_qualifier(dep) {
+        (name, Some(_), rest) => Cow::Owned(format!("{}{}", name, rest)),
+        _ => Cow::Borrowed(dep),
+    }
+}
+
+/// Splits a dependency like `libfoo:amd64 (>= 1.0)` into the name with its architecture
+/// qualifier and the relation and version of the constraint, if any. Debian's `<<` and `>>` are
+/// returned as `<` and `>`.
+pub(crate) fn split_version_constraint(dep: &str) -> (&str, Option<(&str, &str)>) {
+    let dep = dep.trim();
+    let end = dep
+        .find(|c: char| c.is_whitespace() || c == '(')
+        .unwrap_or(dep.len());
+    let (name, rest) = dep.split_at(end);
+    let constraint = rest
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim();
+    if constraint.is_empty() {
+        return (name, None);
+    }
+    let op_end = constraint
+        .find(|c| !matches!(c, '<' | '>' | '='))
+        .unwrap_or(constraint.len());
+    let (op, version) = constraint.split_at(op_end);
+    let op = match op {
+        "<<" => "<",
+        ">>" => ">",
+        op => op,
+    };
+    (name, Some((op, version.trim())))
+}
+
+/// Verifies that the architecture qualifier of `dep`, if any, is `any`, `native` or a name of
+/// an architecture
+fn validate_arch_qualifier(dep: &str) -> Result<()> {
+    if let (_, Some(qualifier), _) = split_arch_qualifier(dep) {
+        let is_valid = !qualifier.is_empty()
+            && qualifier
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !is_valid {
+            return Err(anyhow!(
+                "invalid architecture qualifier `{}` in dependency `{}`",
+                qualifier,
+                dep
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(transparent)]
+/// Names of dependencies on distributions where they differ from the names used in the recipe,
+/// like `openssl-dev: {debian: libssl-dev, centos: openssl-devel}`. An empty name skips the
+/// dependency on that distribution.
+pub struct DependencyNames(HashMap<String, HashMap<String, String>>);
+
+impl DependencyNames {
+    pub fn validate(&self) -> Result<()> {
+        for (dep, names) in &self.0 {
+            for distro in names.keys() {
+                if Distro::from_name(distro).is_none() {
+                    return Err(anyhow!(
+                        "unknown distribution `{}` in names of dependency `{}`",
+                        distro,
+                        dep
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the name of `dep` on `distro` keeping its architecture qualifier and version
+    /// constraint, or `None` if the dependency is skipped there
+    pub fn resolve<'dep>(&self, dep: &'dep str, distro: Distro) -> Option<Cow<'dep, str>> {
+        let (name, qualifier, rest) = split_arch_qualifier(dep);
+        let mapped = self.0.get(name).and_then(|names| {
+            names
+                .iter()
+                .find(|(key, _)| Distro::from_name(key) == Some(distro))
+                .map(|(_, name)| name.trim())
+        });
+        match mapped {
+            None => Some(Cow::Borrowed(dep)),
+            Some("") => None,
+            Some(mapped) => Some(Cow::Owned(format!(
+                "{}{}{}",
+                mapped,
+                qualifier.map(|q| format!(":{}", q)).unwrap_or_default(),
+                rest
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Dependencies {
     inner: DepsMap,
@@ -43,7 +163,9 @@ impl TryFrom<Mapping> for Dependencies {
                         ));
                     }
 
-                    deps_set.insert(dep.as_str().unwrap().to_string());
+                    let dep = dep.as_str().unwrap();
+                    validate_arch_qualifier(dep)?;
+                    deps_set.insert(dep.to_string());
                 }
                 deps.inner_mut().insert(
                     image
@@ -70,6 +192,7 @@ impl TryFrom<Sequence> for Dependencies {
         let mut dep_set = HashSet::new();
         for dep in array {
             if let YamlValue::String(dep) = dep {
+                validate_arch_qualifier(&dep)?;
                 dep_set.insert(dep);
             } else {
                 return Err(anyhow!(
@@ -100,7 +223,9 @@ impl TryFrom<YamlValue> for Dependencies {
 }
 
 impl Dependencies {
-    pub fn resolve_names(&self, image: &str) -> HashSet<&str> {
+    /// Returns common dependencies combined with dependencies of `image` and of the architecture
+    /// `arch`.
+    pub fn resolve_names(&self, image: &str, arch: &BuildArch) -> HashSet<&str> {
         // it's ok to unwrap here, the new function adds an empty hashset on initialization
         let mut deps = HashSet::new();
         if let Some(common_deps) = self.inner.get(COMMON_DEPS_KEY) {
@@ -113,10 +238,31 @@ impl Dependencies {
                 deps.insert(dep.as_str());
             });
         }
+        self.inner
+            .iter()
+            .filter_map(|(key, arch_deps)| {
+                key.strip_prefix(ARCH_DEPS_PREFIX)
+                    .filter(|key_arch| BuildArch::from(*key_arch) == *arch)
+                    .map(|_| arch_deps)
+            })
+            .flatten()
+            .for_each(|dep| {
+                deps.insert(dep.as_str());
+            });
 
         deps
     }
 
+    /// Adds all dependencies of `other` to these dependencies
+    pub fn merge(&mut self, other: &Dependencies) {
+        for (key, deps) in &other.inner {
+            self.inner
+                .entry(key.clone())
+                .or_default()
+                .extend(deps.iter().cloned());
+        }
+    }
+
     pub fn inner(&self) -> &DepsMap {
         &self.inner
     }
@@ -134,6 +280,7 @@ mod tests {
     macro_rules! test_deps {
     (
         input = $inp:expr,
+        arch = $arch:expr,
         want = $(
             $image:ident => $($dep:literal),+
         );+) => {
@@ -148,7 +295,7 @@ mod tests {
             $image.insert($dep);
                 )+
 
-            assert_eq!($image, got.resolve_names(stringify!($image)));
+            assert_eq!($image, got.resolve_names(stringify!($image), &$arch));
             )+
 
         }
@@ -163,6 +310,7 @@ build_depends:
   centos8: ["cargo", "openssl-devel"]
   debian10: ["curl", "libssl-dev"]
 "#,
+        arch = BuildArch::All,
         want =
             all      => "gcc", "pkg-config", "git";
             centos8  => "cargo", "openssl-devel", "gcc", "pkg-config", "git";
@@ -175,8 +323,67 @@ build_depends:
   - pkg-config
   - git
 "#,
+        arch = BuildArch::All,
         want =
             all      => "gcc", "pkg-config", "git"
         );
+        test_deps!(
+        input = r#"
+build_depends:
+  all: ["gcc"]
+  debian10: ["curl"]
+  arch:armhf: ["gcc-arm-linux-gnueabihf"]
+  arch:amd64: ["nasm"]
+"#,
+        arch = BuildArch::Armv7h,
+        want =
+            all      => "gcc", "gcc-arm-linux-gnueabihf";
+            debian10 => "gcc", "curl", "gcc-arm-linux-gnueabihf"
+        );
+    }
+
+    #[test]
+    fn handles_arch_qualifiers() {
+        assert_eq!(strip_arch_qualifier("libfoo:any"), "libfoo");
+        assert_eq!(
+            strip_arch_qualifier("libfoo:amd64 (>= 1:2.0)"),
+            "libfoo (>= 1:2.0)"
+        );
+        assert_eq!(
+            strip_arch_qualifier("libfoo (>= 1:2.0)"),
+            "libfoo (>= 1:2.0)"
+        );
+
+        let valid: YamlValue = serde_yaml::from_str("[libfoo:any, python3:native]").unwrap();
+        assert!(Dependencies::try_from(valid).is_ok());
+        let invalid: YamlValue = serde_yaml::from_str("[libfoo:Any]").unwrap();
+        assert!(Dependencies::try_from(invalid).is_err());
+    }
+
+    #[test]
+    fn resolves_dependency_names() {
+        assert_eq!(split_version_constraint("curl"), ("curl", None));
+        assert_eq!(
+            split_version_constraint("libfoo:amd64 (>> 1:2.0)"),
+            ("libfoo:amd64", Some((">", "1:2.0")))
+        );
+
+        let names: DependencyNames = serde_yaml::from_str(
+            "openssl-dev:\n  debian: libssl-dev\n  centos: openssl-devel\npkg-config:\n  arch: ''\n",
+        )
+        .unwrap();
+        assert!(names.validate().is_ok());
+        assert_eq!(
+            names.resolve("openssl-dev (>= 1.1)", Distro::Debian),
+            Some(Cow::Borrowed("libssl-dev (>= 1.1)"))
+        );
+        assert_eq!(
+            names.resolve("openssl-dev", Distro::Alpine),
+            Some(Cow::Borrowed("openssl-dev"))
+        );
+        assert_eq!(names.resolve("pkg-config", Distro::Arch), None);
+
+        let names: DependencyNames = serde_yaml::from_str("curl: {windows: curl}").unwrap();
+        assert!(names.validate().is_err());
     }
 }
@@ -0,0 +1,141 @@
+use crate::{Error, Result};
+
+use serde_json::{json, Value as JsonValue};
+use serde_yaml::Value as YamlValue;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, PartialEq)]
+/// A device of the host made available in the build container
+pub struct Device {
+    pub host: String,
+    pub container: String,
+    /// Cgroup permissions of the device, a combination of `r`, `w` and `m`
+    pub permissions: String,
+}
+
+impl TryFrom<&str> for Device {
+    type Error = Error;
+
+    /// Parses a device in the format of `docker run --device`, like `/dev/fuse` or
+    /// `/dev/sdc:/dev/xvdc:r`
+    fn try_from(device: &str) -> Result<Self> {
+        let mut parts = device.split(':');
+        let host = parts.next().unwrap_or_default();
+        let container = parts.next().unwrap_or(host);
+        let permissions = parts.next().unwrap_or("rwm");
+        if !host.starts_with('/')
+            || !container.starts_with('/')
+            || parts.next().is_some()
+            || permissions.is_empty()
+            || !permissions.chars().all(|c| "rwm".contains(c))
+        {
+            return Err(Error::msg(format!(
+                "invalid device `{}`, expected `host[:container[:permissions]]`",
+                device
+            )));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            container: container.to_string(),
+            permissions: permissions.to_string(),
+        })
+    }
+}
+
+impl Device {
+    /// Returns the device mapping as expected by the Docker API
+    pub fn as_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        map.insert("PathOnHost".to_string(), self.host.clone());
+        map.insert("PathInContainer".to_string(), self.container.clone());
+        map.insert("CgroupPermissions".to_string(), self.permissions.clone());
+        map
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// GPUs requested for the build container
+pub enum Gpus {
+    All,
+    Count(u32),
+    Ids(Vec<String>),
+}
+
+impl TryFrom<YamlValue> for Gpus {
+    type Error = Error;
+
+    /// Parses GPUs in the format of `docker run --gpus`, either `all`, a number of GPUs or
+    /// `device=0,1`
+    fn try_from(value: YamlValue) -> Result<Self> {
+        let gpus = match value {
+            YamlValue::Number(n) => n
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .map(Gpus::Count),
+            YamlValue::String(s) if s == "all" => Some(Gpus::All),
+            YamlValue::String(s) => match s.strip_prefix("device=") {
+                Some(ids) if !ids.is_empty() => Some(Gpus::Ids(
+                    ids.split(',').map(|id| id.trim().to_string()).collect(),
+                )),
+                _ => s.parse::<u32>().ok().map(Gpus::Count),
+            },
+            _ => None,
+        };
+        gpus.ok_or_else(|| {
+            Error::msg("invalid gpus, expected `all`, a number of GPUs or `device=<ids>`")
+        })
+    }
+}
+
+impl Gpus {
+    /// Returns the device request of these GPUs as expected by the Docker API
+    pub fn device_request(&self) -> JsonValue {
+        let (count, ids) = match self {
+            Gpus::All => (-1, vec![]),
+            Gpus::Count(n) => (i64::from(*n), vec![]),
+            Gpus::Ids(ids) => (0, ids.clone()),
+        };
+        json!({
+            "Driver": "",
+            "Count": count,
+            "DeviceIDs": ids,
+            "Capabilities": [["gpu"]],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_devices_and_gpus() {
+        assert_eq!(
+            Device::try_from("/dev/sdc:/dev/xvdc:r").unwrap(),
+            Device {
+                host: "/dev/sdc".to_string(),
+                container: "/dev/xvdc".to_string(),
+                permissions: "r".to_string(),
+            }
+        );
+        assert_eq!(
+            Device::try_from("/dev/fuse").unwrap().container,
+            "/dev/fuse"
+        );
+        assert!(Device::try_from("/dev/fuse:/dev/fuse:x").is_err());
+
+        assert_eq!(
+            Gpus::try_from(YamlValue::String("all".to_string())).unwrap(),
+            Gpus::All
+        );
+        assert_eq!(
+            Gpus::try_from(YamlValue::Number(2.into())).unwrap(),
+            Gpus::Count(2)
+        );
+        let gpus = Gpus::try_from(YamlValue::String("device=0,1".to_string())).unwrap();
+        assert_eq!(gpus.device_request()["DeviceIDs"], json!(["0", "1"]));
+        assert!(Gpus::try_from(YamlValue::String("some".to_string())).is_err());
+    }
+}
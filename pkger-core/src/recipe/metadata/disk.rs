@@ -0,0 +1,117 @@
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Limits of the disk space used by a build. Sizes are specified in bytes or with a `K`, `M`, `G`
+/// or `T` suffix, like `10G`.
+pub struct DiskOptions {
+    /// Maximum size of the writable layer of the build container
+    pub size: Option<String>,
+    /// Size of tmpfs mounts that don't specify one themselves
+    pub tmpfs_size: Option<String>,
+    /// Free space required in the output directory before the build starts
+    pub min_free: Option<String>,
+    /// Size of `/dev/shm` of the build container, Docker defaults to 64M
+    pub shm_size: Option<String>,
+}
+
+/// Parses a size like `512M` or `10G` into a number of bytes
+pub fn parse_size(size: &str) -> Result<u64> {
+    let trimmed = size.trim();
+    let trimmed = trimmed
+        .strip_suffix("iB")
+        .or_else(|| trimmed.strip_suffix('B'))
+        .unwrap_or(trimmed);
+    let (number, multiplier) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1 << 10),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        Some('T') => (&trimmed[..trimmed.len() - 1], 1 << 40),
+        _ => (trimmed, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| Error::msg(format!("invalid size `{}`", size)))
+}
+
+impl DiskOptions {
+    /// Combines these options with `other` so that `other` can only lower the limits and raise
+    /// the required free space. The size of `/dev/shm` of `other` takes precedence as test suites
+    /// of some packages need more than the default.
+    pub fn merge(&self, other: &DiskOptions) -> Result<DiskOptions> {
+        fn pick(a: &Option<String>, b: &Option<String>, smaller: bool) -> Result<Option<String>> {
+            Ok(match (a, b) {
+                (Some(a), Some(b)) => {
+                    let a_is_smaller = parse_size(a)? <= parse_size(b)?;
+                    Some(if a_is_smaller == smaller { a } else { b }.clone())
+                }
+                (a, b) => a.clone().or_else(|| b.clone()),
+            })
+        }
+
+        Ok(DiskOptions {
+            size: pick(&self.size, &other.size, true)?,
+            tmpfs_size: pick(&self.tmpfs_size, &other.tmpfs_size, true)?,
+            min_free: pick(&self.min_free, &other.min_free, false)?,
+            shm_size: other.shm_size.clone().or_else(|| self.shm_size.clone()),
+        })
+    }
+
+    /// Returns the `mount` of a tmpfs like `/var/tmp:mode=1777` with the default size applied
+    /// unless the mount specifies one.
+    pub fn tmpfs_mount(&self, mount: &str) -> String {
+        let size = match &self.tmpfs_size {
+            Some(size) => size,
+            None => return mount.to_string(),
+        };
+        match mount.split_once(':') {
+            Some((_, options)) if options.split(',').any(|opt| opt.starts_with("size=")) => {
+                mount.to_string()
+            }
+            Some(_) => format!("{},size={}", mount, size),
+            None => format!("{}:size={}", mount, size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("10GiB").unwrap(), 10 << 30);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert!(parse_size("ten").is_err());
+
+        let global = DiskOptions {
+            size: Some("20G".to_string()),
+            tmpfs_size: Some("64M".to_string()),
+            min_free: Some("1G".to_string()),
+            shm_size: Some("128M".to_string()),
+        };
+        let recipe = DiskOptions {
+            size: Some("50G".to_string()),
+            tmpfs_size: None,
+            min_free: Some("5G".to_string()),
+            shm_size: Some("2G".to_string()),
+        };
+        let merged = global.merge(&recipe).unwrap();
+        assert_eq!(merged.size.as_deref(), Some("20G"));
+        assert_eq!(merged.min_free.as_deref(), Some("5G"));
+        assert_eq!(merged.shm_size.as_deref(), Some("2G"));
+        assert_eq!(merged.tmpfs_mount("/var/tmp"), "/var/tmp:size=64M");
+        assert_eq!(
+            merged.tmpfs_mount("/var/tmp:mode=1777"),
+            "/var/tmp:mode=1777,size=64M"
+        );
+        assert_eq!(merged.tmpfs_mount("/root:size=1G"), "/root:size=1G");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::recipe::Dependencies;
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureRep {
+    /// Whether the feature is enabled unless default features are disabled
+    #[serde(default)]
+    pub default: bool,
+    pub description: Option<String>,
+    /// Appended to the name of the package when the feature is enabled, like `-ldap`
+    pub name_suffix: Option<String>,
+    /// Build dependencies added when the feature is enabled
+    pub build_depends: Option<YamlValue>,
+    /// Dependencies of the package added when the feature is enabled
+    pub depends: Option<YamlValue>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// Optional part of a recipe enabled with `--features`, so that one recipe can build multiple
+/// variants of a package
+pub struct Feature {
+    pub default: bool,
+    pub description: Option<String>,
+    pub name_suffix: Option<String>,
+    pub build_depends: Option<Dependencies>,
+    pub depends: Option<Dependencies>,
+}
+
+impl TryFrom<FeatureRep> for Feature {
+    type Error = Error;
+
+    fn try_from(rep: FeatureRep) -> Result<Self> {
+        Ok(Self {
+            default: rep.default,
+            description: rep.description,
+            name_suffix: rep.name_suffix,
+            build_depends: rep.build_depends.map(Dependencies::try_from).transpose()?,
+            depends: rep.depends.map(Dependencies::try_from).transpose()?,
+        })
+    }
+}
+
+/// Verifies that `name` can be used as the name of a feature and in the names of environment
+/// variables
+pub fn validate_feature_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "invalid feature name `{}`, only alphanumeric characters, `-` and `_` are allowed",
+            name
+        )))
+    }
+}
+
+/// Name of the environment variable set to `1` in builds with `feature` enabled, like
+/// `PKGER_FEATURE_TLS`
+pub fn feature_env_var(feature: &str) -> String {
+    format!("PKGER_FEATURE_{}", feature.to_uppercase().replace('-', "_"))
+}
+
+/// Returns the features of `requested` that apply to the recipe `recipe`. Entries like
+/// `recipe/feature` apply only to the recipe named `recipe`, other entries to every recipe that
+/// defines the feature.
+pub fn requested_features<'a>(
+    recipe: &str,
+    defined: impl Fn(&str) -> bool,
+    requested: &'a [String],
+) -> Result<Vec<&'a str>> {
+    let mut features = vec![];
+    for entry in requested {
+        match entry.split_once('/') {
+            Some((name, feature)) if name == recipe => {
+                if !defined(feature) {
+                    return Err(Error::msg(format!(
+                        "recipe `{}` has no feature `{}`",
+                        recipe, feature
+                    )));
+                }
+                features.push(feature);
+            }
+            Some(_) => {}
+            None if defined(entry) => features.push(entry.as_str()),
+            None => {}
+        }
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_requested_features() {
+        let defined = |feature: &str| ["tls", "ldap"].contains(&feature);
+        let requested = vec![
+            "tls".to_string(),
+            "foo/ldap".to_string(),
+            "bar/minimal".to_string(),
+            "gui".to_string(),
+        ];
+        assert_eq!(
+            requested_features("foo", defined, &requested).unwrap(),
+            vec!["tls", "ldap"]
+        );
+        assert_eq!(
+            requested_features("bar", |_| false, &requested[..2]).unwrap(),
+            Vec::<&str>::new()
+        );
+        assert!(requested_features("bar", defined, &requested).is_err());
+
+        assert_eq!(feature_env_var("http-2"), "PKGER_FEATURE_HTTP_2");
+        assert!(validate_feature_name("http-2").is_ok());
+        assert!(validate_feature_name("tls,ldap").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+static VERIFY_ATTRIBUTES: &[&str] = &[
+    "md5",
+    "filedigest",
+    "size",
+    "link",
+    "user",
+    "owner",
+    "group",
+    "mtime",
+    "mode",
+    "rdev",
+    "caps",
+];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Attributes of a single file in the `%files` section of a generated spec
+pub struct RpmFileAttributes {
+    #[serde(default)]
+    /// Marks the file as owned by the package without including its contents, like log files
+    /// created at runtime. Ghost files don't have to exist in the build output.
+    pub ghost: bool,
+    /// Attributes checked by `rpm --verify`, like `not md5 size mtime`
+    pub verify: Option<String>,
+    /// File capabilities in the format of `cap_from_text`, like `cap_net_bind_service=+ep`
+    pub caps: Option<String>,
+}
+
+impl RpmFileAttributes {
+    pub fn validate(&self, path: &str) -> Result<()> {
+        if !path.starts_with('/') {
+            return Err(Error::msg(format!(
+                "path of file `{}` with attributes must be absolute",
+                path
+            )));
+        }
+        if let Some(verify) = &self.verify {
+            let mut attributes = verify.split_whitespace().peekable();
+            if attributes.peek() == Some(&"not") {
+                attributes.next();
+            }
+            if attributes.peek().is_none() {
+                return Err(Error::msg(format!(
+                    "verify attributes of `{}` can't be empty",
+                    path
+                )));
+            }
+            if let Some(invalid) = attributes.find(|attr| !VERIFY_ATTRIBUTES.contains(attr)) {
+                return Err(Error::msg(format!(
+                    "invalid verify attribute `{}` of `{}`, expected one of {}",
+                    invalid,
+                    path,
+                    VERIFY_ATTRIBUTES.join(", ")
+                )));
+            }
+        }
+        if let Some(caps) = &self.caps {
+            if caps.trim().is_empty() || caps.contains(['(', ')', '\n']) {
+                return Err(Error::msg(format!(
+                    "invalid capabilities `{}` of `{}`",
+                    caps, path
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the entry of `path` in the `%files` section with all directives applied, like
+    /// `%ghost %verify(not md5) /var/log/foo.log`
+    pub fn render(&self, path: &str) -> String {
+        let mut entry = String::new();
+        if self.ghost {
+            entry.push_str("%ghost ");
+        }
+        if let Some(verify) = &self.verify {
+            entry.push_str(&format!(
+                "%verify({}) ",
+                verify.split_whitespace().collect::<Vec<_>>().join(" ")
+            ));
+        }
+        if let Some(caps) = &self.caps {
+            entry.push_str(&format!("%caps({}) ", caps.trim()));
+        }
+        entry.push_str(path);
+        entry
+    }
+}
+
+/// Returns entries of the `%files` section for `files` found in the build output with attributes
+/// applied. Ghost files missing from the output are appended.
+pub fn rpm_files_entries(
+    files: &[String],
+    attributes: &BTreeMap<String, RpmFileAttributes>,
+) -> Vec<String> {
+    let mut entries = files
+        .iter()
+        .map(|file| match attributes.get(file) {
+            Some(attrs) => attrs.render(file),
+            None => file.clone(),
+        })
+        .collect::<Vec<_>>();
+    entries.extend(
+        attributes
+            .iter()
+            .filter(|(path, attrs)| attrs.ghost && !files.contains(path))
+            .map(|(path, attrs)| attrs.render(path)),
+    );
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_file_attributes() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "/usr/bin/foo".to_string(),
+            RpmFileAttributes {
+                caps: Some("cap_net_bind_service=+ep".to_string()),
+                ..Default::default()
+            },
+        );
+        attributes.insert(
+            "/var/log/foo.log".to_string(),
+            RpmFileAttributes {
+                ghost: true,
+                verify: Some("not  md5 size mtime".to_string()),
+                ..Default::default()
+            },
+        );
+        for (path, attrs) in &attributes {
+            assert!(attrs.validate(path).is_ok());
+        }
+
+        let files = vec!["/usr/bin/foo".to_string(), "/usr/share/foo".to_string()];
+        assert_eq!(
+            rpm_files_entries(&files, &attributes),
+            vec![
+                "%caps(cap_net_bind_service=+ep) /usr/bin/foo",
+                "/usr/share/foo",
+                "%ghost %verify(not md5 size mtime) /var/log/foo.log",
+            ]
+        );
+
+        let invalid = RpmFileAttributes {
+            verify: Some("not checksum".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate("/usr/bin/foo").is_err());
+        assert!(RpmFileAttributes::default()
+            .validate("usr/bin/foo")
+            .is_err());
+    }
+}
@@ -2,51 +2,99 @@ use crate::{Error, Result};
 
 use serde_yaml::{Mapping, Value as YamlValue};
 use std::convert::TryFrom;
+use std::path::Path;
+
+static DEFAULT_BRANCH: &str = "master";
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct GitSource {
     url: String,
     // defaults to master
-    branch: String,
+    branch: Option<String>,
+    /// Tag checked out instead of a branch
+    tag: Option<String>,
+    /// Commit checked out after cloning
+    commit: Option<String>,
+    /// Number of commits fetched in a shallow clone
+    depth: Option<u32>,
+    submodules: bool,
 }
 
 impl From<&str> for GitSource {
     fn from(s: &str) -> Self {
-        Self {
-            url: s.to_string(),
-            branch: "master".to_string(),
-        }
+        Self::new(s, None::<&str>)
+    }
+}
+
+fn get_string(table: &Mapping, key: &str) -> Result<Option<String>> {
+    match table.get(&YamlValue::from(key)) {
+        None | Some(YamlValue::Null) => Ok(None),
+        Some(YamlValue::String(s)) => Ok(Some(s.clone())),
+        Some(value) => Err(anyhow!("expected a string as {}, found `{:?}`", key, value)),
     }
 }
 
 impl TryFrom<Mapping> for GitSource {
     type Error = Error;
     fn try_from(table: Mapping) -> Result<Self> {
-        if let Some(url) = table.get(&YamlValue::from("url")) {
-            if !url.is_string() {
-                return Err(anyhow!("expected a string as url, found `{:?}`", url));
-            }
+        let url = get_string(&table, "url")?
+            .ok_or_else(|| anyhow!("expected a url entry in a table, found `{:?}`", table))?;
+        let mut source = GitSource::new(url, get_string(&table, "branch")?);
 
-            let url = url.as_str().unwrap().to_string();
+        source.tag = get_string(&table, "tag")?;
+        if source.tag.is_some() && source.branch.is_some() {
+            return Err(Error::msg("only one of `branch` and `tag` can be set"));
+        }
 
-            if let Some(branch) = table.get(&YamlValue::from("branch")) {
-                if !branch.is_string() {
-                    return Err(anyhow!("expected a string as branch, found `{:?}`", branch));
-                }
+        if let Some(commit) = get_string(&table, "commit")? {
+            if commit.len() < 7
+                || commit.len() > 40
+                || !commit.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(anyhow!(
+                    "invalid commit `{}`, expected a hash of 7 to 40 hexadecimal characters",
+                    commit
+                ));
+            }
+            source.commit = Some(commit.to_lowercase());
+        }
 
-                return Ok(GitSource::new(
-                    url,
-                    Some(branch.as_str().unwrap().to_string()),
+        match table.get(&YamlValue::from("depth")) {
+            None | Some(YamlValue::Null) => {}
+            Some(depth) => match depth.as_u64() {
+                Some(depth) if depth > 0 && depth <= u64::from(u32::MAX) => {
+                    source.depth = Some(depth as u32)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "expected a positive number as depth, found `{:?}`",
+                        depth
+                    ))
+                }
+            },
+        }
+        if let (Some(commit), Some(_)) = (&source.commit, source.depth) {
+            // only full hashes can be fetched from the remote
+            if commit.len() != 40 {
+                return Err(anyhow!(
+                    "a shallow clone of commit `{}` requires its full 40 character hash",
+                    commit
                 ));
             }
+        }
 
-            Ok(GitSource::new(url, None::<&str>))
-        } else {
-            Err(anyhow!(
-                "expected a url entry in a table, found `{:?}`",
-                table
-            ))
+        match table.get(&YamlValue::from("submodules")) {
+            None | Some(YamlValue::Null) => {}
+            Some(YamlValue::Bool(submodules)) => source.submodules = *submodules,
+            Some(value) => {
+                return Err(anyhow!(
+                    "expected a boolean as submodules, found `{:?}`",
+                    value
+                ))
+            }
         }
+
+        Ok(source)
     }
 }
 
@@ -71,13 +119,147 @@ impl GitSource {
     {
         Self {
             url: url.into(),
-            branch: branch.map(B::into).unwrap_or_else(|| "master".to_string()),
+            branch: branch.map(B::into),
+            tag: None,
+            commit: None,
+            depth: None,
+            submodules: true,
         }
     }
     pub fn url(&self) -> &str {
         &self.url
     }
     pub fn branch(&self) -> &str {
-        &self.branch
+        self.branch.as_deref().unwrap_or(DEFAULT_BRANCH)
+    }
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+    pub fn commit(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+    pub fn depth(&self) -> Option<u32> {
+        self.depth
+    }
+    pub fn submodules(&self) -> bool {
+        self.submodules
+    }
+
+    /// The tag or the branch that is cloned
+    pub fn reference(&self) -> &str {
+        self.tag().unwrap_or_else(|| self.branch())
+    }
+
+    /// Shell command cloning the repository to `dir` and checking out the configured reference.
+    /// `git` is the invocation of git used by every command, like `git -c key=value`.
+    pub fn clone_command(&self, dir: &Path, git: &str) -> String {
+        let dir = dir.display();
+        let depth = self
+            .depth
+            .map(|depth| format!(" --depth {}", depth))
+            .unwrap_or_default();
+        let submodules = if self.depth.is_some() {
+            format!(" && {} submodule update --init --recursive --depth 1", git)
+        } else {
+            format!(" && {} submodule update --init --recursive", git)
+        };
+        let submodules = if self.submodules {
+            submodules
+        } else {
+            String::new()
+        };
+
+        match (&self.commit, self.depth) {
+            (Some(commit), Some(_)) => format!(
+                "{git} init -q {dir} && cd {dir} && {git} remote add origin {url} && {git} fetch{depth} origin {commit} && {git} checkout -q --detach FETCH_HEAD{submodules}",
+                git = git,
+                dir = dir,
+                url = self.url,
+                depth = depth,
+                commit = commit,
+                submodules = submodules,
+            ),
+            (Some(commit), None) => {
+                // the commit doesn't have to be on the default branch unless one is set
+                let reference = match (&self.tag, &self.branch) {
+                    (Some(reference), _) | (None, Some(reference)) => {
+                        format!(" --single-branch --branch {}", reference)
+                    }
+                    (None, None) => String::new(),
+                };
+                format!(
+                    "{git} clone -j 8{reference} -- {url} {dir} && cd {dir} && {git} checkout -q --detach {commit}{submodules}",
+                    git = git,
+                    reference = reference,
+                    url = self.url,
+                    dir = dir,
+                    commit = commit,
+                    submodules = submodules,
+                )
+            }
+            (None, _) => format!(
+                "{git} clone -j 8 --single-branch --branch {reference}{depth}{submodules} -- {url} {dir}",
+                git = git,
+                reference = self.reference(),
+                depth = depth,
+                submodules = match (self.submodules, self.depth) {
+                    (true, Some(_)) => " --recurse-submodules --shallow-submodules",
+                    (true, None) => " --recurse-submodules",
+                    (false, _) => "",
+                },
+                url = self.url,
+                dir = dir,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<GitSource> {
+        GitSource::try_from(serde_yaml::from_str::<YamlValue>(yaml).unwrap())
+    }
+
+    #[test]
+    fn clones_git_sources() {
+        let dir = Path::new("/tmp/bld");
+        let source = parse("https://example.com/foo.git").unwrap();
+        assert_eq!(
+            source.clone_command(dir, "git"),
+            "git clone -j 8 --single-branch --branch master --recurse-submodules -- https://example.com/foo.git /tmp/bld"
+        );
+
+        let source =
+            parse("{ url: https://example.com/foo.git, tag: v1.0, depth: 1, submodules: false }")
+                .unwrap();
+        assert_eq!(source.reference(), "v1.0");
+        assert_eq!(
+            source.clone_command(dir, "git"),
+            "git clone -j 8 --single-branch --branch v1.0 --depth 1 -- https://example.com/foo.git /tmp/bld"
+        );
+
+        let source = parse("{ url: https://example.com/foo.git, commit: 1A2B3C4 }").unwrap();
+        assert_eq!(
+            source.clone_command(dir, "git"),
+            "git clone -j 8 -- https://example.com/foo.git /tmp/bld && cd /tmp/bld && git checkout -q --detach 1a2b3c4 && git submodule update --init --recursive"
+        );
+
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        let source = parse(&format!(
+            "{{ url: https://example.com/foo.git, commit: {}, depth: 1 }}",
+            commit
+        ))
+        .unwrap();
+        assert_eq!(
+            source.clone_command(dir, "git"),
+            format!("git init -q /tmp/bld && cd /tmp/bld && git remote add origin https://example.com/foo.git && git fetch --depth 1 origin {} && git checkout -q --detach FETCH_HEAD && git submodule update --init --recursive --depth 1", commit)
+        );
+
+        assert!(parse("{ url: https://example.com/foo.git, tag: v1.0, branch: dev }").is_err());
+        assert!(parse("{ url: https://example.com/foo.git, commit: xyz }").is_err());
+        assert!(parse("{ url: https://example.com/foo.git, commit: 1a2b3c4, depth: 1 }").is_err());
+        assert!(parse("{ url: https://example.com/foo.git, depth: 0 }").is_err());
     }
 }
@@ -1,8 +1,8 @@
-use crate::recipe::{BuildTarget, Os};
-use crate::{Error, Result};
+use crate::recipe::{BuildArch, BuildTarget, Distro, Os, RepoSnapshot};
+use crate::{ErrContext, Error, Result};
 
 use serde::{Deserialize, Serialize};
-use serde_yaml::{Mapping, Value as YamlValue};
+use serde_yaml::Value as YamlValue;
 use std::convert::TryFrom;
 
 #[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq, Hash)]
@@ -26,70 +26,317 @@ impl ImageTarget {
     }
 }
 
-impl TryFrom<Mapping> for ImageTarget {
-    type Error = Error;
+/// Infers the build target of `image` when it is not specified explicitly. The target is chosen
+/// based on the operating system if it was provided, otherwise on distribution names that the
+/// image name consists of, like `centos8` or `debian-buster`. Returns an error if the image name
+/// refers to distributions with different package formats. If no distribution is recognized the
+/// default target is used.
+pub fn infer_build_target(image: &str, os: Option<&Os>) -> Result<BuildTarget> {
+    if let Some(os) = os {
+        return Ok(os.distribution().build_target());
+    }
 
-    fn try_from(map: Mapping) -> Result<Self> {
-        if let Some(image) = map.get(&YamlValue::from("name")) {
-            if !image.is_string() {
-                return Err(anyhow!(
-                    "expected a string as image name, found `{:?}`",
-                    image
-                ));
-            }
-            let image = image.as_str().unwrap().to_string();
-
-            let target = if let Some(target) = map.get(&YamlValue::from("target")) {
-                if !target.is_string() {
-                    return Err(anyhow!(
-                        "expected a string as image target, found `{:?}`",
-                        image
-                    ));
-                } else {
-                    BuildTarget::try_from(target.as_str().unwrap())?
-                }
-            } else {
-                BuildTarget::default()
-            };
-
-            let os = if let Some(os) = map.get(&YamlValue::from("os")) {
-                if !os.is_string() {
-                    return Err(anyhow!(
-                        "expected a string as image os, found `{:?}`",
-                        image
-                    ));
-                } else {
-                    Some(Os::new(os.as_str().unwrap(), None::<&str>)?)
-                }
-            } else {
-                None
-            };
-
-            Ok(ImageTarget {
-                image,
-                build_target: target,
-                os,
-            })
-        } else {
-            Err(anyhow!("image name not found in `{:?}`", map))
-        }
+    let mut distros = image
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+        .filter_map(Distro::from_name)
+        .collect::<Vec<_>>();
+    distros.dedup();
+
+    let mut targets = distros.iter().map(Distro::build_target).collect::<Vec<_>>();
+    targets.dedup();
+
+    match targets.as_slice() {
+        [] => Ok(BuildTarget::default()),
+        [target] => Ok(*target),
+        _ => Err(anyhow!(
+            "can't infer the build target of image `{}`, it refers to multiple distributions {:?}, specify the `target` explicitly",
+            image,
+            distros
+                .iter()
+                .map(|distro| distro.as_ref())
+                .collect::<Vec<_>>()
+        )),
     }
 }
 
-impl TryFrom<YamlValue> for ImageTarget {
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged, try_from = "YamlValue")]
+/// An entry of the `images` field of a recipe. Can be either just the name of the image or a map
+/// with additional options.
+///
+/// Examples:
+/// "centos8"
+///
+/// { name = "debian10", target = "deb", arch = "armhf", release_suffix = "~deb10" }
+pub enum ImageTargetRep {
+    Simple(String),
+    Full(ImageTargetOpts),
+}
+
+impl TryFrom<YamlValue> for ImageTargetRep {
     type Error = Error;
+
     fn try_from(value: YamlValue) -> Result<Self> {
         match value {
-            YamlValue::Mapping(map) => Self::try_from(map),
-            YamlValue::String(image) => Ok(Self {
-                image,
-                build_target: BuildTarget::default(),
-                os: None,
-            }),
+            YamlValue::String(image) => Ok(ImageTargetRep::Simple(image)),
+            YamlValue::Mapping(_) => {
+                let name = value
+                    .get("name")
+                    .and_then(YamlValue::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                // the error of serde is kept in the message as only the message of the outermost
+                // error ends up in the error of the recipe
+                serde_yaml::from_value(value)
+                    .map(ImageTargetRep::Full)
+                    .map_err(|e| Error::msg(format!("invalid options of image `{}` - {}", name, e)))
+            }
             value => Err(anyhow!(
-                "expected a map or string for image, found `{:?}`",
+                "expected a name of an image or a mapping with its options, found `{:?}`",
                 value
             )),
         }
     }
 }
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+/// The `target` of an image entry, either a single package format or a list of formats packaged
+/// from the output of one build on the image.
+///
+/// Examples:
+/// "deb"
+///
+/// ["deb", "gzip"]
+pub enum BuildTargetRep {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl BuildTargetRep {
+    fn parse(&self) -> Result<Vec<BuildTarget>> {
+        let targets = match self {
+            BuildTargetRep::Single(target) => vec![BuildTarget::try_from(target.as_str())?],
+            BuildTargetRep::Multiple(targets) => {
+                let mut parsed: Vec<BuildTarget> = vec![];
+                for target in targets {
+                    let target = BuildTarget::try_from(target.as_str())?;
+                    if parsed.contains(&target) {
+                        return Err(anyhow!(
+                            "build target `{}` is listed twice",
+                            target.as_ref()
+                        ));
+                    }
+                    parsed.push(target);
+                }
+                parsed
+            }
+        };
+        if targets.is_empty() {
+            return Err(Error::msg("the list of build targets is empty"));
+        }
+        Ok(targets)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ImageTargetOpts {
+    pub name: String,
+    /// Build target or a list of them, inferred from the operating system if not specified
+    pub target: Option<BuildTargetRep>,
+    /// Operating system of the image, only needed when pkger fails to find it out
+    pub os: Option<String>,
+    /// Architecture of the package built on this image, overrides the recipe `arch`
+    pub arch: Option<String>,
+    /// Appended to the release of the package built on this image, like `.el8`
+    pub release_suffix: Option<String>,
+    /// Whether to install default dependencies on this image, overrides the recipe
+    /// `skip_default_deps`
+    pub skip_default_deps: Option<bool>,
+    /// Condition on the version of the recipe like `version < 2.0`, the image is only built if it
+    /// holds
+    pub when: Option<String>,
+    /// Snapshot of the package repositories from which dependencies are installed on this image
+    pub snapshot: Option<RepoSnapshot>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+/// Options of an image target that change the package or the dependencies installed in the
+/// image but not the image itself
+pub struct ImageOptions {
+    pub arch: Option<BuildArch>,
+    pub release_suffix: Option<String>,
+    pub skip_default_deps: Option<bool>,
+    pub snapshot: Option<RepoSnapshot>,
+}
+
+impl TryFrom<ImageTargetRep> for (ImageTarget, ImageOptions) {
+    type Error = Error;
+
+    fn try_from(rep: ImageTargetRep) -> Result<Self> {
+        let (mut targets, options) = <(Vec<ImageTarget>, ImageOptions)>::try_from(rep)?;
+        if targets.len() > 1 {
+            return Err(anyhow!(
+                "image `{}` lists multiple build targets",
+                targets[0].image
+            ));
+        }
+        Ok((targets.remove(0), options))
+    }
+}
+
+/// Parses an entry of `images` into one target for every package format it lists, in order
+impl TryFrom<ImageTargetRep> for (Vec<ImageTarget>, ImageOptions) {
+    type Error = Error;
+
+    fn try_from(rep: ImageTargetRep) -> Result<Self> {
+        match rep {
+            ImageTargetRep::Simple(image) => Ok((
+                vec![ImageTarget {
+                    build_target: infer_build_target(&image, None)?,
+                    image,
+                    os: None,
+                }],
+                ImageOptions::default(),
+            )),
+            ImageTargetRep::Full(opts) => {
+                let os = if let Some(os) = opts.os {
+                    Some(Os::new(os, None::<&str>)?)
+                } else {
+                    None
+                };
+
+                if let Some(snapshot) = &opts.snapshot {
+                    snapshot.validate()?;
+                }
+
+                let build_targets = if let Some(target) = &opts.target {
+                    target
+                        .parse()
+                        .context(format!("invalid target of image `{}`", opts.name))?
+                } else {
+                    vec![infer_build_target(&opts.name, os.as_ref())?]
+                };
+
+                let name = &opts.name;
+                Ok((
+                    build_targets
+                        .into_iter()
+                        .map(|build_target| ImageTarget {
+                            image: name.clone(),
+                            build_target,
+                            os: os.clone(),
+                        })
+                        .collect(),
+                    ImageOptions {
+                        arch: opts.arch.map(|arch| BuildArch::from(arch.as_str())),
+                        release_suffix: opts.release_suffix,
+                        skip_default_deps: opts.skip_default_deps,
+                        snapshot: opts.snapshot,
+                    },
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn infers_build_target() {
+        let infer = |image| infer_build_target(image, None);
+        assert_eq!(infer("centos8").unwrap(), BuildTarget::Rpm);
+        assert_eq!(infer("debian10").unwrap(), BuildTarget::Deb);
+        assert_eq!(infer("ubuntu-20.04").unwrap(), BuildTarget::Deb);
+        assert_eq!(infer("arch").unwrap(), BuildTarget::Pkg);
+        assert_eq!(infer("alpine3.14").unwrap(), BuildTarget::Apk);
+        assert_eq!(infer("fedora-rhel8").unwrap(), BuildTarget::Rpm);
+        assert_eq!(infer("custom-image").unwrap(), BuildTarget::Gzip);
+        assert_eq!(infer("research").unwrap(), BuildTarget::Gzip);
+        assert!(infer("debian-to-centos").is_err());
+
+        let os = Os::new("ubuntu", None::<&str>).unwrap();
+        assert_eq!(
+            infer_build_target("centos8", Some(&os)).unwrap(),
+            BuildTarget::Deb
+        );
+    }
+
+    #[test]
+    fn parses_image_targets() {
+        let reps: Vec<ImageTargetRep> = serde_yaml::from_str(
+            r#"
+- centos8
+- name: debian10
+  arch: armhf
+  release_suffix: "~deb10"
+  skip_default_deps: true
+- name: custom
+  target: pkg
+"#,
+        )
+        .unwrap();
+        let targets = reps
+            .into_iter()
+            .map(<(ImageTarget, ImageOptions)>::try_from)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(
+            targets[0],
+            (
+                ImageTarget::new("centos8", BuildTarget::Rpm, None::<&str>),
+                ImageOptions::default()
+            )
+        );
+        assert_eq!(
+            targets[1],
+            (
+                ImageTarget::new("debian10", BuildTarget::Deb, None::<&str>),
+                ImageOptions {
+                    arch: Some(BuildArch::Armv7h),
+                    release_suffix: Some("~deb10".to_string()),
+                    skip_default_deps: Some(true),
+                    snapshot: None,
+                }
+            )
+        );
+        assert_eq!(targets[2].0.build_target, BuildTarget::Pkg);
+
+        let err = serde_yaml::from_str::<ImageTargetRep>("{ name: centos8, tagret: rpm }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("invalid options of image `centos8` - unknown field `tagret`"));
+        assert!(serde_yaml::from_str::<ImageTargetRep>("[centos8]").is_err());
+    }
+
+    #[test]
+    fn parses_multiple_build_targets() {
+        let parse = |yaml: &str| {
+            <(Vec<ImageTarget>, ImageOptions)>::try_from(
+                serde_yaml::from_str::<ImageTargetRep>(yaml).unwrap(),
+            )
+        };
+        let (targets, _) = parse("{ name: debian10, target: [deb, gzip] }").unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                ImageTarget::new("debian10", BuildTarget::Deb, None::<&str>),
+                ImageTarget::new("debian10", BuildTarget::Gzip, None::<&str>),
+            ]
+        );
+        let (targets, _) = parse("debian10").unwrap();
+        assert_eq!(targets.len(), 1);
+        assert!(parse("{ name: debian10, target: [deb, deb] }").is_err());
+        assert!(parse("{ name: debian10, target: [] }").is_err());
+        assert!(<(ImageTarget, ImageOptions)>::try_from(
+            serde_yaml::from_str::<ImageTargetRep>("{ name: debian10, target: [deb, gzip] }")
+                .unwrap()
+        )
+        .is_err());
+    }
+}
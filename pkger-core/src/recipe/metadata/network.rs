@@ -0,0 +1,79 @@
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Name resolution inside of the build container, for build systems or license servers that
+/// expect resolvable hostnames
+pub struct NetworkOptions {
+    /// Hostname of the build container
+    pub hostname: Option<String>,
+    #[serde(default)]
+    /// Entries added to `/etc/hosts` in the format `host:ip`, like `license.local:10.0.0.5`
+    pub extra_hosts: Vec<String>,
+    #[serde(default)]
+    /// Addresses of DNS servers used instead of the ones of the Docker host
+    pub dns: Vec<String>,
+}
+
+impl NetworkOptions {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(hostname) = &self.hostname {
+            if hostname.is_empty()
+                || hostname.len() > 253
+                || !hostname
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            {
+                return Err(Error::msg(format!("invalid hostname `{}`", hostname)));
+            }
+        }
+        for entry in &self.extra_hosts {
+            match entry.split_once(':') {
+                Some((host, ip)) if !host.is_empty() && ip.parse::<IpAddr>().is_ok() => {}
+                _ => {
+                    return Err(Error::msg(format!(
+                        "invalid hosts entry `{}`, expected `host:ip`",
+                        entry
+                    )))
+                }
+            }
+        }
+        for server in &self.dns {
+            if server.parse::<IpAddr>().is_err() {
+                return Err(Error::msg(format!(
+                    "invalid address of DNS server `{}`",
+                    server
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_network_options() {
+        let mut opts = NetworkOptions {
+            hostname: Some("build-01.example.com".to_string()),
+            extra_hosts: vec![
+                "license.local:10.0.0.5".to_string(),
+                "mirror:fd00::1".to_string(),
+            ],
+            dns: vec!["1.1.1.1".to_string()],
+        };
+        assert!(opts.validate().is_ok());
+
+        opts.extra_hosts.push("license.local".to_string());
+        assert!(opts.validate().is_err());
+        opts.extra_hosts.pop();
+
+        opts.hostname = Some("build_01".to_string());
+        assert!(opts.validate().is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::recipe::metadata::deps::split_version_constraint;
+use crate::recipe::BuildTarget;
 use crate::{Error, Result};
 
 use serde::{Deserialize, Serialize};
@@ -36,8 +38,13 @@ impl Os {
         self.distribution.as_ref()
     }
 
+    pub fn distribution(&self) -> Distro {
+        self.distribution
+    }
+
     pub fn package_manager(&self) -> PackageManager {
         match self.distribution {
+            Distro::Alpine => PackageManager::Apk,
             Distro::Arch => PackageManager::Pacman,
             Distro::Debian | Distro::Ubuntu => PackageManager::Apt,
             Distro::RedHat | Distro::CentOS | Distro::Fedora
@@ -46,6 +53,7 @@ impl Os {
                 PackageManager::Dnf
             }
             Distro::RedHat | Distro::CentOS | Distro::Fedora => PackageManager::Yum,
+            Distro::Busybox => PackageManager::Unavailable,
         }
     }
 }
@@ -55,7 +63,10 @@ impl Os {
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Debug, Deserialize, Clone, Serialize, PartialEq, Eq, Hash)]
 pub enum Distro {
+    Alpine,
     Arch,
+    /// Fixture image of the sandbox mode without a package manager
+    Busybox,
     CentOS,
     Debian,
     Fedora,
@@ -67,7 +78,9 @@ impl AsRef<str> for Distro {
     fn as_ref(&self) -> &str {
         use Distro::*;
         match self {
+            Alpine => "alpine",
             Arch => "arch",
+            Busybox => "busybox",
             CentOS => "centos",
             Debian => "debian",
             Fedora => "fedora",
@@ -77,12 +90,48 @@ impl AsRef<str> for Distro {
     }
 }
 
+impl Distro {
+    /// Returns the native package format of this distribution
+    pub fn build_target(&self) -> BuildTarget {
+        use Distro::*;
+        match self {
+            Arch => BuildTarget::Pkg,
+            CentOS | Fedora | RedHat => BuildTarget::Rpm,
+            Debian | Ubuntu => BuildTarget::Deb,
+            Alpine => BuildTarget::Apk,
+            Busybox => BuildTarget::Gzip,
+        }
+    }
+
+    /// Finds a distribution whose name exactly matches `name` ignoring the case and a trailing
+    /// version, like `centos8` or `debian10`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        use Distro::*;
+        let name = name
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .to_lowercase();
+        match name.as_str() {
+            "alpine" => Some(Alpine),
+            "arch" | "archlinux" => Some(Arch),
+            "busybox" => Some(Busybox),
+            "centos" => Some(CentOS),
+            "debian" => Some(Debian),
+            "fedora" => Some(Fedora),
+            "redhat" | "rhel" => Some(RedHat),
+            "ubuntu" => Some(Ubuntu),
+            _ => None,
+        }
+    }
+}
+
 impl TryFrom<&str> for Distro {
     type Error = Error;
     fn try_from(s: &str) -> Result<Self> {
         use Distro::*;
-        const DISTROS: [(&str, Distro); 7] = [
+        const DISTROS: [(&str, Distro); 9] = [
+            ("alpine", Alpine),
             ("arch", Arch),
+            ("busybox", Busybox),
             ("centos", CentOS),
             ("debian", Debian),
             ("fedora", Fedora),
@@ -105,19 +154,24 @@ impl TryFrom<&str> for Distro {
 
 #[derive(Debug, Clone)]
 pub enum PackageManager {
+    Apk,
     Apt,
     Dnf,
     Pacman,
     Yum,
+    /// No package manager is available, dependencies can't be installed
+    Unavailable,
 }
 
 impl AsRef<str> for PackageManager {
     fn as_ref(&self) -> &str {
         match self {
+            Self::Apk => "apk",
             Self::Apt => "apt-get",
             Self::Dnf => "dnf",
             Self::Pacman => "pacman",
             Self::Yum => "yum",
+            Self::Unavailable => "",
         }
     }
 }
@@ -126,26 +180,134 @@ impl AsRef<str> for PackageManager {
 impl PackageManager {
     pub fn install_args(&self) -> Vec<&'static str> {
         match self {
+            Self::Apk => vec!["add"],
             Self::Apt => vec!["install", "-y"],
             Self::Dnf => vec!["install", "-y"],
             Self::Pacman => vec!["-S", "--noconfirm"],
             Self::Yum => vec!["install", "-y"],
+            Self::Unavailable => vec![],
+        }
+    }
+
+    /// Returns `dep`, a dependency like `libfoo (>= 1.0)`, as an argument of an install with
+    /// this package manager quoted for the shell. Architecture qualifiers are only kept for apt
+    /// which also can't install a minimal version, so only `=` constraints are kept there.
+    pub fn install_spec(&self, dep: &str) -> String {
+        let (name, constraint) = split_version_constraint(dep);
+        let (bare_name, qualifier) = match name.split_once(':') {
+            Some((bare_name, qualifier)) => (bare_name, Some(qualifier)),
+            None => (name, None),
+        };
+        match (self, constraint) {
+            (Self::Apt, constraint) => {
+                let name = match qualifier {
+                    Some("any") | Some("native") | None => bare_name,
+                    Some(_) => name,
+                };
+                match constraint {
+                    Some(("=", version)) => format!("{}={}", name, version),
+                    _ => name.to_string(),
+                }
+            }
+            (Self::Apk, Some((op, version))) | (Self::Pacman, Some((op, version))) => {
+                format!("'{}{}{}'", bare_name, op, version)
+            }
+            (Self::Dnf, Some((op, version))) | (Self::Yum, Some((op, version))) => {
+                format!("'{} {} {}'", bare_name, op, version)
+            }
+            _ => bare_name.to_string(),
+        }
+    }
+
+    /// Arguments of a query that succeeds only if the appended dependency can be installed from
+    /// the local cache of the repositories, with virtual provides resolved by the package manager
+    pub fn resolve_args(&self) -> Vec<&'static str> {
+        match self {
+            Self::Apk => vec!["add", "--simulate", "--quiet"],
+            Self::Apt => vec!["install", "-s", "-qq"],
+            Self::Dnf | Self::Yum => vec!["-C", "-q", "provides"],
+            Self::Pacman => vec!["-Sp", "--print-format", "%n"],
+            Self::Unavailable => vec![],
+        }
+    }
+
+    /// Arguments of installs from the repositories retrying downloads from other mirrors and
+    /// failing when any of the packages doesn't exist
+    pub fn install_resilience_args(&self) -> Vec<&'static str> {
+        match self {
+            Self::Apt => vec!["-o", "Acquire::Retries=3"],
+            Self::Dnf => vec![
+                "--setopt=retries=5",
+                "--setopt=max_parallel_downloads=10",
+                "--setopt=strict=True",
+            ],
+            Self::Yum => vec![
+                "--setopt=retries=5",
+                "--setopt=skip_missing_names_on_install=False",
+            ],
+            Self::Apk | Self::Pacman | Self::Unavailable => vec![],
+        }
+    }
+
+    /// Returns sed expressions extracting the names of packages missing from the repositories
+    /// out of the output of a failed install
+    pub fn missing_package_patterns(&self) -> Vec<&'static str> {
+        match self {
+            Self::Apk => vec![r"^ *\([^ ]*\) (no such package).*$"],
+            Self::Apt => vec![
+                r"^E: Unable to locate package \(.*\)$",
+                r"^E: Package .\(.*\). has no installation candidate$",
+            ],
+            Self::Dnf => vec![r"^No match for argument: \(.*\)$"],
+            Self::Pacman => vec![r"^error: target not found: \(.*\)$"],
+            Self::Yum => vec![r"^No package \(.*\) available\.$"],
+            Self::Unavailable => vec![],
+        }
+    }
+
+    /// Arguments installing package files instead of packages from the repositories
+    pub fn install_local_args(&self) -> Vec<&'static str> {
+        match self {
+            Self::Apk => vec!["add", "--allow-untrusted"],
+            Self::Apt => vec!["install", "-y"],
+            Self::Dnf => vec!["install", "-y"],
+            Self::Pacman => vec!["-U", "--noconfirm"],
+            Self::Yum => vec!["localinstall", "-y"],
+            Self::Unavailable => vec![],
+        }
+    }
+
+    /// Returns a configuration file of this package manager and a line that makes it assume yes
+    /// to all prompts, if the package manager supports it
+    pub fn assume_yes_config(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::Apt => Some((
+                "/etc/apt/apt.conf.d/90pkger-assume-yes",
+                r#"APT::Get::Assume-Yes "true";"#,
+            )),
+            Self::Dnf => Some(("/etc/dnf/dnf.conf", "assumeyes=1")),
+            Self::Yum => Some(("/etc/yum.conf", "assumeyes=1")),
+            Self::Apk | Self::Pacman | Self::Unavailable => None,
         }
     }
 
     pub fn update_repos_args(&self) -> Vec<&'static str> {
         match self {
+            Self::Apk => vec!["update"],
             Self::Apt => vec!["update", "-y"],
             Self::Dnf | Self::Yum => vec!["clean", "metadata"],
             Self::Pacman => vec!["-Sy", "--noconfirm"],
+            Self::Unavailable => vec![],
         }
     }
 
     pub fn upgrade_packages_args(&self) -> Vec<&'static str> {
         match self {
+            Self::Apk => vec!["upgrade"],
             Self::Apt => vec!["dist-upgrade", "-y"],
             Self::Dnf | Self::Yum => vec!["update", "-y"],
             Self::Pacman => vec!["-Syu", "--noconfirm"],
+            Self::Unavailable => vec![],
         }
     }
 }
@@ -4,13 +4,44 @@ use crate::Result;
 use serde_yaml::{Mapping, Sequence, Value as YamlValue};
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::path::Path;
+use std::str::FromStr;
 
 pub static COMMON_PATCHES_KEY: &str = "all";
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+/// Tool applying a patch
+pub enum PatchMethod {
+    /// `patch -p<strip>`
+    #[default]
+    Patch,
+    /// `git apply -p<strip>`, supports binary diffs and renames of git patches
+    Git,
+}
+
+impl FromStr for PatchMethod {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "patch" => Ok(PatchMethod::Patch),
+            "git" => Ok(PatchMethod::Git),
+            _ => Err(anyhow!(
+                "unknown patch method `{}`, expected `patch` or `git`",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Patch {
     patch: String,
     strip: u8,
+    method: PatchMethod,
+    /// Patches are applied in ascending order, patches with the same order in the order they are
+    /// listed with patches for all images first
+    order: i32,
 }
 
 impl Patch {
@@ -18,9 +49,21 @@ impl Patch {
         Self {
             patch: patch.into(),
             strip,
+            method: PatchMethod::default(),
+            order: 0,
         }
     }
 
+    pub fn with_method(mut self, method: PatchMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_order(mut self, order: i32) -> Self {
+        self.order = order;
+        self
+    }
+
     pub fn patch(&self) -> &str {
         &self.patch
     }
@@ -28,6 +71,22 @@ impl Patch {
     pub fn strip_level(&self) -> u8 {
         self.strip
     }
+
+    pub fn method(&self) -> PatchMethod {
+        self.method
+    }
+
+    pub fn order(&self) -> i32 {
+        self.order
+    }
+
+    /// Command applying the patch saved at `location`
+    pub fn apply_command(&self, location: &Path) -> String {
+        match self.method {
+            PatchMethod::Patch => format!("patch -p{} < {}", self.strip, location.display()),
+            PatchMethod::Git => format!("git apply -p{} {}", self.strip, location.display()),
+        }
+    }
 }
 
 impl TryFrom<YamlValue> for Patch {
@@ -113,13 +172,38 @@ impl TryFrom<&Mapping> for Patch {
             ));
         }
 
-        match u8::try_from(level.as_u64().unwrap()) {
-            Ok(level) => Ok(Patch::new(name, level)),
-            Err(_) => Err(anyhow!(
-                "expected a number in range of 0-255, found `{:?}`",
-                level
-            )),
-        }
+        let level = match level.as_u64().map(u8::try_from) {
+            Some(Ok(level)) => level,
+            _ => {
+                return Err(anyhow!(
+                    "expected a number in range of 0-255, found `{:?}`",
+                    level
+                ))
+            }
+        };
+
+        let method = match mapping.get(&YamlValue::from("method")) {
+            None => PatchMethod::default(),
+            Some(YamlValue::String(method)) => method.parse()?,
+            Some(method) => {
+                return Err(anyhow!(
+                    "expected a string as patch method, found `{:?}`",
+                    method
+                ))
+            }
+        };
+
+        let order = match mapping.get(&YamlValue::from("order")) {
+            None => 0,
+            Some(order) => order
+                .as_i64()
+                .and_then(|order| i32::try_from(order).ok())
+                .ok_or_else(|| anyhow!("expected a number as patch order, found `{:?}`", order))?,
+        };
+
+        Ok(Patch::new(name, level)
+            .with_method(method)
+            .with_order(order))
     }
 }
 
@@ -183,6 +267,7 @@ impl TryFrom<YamlValue> for Patches {
 }
 
 impl Patches {
+    /// Returns the patches of `image` in the order they are applied
     pub fn resolve_names(&self, image: &str) -> Vec<&Patch> {
         // it's ok to unwrap here, the new function adds an empty vec on initialization
         let mut patches = Vec::new();
@@ -198,10 +283,20 @@ impl Patches {
                 });
             }
         }
+        // stable so that patches with the same order keep the order they are listed in
+        patches.sort_by_key(|p| p.order);
 
         patches
     }
 
+    /// Whether any patch of any image is applied with `method`
+    pub fn uses_method(&self, method: PatchMethod) -> bool {
+        self.inner
+            .values()
+            .flatten()
+            .any(|patch| patch.method == method)
+    }
+
     pub fn inner(&self) -> &PatchesMap {
         &self.inner
     }
@@ -268,4 +363,44 @@ patches:
             debian10 => "test.patch" 0, "1.patch" 0, "http://remote.com/file.patch" 0, "only-deb.patch" 2
         );
     }
+
+    #[test]
+    fn orders_patches() {
+        let patches: YamlValue = serde_yaml::from_str(
+            r#"
+all:
+  - fix-build.patch
+  - patch: last.patch
+    order: 10
+centos8:
+  - patch: first.patch
+    order: -1
+    strip: 1
+    method: git
+"#,
+        )
+        .unwrap();
+        let patches = Patches::try_from(patches).unwrap();
+        let names = patches
+            .resolve_names("centos8")
+            .into_iter()
+            .map(Patch::patch)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["first.patch", "fix-build.patch", "last.patch"]);
+        let first = patches.resolve_names("centos8")[0];
+        assert_eq!(first.method(), PatchMethod::Git);
+        assert_eq!(
+            first.apply_command(Path::new("/tmp/patches/first.patch")),
+            "git apply -p1 /tmp/patches/first.patch"
+        );
+        assert!(patches.uses_method(PatchMethod::Git));
+        assert!(
+            !Patches::try_from(serde_yaml::from_str::<YamlValue>("[a.patch]").unwrap())
+                .unwrap()
+                .uses_method(PatchMethod::Git)
+        );
+
+        let invalid: YamlValue = serde_yaml::from_str("[{patch: a.patch, method: quilt}]").unwrap();
+        assert!(Patches::try_from(invalid).is_err());
+    }
 }
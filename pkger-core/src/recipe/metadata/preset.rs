@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// Predefined build setups
+pub enum Preset {
+    /// Statically linked binaries built once on a musl based image and packaged on every image
+    /// target. Binaries that are not statically linked fail the build.
+    Static,
+}
+
+impl Preset {
+    /// Environment variables set in the build container unless the recipe sets them
+    pub fn env(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Preset::Static => &[
+                ("LDFLAGS", "-static"),
+                ("CGO_ENABLED", "0"),
+                ("RUSTFLAGS", "-C target-feature=+crt-static"),
+                ("PKG_CONFIG_ALL_STATIC", "1"),
+            ],
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::recipe::PackageManager;
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Snapshot of the package repositories from which the dependencies of an image are installed,
+/// so that the same dependency set can be installed again later
+pub struct RepoSnapshot {
+    /// Time of the snapshot like `20211015T000000Z`. On APT images the Debian mirrors are
+    /// replaced with snapshot.debian.org, on pacman images the mirrors are replaced with the Arch
+    /// Linux Archive of that day.
+    pub timestamp: Option<String>,
+    /// Base URLs of repository snapshots by repository id. On dnf and yum images dependencies
+    /// are installed only from these repositories.
+    #[serde(default)]
+    pub repos: BTreeMap<String, String>,
+}
+
+impl RepoSnapshot {
+    pub fn validate(&self) -> Result<()> {
+        if self.timestamp.is_none() && self.repos.is_empty() {
+            return Err(Error::msg(
+                "`snapshot` requires a `timestamp` or snapshot `repos`",
+            ));
+        }
+        if let Some(timestamp) = &self.timestamp {
+            let valid = timestamp.len() == 16
+                && timestamp.char_indices().all(|(i, c)| match i {
+                    8 => c == 'T',
+                    15 => c == 'Z',
+                    _ => c.is_ascii_digit(),
+                });
+            if !valid {
+                return Err(Error::msg(format!(
+                    "invalid snapshot timestamp `{}`, expected `YYYYMMDDThhmmssZ`",
+                    timestamp
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Identifies the snapshot, recorded in the image state and in manifests of artifacts
+    pub fn id(&self) -> String {
+        let mut parts = vec![];
+        if let Some(timestamp) = &self.timestamp {
+            parts.push(timestamp.clone());
+        }
+        parts.extend(self.repos.iter().map(|(id, url)| format!("{}={}", id, url)));
+        parts.join(" ")
+    }
+
+    /// Returns a command pointing the package manager at the snapshot, if the package manager
+    /// is configured through files
+    pub fn setup_command(&self, pkg_mngr: &PackageManager) -> Result<Option<String>> {
+        match (pkg_mngr, &self.timestamp) {
+            (PackageManager::Apt, Some(timestamp)) => Ok(Some(format!(
+                r#"for f in /etc/apt/sources.list /etc/apt/sources.list.d/*; do [ -f "$f" ] && sed -i -E 's#https?://(deb|security)\.debian\.org/debian-security#http://snapshot.debian.org/archive/debian-security/{0}#g; s#https?://deb\.debian\.org/debian#http://snapshot.debian.org/archive/debian/{0}#g' "$f"; done; echo 'Acquire::Check-Valid-Until "false";' > /etc/apt/apt.conf.d/90pkger-snapshot"#,
+                timestamp
+            ))),
+            (PackageManager::Pacman, Some(timestamp)) => Ok(Some(format!(
+                "echo 'Server = https://archive.archlinux.org/repos/{}/{}/{}/$repo/os/$arch' > /etc/pacman.d/mirrorlist",
+                &timestamp[0..4],
+                &timestamp[4..6],
+                &timestamp[6..8]
+            ))),
+            (PackageManager::Dnf, _) | (PackageManager::Yum, _) if !self.repos.is_empty() => {
+                Ok(None)
+            }
+            (pkg_mngr, _) => Err(Error::msg(format!(
+                "repository snapshots are not supported with `{}`{}",
+                pkg_mngr.as_ref(),
+                match pkg_mngr {
+                    PackageManager::Dnf | PackageManager::Yum => ", they require snapshot `repos`",
+                    PackageManager::Apt | PackageManager::Pacman => ", they require a `timestamp`",
+                    _ => "",
+                }
+            ))),
+        }
+    }
+
+    /// Arguments of the package manager selecting the snapshot repositories
+    pub fn package_manager_args(&self, pkg_mngr: &PackageManager) -> Vec<String> {
+        match pkg_mngr {
+            PackageManager::Dnf | PackageManager::Yum if !self.repos.is_empty() => {
+                let mut args = vec!["--disablerepo='*'".to_string()];
+                for (id, url) in &self.repos {
+                    args.push(format!("--repofrompath={},{}", id, url));
+                    args.push(format!("--enablerepo={}", id));
+                }
+                args
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configures_repo_snapshots() {
+        let snapshot: RepoSnapshot = serde_yaml::from_str(
+            "timestamp: 20211015T000000Z\nrepos:\n  baseos: https://mirror.example.org/8.4/BaseOS/x86_64/os/",
+        )
+        .unwrap();
+        assert!(snapshot.validate().is_ok());
+        assert_eq!(
+            snapshot.id(),
+            "20211015T000000Z baseos=https://mirror.example.org/8.4/BaseOS/x86_64/os/"
+        );
+        assert!(snapshot
+            .setup_command(&PackageManager::Apt)
+            .unwrap()
+            .unwrap()
+            .contains("http://snapshot.debian.org/archive/debian/20211015T000000Z"));
+        assert!(snapshot
+            .setup_command(&PackageManager::Pacman)
+            .unwrap()
+            .unwrap()
+            .contains("https://archive.archlinux.org/repos/2021/10/15/"));
+        assert_eq!(snapshot.setup_command(&PackageManager::Dnf).unwrap(), None);
+        assert_eq!(
+            snapshot.package_manager_args(&PackageManager::Dnf),
+            vec![
+                "--disablerepo='*'",
+                "--repofrompath=baseos,https://mirror.example.org/8.4/BaseOS/x86_64/os/",
+                "--enablerepo=baseos"
+            ]
+        );
+        assert!(snapshot.setup_command(&PackageManager::Apk).is_err());
+
+        let invalid: RepoSnapshot = serde_yaml::from_str("timestamp: 2021-10-15").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Checks of RPATHs of ELF binaries in the build output. Entries relative to `$ORIGIN` are always
+/// allowed, any other entry fails the build unless it is allowed explicitly or `rewrite` is set.
+pub struct RpathOptions {
+    #[serde(default)]
+    /// Absolute entries that are allowed to stay in the RPATH, like `/opt/foo/lib`
+    pub allowed: Vec<String>,
+    /// Replaces insecure entries with this value using `patchelf`, like `$ORIGIN/../lib`. An empty
+    /// value removes insecure entries.
+    pub rewrite: Option<String>,
+}
+
+impl RpathOptions {
+    fn is_allowed(&self, entry: &str) -> bool {
+        entry.starts_with("$ORIGIN")
+            || entry.starts_with("${ORIGIN}")
+            || self
+                .allowed
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/') == entry.trim_end_matches('/'))
+    }
+
+    /// Returns entries of `rpath` that point to absolute paths that aren't allowed, like the build
+    /// directory, or are relative to the working directory
+    pub fn insecure_entries<'a>(&self, rpath: &'a str) -> Vec<&'a str> {
+        rpath
+            .split(':')
+            .filter(|entry| !self.is_allowed(entry))
+            .collect()
+    }
+
+    /// Returns `rpath` with insecure entries replaced by the `rewrite` value or `None` if no
+    /// rewrite is configured
+    pub fn rewritten(&self, rpath: &str) -> Option<String> {
+        let rewrite = self.rewrite.as_deref()?;
+        let mut entries = vec![];
+        for entry in rpath.split(':') {
+            let entry = if self.is_allowed(entry) {
+                entry
+            } else {
+                rewrite
+            };
+            if !entry.is_empty() && !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+        Some(entries.join(":"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_and_rewrites_insecure_entries() {
+        let mut opts = RpathOptions {
+            allowed: vec!["/opt/foo/lib/".to_string()],
+            rewrite: None,
+        };
+        let rpath = "$ORIGIN/../lib:/tmp/build/lib:/opt/foo/lib::lib";
+
+        assert_eq!(
+            opts.insecure_entries(rpath),
+            vec!["/tmp/build/lib", "", "lib"]
+        );
+        assert_eq!(opts.rewritten(rpath), None);
+
+        opts.rewrite = Some("$ORIGIN/../lib".to_string());
+        assert_eq!(
+            opts.rewritten(rpath),
+            Some("$ORIGIN/../lib:/opt/foo/lib".to_string())
+        );
+
+        opts.rewrite = Some(String::new());
+        assert_eq!(opts.rewritten("/tmp/build/lib"), Some(String::new()));
+    }
+}
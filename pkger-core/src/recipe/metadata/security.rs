@@ -0,0 +1,134 @@
+use crate::{ErrContext, Result};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+static UNCONFINED: &str = "unconfined";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Restrictions applied to the container in which the scripts of a recipe are executed. They are
+/// not applied to the container installing dependencies of the image.
+pub struct SecurityOptions {
+    /// Path to a seccomp profile in JSON format or `unconfined`
+    pub seccomp: Option<String>,
+    /// Name of an AppArmor profile loaded on the Docker host or `unconfined`
+    pub apparmor: Option<String>,
+    #[serde(default)]
+    /// Capabilities dropped from the container, `ALL` drops every capability
+    pub cap_drop: Vec<String>,
+    #[serde(default)]
+    /// Prevents processes from gaining new privileges, for example with `sudo` or setuid binaries
+    pub no_new_privileges: bool,
+    #[serde(default)]
+    /// Mounts the root filesystem of the container as read only. Build directories of pkger are
+    /// kept writable with volumes removed together with the container.
+    pub read_only: bool,
+    #[serde(default)]
+    /// Writable scratch directories mounted as tmpfs, like `/var/tmp` or `/root:size=64m`
+    pub tmpfs: Vec<String>,
+}
+
+impl SecurityOptions {
+    /// Combines these options with `other` so that `other` can only add restrictions. Profiles set
+    /// in these options take precedence over the ones in `other`.
+    pub fn merge(&self, other: &SecurityOptions) -> SecurityOptions {
+        let mut cap_drop = self.cap_drop.clone();
+        cap_drop.extend(
+            other
+                .cap_drop
+                .iter()
+                .filter(|cap| !self.cap_drop.contains(cap))
+                .cloned(),
+        );
+        let mut tmpfs = self.tmpfs.clone();
+        tmpfs.extend(
+            other
+                .tmpfs
+                .iter()
+                .filter(|mount| !self.tmpfs.contains(mount))
+                .cloned(),
+        );
+
+        SecurityOptions {
+            seccomp: self.seccomp.clone().or_else(|| other.seccomp.clone()),
+            apparmor: self.apparmor.clone().or_else(|| other.apparmor.clone()),
+            cap_drop,
+            no_new_privileges: self.no_new_privileges || other.no_new_privileges,
+            read_only: self.read_only || other.read_only,
+            tmpfs,
+        }
+    }
+
+    /// Returns these options with a relative seccomp profile path joined to `dir`
+    pub fn relative_to(mut self, dir: &Path) -> SecurityOptions {
+        if let Some(seccomp) = &self.seccomp {
+            if seccomp != UNCONFINED && Path::new(seccomp).is_relative() {
+                self.seccomp = Some(dir.join(seccomp).to_string_lossy().to_string());
+            }
+        }
+        self
+    }
+
+    /// Returns the values of `SecurityOpt` of the container. Docker expects the content of
+    /// a seccomp profile rather than its path so the profile is read here.
+    pub fn security_opts(&self) -> Result<Vec<String>> {
+        let mut opts = vec![];
+        if let Some(seccomp) = &self.seccomp {
+            let profile = if seccomp == UNCONFINED {
+                seccomp.clone()
+            } else {
+                fs::read_to_string(seccomp)
+                    .context(format!("failed to read seccomp profile `{}`", seccomp))?
+            };
+            opts.push(format!("seccomp={}", profile));
+        }
+        if let Some(apparmor) = &self.apparmor {
+            opts.push(format!("apparmor={}", apparmor));
+        }
+        if self.no_new_privileges {
+            opts.push("no-new-privileges".to_string());
+        }
+        Ok(opts)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &SecurityOptions::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_restrictively() {
+        let global = SecurityOptions {
+            apparmor: Some("pkger".to_string()),
+            cap_drop: vec!["NET_RAW".to_string()],
+            no_new_privileges: true,
+            ..Default::default()
+        };
+        let recipe: SecurityOptions = serde_yaml::from_str(
+            r#"
+apparmor: unconfined
+cap_drop: [NET_RAW, SYS_ADMIN]
+read_only: true
+tmpfs: [/var/tmp]
+"#,
+        )
+        .unwrap();
+
+        let merged = global.merge(&recipe);
+        assert_eq!(merged.apparmor.as_deref(), Some("pkger"));
+        assert_eq!(merged.cap_drop, vec!["NET_RAW", "SYS_ADMIN"]);
+        assert!(merged.no_new_privileges);
+        assert!(merged.read_only);
+        assert_eq!(merged.tmpfs, vec!["/var/tmp"]);
+        assert_eq!(
+            merged.security_opts().unwrap(),
+            vec!["apparmor=pkger", "no-new-privileges"]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+static DEFAULT_KEYSERVER: &str = "hkps://keys.openpgp.org";
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Upstream GPG signature of the source. Archives are verified with a detached signature, git
+/// sources with the signature of the tag they are cloned from.
+pub struct SourceSignature {
+    /// URL or path of the detached signature of the source archive, like `foo-1.0.tar.gz.asc`
+    pub url: Option<String>,
+    /// Fingerprints of the keys trusted to sign the source
+    pub keys: Vec<String>,
+    /// Keyserver from which the trusted keys are fetched, defaults to `hkps://keys.openpgp.org`
+    pub keyserver: Option<String>,
+}
+
+impl SourceSignature {
+    pub fn keyserver(&self) -> &str {
+        self.keyserver.as_deref().unwrap_or(DEFAULT_KEYSERVER)
+    }
+
+    /// Returns the trusted fingerprints in uppercase without whitespace
+    pub fn fingerprints(&self) -> Vec<String> {
+        self.keys
+            .iter()
+            .map(|key| {
+                key.chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect::<String>()
+                    .trim_start_matches("0x")
+                    .to_uppercase()
+            })
+            .collect()
+    }
+
+    /// Returns the fingerprint of the trusted key that made a valid signature according to
+    /// `status`, the output of `gpg --status-fd`. Both the fingerprint of the signing key and of
+    /// its primary key are accepted.
+    pub fn trusted_signer(&self, status: &str) -> Option<String> {
+        let trusted = self.fingerprints();
+        status
+            .lines()
+            .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+            .flat_map(|fields| {
+                let fields = fields.split_whitespace().collect::<Vec<_>>();
+                let primary = fields.get(9).copied();
+                fields.into_iter().take(1).chain(primary)
+            })
+            .find(|fingerprint| trusted.iter().any(|trusted| trusted == fingerprint))
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_trusted_signer() {
+        let signature = SourceSignature {
+            url: Some("https://example.com/foo-1.0.tar.gz.asc".to_string()),
+            keys: vec!["0x7F7B 1F0D 3C5E 6A29 B2F1  8D3A 4C1E 9B70 5A2D 31C8".to_string()],
+            keyserver: None,
+        };
+        let primary = "[GNUPG:] NEWSIG\n[GNUPG:] VALIDSIG 1111111111111111111111111111111111111111 2021-06-01 1622505600 0 4 0 1 10 00 7F7B1F0D3C5E6A29B2F18D3A4C1E9B705A2D31C8\n";
+        assert_eq!(
+            signature.trusted_signer(primary),
+            Some("7F7B1F0D3C5E6A29B2F18D3A4C1E9B705A2D31C8".to_string())
+        );
+        let untrusted = "[GNUPG:] VALIDSIG 1111111111111111111111111111111111111111 2021-06-01 1622505600 0 4 0 1 10 00 1111111111111111111111111111111111111111\n";
+        assert_eq!(signature.trusted_signer(untrusted), None);
+        assert_eq!(
+            signature.trusted_signer("[GNUPG:] BADSIG 4C1E9B705A2D31C8 foo\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+/// The `source` field of a recipe, either a single source or a list of them. The first source is
+/// the main one, `mirrors`, `checksum` and `signature` only apply to it.
+///
+/// Examples:
+/// https://example.com/foo-1.0.tar.gz
+///
+/// [https://example.com/foo-1.0.tar.gz, https://example.com/foo-data-1.0.tar.gz, extra.conf]
+pub enum SourceRep {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<String> for SourceRep {
+    fn from(source: String) -> Self {
+        SourceRep::Single(source)
+    }
+}
+
+impl SourceRep {
+    /// Returns `None` for an empty list of sources
+    pub fn from_list(mut sources: Vec<String>) -> Option<Self> {
+        match sources.len() {
+            0 => None,
+            1 => sources.pop().map(SourceRep::Single),
+            _ => Some(SourceRep::Multiple(sources)),
+        }
+    }
+
+    /// Splits the sources into the main source and the additional ones
+    pub fn split(self) -> (Option<String>, Vec<String>) {
+        match self {
+            SourceRep::Single(source) => (Some(source), vec![]),
+            SourceRep::Multiple(mut sources) => {
+                if sources.is_empty() {
+                    (None, sources)
+                } else {
+                    let main = sources.remove(0);
+                    (Some(main), sources)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources() {
+        let parse = |yaml: &str| serde_yaml::from_str::<SourceRep>(yaml).unwrap().split();
+        assert_eq!(
+            parse("https://example.com/foo.tar.gz"),
+            (Some("https://example.com/foo.tar.gz".to_string()), vec![])
+        );
+        assert_eq!(
+            parse("[foo.tar.gz, extra.conf, data.zip]"),
+            (
+                Some("foo.tar.gz".to_string()),
+                vec!["extra.conf".to_string(), "data.zip".to_string()]
+            )
+        );
+        assert_eq!(parse("[]"), (None, vec![]));
+        assert_eq!(SourceRep::from_list(vec![]), None);
+        assert_eq!(
+            SourceRep::from_list(vec!["foo.tar.gz".to_string()]),
+            Some(SourceRep::Single("foo.tar.gz".to_string()))
+        );
+    }
+}
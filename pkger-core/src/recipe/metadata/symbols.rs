@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+/// Tracking of symbols exported by shared libraries in the build output
+pub struct SymbolsOptions {
+    /// Path to the file with the symbols of the previous release relative to the recipe directory.
+    /// The file is created from the build output if it doesn't exist.
+    pub baseline: String,
+    #[serde(default)]
+    /// Symbols whose removal was announced and doesn't fail the build
+    pub allow_removed: Vec<String>,
+}
@@ -9,6 +9,7 @@ pub enum BuildTarget {
     Deb,
     Gzip,
     Pkg,
+    Apk,
 }
 
 impl Default for BuildTarget {
@@ -26,6 +27,7 @@ impl TryFrom<&str> for BuildTarget {
             "deb" => Ok(Self::Deb),
             "gzip" => Ok(Self::Gzip),
             "pkg" => Ok(Self::Pkg),
+            "apk" => Ok(Self::Apk),
             target => Err(anyhow!("unknown build target `{}`", target)),
         }
     }
@@ -38,6 +40,7 @@ impl AsRef<str> for BuildTarget {
             BuildTarget::Deb => "deb",
             BuildTarget::Gzip => "gzip",
             BuildTarget::Pkg => "pkg",
+            BuildTarget::Apk => "apk",
         }
     }
 }
@@ -1,28 +1,47 @@
 mod cmd;
+mod docs;
 mod envs;
+mod export;
+mod inherit;
 mod metadata;
+mod template;
 
 pub use cmd::Command;
 pub use envs::Env;
+pub use export::DebianDir;
 pub use metadata::{
-    BuildTarget, DebInfo, DebRep, Dependencies, Distro, GitSource, ImageTarget, Metadata,
-    MetadataRep, Os, PackageManager, Patch, Patches, PkgInfo, PkgRep, RpmInfo, RpmRep,
+    compare_versions, feature_env_var, load_changelog, parse_size, requested_features,
+    strip_arch_qualifier, ApkInfo, ApkRep, BuildArch, BuildTarget, BuildTargetRep, Changelog,
+    ChangelogEntry, Checksum, DebInfo, DebRep, Dependencies, DependencyNames, Deprecation,
+    DeprecationRep, Device, DiskOptions, Distro, Feature, FeatureRep, FetchedArtifact, GitSource,
+    Gpus, ImageOptions, ImageTarget, ImageTargetOpts, ImageTargetRep, Metadata, MetadataRep,
+    NetworkOptions, Os, PackageManager, Patch, PatchMethod, Patches, PkgInfo, PkgRep, Preset,
+    RepoSnapshot, RpathOptions, RpmFileAttributes, RpmInfo, RpmRep, SecurityOptions, SourceRep,
+    SourceSignature, SymbolsOptions, VersionCondition, DEFAULT_CHANGELOG_FILE,
 };
+pub use template::clear_variables_cache;
 
-use crate::{Error, Result};
+use crate::failure::FailureKind;
+use crate::secrets;
+use crate::{ErrContext, Error, Result};
 
+use apkbuild::ApkBuild;
 use deb_control::{binary::BinaryDebControl, DebControlBuilder};
 use pkgbuild::PkgBuild;
-use rpmspec::RpmSpec;
+use rpmspec::{RpmSpec, RpmSpecBuilder};
 use serde::{Deserialize, Serialize};
 use serde_yaml::Mapping;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryFrom;
 use std::fs::{self, DirEntry};
 use std::path::Path;
 use std::path::PathBuf;
 use tracing::{info_span, trace, warn};
 
-const DEFAULT_RECIPE_FILE: &str = "recipe.yml";
+/// Names of the recipe file in the directory of a recipe, the first one is the default
+pub const RECIPE_FILES: &[&str] = &["recipe.yml", "recipe.yaml"];
+/// Maintainer of packaging files of recipes without one
+pub(crate) const UNKNOWN_MAINTAINER: &str = "Unknown <unknown@localhost>";
 
 #[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq, Hash)]
 pub struct RecipeTarget {
@@ -39,7 +58,6 @@ impl RecipeTarget {
         &self.image_target.build_target
     }
 
-    #[allow(dead_code)]
     pub fn recipe(&self) -> &str {
         &self.name
     }
@@ -56,6 +74,9 @@ impl RecipeTarget {
 #[derive(Clone, Debug, Default)]
 pub struct Loader {
     path: PathBuf,
+    /// Variables referenced with `env:VAR` that take precedence over the environment
+    vars: HashMap<String, String>,
+    trusted: bool,
 }
 
 impl Loader {
@@ -68,14 +89,30 @@ impl Loader {
 
         Ok(Loader {
             path: path.to_path_buf(),
+            vars: HashMap::new(),
+            trusted: true,
         })
     }
 
+    /// Whether recipes are trusted. Untrusted recipes can't run commands or reference the
+    /// environment except for the provided variables and can only read files in their directory,
+    /// use it for recipes from untrusted repositories.
+    pub fn with_trusted(mut self, trusted: bool) -> Self {
+        self.trusted = trusted;
+        self
+    }
+
+    /// Sets variables like secrets fetched from Vault that recipes can reference with `env:VAR`
+    pub fn with_vars(mut self, vars: HashMap<String, String>) -> Self {
+        self.vars = vars;
+        self
+    }
+
     pub fn load(&self, recipe: &str) -> Result<Recipe> {
-        let path = self.path.join(recipe).join("recipe.yml");
-        RecipeRep::load(&path).and_then(|rep| Recipe::new(rep, path))
+        load_recipe(self.path.join(recipe), &self.vars, self.trusted).context(FailureKind::Recipe)
     }
 
+    /// Names of all recipes except abstract ones
     pub fn list(&self) -> Vec<String> {
         fs::read_dir(&self.path)
             .map(|entries| {
@@ -84,6 +121,11 @@ impl Loader {
                         entry
                             .ok()
                             .filter(|e| e.file_type().map(|e| e.is_dir()).unwrap_or(false))
+                            .filter(|e| {
+                                !recipe_file(e.path())
+                                    .map(|file| inherit::is_abstract(&file))
+                                    .unwrap_or(false)
+                            })
                             .map(|e| e.file_name().to_string_lossy().to_string())
                     })
                     .collect()
@@ -104,9 +146,20 @@ impl Loader {
                 Ok(entry) => {
                     let filename = entry.file_name().to_string_lossy().to_string();
                     let path = entry.path();
-                    match RecipeRep::try_from(entry).map(|rep| Recipe::new(rep, path)) {
-                        Ok(result) => {
-                            let recipe = result?;
+                    if recipe_file(&path)
+                        .map(|file| inherit::is_abstract(&file))
+                        .unwrap_or(false)
+                    {
+                        trace!(recipe = %filename, "skipping abstract recipe");
+                        continue;
+                    }
+                    match recipe_file(&path).and_then(|file| {
+                        RecipeRep::load_with_bases(&file, &self.vars, self.trusted)
+                    }) {
+                        Ok((rep, bases)) => {
+                            let recipe = Recipe::new(rep, path)
+                                .map(|recipe| recipe.with_bases(bases))
+                                .context(FailureKind::Recipe)?;
                             trace!(recipe = ?recipe);
                             recipes.push(recipe);
                         }
@@ -129,12 +182,23 @@ pub struct Recipe {
     pub build_script: BuildScript,
     pub install_script: Option<InstallScript>,
     pub recipe_dir: PathBuf,
+    /// Directories of the recipes this recipe extends
+    pub bases: Vec<PathBuf>,
 }
 
 impl Recipe {
     pub fn new(rep: RecipeRep, recipe_dir: PathBuf) -> Result<Self> {
+        let changelog = rep
+            .metadata
+            .changelog
+            .clone()
+            .unwrap_or_else(|| Changelog::File(PathBuf::from(DEFAULT_CHANGELOG_FILE)));
+        let mut metadata = Metadata::try_from(rep.metadata)?;
+        metadata.changelog = changelog
+            .entries(&recipe_dir)
+            .context("invalid changelog")?;
         Ok(Self {
-            metadata: Metadata::try_from(rep.metadata)?,
+            metadata,
             env: Env::from(rep.env),
             configure_script: if let Some(script) = rep.configure {
                 Some(ConfigureScript::try_from(script)?)
@@ -148,17 +212,126 @@ impl Recipe {
                 None
             },
             recipe_dir,
+            bases: vec![],
         })
     }
+
+    pub fn with_bases(mut self, bases: Vec<PathBuf>) -> Self {
+        self.bases = bases;
+        self
+    }
+
+    /// Enables the features of `requested` that apply to this recipe together with its default
+    /// features, unless `default_features` is false. Enabled features append their suffixes to
+    /// the name of the package, add their dependencies and are exported to the scripts as
+    /// `PKGER_FEATURES` and `PKGER_FEATURE_<NAME>=1`.
+    pub fn with_features(mut self, requested: &[String], default_features: bool) -> Result<Self> {
+        let metadata = &mut self.metadata;
+        let mut enabled = BTreeSet::new();
+        if default_features {
+            enabled.extend(
+                metadata
+                    .features
+                    .iter()
+                    .filter(|(_, feature)| feature.default)
+                    .map(|(name, _)| name.clone()),
+            );
+        }
+        let features = &metadata.features;
+        enabled.extend(
+            requested_features(&metadata.name, |f| features.contains_key(f), requested)?
+                .into_iter()
+                .map(str::to_string),
+        );
+
+        for name in &enabled {
+            let feature = &metadata.features[name];
+            if let Some(suffix) = &feature.name_suffix {
+                metadata.name.push_str(suffix);
+            }
+            if let Some(deps) = &feature.build_depends {
+                metadata
+                    .build_depends
+                    .get_or_insert_with(Dependencies::default)
+                    .merge(deps);
+            }
+            if let Some(deps) = &feature.depends {
+                metadata
+                    .depends
+                    .get_or_insert_with(Dependencies::default)
+                    .merge(deps);
+            }
+            self.env.insert(feature_env_var(name), "1");
+        }
+        let enabled = enabled.into_iter().collect::<Vec<_>>();
+        self.env.insert("PKGER_FEATURES", enabled.join(","));
+        metadata.enabled_features = enabled;
+
+        Ok(self)
+    }
 }
 
 impl Recipe {
+    /// Returns true if the package for `target` is built from existing packaging files like a
+    /// spec file or a `debian/` directory instead of the scripts of this recipe.
+    pub fn uses_native_packaging(&self, target: &BuildTarget) -> bool {
+        match target {
+            BuildTarget::Rpm => self
+                .metadata
+                .rpm
+                .as_ref()
+                .map(|rpm| rpm.spec_file.is_some())
+                .unwrap_or(false),
+            BuildTarget::Deb => self
+                .metadata
+                .deb
+                .as_ref()
+                .map(|deb| deb.debian_dir.is_some())
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Resolves `deps` for `image` in a stable order so that generated packaging files don't
+    /// change between runs
+    fn sorted_deps<'a>(
+        &self,
+        deps: &'a Dependencies,
+        image: &str,
+        target: BuildTarget,
+    ) -> Vec<&'a str> {
+        let mut deps = deps
+            .resolve_names(image, self.metadata.arch_for(image, target))
+            .into_iter()
+            .collect::<Vec<_>>();
+        deps.sort_unstable();
+        deps
+    }
+
+    /// Same as `sorted_deps` but with the architecture qualifiers of Debian multiarch removed, as
+    /// other package formats don't support them
+    fn sorted_unqualified_deps(
+        &self,
+        deps: &Dependencies,
+        image: &str,
+        target: BuildTarget,
+    ) -> Vec<String> {
+        let mut deps = self
+            .sorted_deps(deps, image, target)
+            .into_iter()
+            .map(|dep| strip_arch_qualifier(dep).into_owned())
+            .collect::<Vec<_>>();
+        deps.sort_unstable();
+        deps.dedup();
+        deps
+    }
+
     pub fn as_deb_control(&self, image: &str) -> BinaryDebControl {
         let mut builder = DebControlBuilder::binary_package_builder(&self.metadata.name)
             .version(&self.metadata.version)
-            .revision(self.metadata.release())
-            .description(&self.metadata.description)
-            .architecture(self.metadata.arch.deb_name());
+            .revision(self.metadata.release_for(image, BuildTarget::Deb))
+            .description(self.metadata.package_description())
+            .architecture(self.metadata.arch_for(image, BuildTarget::Deb).deb_name());
 
         if let Some(epoch) = &self.metadata.epoch {
             builder = builder.epoch(epoch);
@@ -167,13 +340,16 @@ impl Recipe {
             builder = builder.section(group);
         }
         if let Some(depends) = &self.metadata.depends {
-            builder = builder.add_depends_entries(depends.resolve_names(image));
+            builder =
+                builder.add_depends_entries(self.sorted_deps(depends, image, BuildTarget::Deb));
         }
         if let Some(conflicts) = &self.metadata.conflicts {
-            builder = builder.add_conflicts_entries(conflicts.resolve_names(image));
+            builder =
+                builder.add_conflicts_entries(self.sorted_deps(conflicts, image, BuildTarget::Deb));
         }
         if let Some(provides) = &self.metadata.provides {
-            builder = builder.add_provides_entries(provides.resolve_names(image));
+            builder =
+                builder.add_provides_entries(self.sorted_deps(provides, image, BuildTarget::Deb));
         }
         if let Some(maintainer) = &self.metadata.maintainer {
             builder = builder.maintainer(maintainer);
@@ -186,6 +362,9 @@ impl Recipe {
             if let Some(priority) = &deb.priority {
                 builder = builder.priority(priority);
             }
+            if let Some(multi_arch) = &deb.multi_arch {
+                builder = builder.multi_arch(multi_arch);
+            }
             if let Some(installed_size) = &deb.installed_size {
                 builder = builder.installed_size(installed_size);
             }
@@ -197,22 +376,46 @@ impl Recipe {
             }
 
             if let Some(pre_depends) = &deb.pre_depends {
-                builder = builder.add_pre_depends_entries(pre_depends.resolve_names(image));
+                builder = builder.add_pre_depends_entries(self.sorted_deps(
+                    pre_depends,
+                    image,
+                    BuildTarget::Deb,
+                ));
             }
             if let Some(recommends) = &deb.recommends {
-                builder = builder.add_recommends_entries(recommends.resolve_names(image));
+                builder = builder.add_recommends_entries(self.sorted_deps(
+                    recommends,
+                    image,
+                    BuildTarget::Deb,
+                ));
             }
             if let Some(suggests) = &deb.suggests {
-                builder = builder.add_suggests_entries(suggests.resolve_names(image));
+                builder = builder.add_suggests_entries(self.sorted_deps(
+                    suggests,
+                    image,
+                    BuildTarget::Deb,
+                ));
             }
             if let Some(breaks) = &deb.breaks {
-                builder = builder.add_breaks_entries(breaks.resolve_names(image));
+                builder =
+                    builder.add_breaks_entries(self.sorted_deps(breaks, image, BuildTarget::Deb));
             }
             if let Some(replaces) = &deb.replaces {
-                builder = builder.add_replaces_entries(replaces.resolve_names(image));
+                builder = builder.add_replaces_entries(self.sorted_deps(
+                    replaces,
+                    image,
+                    BuildTarget::Deb,
+                ));
             }
             if let Some(enchances) = &deb.enchances {
-                builder = builder.add_enchances_entries(enchances.resolve_names(image));
+                builder = builder.add_enchances_entries(self.sorted_deps(
+                    enchances,
+                    image,
+                    BuildTarget::Deb,
+                ));
+            }
+            for (name, value) in &deb.extra_fields {
+                builder = builder.add_field(name, value);
             }
         }
 
@@ -228,22 +431,37 @@ impl Recipe {
                 s
             });
 
-        let mut builder = RpmSpec::builder()
-            .name(&self.metadata.name)
-            .build_arch(self.metadata.arch.rpm_name())
-            .description(&self.metadata.description)
-            .license(&self.metadata.license)
-            .version(&self.metadata.version)
-            .release(self.metadata.release())
+        let files = match &self.metadata.rpm {
+            Some(rpm) => metadata::rpm_files_entries(files, &rpm.file_attributes),
+            None => files.to_vec(),
+        };
+
+        self.rpm_spec_builder(image)
             .add_files_entries(files)
             .add_sources_entries(sources)
             .add_macro("__os_install_post", None::<&str>, "%{nil}") // disable binary stripping
             .install_script(&install_script)
-            .description(&self.metadata.description);
+            .build()
+    }
+
+    /// Creates a spec builder with all fields derived from the metadata of this recipe
+    fn rpm_spec_builder(&self, image: &str) -> RpmSpecBuilder {
+        let mut builder = RpmSpec::builder()
+            .name(&self.metadata.name)
+            .build_arch(self.metadata.arch_for(image, BuildTarget::Rpm).rpm_name())
+            .description(self.metadata.package_description())
+            .license(&self.metadata.license)
+            .version(&self.metadata.version)
+            .release(self.metadata.release_for(image, BuildTarget::Rpm))
+            .description(self.metadata.package_description());
 
         if let Some(rpm) = &self.metadata.rpm {
             if let Some(obsoletes) = &rpm.obsoletes {
-                builder = builder.add_obsoletes_entries(obsoletes.resolve_names(image));
+                builder = builder.add_obsoletes_entries(self.sorted_unqualified_deps(
+                    obsoletes,
+                    image,
+                    BuildTarget::Rpm,
+                ));
             }
             if let Some(vendor) = &rpm.vendor {
                 builder = builder.vendor(vendor);
@@ -269,14 +487,29 @@ impl Recipe {
             if let Some(summary) = &rpm.summary {
                 builder = builder.summary(summary);
             } else {
-                builder = builder.summary(&self.metadata.description);
+                builder = builder.summary(self.metadata.package_description());
+            }
+            for (name, value) in &rpm.extra_tags {
+                builder = builder.add_tag(name, value);
             }
         }
         if let Some(group) = &self.metadata.group {
             builder = builder.group(group);
         }
+        let has_packager = self
+            .metadata
+            .rpm
+            .as_ref()
+            .map(|rpm| {
+                rpm.extra_tags
+                    .keys()
+                    .any(|tag| tag.eq_ignore_ascii_case("packager"))
+            })
+            .unwrap_or_default();
         if let Some(maintainer) = &self.metadata.maintainer {
-            builder = builder.packager(maintainer);
+            if !has_packager {
+                builder = builder.packager(maintainer);
+            }
         }
         if let Some(url) = &self.metadata.url {
             builder = builder.url(url);
@@ -285,16 +518,130 @@ impl Recipe {
             builder = builder.epoch(epoch);
         }
         if let Some(conflicts) = &self.metadata.conflicts {
-            builder = builder.add_conflicts_entries(conflicts.resolve_names(image));
+            builder = builder.add_conflicts_entries(self.sorted_unqualified_deps(
+                conflicts,
+                image,
+                BuildTarget::Rpm,
+            ));
         }
         if let Some(provides) = &self.metadata.provides {
-            builder = builder.add_provides_entries(provides.resolve_names(image));
+            builder = builder.add_provides_entries(self.sorted_unqualified_deps(
+                provides,
+                image,
+                BuildTarget::Rpm,
+            ));
         }
         if let Some(requires) = &self.metadata.depends {
-            builder = builder.add_requires_entries(requires.resolve_names(image));
+            builder = builder.add_requires_entries(self.sorted_unqualified_deps(
+                requires,
+                image,
+                BuildTarget::Rpm,
+            ));
         }
 
-        builder.build()
+        builder.add_changelog_entries(self.rpm_changelog(image))
+    }
+
+    /// Returns the full version of the package in a changelog `entry`
+    fn changelog_version(
+        &self,
+        entry: &ChangelogEntry,
+        image: &str,
+        target: BuildTarget,
+    ) -> String {
+        let release = entry
+            .release
+            .clone()
+            .unwrap_or_else(|| self.metadata.release_for(image, target));
+        match &self.metadata.epoch {
+            Some(epoch) => format!("{}:{}-{}", epoch, entry.version, release),
+            None => format!("{}-{}", entry.version, release),
+        }
+    }
+
+    fn changelog_author<'a>(&'a self, entry: &'a ChangelogEntry) -> &'a str {
+        entry
+            .author
+            .as_deref()
+            .or(self.metadata.maintainer.as_deref())
+            .unwrap_or(UNKNOWN_MAINTAINER)
+    }
+
+    /// Returns the changelog entries of the `%changelog` section of a spec file
+    fn rpm_changelog(&self, image: &str) -> Vec<String> {
+        self.metadata
+            .changelog
+            .iter()
+            .map(|entry| {
+                let date = entry
+                    .parsed_date()
+                    .map(|date| date.format("%a %b %d %Y").to_string())
+                    .unwrap_or_default();
+                let changes = entry
+                    .changes
+                    .iter()
+                    .map(|change| format!("- {}\n", change.trim().replace('\n', "\n  ")))
+                    .collect::<String>();
+                format!(
+                    "* {} {} - {}\n{}",
+                    date,
+                    self.changelog_author(entry),
+                    self.changelog_version(entry, image, BuildTarget::Rpm),
+                    changes
+                )
+            })
+            .collect()
+    }
+
+    /// Renders the `conffiles` control file, returns None if the recipe has no conffiles
+    pub fn deb_conffiles(&self) -> Option<String> {
+        let conffiles = &self.metadata.deb.as_ref()?.conffiles;
+        if conffiles.is_empty() {
+            return None;
+        }
+        Some(
+            conffiles
+                .iter()
+                .map(|conffile| format!("{}\n", conffile))
+                .collect(),
+        )
+    }
+
+    /// Renders the changelog in the format of `debian/changelog`, returns None if the recipe has
+    /// no changelog
+    pub fn debian_changelog(&self, image: &str) -> Option<String> {
+        if self.metadata.changelog.is_empty() {
+            return None;
+        }
+        let entries = self
+            .metadata
+            .changelog
+            .iter()
+            .map(|entry| {
+                let date = entry
+                    .parsed_date()
+                    .map(|date| {
+                        date.and_hms(0, 0, 0)
+                            .format("%a, %d %b %Y %H:%M:%S +0000")
+                            .to_string()
+                    })
+                    .unwrap_or_default();
+                let changes = entry
+                    .changes
+                    .iter()
+                    .map(|change| format!("  * {}\n", change.trim().replace('\n', "\n    ")))
+                    .collect::<String>();
+                format!(
+                    "{} ({}) unstable; urgency=medium\n\n{}\n -- {}  {}\n",
+                    self.metadata.name,
+                    self.changelog_version(entry, image, BuildTarget::Deb),
+                    changes,
+                    self.changelog_author(entry),
+                    date
+                )
+            })
+            .collect::<Vec<_>>();
+        Some(entries.join("\n"))
     }
 
     pub fn as_pkgbuild(&self, image: &str, sources: &[String], checksums: &[String]) -> PkgBuild {
@@ -306,9 +653,13 @@ impl Recipe {
         let mut builder = PkgBuild::builder()
             .pkgname(&self.metadata.name)
             .pkgver(&self.metadata.version)
-            .pkgdesc(&self.metadata.description)
+            .pkgdesc(self.metadata.package_description())
             .add_license_entries(vec![&self.metadata.license])
-            .add_arch_entries(vec![self.metadata.arch.pkg_name().to_string()])
+            .add_arch_entries(vec![self
+                .metadata
+                .arch_for(image, BuildTarget::Pkg)
+                .pkg_name()
+                .to_string()])
             .add_source_entries(sources)
             .add_md5sums_entries(checksums)
             .package_func(package_func);
@@ -320,16 +671,128 @@ impl Recipe {
             builder = builder.add_groups_entries(vec![group]);
         }
         if let Some(depends) = &self.metadata.depends {
-            builder = builder.add_depends_entries(depends.resolve_names(image));
+            builder = builder.add_depends_entries(self.sorted_unqualified_deps(
+                depends,
+                image,
+                BuildTarget::Pkg,
+            ));
+        }
+        if let Some(conflicts) = &self.metadata.conflicts {
+            builder = builder.add_conflicts_entries(self.sorted_unqualified_deps(
+                conflicts,
+                image,
+                BuildTarget::Pkg,
+            ));
+        }
+        if let Some(provides) = &self.metadata.provides {
+            builder = builder.add_provides_entries(self.sorted_unqualified_deps(
+                provides,
+                image,
+                BuildTarget::Pkg,
+            ));
+        }
+        if let Some(epoch) = &self.metadata.epoch {
+            builder = builder.epoch(epoch);
+        }
+        if let Some(pkg) = &self.metadata.pkg {
+            if let Some(install) = &pkg.install {
+                builder = builder.install(install);
+            }
+            if let Some(backup) = &pkg.backup {
+                builder = builder.add_backup_entries(backup);
+            }
+            if let Some(replaces) = &pkg.replaces {
+                builder = builder.add_replaces_entries(self.sorted_unqualified_deps(
+                    replaces,
+                    image,
+                    BuildTarget::Pkg,
+                ));
+            }
+            if let Some(optdepends) = &pkg.optdepends {
+                builder = builder.add_optdepends_entries(optdepends);
+            }
+        }
+
+        builder = builder.pkgrel(self.metadata.release_for(image, BuildTarget::Pkg));
+
+        builder.build()
+    }
+
+    /// Creates an APKBUILD extracting `sources` into the package. `checksums` are lines of
+    /// `sha512sum` output for every source.
+    pub fn as_apkbuild(&self, image: &str, sources: &[String], checksums: &[String]) -> ApkBuild {
+        let package_func =
+            sources
+                .iter()
+                .fold("    mkdir -p \"$pkgdir\"\n".to_string(), |mut s, src| {
+                    s.push_str(&format!("    tar xvf \"$srcdir\"/{} -C \"$pkgdir\"\n", src));
+                    s
+                });
+
+        let mut builder = ApkBuild::builder()
+            .pkgname(&self.metadata.name)
+            .pkgver(&self.metadata.version)
+            .pkgrel(self.metadata.release_for(image, BuildTarget::Apk))
+            .pkgdesc(self.metadata.package_description())
+            .license(&self.metadata.license)
+            .add_arch_entries(vec![self
+                .metadata
+                .arch_for(image, BuildTarget::Apk)
+                .apk_name()
+                .to_string()])
+            // the scripts of the recipe already ran, no tests and binary stripping
+            .add_options_entries(vec!["!check", "!strip"])
+            .add_source_entries(sources)
+            .add_sha512sums_entries(checksums)
+            // sources are extracted straight into the package
+            .unpack_func("    :")
+            .package_func(package_func.trim_end());
+
+        if let Some(maintainer) = &self.metadata.maintainer {
+            builder = builder.maintainer(maintainer);
+        }
+        if let Some(url) = &self.metadata.url {
+            builder = builder.url(url);
+        }
+        let mut depends = vec![];
+        if let Some(deps) = &self.metadata.depends {
+            depends.extend(self.sorted_unqualified_deps(deps, image, BuildTarget::Apk));
         }
         if let Some(conflicts) = &self.metadata.conflicts {
-            builder = builder.add_conflicts_entries(conflicts.resolve_names(image));
+            depends.extend(
+                self.sorted_unqualified_deps(conflicts, image, BuildTarget::Apk)
+                    .into_iter()
+                    .map(|conflict| format!("!{}", conflict)),
+            );
         }
+        builder = builder.add_depends_entries(depends);
         if let Some(provides) = &self.metadata.provides {
-            builder = builder.add_provides_entries(provides.resolve_names(image));
+            builder = builder.add_provides_entries(self.sorted_unqualified_deps(
+                provides,
+                image,
+                BuildTarget::Apk,
+            ));
         }
 
-        builder = builder.pkgrel(self.metadata.release());
+        if let Some(apk) = &self.metadata.apk {
+            if let Some(replaces) = &apk.replaces {
+                builder = builder.add_replaces_entries(self.sorted_unqualified_deps(
+                    replaces,
+                    image,
+                    BuildTarget::Apk,
+                ));
+            }
+            if let Some(priority) = apk.provider_priority {
+                builder = builder.provider_priority(priority.to_string());
+            }
+            builder = builder
+                .add_install_if_entries(&apk.install_if)
+                .add_install_entries(
+                    apk.install_scripts()
+                        .into_iter()
+                        .map(|(suffix, _)| format!("{}.{}", self.metadata.name, suffix)),
+                );
+        }
 
         builder.build()
     }
@@ -345,12 +808,60 @@ pub struct RecipeRep {
 }
 
 impl RecipeRep {
+    /// Parses a recipe substituting variables and resolving all `env:VAR` and `file:path`
+    /// references. Files and commands are relative to the current directory.
     pub fn from_yaml_bytes(data: &[u8]) -> Result<Self> {
-        Ok(serde_yaml::from_slice(&data)?)
+        Self::from_value(
+            serde_yaml::from_slice(data)?,
+            Path::new("."),
+            &HashMap::new(),
+            true,
+        )
+    }
+
+    fn from_value(
+        mut value: serde_yaml::Value,
+        dir: &Path,
+        vars: &HashMap<String, String>,
+        trusted: bool,
+    ) -> Result<Self> {
+        template::render(&mut value, dir, trusted).context("failed to render recipe variables")?;
+        secrets::resolve_with(
+            &mut value,
+            vars,
+            &|file| template::read_file(dir, file, trusted),
+            trusted,
+        )?;
+        Ok(serde_yaml::from_value(value)?)
     }
 
+    /// Loads the recipe file at `path` merged with the recipes it extends
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Self::from_yaml_bytes(&fs::read(&path)?)
+        Self::load_with_bases(path.as_ref(), &HashMap::new(), true).map(|(rep, _)| rep)
+    }
+
+    /// Like [`load`](RecipeRep::load) but also returns the directories of the extended recipes.
+    /// `env:VAR` references are looked up in `vars` before the environment, which is only
+    /// available to `trusted` recipes.
+    pub fn load_with_bases(
+        path: &Path,
+        vars: &HashMap<String, String>,
+        trusted: bool,
+    ) -> Result<(Self, Vec<PathBuf>)> {
+        let inherited = inherit::load(path)?;
+        if inherited.is_abstract {
+            return Err(Error::msg(
+                "the recipe is abstract, it can only be extended by other recipes",
+            ));
+        }
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        Ok((
+            Self::from_value(inherited.value, dir, vars, trusted)?,
+            inherited.bases,
+        ))
     }
 }
 
@@ -358,9 +869,36 @@ impl TryFrom<DirEntry> for RecipeRep {
     type Error = Error;
 
     fn try_from(entry: DirEntry) -> Result<Self> {
-        let mut path = entry.path();
-        path.push(DEFAULT_RECIPE_FILE);
-        RecipeRep::load(path)
+        RecipeRep::load(recipe_file(entry.path())?)
+    }
+}
+
+/// Loads the recipe in directory `dir`
+fn load_recipe(dir: PathBuf, vars: &HashMap<String, String>, trusted: bool) -> Result<Recipe> {
+    let (rep, bases) = RecipeRep::load_with_bases(&recipe_file(&dir)?, vars, trusted)?;
+    Recipe::new(rep, dir).map(|recipe| recipe.with_bases(bases))
+}
+
+/// Returns the path of the recipe file in the directory of a recipe, either `recipe.yml` or
+/// `recipe.yaml`
+pub fn recipe_file<P: AsRef<Path>>(dir: P) -> Result<PathBuf> {
+    let dir = dir.as_ref();
+    let mut found = RECIPE_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file());
+    match (found.next(), found.next()) {
+        (Some(path), None) => Ok(path),
+        (Some(_), Some(_)) => Err(Error::msg(format!(
+            "`{}` contains both `{}`, remove one of them",
+            dir.display(),
+            RECIPE_FILES.join("` and `")
+        ))),
+        _ => Err(Error::msg(format!(
+            "`{}` contains no recipe file, expected one of `{}`",
+            dir.display(),
+            RECIPE_FILES.join("`, `")
+        ))),
     }
 }
 
@@ -435,4 +973,122 @@ mod tests {
         assert_eq!(install.working_dir, rep_install.working_dir);
         assert_eq!(install.shell, rep_install.shell);
     }
+
+    #[test]
+    fn renders_extra_fields() {
+        let recipe = |extra: &str| {
+            let rep = RecipeRep::from_yaml_bytes(
+                format!(
+                    "metadata:\n  name: foo\n  version: 1.0.0\n  description: foo\n  license: MIT\n  maintainer: Foo <foo@example.com>\n{}build:\n  steps: []\n",
+                    extra
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            Recipe::new(rep, PathBuf::new())
+        };
+
+        let parsed = recipe(
+            "  deb:\n    extra_fields:\n      XB-Custom-Field: value\n  rpm:\n    extra_tags:\n      Packager: Bar <bar@example.com>\n",
+        )
+        .unwrap();
+        assert!(parsed
+            .as_deb_control("")
+            .render()
+            .contains("\nXB-Custom-Field: value\n"));
+        let spec = parsed.as_rpm_spec(&[], &[], "").render();
+        assert!(spec.contains("\nPackager: Bar <bar@example.com>\n"));
+        assert!(!spec.contains("Foo <foo@example.com>"));
+
+        assert!(recipe("  deb:\n    extra_fields:\n      depends: libc6\n").is_err());
+        assert!(recipe("  rpm:\n    extra_tags:\n      Source1: foo.tar.gz\n").is_err());
+        assert!(recipe("  rpm:\n    extra_tags:\n      'Bad Tag': value\n").is_err());
+    }
+
+    #[test]
+    fn renders_conffiles() {
+        let recipe = |deb: &str| {
+            let rep = RecipeRep::from_yaml_bytes(
+                format!(
+                    "metadata:\n  name: foo\n  version: 1.0.0\n  description: foo\n  license: MIT\n  deb:\n{}build:\n  steps: []\n",
+                    deb
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            Recipe::new(rep, PathBuf::new())
+        };
+
+        let parsed = recipe("    conffiles: [/etc/foo/config.toml, /etc/default/foo]\n").unwrap();
+        assert_eq!(
+            parsed.deb_conffiles().unwrap(),
+            "/etc/foo/config.toml\n/etc/default/foo\n"
+        );
+        assert_eq!(
+            recipe("    priority: optional\n").unwrap().deb_conffiles(),
+            None
+        );
+        assert!(recipe("    conffiles: [etc/foo/config.toml]\n").is_err());
+    }
+
+    #[test]
+    fn renders_changelog() {
+        let rep = RecipeRep::from_yaml_bytes(
+            b"metadata:\n  name: foo\n  version: 1.1.0\n  release: '2'\n  description: foo\n  license: MIT\n  maintainer: Foo <foo@example.com>\n  changelog:\n    - version: 1.1.0\n      date: 2021-07-01\n      changes:\n        - Update to version 1.1.0\n        - Fix build\n    - version: 1.0.0\n      release: '1'\n      date: 2021-06-01\n      author: Bar <bar@example.com>\n      changes:\n        - Initial release\nbuild:\n  steps: []\n",
+        )
+        .unwrap();
+        let recipe = Recipe::new(rep, PathBuf::new()).unwrap();
+
+        assert_eq!(
+            recipe.debian_changelog("").unwrap(),
+            "foo (1.1.0-2) unstable; urgency=medium\n\n  * Update to version 1.1.0\n  * Fix build\n\n -- Foo <foo@example.com>  Thu, 01 Jul 2021 00:00:00 +0000\n\nfoo (1.0.0-1) unstable; urgency=medium\n\n  * Initial release\n\n -- Bar <bar@example.com>  Tue, 01 Jun 2021 00:00:00 +0000\n"
+        );
+        let spec = recipe.as_rpm_spec(&[], &[], "").render();
+        assert!(spec.ends_with("%changelog\n* Thu Jul 01 2021 Foo <foo@example.com> - 1.1.0-2\n- Update to version 1.1.0\n- Fix build\n\n* Tue Jun 01 2021 Bar <bar@example.com> - 1.0.0-1\n- Initial release\n\n"));
+    }
+
+    #[test]
+    fn finds_recipe_file() {
+        let dir = tempdir::TempDir::new("pkger-recipe").unwrap();
+        let dir = dir.path();
+        assert!(recipe_file(dir).is_err());
+        fs::write(dir.join("recipe.yaml"), TEST_RECIPE).unwrap();
+        assert_eq!(recipe_file(dir).unwrap(), dir.join("recipe.yaml"));
+        let loaded = RecipeRep::load(recipe_file(dir).unwrap()).unwrap();
+        assert_eq!(loaded.metadata.name, "test-pkger");
+        fs::write(dir.join("recipe.yml"), TEST_RECIPE).unwrap();
+        assert!(recipe_file(dir).is_err());
+    }
+
+    #[test]
+    fn keeps_options_of_each_image_target() {
+        let rep = RecipeRep::from_yaml_bytes(
+            b"metadata:\n  name: foo\n  version: 1.0.0\n  release: '1'\n  description: foo\n  license: MIT\n  images:\n    - name: debian10\n      target: deb\n      release_suffix: '~deb10'\n    - name: debian10\n      target: gzip\nbuild:\n  steps: []\n",
+        )
+        .unwrap();
+        let recipe = Recipe::new(rep, PathBuf::new()).unwrap();
+        let metadata = &recipe.metadata;
+        assert_eq!(
+            metadata.release_for("debian10", BuildTarget::Deb),
+            "1~deb10"
+        );
+        assert_eq!(metadata.release_for("debian10", BuildTarget::Gzip), "1");
+    }
+
+    #[test]
+    fn reads_secret_files_relative_to_recipe() {
+        let dir = tempdir::TempDir::new("pkger-recipe").unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("token"), "t0ken\n").unwrap();
+        fs::write(
+            dir.join("recipe.yml"),
+            "metadata:\n  name: foo\n  version: 1.0.0\n  description: foo\n  license: MIT\nenv:\n  API_TOKEN: file:token\nbuild:\n  steps: []\n",
+        )
+        .unwrap();
+        let loaded = RecipeRep::load(dir.join("recipe.yml")).unwrap();
+        assert_eq!(
+            loaded.env.unwrap().get(&"API_TOKEN".into()),
+            Some(&"t0ken".into())
+        );
+    }
 }
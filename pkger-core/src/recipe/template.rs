@@ -0,0 +1,397 @@
+//! Variables of recipes. Any string value can reference a variable defined in `vars` with
+//! `${NAME}`, a variable of the host environment with `${env:NAME}` and the name, version and
+//! release of the recipe with `${PKGER_NAME}`, `${PKGER_VERSION}` and `${PKGER_RELEASE}`.
+//! Undefined variables are an error except in scripts and environment variables of the recipe,
+//! where they are left to the shell. `$${` is a literal `${`.
+//!
+//! Values of `vars` can also be read from a file with `file:path` or taken from the output of a
+//! command with `cmd:command`, both relative to the directory of the recipe and evaluated on the
+//! host when the recipe is loaded. Commands and the host environment are only available to trusted
+//! recipes.
+use crate::{ErrContext, Error, Result};
+
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use tracing::debug;
+
+static VARS_KEY: &str = "vars";
+static ENV_PREFIX: &str = "env:";
+static FILE_PREFIX: &str = "file:";
+static CMD_PREFIX: &str = "cmd:";
+static RESERVED_PREFIX: &str = "PKGER_";
+
+/// Outputs of commands of variables by the directory they ran in and the command
+static COMMAND_OUTPUTS: Mutex<BTreeMap<(PathBuf, String), String>> = Mutex::new(BTreeMap::new());
+
+/// Keys of values in which undefined variables are left to the shell
+static SHELL_KEYS: &[&str] = &["env", "configure", "build", "install", "steps"];
+static SHELL_KEY_SUFFIX: &str = "script";
+
+type Variables = HashMap<String, String>;
+
+/// Forgets the cached outputs of commands of variables so that they run again on the next load
+pub fn clear_variables_cache() {
+    if let Ok(mut outputs) = COMMAND_OUTPUTS.lock() {
+        outputs.clear();
+    }
+}
+
+/// Substitutes variables in all string values of a recipe removing the `vars` section. Files and
+/// commands of variables are relative to `dir`. Variables of recipes that are not `trusted` can't
+/// run commands or reference the environment and can only read files in `dir`, use it for recipes
+/// from untrusted repositories.
+pub fn render(recipe: &mut Value, dir: &Path, trusted: bool) -> Result<()> {
+    let mut vars = Variables::new();
+    if let Value::Mapping(recipe) = recipe {
+        if let Some(defined) = recipe.remove(&Value::from(VARS_KEY)) {
+            define_vars(&defined, &mut vars, dir, trusted)?;
+        }
+    }
+
+    // the builtin variables are available once the fields they come from are rendered
+    let metadata = recipe.get("metadata").cloned();
+    for (field, var) in &[
+        ("name", "PKGER_NAME"),
+        ("version", "PKGER_VERSION"),
+        ("release", "PKGER_RELEASE"),
+    ] {
+        let value = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(field))
+            .and_then(scalar_string);
+        if let Some(value) = value {
+            let rendered = expand(&value, &vars, true, trusted)
+                .map_err(|e| e.context(format!("invalid value of `metadata.{}`", field)))?;
+            vars.insert(var.to_string(), rendered);
+        }
+    }
+    vars.entry("PKGER_RELEASE".to_string())
+        .or_insert_with(|| "0".to_string());
+
+    render_value(recipe, &vars, "", false, trusted)
+}
+
+fn define_vars(defined: &Value, vars: &mut Variables, dir: &Path, trusted: bool) -> Result<()> {
+    let defined = match defined {
+        Value::Mapping(defined) => defined,
+        Value::Null => return Ok(()),
+        _ => return Err(Error::msg("`vars` must be a mapping of names to values")),
+    };
+    for (name, value) in defined {
+        let name = name
+            .as_str()
+            .ok_or_else(|| Error::msg("names of `vars` must be strings"))?;
+        if !is_valid_name(name) {
+            return Err(Error::msg(format!(
+                "invalid variable name `{}`, names contain only letters, digits and `_`",
+                name
+            )));
+        }
+        if name.starts_with(RESERVED_PREFIX) {
+            return Err(Error::msg(format!(
+                "variable `{}` uses the reserved prefix `{}`",
+                name, RESERVED_PREFIX
+            )));
+        }
+        let value = scalar_string(value)
+            .ok_or_else(|| Error::msg(format!("value of variable `{}` must be a scalar", name)))?;
+        // variables can only reference the environment so that the order of definition is
+        // irrelevant
+        let value = if let Some(file) = value.strip_prefix(FILE_PREFIX) {
+            expand(file.trim(), &Variables::new(), true, trusted)
+                .and_then(|file| read_file(dir, &file, trusted))
+        } else if let Some(command) = value.strip_prefix(CMD_PREFIX) {
+            expand(command.trim(), &Variables::new(), true, trusted)
+                .and_then(|command| run_command(dir, &command, trusted))
+        } else {
+            expand(&value, &Variables::new(), true, trusted)
+        }
+        .map_err(|e| e.context(format!("invalid value of variable `{}`", name)))?;
+        vars.insert(name.to_string(), value);
+    }
+    Ok(())
+}
+
+/// Reads the contents of `file` relative to `dir` without the trailing newline. Files of untrusted
+/// recipes have to be in `dir`.
+pub(crate) fn read_file(dir: &Path, file: &str, trusted: bool) -> Result<String> {
+    let path = dir.join(file);
+    if !trusted {
+        let outside = match (path.canonicalize(), dir.canonicalize()) {
+            (Ok(path), Ok(dir)) => !path.starts_with(dir),
+            _ => true,
+        };
+        if outside {
+            return Err(Error::msg(format!(
+                "file `{}` is outside of the recipe directory, untrusted recipes can only read files in it",
+                file
+            )));
+        }
+    }
+    fs::read_to_string(&path)
+        .map(|content| content.trim_end_matches(['\n', '\r']).to_string())
+        .context(format!("failed to read file `{}`", path.display()))
+}
+
+/// Runs `command` with the shell of the host in `dir` returning its output without the trailing
+/// newline. Outputs are cached so that every command runs once no matter how many times the
+/// recipe is loaded.
+fn run_command(dir: &Path, command: &str, trusted: bool) -> Result<String> {
+    if !trusted {
+        return Err(Error::msg(format!(
+            "running command `{}` is not allowed, recipes are not trusted",
+            command
+        )));
+    }
+    let key = (dir.to_path_buf(), command.to_string());
+    if let Some(output) = COMMAND_OUTPUTS
+        .lock()
+        .ok()
+        .and_then(|outputs| outputs.get(&key).cloned())
+    {
+        return Ok(output);
+    }
+
+    debug!(command = %command, dir = %dir.display(), "running command of variable");
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .output()
+        .context(format!("failed to run command `{}`", command))?;
+    if !out.status.success() {
+        return Err(Error::msg(format!(
+            "command `{}` failed with {}\n{}",
+            command,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    let output = String::from_utf8_lossy(&out.stdout)
+        .trim_end_matches(['\n', '\r'])
+        .to_string();
+    if let Ok(mut outputs) = COMMAND_OUTPUTS.lock() {
+        outputs.insert(key, output.clone());
+    }
+    Ok(output)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn is_shell_key(key: &str) -> bool {
+    SHELL_KEYS.contains(&key) || key.ends_with(SHELL_KEY_SUFFIX)
+}
+
+fn render_value(
+    value: &mut Value,
+    vars: &Variables,
+    path: &str,
+    shell: bool,
+    trusted: bool,
+) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains('$') => {
+            *s = expand(s, vars, !shell, trusted)
+                .map_err(|e| e.context(format!("invalid value of `{}`", path)))?;
+        }
+        Value::Sequence(seq) => {
+            for (i, item) in seq.iter_mut().enumerate() {
+                render_value(item, vars, &format!("{}[{}]", path, i), shell, trusted)?;
+            }
+        }
+        Value::Mapping(map) => render_mapping(map, vars, path, shell, trusted)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn render_mapping(
+    map: &mut Mapping,
+    vars: &Variables,
+    path: &str,
+    shell: bool,
+    trusted: bool,
+) -> Result<()> {
+    for (key, value) in map.iter_mut() {
+        let key = key.as_str().unwrap_or_default();
+        let path = if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        render_value(value, vars, &path, shell || is_shell_key(key), trusted)?;
+    }
+    Ok(())
+}
+
+/// Substitutes variables in `s`. With `strict` an undefined variable is an error, otherwise it is
+/// kept as is. Undefined variables of the environment are always an error, as are references to
+/// the environment if the recipe is not `trusted`.
+fn expand(s: &str, vars: &Variables, strict: bool, trusted: bool) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let inner = match tail.strip_prefix("${") {
+            Some(inner) => inner,
+            None => {
+                out.push('$');
+                rest = &tail[1..];
+                continue;
+            }
+        };
+        let end = match inner.find('}') {
+            Some(end) => end,
+            None if strict => {
+                return Err(Error::msg(format!(
+                    "unterminated variable reference `{}`",
+                    tail
+                )))
+            }
+            None => {
+                out.push_str(tail);
+                rest = "";
+                break;
+            }
+        };
+        let name = &inner[..end];
+        if let Some(var) = name.strip_prefix(ENV_PREFIX) {
+            if !trusted {
+                return Err(Error::msg(format!(
+                    "referencing environment variable `{}` is not allowed, recipes are not trusted",
+                    var
+                )));
+            }
+            let value = env::var(var).map_err(|_| {
+                Error::msg(format!("environment variable `{}` is not defined", var))
+            })?;
+            out.push_str(&value);
+        } else if let Some(value) = vars.get(name) {
+            out.push_str(value);
+        } else if strict {
+            return Err(Error::msg(format!("variable `{}` is not defined", name)));
+        } else {
+            out.push_str(&tail[..end + 3]);
+        }
+        rest = &inner[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_variables() {
+        env::set_var("PKGER_TEMPLATE_TEST", "mirror.example.com");
+        let mut recipe: Value = serde_yaml::from_str(
+            r#"
+vars:
+  mirror: ${env:PKGER_TEMPLATE_TEST}
+  major: 1
+metadata:
+  name: foo
+  version: ${major}.2.0
+  source: https://${mirror}/foo-${PKGER_VERSION}-${PKGER_RELEASE}.tar.gz
+  description: costs $$5, literal $${major}
+build:
+  steps:
+    - cmd: echo ${PKGER_NAME} ${HOME} ${RANDOM:-1}
+"#,
+        )
+        .unwrap();
+        render(&mut recipe, Path::new("."), true).unwrap();
+        assert!(recipe.get("vars").is_none());
+        assert_eq!(recipe["metadata"]["version"], Value::from("1.2.0"));
+        assert_eq!(
+            recipe["metadata"]["source"],
+            Value::from("https://mirror.example.com/foo-1.2.0-0.tar.gz")
+        );
+        assert_eq!(
+            recipe["metadata"]["description"],
+            Value::from("costs $$5, literal ${major}")
+        );
+        assert_eq!(
+            recipe["build"]["steps"][0]["cmd"],
+            Value::from("echo foo ${HOME} ${RANDOM:-1}")
+        );
+
+        let mut recipe: Value =
+            serde_yaml::from_str("metadata:\n  name: foo\n  url: https://${host}/\n").unwrap();
+        let err = render(&mut recipe, Path::new("."), true).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "invalid value of `metadata.url`: variable `host` is not defined"
+        );
+    }
+
+    #[test]
+    fn sources_variables_from_files_and_commands() {
+        let dir = tempdir::TempDir::new("pkger-template").unwrap();
+        fs::write(dir.path().join("VERSION"), "2.1.0\n").unwrap();
+        let mut recipe: Value = serde_yaml::from_str(
+            r#"
+vars:
+  version: file:VERSION
+  describe: cmd:printf 'v%s-3-g1a2b3c4\n' $(cat VERSION)
+metadata:
+  name: foo
+  version: ${version}
+  release: ${describe}
+"#,
+        )
+        .unwrap();
+        render(&mut recipe, dir.path(), true).unwrap();
+        assert_eq!(recipe["metadata"]["version"], Value::from("2.1.0"));
+        assert_eq!(
+            recipe["metadata"]["release"],
+            Value::from("v2.1.0-3-g1a2b3c4")
+        );
+
+        // the output of the command is cached
+        fs::write(dir.path().join("VERSION"), "2.2.0\n").unwrap();
+        assert_eq!(
+            run_command(
+                dir.path(),
+                "printf 'v%s-3-g1a2b3c4\\n' $(cat VERSION)",
+                true
+            )
+            .unwrap(),
+            "v2.1.0-3-g1a2b3c4"
+        );
+        assert!(run_command(dir.path(), "exit 3", true).is_err());
+
+        assert!(run_command(dir.path(), "echo 1", false).is_err());
+        assert!(read_file(dir.path(), "/etc/hostname", false).is_err());
+        assert_eq!(read_file(dir.path(), "VERSION", false).unwrap(), "2.2.0");
+        let mut recipe: Value =
+            serde_yaml::from_str("metadata:\n  name: foo\n  url: ${env:HOME}\n").unwrap();
+        assert!(render(&mut recipe, dir.path(), false).is_err());
+    }
+}
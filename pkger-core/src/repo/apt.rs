@@ -0,0 +1,444 @@
+//! Flat APT repositories. The `Packages` and `Release` files are saved in the root of the
+//! repository and reference the packages by paths relative to it, so the repository is added to
+//! sources like `deb [trusted=yes] http://example.com/repo ./`.
+use crate::build::sign::{export_public_key, sign_file, SigningConfig};
+use crate::repo::{
+    apply_changes, find_packages, relative_path, remove_package, superseded, Changes, PackageId,
+    Update,
+};
+use crate::{ErrContext, Error, Result};
+
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use tracing::{info, info_span, trace, warn};
+use xz2::read::XzDecoder;
+
+static AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_LEN: usize = 60;
+
+/// File with the public key of a signed repository
+pub static PUBLIC_KEY: &str = "Release.key";
+
+/// Fields of a control paragraph in their original order, values of multiline fields contain the
+/// continuation lines
+type Paragraph = Vec<(String, String)>;
+
+/// Updates the index of DEB packages in `dir` and its subdirectories saving `Packages`,
+/// `Packages.gz` and `Release` in `dir`. Packages unchanged since the last update are not read
+/// again. With `signing` the release is also signed as `InRelease` and `Release.gpg` and the
+/// public key is saved as `Release.key`.
+pub fn generate(dir: &Path, signing: Option<&SigningConfig>, changes: &Changes) -> Result<Update> {
+    let span = info_span!("apt-repo", dir = %dir.display());
+    let _enter = span.enter();
+
+    let mut update = apply_changes(dir, changes, "deb")?;
+    let mut indexed = previous_index(dir);
+
+    let mut entries = vec![];
+    let mut seen = HashSet::new();
+    let mut read = 0;
+    for package in find_packages(dir, "deb")? {
+        let filename = relative_path(dir, &package);
+        let paragraph = match indexed.remove(&filename) {
+            Some((paragraph, modified))
+                if is_unchanged(&package, field(&paragraph, "Size"), modified) =>
+            {
+                paragraph
+            }
+            _ => {
+                read += 1;
+                index_package(&package, filename)?
+            }
+        };
+
+        let key = ["Package", "Version", "Architecture"]
+            .iter()
+            .map(|name| field(&paragraph, name).unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        if !seen.insert(key.clone()) {
+            warn!(package = %package.display(), "skipping, `{}` is already indexed", key.join(" "));
+            continue;
+        }
+        entries.push((package, paragraph));
+    }
+
+    if let Some(keep) = changes.keep {
+        let ids = entries
+            .iter()
+            .map(|(_, paragraph)| PackageId {
+                name: field(paragraph, "Package").unwrap_or_default().to_string(),
+                arch: field(paragraph, "Architecture")
+                    .unwrap_or_default()
+                    .to_string(),
+                version: field(paragraph, "Version").unwrap_or_default().to_string(),
+            })
+            .collect::<Vec<_>>();
+        for i in superseded(&ids, keep).into_iter().rev() {
+            let (package, _) = entries.remove(i);
+            update.removed.extend(remove_package(&package)?);
+        }
+    }
+
+    let mut archs = BTreeSet::new();
+    let mut stanzas = vec![];
+    for (_, paragraph) in &entries {
+        if let Some(arch) = field(paragraph, "Architecture") {
+            archs.insert(arch.to_string());
+        }
+        stanzas.push(render_paragraph(paragraph));
+    }
+    info!(packages = %stanzas.len(), read = %read, "indexed packages");
+
+    let packages = stanzas.join("\n");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(packages.as_bytes())?;
+    let packages_gz = encoder.finish()?;
+
+    let mut release = format!(
+        "Date: {}\nArchitectures: {}\nSHA256:\n",
+        Utc::now().format("%a, %d %b %Y %H:%M:%S UTC"),
+        archs.into_iter().collect::<Vec<_>>().join(" ")
+    );
+    let indexes: [(&str, &[u8]); 2] = [
+        ("Packages", packages.as_bytes()),
+        ("Packages.gz", &packages_gz),
+    ];
+    for (name, data) in &indexes {
+        release.push_str(&format!(" {} {} {}\n", sha256(data), data.len(), name));
+    }
+
+    let saved = &mut update.saved;
+    for (name, data) in indexes.iter().chain(&[("Release", release.as_bytes())]) {
+        let path = dir.join(name);
+        fs::write(&path, data).context(format!("failed to save `{}`", path.display()))?;
+        saved.push(path);
+    }
+
+    let release_path = dir.join("Release");
+    for (name, clearsign) in &[("InRelease", true), ("Release.gpg", false)] {
+        let path = dir.join(name);
+        if let Some(signing) = signing {
+            sign_file(signing, &release_path, &path, *clearsign)
+                .context(format!("failed to sign `{}`", path.display()))?;
+            saved.push(path);
+        } else if path.exists() {
+            // a stale signature would make apt reject the new release
+            fs::remove_file(&path).context(format!("failed to remove `{}`", path.display()))?;
+        }
+    }
+
+    let key_path = dir.join(PUBLIC_KEY);
+    if let Some(signing) = signing {
+        export_public_key(signing, &key_path)
+            .context(format!("failed to save `{}`", key_path.display()))?;
+        saved.push(key_path);
+    } else if key_path.exists() {
+        fs::remove_file(&key_path).context(format!("failed to remove `{}`", key_path.display()))?;
+    }
+
+    Ok(update)
+}
+
+/// Returns the paragraphs of the saved `Packages` index by file names of the packages with the
+/// time the index was saved
+fn previous_index(dir: &Path) -> HashMap<String, (Paragraph, SystemTime)> {
+    let path = dir.join("Packages");
+    let (index, modified) = match fs::read_to_string(&path)
+        .and_then(|index| Ok((index, fs::metadata(&path)?.modified()?)))
+    {
+        Ok(index) => index,
+        Err(_) => return HashMap::new(),
+    };
+
+    index
+        .split("\n\n")
+        .filter_map(|stanza| parse_paragraph(stanza).ok())
+        .filter_map(|paragraph| {
+            let filename = field(&paragraph, "Filename")?.to_string();
+            Some((filename, (paragraph, modified)))
+        })
+        .collect()
+}
+
+/// Whether `package` has the indexed size and was not modified after the index was saved
+fn is_unchanged(package: &Path, size: Option<&str>, indexed: SystemTime) -> bool {
+    fs::metadata(package)
+        .and_then(|meta| Ok((meta.len(), meta.modified()?)))
+        .map(|(len, modified)| Some(len.to_string().as_str()) == size && modified <= indexed)
+        .unwrap_or_default()
+}
+
+/// Returns the paragraph of `package` from its control file with the fields of the file
+fn index_package(package: &Path, filename: String) -> Result<Paragraph> {
+    trace!(package = %package.display(), "indexing");
+    let data = fs::read(package).context(format!("failed to read `{}`", package.display()))?;
+    let mut paragraph = control_of(&data)
+        .and_then(|control| parse_paragraph(&control))
+        .context(format!("failed to read control of `{}`", package.display()))?;
+
+    let at = paragraph
+        .iter()
+        .position(|(name, _)| name == "Description")
+        .unwrap_or(paragraph.len());
+    let file_fields = vec![
+        ("Filename".to_string(), filename),
+        ("Size".to_string(), data.len().to_string()),
+        ("SHA256".to_string(), sha256(&data)),
+    ];
+    for (i, file_field) in file_fields.into_iter().enumerate() {
+        paragraph.insert(at + i, file_field);
+    }
+    Ok(paragraph)
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the `control` file of a DEB package
+fn control_of(deb: &[u8]) -> Result<String> {
+    if !deb.starts_with(AR_MAGIC) {
+        return Err(Error::msg("not a DEB package"));
+    }
+
+    let mut offset = AR_MAGIC.len();
+    while offset + AR_HEADER_LEN <= deb.len() {
+        let header = &deb[offset..offset + AR_HEADER_LEN];
+        let name = String::from_utf8_lossy(&header[0..16]);
+        let name = name.trim_end().trim_end_matches('/');
+        let size = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| Error::msg(format!("invalid size of archive member `{}`", name)))?;
+        let start = offset + AR_HEADER_LEN;
+        let data = deb
+            .get(start..start + size)
+            .ok_or_else(|| Error::msg(format!("archive member `{}` is truncated", name)))?;
+
+        if let Some(compression) = name.strip_prefix("control.tar") {
+            let reader: Box<dyn Read + '_> = match compression {
+                "" => Box::new(data),
+                ".gz" => Box::new(GzDecoder::new(data)),
+                ".xz" => Box::new(XzDecoder::new(data)),
+                ".zst" => Box::new(zstd::Decoder::new(data)?),
+                _ => {
+                    return Err(Error::msg(format!(
+                        "unsupported control archive `{}`",
+                        name
+                    )))
+                }
+            };
+            return read_control(reader);
+        }
+
+        // members are aligned to an even offset
+        offset = start + size + size % 2;
+    }
+
+    Err(Error::msg("package has no control archive"))
+}
+
+fn read_control(reader: impl Read) -> Result<String> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy().trim_start_matches("./") == "control" {
+            let mut control = String::new();
+            entry.read_to_string(&mut control)?;
+            return Ok(control);
+        }
+    }
+    Err(Error::msg("control archive has no `control` file"))
+}
+
+fn parse_paragraph(control: &str) -> Result<Paragraph> {
+    let mut paragraph: Paragraph = vec![];
+    for line in control.lines() {
+        if line.trim().is_empty() {
+            if paragraph.is_empty() {
+                continue;
+            }
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            let (_, value) = paragraph
+                .last_mut()
+                .ok_or_else(|| Error::msg("control starts with a continuation line"))?;
+            value.push('\n');
+            value.push_str(line);
+        } else if let Some((name, value)) = line.split_once(':') {
+            paragraph.push((name.trim().to_string(), value.trim().to_string()));
+        } else {
+            return Err(Error::msg(format!("invalid control line `{}`", line)));
+        }
+    }
+    Ok(paragraph)
+}
+
+fn field<'a>(paragraph: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    paragraph
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn render_paragraph(paragraph: &[(String, String)]) -> String {
+    let mut rendered = String::new();
+    for (name, value) in paragraph {
+        if value.starts_with('\n') || value.is_empty() {
+            rendered.push_str(&format!("{}:{}\n", name, value));
+        } else {
+            rendered.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::sign::signature_path;
+    use tempdir::TempDir;
+
+    fn deb(control: &str) -> Vec<u8> {
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(control.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "./control", control.as_bytes())
+            .unwrap();
+        let control_tar = tar.into_inner().unwrap().finish().unwrap();
+
+        let mut deb = AR_MAGIC.to_vec();
+        for (name, data) in &[
+            ("debian-binary", &b"2.0\n"[..]),
+            ("control.tar.gz", &control_tar[..]),
+        ] {
+            deb.extend(
+                format!(
+                    "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                    name,
+                    0,
+                    0,
+                    0,
+                    100644,
+                    data.len()
+                )
+                .as_bytes(),
+            );
+            deb.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                deb.push(b'\n');
+            }
+        }
+        deb
+    }
+
+    #[test]
+    fn generates_apt_repository() {
+        let dir = TempDir::new("pkger-apt-repo").unwrap();
+        let control = "Package: foo\nVersion: 1.0-1\nArchitecture: amd64\nDescription: Foo\n Longer description\n";
+        fs::create_dir(dir.path().join("debian10")).unwrap();
+        let package = deb(control);
+        fs::write(dir.path().join("debian10/foo_1.0-1_amd64.deb"), &package).unwrap();
+
+        // left from a signed run
+        fs::write(dir.path().join(PUBLIC_KEY), "key").unwrap();
+
+        let update = generate(dir.path(), None, &Changes::default()).unwrap();
+        assert_eq!(update.saved.len(), 3);
+        assert!(!dir.path().join(PUBLIC_KEY).exists());
+
+        let packages = fs::read_to_string(dir.path().join("Packages")).unwrap();
+        assert_eq!(
+            packages,
+            format!(
+                "Package: foo\nVersion: 1.0-1\nArchitecture: amd64\nFilename: debian10/foo_1.0-1_amd64.deb\nSize: {}\nSHA256: {}\nDescription: Foo\n Longer description\n",
+                package.len(),
+                sha256(&package)
+            )
+        );
+        let release = fs::read_to_string(dir.path().join("Release")).unwrap();
+        assert!(release.contains("Architectures: amd64\n"));
+        assert!(release.contains(&format!(
+            " {} {} Packages\n",
+            sha256(packages.as_bytes()),
+            packages.len()
+        )));
+
+        assert!(control_of(b"not a package").is_err());
+    }
+
+    #[test]
+    fn updates_apt_repository() {
+        let dir = TempDir::new("pkger-apt-repo").unwrap();
+        let old = dir.path().join("foo_1.0-1_amd64.deb");
+        let bar = dir.path().join("bar_1.0-1_amd64.deb");
+        fs::write(
+            &old,
+            deb("Package: foo\nVersion: 1.0-1\nArchitecture: amd64\n"),
+        )
+        .unwrap();
+        fs::write(
+            &bar,
+            deb("Package: bar\nVersion: 1.0-1\nArchitecture: amd64\n"),
+        )
+        .unwrap();
+        fs::write(signature_path(&old), "signature").unwrap();
+        generate(dir.path(), None, &Changes::default()).unwrap();
+
+        // unchanged packages are not read again
+        let index = fs::read_to_string(dir.path().join("Packages")).unwrap();
+        fs::write(
+            dir.path().join("Packages"),
+            index.replace("Package: bar\n", "Package: bar\nX-Reused: yes\n"),
+        )
+        .unwrap();
+
+        let source = TempDir::new("pkger-apt-package").unwrap();
+        let new = source.path().join("foo_1.1-1_amd64.deb");
+        fs::write(
+            &new,
+            deb("Package: foo\nVersion: 1.1-1\nArchitecture: amd64\n"),
+        )
+        .unwrap();
+        let changes = Changes {
+            add: vec![new],
+            remove: vec![],
+            keep: Some(1),
+        };
+        let update = generate(dir.path(), None, &changes).unwrap();
+        assert_eq!(update.added, vec![dir.path().join("foo_1.1-1_amd64.deb")]);
+        assert_eq!(update.removed, vec![old.clone(), signature_path(&old)]);
+        assert!(!old.exists());
+
+        let index = fs::read_to_string(dir.path().join("Packages")).unwrap();
+        assert!(index.contains("X-Reused: yes\n"));
+        assert!(index.contains("Filename: foo_1.1-1_amd64.deb\n"));
+        assert!(!index.contains("Version: 1.0-1\nArchitecture: amd64\nFilename: foo"));
+
+        let changes = Changes {
+            remove: vec!["bar_1.0-1_amd64.deb".to_string()],
+            ..Default::default()
+        };
+        let update = generate(dir.path(), None, &changes).unwrap();
+        assert_eq!(update.removed, vec![bar]);
+        let index = fs::read_to_string(dir.path().join("Packages")).unwrap();
+        assert!(!index.contains("Package: bar"));
+
+        let changes = Changes {
+            remove: vec!["../missing.deb".to_string()],
+            ..Default::default()
+        };
+        assert!(generate(dir.path(), None, &changes).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Package repositories generated from built packages, so that the output directory can be served
+//! to package managers directly without a separate tool managing the repository.
+pub mod apt;
+pub mod yum;
+
+use crate::build::sign::signature_path;
+use crate::recipe::compare_versions;
+use crate::{ErrContext, Error, Result};
+
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tracing::info;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RepoType {
+    Apt,
+    Yum,
+}
+
+impl FromStr for RepoType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "apt" | "deb" => Ok(RepoType::Apt),
+            "yum" | "dnf" | "rpm" => Ok(RepoType::Yum),
+            _ => Err(Error::msg(format!("unknown repository type {}", s))),
+        }
+    }
+}
+
+impl AsRef<str> for RepoType {
+    fn as_ref(&self) -> &str {
+        match self {
+            RepoType::Apt => "apt",
+            RepoType::Yum => "yum",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RepoConfig {
+    /// Number of the newest versions of every package kept in repositories, older ones are
+    /// removed when the metadata is updated. All versions are kept by default.
+    pub keep_versions: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default)]
+/// Changes of the packages of a repository applied before its metadata is updated
+pub struct Changes {
+    /// Packages copied into the root of the repository, packages already in the repository are
+    /// only indexed
+    pub add: Vec<PathBuf>,
+    /// Packages removed from the repository, by paths relative to it
+    pub remove: Vec<String>,
+    /// Number of the newest versions of every package that are kept
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+/// Files changed by an update of a repository
+pub struct Update {
+    /// Saved metadata of the repository
+    pub saved: Vec<PathBuf>,
+    /// Packages added to the repository
+    pub added: Vec<PathBuf>,
+    /// Removed packages and their signatures
+    pub removed: Vec<PathBuf>,
+}
+
+/// Name, architecture and version of a package, the version includes the epoch and release
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PackageId {
+    pub name: String,
+    pub arch: String,
+    pub version: String,
+}
+
+/// Copies the added packages into `dir` and removes the removed ones with their signatures
+pub(crate) fn apply_changes(dir: &Path, changes: &Changes, extension: &str) -> Result<Update> {
+    let mut update = Update::default();
+    for package in &changes.add {
+        if package
+            .extension()
+            .map(|ext| ext != extension)
+            .unwrap_or(true)
+        {
+            return Err(Error::msg(format!(
+                "`{}` is not a `{}` package",
+                package.display(),
+                extension
+            )));
+        }
+        let canonical =
+            fs::canonicalize(package).context(format!("failed to read `{}`", package.display()))?;
+        let dest = if canonical.starts_with(fs::canonicalize(dir)?) {
+            package.clone()
+        } else {
+            let dest = dir.join(package.file_name().unwrap_or_default());
+            fs::copy(package, &dest).context(format!(
+                "failed to copy `{}` to the repository",
+                package.display()
+            ))?;
+            dest
+        };
+        info!(package = %dest.display(), "added");
+        update.added.push(dest);
+    }
+
+    for package in &changes.remove {
+        let relative = Path::new(package);
+        let path = dir.join(relative);
+        let is_inside = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !is_inside
+            || !path.is_file()
+            || path.extension().map(|ext| ext != extension).unwrap_or(true)
+        {
+            return Err(Error::msg(format!(
+                "package `{}` is not in the repository",
+                package
+            )));
+        }
+        update.removed.extend(remove_package(&path)?);
+    }
+    Ok(update)
+}
+
+/// Removes `package` and its signature returning the paths of the removed files
+pub(crate) fn remove_package(package: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    for path in [package.to_path_buf(), signature_path(package)] {
+        if path.exists() {
+            fs::remove_file(&path).context(format!("failed to remove `{}`", path.display()))?;
+            removed.push(path);
+        }
+    }
+    info!(package = %package.display(), "removed");
+    Ok(removed)
+}
+
+/// Compares versions of packages like `1:2.0-1`, a missing epoch is `0`
+pub(crate) fn compare_package_versions(a: &str, b: &str) -> Ordering {
+    fn split(version: &str) -> (u64, &str) {
+        match version.split_once(':') {
+            Some((epoch, version)) if epoch.chars().all(|c| c.is_ascii_digit()) => {
+                (epoch.parse().unwrap_or_default(), version)
+            }
+            _ => (0, version),
+        }
+    }
+    let ((epoch_a, a), (epoch_b, b)) = (split(a), split(b));
+    epoch_a.cmp(&epoch_b).then_with(|| compare_versions(a, b))
+}
+
+/// Returns the indices of `packages` that have at least `keep` newer versions with the same name
+/// and architecture
+pub(crate) fn superseded(packages: &[PackageId], keep: usize) -> Vec<usize> {
+    let mut groups: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (i, package) in packages.iter().enumerate() {
+        groups
+            .entry((&package.name, &package.arch))
+            .or_default()
+            .push(i);
+    }
+
+    let mut superseded = vec![];
+    for (_, mut group) in groups {
+        group
+            .sort_by(|a, b| compare_package_versions(&packages[*b].version, &packages[*a].version));
+        superseded.extend(group.into_iter().skip(keep));
+    }
+    superseded.sort_unstable();
+    superseded
+}
+
+/// Returns the paths of all files with `extension` in `dir` and its subdirectories in a stable
+/// order
+pub(crate) fn find_packages(dir: &Path, extension: &str) -> Result<Vec<PathBuf>> {
+    let mut packages = vec![];
+    let entries =
+        fs::read_dir(dir).context(format!("failed to read directory `{}`", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            packages.extend(find_packages(&path, extension)?);
+        } else if path
+            .extension()
+            .map(|ext| ext == extension)
+            .unwrap_or_default()
+        {
+            packages.push(path);
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// Path of `package` relative to the root of the repository with `/` as separator
+pub(crate) fn relative_path(root: &Path, package: &Path) -> String {
+    package
+        .strip_prefix(root)
+        .unwrap_or(package)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_repo_type() {
+        assert_eq!(RepoType::from_str("deb").unwrap(), RepoType::Apt);
+        assert_eq!(RepoType::from_str("dnf").unwrap(), RepoType::Yum);
+        assert!(RepoType::from_str("apk").is_err());
+        assert_eq!(
+            relative_path(Path::new("/out"), Path::new("/out/centos8/foo.rpm")),
+            "centos8/foo.rpm"
+        );
+    }
+
+    #[test]
+    fn finds_superseded_versions() {
+        let package = |name: &str, arch: &str, version: &str| PackageId {
+            name: name.to_string(),
+            arch: arch.to_string(),
+            version: version.to_string(),
+        };
+        let packages = vec![
+            package("foo", "amd64", "1.9-1"),
+            package("foo", "amd64", "1:0.1-1"),
+            package("foo", "amd64", "1.10-1"),
+            package("foo", "arm64", "1.9-1"),
+            package("bar", "amd64", "1.0-2"),
+            package("bar", "amd64", "1.0-10"),
+        ];
+        assert_eq!(superseded(&packages, 1), vec![0, 2, 4]);
+        assert_eq!(superseded(&packages, 2), vec![0]);
+        assert!(superseded(&packages, 3).is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+//! YUM/DNF repositories created with `createrepo_c`. If `createrepo_c` is not installed on the
+//! host it runs in a container and the packages are copied into it through the Docker API, so
+//! remote Docker daemons work as well.
+use crate::build::image::{ImageBuildError, LATEST};
+use crate::build::sign::{export_public_key, sign_file, signature_path, SigningConfig};
+use crate::container::{DockerContainer, ExecOpts};
+use crate::docker::{
+    api::{BuildOpts, ContainerCreateOpts, ImageBuildChunk},
+    Docker,
+};
+use crate::labels;
+use crate::repo::{
+    apply_changes, find_packages, relative_path, remove_package, superseded, Changes, PackageId,
+    Update,
+};
+use crate::{ErrContext, Error, Result};
+
+use futures::StreamExt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+use tempdir::TempDir;
+use tracing::{info, info_span, trace, warn, Instrument};
+
+/// Name of the image in which `createrepo_c` runs when it's missing on the host
+pub static CREATEREPO_IMAGE: &str = "pkger-createrepo";
+
+static CREATEREPO_DOCKERFILE: &str = r#"FROM fedora:latest
+RUN dnf install -y createrepo_c && dnf clean all
+"#;
+
+static CREATEREPO: &str = "createrepo_c";
+
+/// File in `repodata/` with the public key of a signed repository
+pub static PUBLIC_KEY: &str = "repomd.xml.key";
+
+/// Directory of the repository in the container
+static CONTAINER_REPO_DIR: &str = "/repo";
+
+const RPM_LEAD_LEN: usize = 96;
+static RPM_LEAD_MAGIC: &[u8] = &[0xed, 0xab, 0xee, 0xdb];
+static RPM_HEADER_MAGIC: &[u8] = &[0x8e, 0xad, 0xe8, 0x01];
+
+const RPMTAG_NAME: u32 = 1000;
+const RPMTAG_VERSION: u32 = 1001;
+const RPMTAG_RELEASE: u32 = 1002;
+const RPMTAG_EPOCH: u32 = 1003;
+const RPMTAG_ARCH: u32 = 1022;
+
+/// Updates `repodata/` in `dir` indexing all RPM packages in `dir` and its subdirectories,
+/// `createrepo_c` reuses the metadata of packages unchanged since the last update. With `signing`
+/// the `repomd.xml` file is also signed as `repomd.xml.asc` and the public key is saved as
+/// `repomd.xml.key`.
+pub async fn generate(
+    dir: &Path,
+    signing: Option<&SigningConfig>,
+    changes: &Changes,
+    docker: &Docker,
+) -> Result<Update> {
+    let span = info_span!("yum-repo", dir = %dir.display());
+    async move {
+        let mut update = apply_changes(dir, changes, "rpm")?;
+        if let Some(keep) = changes.keep {
+            let packages = find_packages(dir, "rpm")?;
+            let ids = packages
+                .iter()
+                .map(|package| {
+                    package_id(package)
+                        .context(format!("failed to read header of `{}`", package.display()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for i in superseded(&ids, keep) {
+                update.removed.extend(remove_package(&packages[i])?);
+            }
+        }
+
+        let repodata = dir.join("repodata");
+        if createrepo_on_host() {
+            info!("running createrepo_c on the host");
+            let status = Command::new(CREATEREPO)
+                .arg("--update")
+                .arg(dir)
+                .stdout(Stdio::null())
+                .status()
+                .context("failed to run createrepo_c")?;
+            if !status.success() {
+                return Err(Error::msg(format!("createrepo_c exited with {}", status)));
+            }
+        } else {
+            info!("createrepo_c is not installed, running it in a container");
+            createrepo_in_container(dir, &repodata, docker).await?;
+        }
+
+        let saved = &mut update.saved;
+        saved.push(repodata.clone());
+        let repomd = repodata.join("repomd.xml");
+        let signature = signature_path(&repomd);
+        let key = repodata.join(PUBLIC_KEY);
+        if let Some(signing) = signing {
+            sign_file(signing, &repomd, &signature, false)
+                .context(format!("failed to sign `{}`", repomd.display()))?;
+            export_public_key(signing, &key)
+                .context(format!("failed to save `{}`", key.display()))?;
+            saved.extend([signature, key]);
+        } else {
+            // a stale signature would make dnf reject the new metadata
+            for path in &[signature, key] {
+                if path.exists() {
+                    fs::remove_file(path)
+                        .context(format!("failed to remove `{}`", path.display()))?;
+                }
+            }
+        }
+
+        Ok(update)
+    }
+    .instrument(span)
+    .await
+}
+
+fn createrepo_on_host() -> bool {
+    Command::new(CREATEREPO)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or_default()
+}
+
+async fn createrepo_in_container(dir: &Path, repodata: &Path, docker: &Docker) -> Result<()> {
+    let image = build_image(docker)
+        .await
+        .context("failed to build createrepo image")?;
+
+    let labels = labels::common();
+    let opts = ContainerCreateOpts::builder(&image)
+        .cmd(vec!["sleep", "infinity"])
+        .labels(labels::as_opts(&labels))
+        .build();
+    let mut container = DockerContainer::new(docker, None);
+    container
+        .spawn(&opts)
+        .await
+        .context("failed to spawn container")?;
+
+    let result = run_createrepo(&container, dir, repodata).await;
+    if let Err(e) = container.remove().await {
+        warn!(reason = %e, "failed to remove container");
+    }
+    result
+}
+
+async fn run_createrepo(
+    container: &DockerContainer<'_>,
+    dir: &Path,
+    repodata: &Path,
+) -> Result<()> {
+    // existing metadata is copied as well so that `createrepo_c --update` only reads the
+    // packages whose size or modification time changed
+    let mut files = find_packages(dir, "rpm")?;
+    info!(packages = %files.len(), "copying packages to container");
+    if repodata.is_dir() {
+        for entry in fs::read_dir(repodata)? {
+            files.push(entry?.path());
+        }
+    }
+
+    let mut archive = tar::Builder::new(Vec::new());
+    for file in &files {
+        let data = fs::read(file).context(format!("failed to read `{}`", file.display()))?;
+        let mtime = fs::metadata(file)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|mtime| mtime.as_secs())
+            .unwrap_or_default();
+        let dest = Path::new(CONTAINER_REPO_DIR).join(relative_path(dir, file));
+        trace!(file = %file.display(), dest = %dest.display(), "copying");
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        archive.append_data(&mut header, dest.strip_prefix("/")?, data.as_slice())?;
+    }
+    container
+        .inner()
+        .copy_to(Path::new("/"), archive.into_inner()?.into())
+        .await
+        .context("failed to copy packages to container")?;
+
+    let out = container
+        .exec(
+            &ExecOpts::default()
+                .cmd(&format!(
+                    "mkdir -p {0} && {1} --update {0}",
+                    CONTAINER_REPO_DIR, CREATEREPO
+                ))
+                .build(),
+        )
+        .await?;
+    if out.exit_code != 0 {
+        return Err(Error::msg(format!(
+            "createrepo_c exited with code {}\nstderr:\n{}",
+            out.exit_code,
+            out.stderr.join("").trim()
+        )));
+    }
+
+    if repodata.exists() {
+        fs::remove_dir_all(repodata)
+            .context(format!("failed to remove `{}`", repodata.display()))?;
+    }
+    fs::create_dir_all(repodata)?;
+    container
+        .download_files(&Path::new(CONTAINER_REPO_DIR).join("repodata"), repodata)
+        .await
+        .context("failed to download repository metadata")
+}
+
+/// Reads the name, architecture and version of an RPM package from its header
+fn package_id(package: &Path) -> Result<PackageId> {
+    let mut file = fs::File::open(package)?;
+    let mut lead = [0; RPM_LEAD_LEN];
+    file.read_exact(&mut lead)?;
+    if !lead.starts_with(RPM_LEAD_MAGIC) {
+        return Err(Error::msg("not an RPM package"));
+    }
+
+    // the signature header is padded to 8 bytes, the main header follows it
+    let (entries, data) = read_header(&mut file)?;
+    let mut padding = vec![0; (8 - (entries.len() + data.len()) % 8) % 8];
+    file.read_exact(&mut padding)?;
+    let (entries, data) = read_header(&mut file)?;
+
+    let mut id = PackageId {
+        name: String::new(),
+        arch: String::new(),
+        version: String::new(),
+    };
+    let (mut epoch, mut version, mut release) = (None, String::new(), String::new());
+    for entry in entries.chunks(16) {
+        let value =
+            |i: usize| u32::from_be_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+        let (tag, offset) = (value(0), value(8) as usize);
+        let string = || {
+            data.get(offset..)
+                .and_then(|data| data.split(|b| *b == 0).next())
+                .map(|s| String::from_utf8_lossy(s).to_string())
+                .unwrap_or_default()
+        };
+        match tag {
+            RPMTAG_NAME => id.name = string(),
+            RPMTAG_VERSION => version = string(),
+            RPMTAG_RELEASE => release = string(),
+            RPMTAG_ARCH => id.arch = string(),
+            RPMTAG_EPOCH => {
+                epoch = data
+                    .get(offset..offset + 4)
+                    .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            }
+            _ => {}
+        }
+    }
+    if id.name.is_empty() || version.is_empty() {
+        return Err(Error::msg("header has no name or version"));
+    }
+
+    id.version = format!("{}:{}-{}", epoch.unwrap_or_default(), version, release);
+    Ok(id)
+}
+
+/// Reads a header structure returning its index entries and data
+fn read_header(file: &mut impl Read) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut intro = [0; 16];
+    file.read_exact(&mut intro)?;
+    if !intro.starts_with(RPM_HEADER_MAGIC) {
+        return Err(Error::msg("invalid header magic"));
+    }
+    let entries_count = u32::from_be_bytes([intro[8], intro[9], intro[10], intro[11]]) as usize;
+    let data_len = u32::from_be_bytes([intro[12], intro[13], intro[14], intro[15]]) as usize;
+
+    let mut entries = vec![0; entries_count * 16];
+    file.read_exact(&mut entries)?;
+    let mut data = vec![0; data_len];
+    file.read_exact(&mut data)?;
+    Ok((entries, data))
+}
+
+/// Builds the image with `createrepo_c`, the build is cached by Docker after the first time
+async fn build_image(docker: &Docker) -> Result<String> {
+    let dir = TempDir::new(CREATEREPO_IMAGE)?;
+    fs::write(dir.path().join("Dockerfile"), CREATEREPO_DOCKERFILE)?;
+
+    let tag = format!("{}:{}", CREATEREPO_IMAGE, LATEST);
+    let opts = BuildOpts::builder(dir.path()).tag(&tag).build();
+    let images = docker.images();
+    let mut stream = images.build(&opts);
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            ImageBuildChunk::Error {
+                error,
+                error_detail: _,
+            } => {
+                return Err(ImageBuildError {
+                    image: CREATEREPO_IMAGE.to_string(),
+                    message: error,
+                }
+                .into());
+            }
+            ImageBuildChunk::Update { stream } => trace!("{}", stream.trim()),
+            _ => {}
+        }
+    }
+
+    Ok(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn rpm(tags: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let header = |tags: &[(u32, u32, &[u8])]| {
+            let (mut entries, mut data) = (vec![], vec![]);
+            for (tag, kind, value) in tags {
+                for field in &[*tag, *kind, data.len() as u32, 1] {
+                    entries.extend_from_slice(&field.to_be_bytes());
+                }
+                data.extend_from_slice(value);
+            }
+            let mut header = RPM_HEADER_MAGIC.to_vec();
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            header.extend(entries);
+            header.extend(data);
+            header
+        };
+
+        let mut rpm = RPM_LEAD_MAGIC.to_vec();
+        rpm.resize(RPM_LEAD_LEN, 0);
+        // the signature header is padded to 8 bytes
+        rpm.extend(header(&[(62, 7, b"sig")]));
+        rpm.resize(rpm.len() + 5, 0);
+        rpm.extend(header(tags));
+        rpm
+    }
+
+    #[test]
+    fn reads_rpm_header() {
+        let dir = TempDir::new("pkger-yum-repo").unwrap();
+        let path = dir.path().join("foo-1.0-1.x86_64.rpm");
+        fs::write(
+            &path,
+            rpm(&[
+                (RPMTAG_NAME, 6, b"foo\0"),
+                (RPMTAG_EPOCH, 4, &2u32.to_be_bytes()),
+                (RPMTAG_VERSION, 6, b"1.0\0"),
+                (RPMTAG_RELEASE, 6, b"1.el8\0"),
+                (RPMTAG_ARCH, 6, b"x86_64\0"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            package_id(&path).unwrap(),
+            PackageId {
+                name: "foo".to_string(),
+                arch: "x86_64".to_string(),
+                version: "2:1.0-1.el8".to_string(),
+            }
+        );
+
+        fs::write(&path, b"not a package").unwrap();
+        assert!(package_id(&path).is_err());
+    }
+}
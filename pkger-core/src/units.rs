@@ -0,0 +1,140 @@
+//! Human friendly formatting of durations and sizes in the output, like `3m 42s` or `1.5 MiB`.
+//! When the output is meant for machines raw numbers of seconds and bytes are printed instead.
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+static SIZE_UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Languages that separate the fractional part of numbers with a comma
+static COMMA_LANGUAGES: &[&str] = &[
+    "cs", "da", "de", "es", "fi", "fr", "id", "it", "nb", "nl", "pl", "pt", "ru", "sk", "sv", "tr",
+    "uk",
+];
+
+/// Makes all durations and sizes print as raw numbers of seconds and bytes
+pub fn set_raw(raw: bool) {
+    RAW.store(raw, Ordering::Relaxed);
+}
+
+fn is_raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+/// Returns the decimal separator of the locale set in the environment
+fn decimal_separator() -> char {
+    let locale = ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_default();
+    decimal_separator_of(&locale)
+}
+
+fn decimal_separator_of(locale: &str) -> char {
+    let language = locale.split(['_', '.', '@']).next();
+    match language {
+        Some(language) if COMMA_LANGUAGES.contains(&language) => ',',
+        _ => '.',
+    }
+}
+
+/// Formats a number with one decimal place using `separator`
+fn decimal(value: f64, separator: char) -> String {
+    let formatted = format!("{:.1}", value);
+    if separator == '.' {
+        formatted
+    } else {
+        formatted.replace('.', &separator.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Displays a duration like `3m 42s`, or a number of seconds in raw mode
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    fn render(&self, separator: char) -> String {
+        let secs = self.0.as_secs();
+        let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if hours > 0 {
+            format!("{}h {}m {}s", hours, minutes, seconds)
+        } else if minutes > 0 {
+            format!("{}m {}s", minutes, seconds)
+        } else {
+            format!("{}s", decimal(self.0.as_secs_f64(), separator))
+        }
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_raw() {
+            write!(f, "{}", self.0.as_secs_f64())
+        } else {
+            f.write_str(&self.render(decimal_separator()))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Displays a number of bytes in binary units like `1.5 MiB`, or the number itself in raw mode
+pub struct HumanSize(pub u64);
+
+impl HumanSize {
+    fn render(&self, separator: char) -> String {
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1024. && unit < SIZE_UNITS.len() - 1 {
+            size /= 1024.;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} {}", self.0, SIZE_UNITS[0])
+        } else {
+            format!("{} {}", decimal(size, separator), SIZE_UNITS[unit])
+        }
+    }
+}
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_raw() {
+            write!(f, "{}", self.0)
+        } else {
+            f.write_str(&self.render(decimal_separator()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_durations_and_sizes() {
+        assert_eq!(
+            HumanDuration(Duration::from_secs(222)).render('.'),
+            "3m 42s"
+        );
+        assert_eq!(
+            HumanDuration(Duration::from_secs(3725)).render('.'),
+            "1h 2m 5s"
+        );
+        assert_eq!(
+            HumanDuration(Duration::from_millis(4250)).render(','),
+            "4,2s"
+        );
+
+        assert_eq!(HumanSize(512).render('.'), "512 B");
+        assert_eq!(HumanSize(1536 * 1024).render('.'), "1.5 MiB");
+        assert_eq!(HumanSize(10 << 30).render(','), "10,0 GiB");
+
+        assert_eq!(decimal_separator_of("pl_PL.UTF-8"), ',');
+        assert_eq!(decimal_separator_of("en_US.UTF-8"), '.');
+        assert_eq!(decimal_separator_of("C"), '.');
+    }
+}